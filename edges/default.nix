@@ -12,6 +12,7 @@ in
   KvKeyTValT = callPackage ./kv/key/t/val/t {};
   KvKeyTValI64 = callPackage ./kv/key/t/val/i64 {};
   KvListKeyTValT = callPackage ./kv/list/key/t/val/t {};
+  MathsDecimal = callPackage ./maths/decimal {};
   NtupTupleTt = callPackage ./ntup/tuple/tt {};
   NtupTupleTb = callPackage ./ntup/tuple/tb {};
   NtupTripleTtt = callPackage ./ntup/triple/ttt {};
@@ -28,6 +29,9 @@ in
   NetNdnEdges = buffet.fractals.net_ndn.edges;
   NetProtocolDomainPort = callPackage ./net/protocol/domain/port {};
   NetUrl = callPackage ./net/url {};
+  TimeDate = callPackage ./time/date {};
+  TimeDateTime = callPackage ./time/date/time {};
+  TimeDuration = callPackage ./time/duration {};
 
   # draft
   CoreAction = callPackage ./core/action {};
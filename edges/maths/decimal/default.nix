@@ -0,0 +1,15 @@
+{ edge, edges }:
+
+edge {
+  src = ./.;
+  edges =  with edges; [];
+  schema = with edges; ''
+    # Exact decimal, the value is mantissa * 10^-scale: 123.45 is mantissa 12345, scale 2.
+    # The scale keeps the trailing zeros, 1.50 is mantissa 150, scale 2.
+
+    struct MathsDecimal {
+            mantissa @0 :Int64;
+            scale @1 :Int8;
+    }
+  '';
+}
@@ -0,0 +1,15 @@
+{ edge, edges }:
+
+edge {
+  src = ./.;
+  edges =  with edges; [];
+  schema = with edges; ''
+    # Proleptic Gregorian calendar, month and day start at 1.
+
+    struct TimeDate {
+            year @0 :Int16;
+            month @1 :UInt8;
+            day @2 :UInt8;
+    }
+  '';
+}
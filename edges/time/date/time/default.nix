@@ -0,0 +1,15 @@
+{ edge, edges }:
+
+edge {
+  src = ./.;
+  edges =  with edges; [ TimeDate ];
+  schema = with edges; ''
+    struct TimeDateTime {
+            date @0 :TimeDate;
+            hour @1 :UInt8;
+            minute @2 :UInt8;
+            second @3 :UInt8;
+            nanosecond @4 :UInt32;
+    }
+  '';
+}
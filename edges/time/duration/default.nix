@@ -0,0 +1,17 @@
+{ edge, edges }:
+
+edge {
+  src = ./.;
+  edges =  with edges; [];
+  schema = with edges; ''
+    # Calendar duration, added to a TimeDate by rustfbp::date::date_add: the years and the months
+    # together, then the days. When the month is shorter, the day is clamped to its last day
+    # (Jan 31 + 1 month is Feb 28 or 29).
+
+    struct TimeDuration {
+            years @0 :Int32;
+            months @1 :Int32;
+            days @2 :Int32;
+    }
+  '';
+}
@@ -2,6 +2,6 @@
 
 crate {
   name = "rustfbp";
-  mods = with crates; [ capnp libloading threadpool ];
+  mods = with crates; [ capnp libloading threadpool log chrono lazy_static ];
   src = ./.;
 }
@@ -8,10 +8,12 @@
 extern crate capnp;
 
 // TODO : Add method to remove agents
-use ports::{MsgSender, MsgReceiver};
+use ports::{Msg, MsgSender, MsgReceiver};
 use scheduler::Signal;
 use result::Result;
 
+use std::collections::HashMap;
+
 /// Provide the generic functions of agents
 ///
 /// These three functions are used by the scheduler
@@ -24,8 +26,55 @@ pub trait Agent {
     fn connect_array(&mut self, port: &str, element: String, sender: MsgSender) -> Result<()>;
     /// Add input element
     fn add_inarr_element(&mut self, port: &str, element: String, recv: MsgReceiver) -> Result<()>;
+    /// Remove input element, the Msgs waiting on it are dropped
+    fn remove_inarr_element(&mut self, port: &str, element: &str) -> Result<()>;
+    /// Disconnect output port
+    fn disconnect(&mut self, port: &str) -> Result<()>;
+    /// Disconnect an element of an array output port
+    fn disconnect_array(&mut self, port: &str, element: &str) -> Result<()>;
+    /// Replace the receiver of an input port
+    fn set_receiver(&mut self, port: &str, recv: MsgReceiver) -> Result<()>;
     /// Run the method of the agent, his personal logic
     fn run(&mut self) -> Result<Signal>;
+    /// Read the config set with `Scheduler::configure`, called before `on_start`
+    fn configure(&mut self, _config: &[u8]) -> Result<()> { Ok(()) }
+    /// Called once, before the first run
+    fn on_start(&mut self) -> Result<()> { Ok(()) }
+    /// Called once, when the scheduler stops
+    fn on_stop(&mut self) -> Result<()> { Ok(()) }
+    /// Put back the state of the agent to its initial value, keeping the ports connected
+    fn reset(&mut self) {}
+    /// Save the state of the agent, `None` if it has nothing to save
+    fn checkpoint(&self) -> Option<Vec<u8>> { None }
+    /// Load a state saved by `checkpoint`, called after `on_start`
+    fn restore(&mut self, _data: &[u8]) -> Result<()> { Ok(()) }
+    /// Destroy the agent and keep its ports
+    fn into_ports(self: Box<Self>) -> AgentPorts;
+    /// Replace the ports of the agent. The ports that the agent doesn't have are dropped
+    fn set_ports(&mut self, ports: AgentPorts);
+    /// The declared ports of the agent
+    fn ports(&self) -> PortList;
+}
+
+/// The declared ports of an agent, with the type id of their capnp schema, `None` for `any`
+///
+/// The elements of the array ports are not listed, only the array ports.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct PortList {
+    pub inputs: Vec<(String, Option<u64>)>,
+    pub outputs: Vec<(String, Option<u64>)>,
+    pub array_inputs: Vec<(String, Option<u64>)>,
+    pub array_outputs: Vec<(String, Option<u64>)>,
+}
+
+/// All the ports of an agent, to move them to another agent
+pub struct AgentPorts {
+    pub inputs: HashMap<String, MsgReceiver>,
+    pub inputs_array: HashMap<String, HashMap<String, MsgReceiver>>,
+    pub outputs: HashMap<String, MsgSender>,
+    pub outputs_array: HashMap<String, HashMap<String, MsgSender>>,
+    /// The last Msg received on the option port
+    pub option_msg: Option<Msg>,
 }
 
 
@@ -59,6 +108,50 @@ pub trait Agent {
 ///    }
 /// }
 /// ```
+///
+/// `Scheduler::connect` compares the type ids of the capnp schemas of the two ports, and
+/// returns `Error::TypeMismatch` if they differ. A port of the edge `any` accepts every
+/// schema. `Agent::ports` lists the declared ports with these type ids.
+///
+/// An output port can also be chosen at runtime by its name :
+///
+/// ```rust,ignore
+///    let port = if odd { "odd" } else { "even" };
+///    try!(self.output.send_to(port, msg));
+/// ```
+///
+/// The optional hooks `configure`, `on_start` and `on_stop` can follow `run`. `configure`
+/// gets the config set with `Scheduler::configure`, usually a capnp message :
+///
+/// ```rust,ignore
+///    fn configure(&mut self, config: &[u8]) -> Result<()> {
+///        let reader = try!(capnp::serialize::read_message(&mut &config[..], capnp::message::ReaderOptions::new()));
+///        let config: prim_text::Reader = try!(reader.get_root());
+///        self.state.path = try!(config.get_text()).into();
+///        Ok(())
+///    }
+///    fn on_start(&mut self) -> Result<()> {
+///        // Open the file
+///        Ok(())
+///    }
+///    fn on_stop(&mut self) -> Result<()> {
+///        // Flush the file
+///        Ok(())
+///    }
+/// ```
+///
+/// Then `checkpoint` and `restore`, to save the state of the agent with
+/// `Scheduler::checkpoint_all` and load it back with `Scheduler::restore_all` :
+///
+/// ```rust,ignore
+///    fn checkpoint(&self) -> Option<Vec<u8>> {
+///        Some(format!("{}", self.state.count).into_bytes())
+///    }
+///    fn restore(&mut self, data: &[u8]) -> Result<()> {
+///        self.state.count = try!(String::from_utf8(data.to_vec())).parse().unwrap_or(0);
+///        Ok(())
+///    }
+/// ```
 #[macro_export]
 macro_rules! agent {
     (
@@ -70,20 +163,27 @@ macro_rules! agent {
         $( option($option:ident), )*
         $( accumulator($accumulator:ident ), )*
         fn run(&mut $arg:ident) -> Result<Signal> $fun:block
+        $( fn configure(&mut $configure_arg:ident, $configure_data:ident: &[u8]) -> Result<()> $configure_fun:block )*
+        $( fn on_start(&mut $start_arg:ident) -> Result<()> $start_fun:block )*
+        $( fn on_stop(&mut $stop_arg:ident) -> Result<()> $stop_fun:block )*
+        $( fn checkpoint(&$checkpoint_arg:ident) -> Option<Vec<u8>> $checkpoint_fun:block )*
+        $( fn restore(&mut $restore_arg:ident, $restore_data:ident: &[u8]) -> Result<()> $restore_fun:block )*
     )
         =>
     {
-        use rustfbp::agent::Agent;
+        use rustfbp::agent::{Agent, AgentPorts, PortList};
 
         use rustfbp::result;
         use rustfbp::result::Result;
-        use rustfbp::scheduler::{CompMsg, Signal};
+        use rustfbp::scheduler::{CompMsg, Signal, AgentCtx};
         use std::error::Error;
 
         use std::sync::mpsc::{Sender};
         use std::sync::mpsc::channel;
 
-        use rustfbp::ports::{Msg, MsgSender, MsgReceiver, OutputSend};
+        use rustfbp::ports::{Msg, MsgSender, MsgReceiver, OutputSend, OutputArraySend, Disconnected};
+        #[allow(unused_imports)]
+        use rustfbp::ports::{MsgKind, forward_brackets};
 
         #[allow(unused_imports)]
         use std::collections::HashMap;
@@ -144,6 +244,9 @@ macro_rules! agent {
 
             fn connect(&mut self, port: &str, sender: MsgSender) -> Result<()> {
                 match port {
+                    "accumulator" => {
+                        self.output.accumulator = Some(sender);
+                    }
                     $($(
                         stringify!($output_name) => {
                             self.output.$output_name = Some(sender);
@@ -170,6 +273,51 @@ macro_rules! agent {
                 Ok(())
             }
 
+            fn disconnect(&mut self, port: &str) -> Result<()> {
+                match port {
+                    $($(
+                        stringify!($output_name) => {
+                            self.output.$output_name = None;
+                        }
+                    )*)*
+                        _ => {
+                            return Err(result::Error::PortDontExist(port.into()));
+                        }
+                }
+                Ok(())
+            }
+
+            fn disconnect_array(&mut self, port: &str, element: &str) -> Result<()> {
+                match port {
+                    $($(
+                        stringify!($output_a_name) => {
+                            self.outarr.$output_a_name.remove(element);
+                        }
+                    )*)*
+                        _ => {
+                            return Err(result::Error::PortDontExist(port.into()));
+                        }
+                }
+                Ok(())
+            }
+
+            fn remove_inarr_element(&mut self, port: &str, element: &str) -> Result<()> {
+                match port {
+                    $($(
+                        stringify!($input_a_name) => {
+                            if let Some(recv) = self.inarr.$input_a_name.remove(element) {
+                                // Account for the Msgs that will never be received
+                                while let Ok(_) = recv.try_recv() {}
+                            }
+                            Ok(())
+                        }
+                    )*)*
+                        _ => {
+                            Err(result::Error::PortDontExist(port.into()))
+                        }
+                }
+            }
+
             fn add_inarr_element(&mut self, port: &str, element: String, recv: MsgReceiver) -> Result<()> {
                 match port {
                     $($(
@@ -184,8 +332,134 @@ macro_rules! agent {
                 }
             }
 
+            fn set_receiver(&mut self, port: &str, recv: MsgReceiver) -> Result<()> {
+                match port {
+                    "option" => {
+                        self.input.option = recv;
+                    }
+                    "accumulator" => {
+                        self.input.accumulator = recv;
+                    }
+                    $($(
+                        stringify!($input_name) => {
+                            self.input.$input_name = recv;
+                        }
+                    )*)*
+                        _ => {
+                            return Err(result::Error::PortDontExist(port.into()));
+                        }
+                }
+                Ok(())
+            }
+
             fn run(&mut $arg) -> Result<Signal> $fun
 
+            $(
+            fn configure(&mut $configure_arg, $configure_data: &[u8]) -> Result<()> $configure_fun
+            )*
+
+            $(
+            fn on_start(&mut $start_arg) -> Result<()> $start_fun
+            )*
+
+            $(
+            fn on_stop(&mut $stop_arg) -> Result<()> $stop_fun
+            )*
+
+            $(
+            fn checkpoint(&$checkpoint_arg) -> Option<Vec<u8>> $checkpoint_fun
+            )*
+
+            $(
+            fn restore(&mut $restore_arg, $restore_data: &[u8]) -> Result<()> $restore_fun
+            )*
+
+            fn into_ports(self: Box<Self>) -> AgentPorts {
+                let this = *self;
+                let mut inputs = HashMap::new();
+                inputs.insert("option".to_string(), this.input.option);
+                inputs.insert("accumulator".to_string(), this.input.accumulator);
+                $($(
+                    inputs.insert(stringify!($input_name).to_string(), this.input.$input_name);
+                )*)*
+                #[allow(unused_mut)]
+                let mut inputs_array = HashMap::new();
+                $($(
+                    inputs_array.insert(stringify!($input_a_name).to_string(), this.inarr.$input_a_name);
+                )*)*
+                let mut outputs = HashMap::new();
+                if let Some(sender) = this.output.accumulator {
+                    outputs.insert("accumulator".to_string(), sender);
+                }
+                $($(
+                    if let Some(sender) = this.output.$output_name {
+                        outputs.insert(stringify!($output_name).to_string(), sender);
+                    }
+                )*)*
+                #[allow(unused_mut)]
+                let mut outputs_array = HashMap::new();
+                $($(
+                    outputs_array.insert(stringify!($output_a_name).to_string(), this.outarr.$output_a_name);
+                )*)*
+                AgentPorts {
+                    inputs: inputs,
+                    inputs_array: inputs_array,
+                    outputs: outputs,
+                    outputs_array: outputs_array,
+                    option_msg: this.option_msg,
+                }
+            }
+
+            fn set_ports(&mut self, ports: AgentPorts) {
+                for (port, recv) in ports.inputs {
+                    let _ = self.set_receiver(&port, recv);
+                }
+                for (port, elements) in ports.inputs_array {
+                    for (element, recv) in elements {
+                        let _ = self.add_inarr_element(&port, element, recv);
+                    }
+                }
+                for (port, sender) in ports.outputs {
+                    let _ = self.connect(&port, sender);
+                }
+                for (port, elements) in ports.outputs_array {
+                    for (element, sender) in elements {
+                        let _ = self.connect_array(&port, element, sender);
+                    }
+                }
+                self.option_msg = ports.option_msg;
+            }
+
+            fn ports(&self) -> PortList {
+                #[allow(unused_mut)]
+                let mut ports = PortList::default();
+                $($(
+                    ports.inputs.push((stringify!($input_name).to_string(), edge_type_id!($input_contract)));
+                )*)*
+                $(
+                    ports.inputs.push(("option".to_string(), edge_type_id!($option)));
+                )*
+                $(
+                    ports.inputs.push(("accumulator".to_string(), edge_type_id!($accumulator)));
+                )*
+                $($(
+                    ports.array_inputs.push((stringify!($input_a_name).to_string(), edge_type_id!($input_a_contract)));
+                )*)*
+                $($(
+                    ports.outputs.push((stringify!($output_name).to_string(), edge_type_id!($output_contract)));
+                )*)*
+                $($(
+                    ports.array_outputs.push((stringify!($output_a_name).to_string(), edge_type_id!($output_a_contract)));
+                )*)*
+                ports
+            }
+
+            fn reset(&mut self) {
+                $(
+                    self.state = $state_value;
+                )*
+            }
+
         }
 
         pub struct Input {
@@ -209,6 +483,41 @@ macro_rules! agent {
             )*)*
         }
 
+        #[allow(dead_code)]
+        impl Output {
+            /// The output port named `name`, to choose the port at runtime
+            pub fn get(&self, name: &str) -> Option<&Option<MsgSender>> {
+                match name {
+                    "accumulator" => Some(&self.accumulator),
+                    $($(
+                        stringify!($output_name) => Some(&self.$output_name),
+                    )*)*
+                    _ => None,
+                }
+            }
+
+            /// The output port named `name`, to choose the port at runtime
+            pub fn get_mut(&mut self, name: &str) -> Option<&mut Option<MsgSender>> {
+                match name {
+                    "accumulator" => Some(&mut self.accumulator),
+                    $($(
+                        stringify!($output_name) => Some(&mut self.$output_name),
+                    )*)*
+                    _ => None,
+                }
+            }
+
+            /// Send an Msg to the output port named `name`
+            ///
+            /// Returns `Error::PortDontExist` if the agent has no such output port.
+            pub fn send_to(&self, name: &str, msg: Msg) -> Result<()> {
+                match self.get(name) {
+                    Some(port) => port.send(msg),
+                    None => Err(result::Error::PortDontExist(name.into())),
+                }
+            }
+        }
+
         pub struct Outarr {
             $($(
                 $output_a_name: HashMap<String, MsgSender>,
@@ -227,21 +536,28 @@ macro_rules! agent {
             pub outarr: Outarr,
             pub option_msg: Option<Msg>,
             sched: Sender<CompMsg>,
+            ctx: AgentCtx,
             $(
             pub state: $state_type ,
             )*
         }
 
         #[allow(dead_code)]
-        pub fn new(id: usize, sched: Sender<CompMsg>) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
+        pub fn new(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
+            $($( register_edge!($input_contract); )*)*
+            $($( register_edge!($input_a_contract); )*)*
+            $($( register_edge!($output_contract); )*)*
+            $($( register_edge!($output_a_contract); )*)*
+            $( register_edge!($option); )*
+            $( register_edge!($accumulator); )*
 
             let mut senders: HashMap<String, MsgSender> = HashMap::new();
-            let option = MsgReceiver::new(id, sched.clone(), false);
+            let option = MsgReceiver::new(id, "option", sched.clone(), ctx.clone(), false);
             senders.insert("option".to_string(), option.1);
-            let accumulator = MsgReceiver::new(id, sched.clone(), false);
+            let accumulator = MsgReceiver::new(id, "accumulator", sched.clone(), ctx.clone(), false);
             senders.insert("accumulator".to_string(), accumulator.1.clone());
             $($(
-                let $input_name = MsgReceiver::new(id, sched.clone(), true);
+                let $input_name = MsgReceiver::new(id, stringify!($input_name), sched.clone(), ctx.clone(), true);
                 senders.insert(stringify!($input_name).to_string(), $input_name.1);
             )*)*
             let input = Input {
@@ -279,6 +595,7 @@ macro_rules! agent {
                 outarr: outarr,
                 option_msg: None,
                 sched: sched,
+                ctx: ctx,
                 $(
                     state: $state_value,
                 )*
@@ -288,8 +605,8 @@ macro_rules! agent {
         }
 
         #[no_mangle]
-        pub extern fn create_agent(id: usize, sched: Sender<CompMsg>) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
-            new(id, sched)
+        pub extern fn create_agent(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
+            new(id, sched, ctx)
         }
 
         #[no_mangle]
@@ -337,9 +654,71 @@ macro_rules! agent {
                 _ => { Err(result::Error::PortDontExist(port.into())) }
             }
         }
+
+        #[no_mangle]
+        pub extern fn get_type_id_input(port: &str) -> Result<Option<u64>> {
+            match port {
+                $($(
+                    stringify!($input_name)=> Ok(edge_type_id!($input_contract)),
+                )*)*
+                $(
+                    "option" => Ok(edge_type_id!($option)),
+                )*
+                $(
+                    "accumulator" => Ok(edge_type_id!($accumulator)),
+                )*
+                _ => { Err(result::Error::PortDontExist(port.into())) }
+            }
+        }
+
+        #[no_mangle]
+        pub extern fn get_type_id_input_array(port: &str) -> Result<Option<u64>> {
+            match port {
+                $($(
+                    stringify!($input_a_name) => Ok(edge_type_id!($input_a_contract)),
+                )*)*
+                _ => { Err(result::Error::PortDontExist(port.into())) }
+            }
+        }
+
+        #[no_mangle]
+        pub extern fn get_type_id_output(port: &str) -> Result<Option<u64>> {
+            match port {
+                $($(
+                    stringify!($output_name)=> Ok(edge_type_id!($output_contract)),
+                )*)*
+                _ => { Err(result::Error::PortDontExist(port.into())) }
+            }
+        }
+
+        #[no_mangle]
+        pub extern fn get_type_id_output_array(port: &str) -> Result<Option<u64>> {
+            match port {
+                $($(
+                    stringify!($output_a_name) => Ok(edge_type_id!($output_a_contract)),
+                )*)*
+                _ => { Err(result::Error::PortDontExist(port.into())) }
+            }
+        }
     }
 }
 
+/// The type id of the capnp schema of an edge, `None` for `any`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! edge_type_id {
+    (any) => { None };
+    ($edge:ident) => { Some(<$edge::Reader as ::capnp::traits::HasTypeId>::type_id()) };
+}
+
+/// Name the capnp schema of an edge in `typed::registry`, nothing for `any`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! register_edge {
+    (any) => {};
+    ($edge:ident) => { ::rustfbp::typed::register_schema(<$edge::Reader as ::capnp::traits::HasTypeId>::type_id(), stringify!($edge)) };
+}
+
 #[macro_export]
 macro_rules! send_action {
     ($agent: ident, $port:ident, $msg:ident) => {{
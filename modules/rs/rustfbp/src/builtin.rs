@@ -0,0 +1,2335 @@
+//! Agents provided by rustfbp, without a shared library
+//!
+//! Their `new` functions have the signature of the `new` generated by the `agent!` macro,
+//! so they can be added with `Scheduler::add_agent_boxed` or given to an `AgentTester`.
+
+extern crate capnp;
+
+use agent::{Agent, AgentPorts, PortList};
+use result;
+use result::Result;
+use ports::{Msg, MsgKind, MsgSender, MsgReceiver, OutputSend, forward_brackets};
+use typed;
+use scheduler::{AgentCtx, BoxedComp, CompMsg, Signal};
+
+use std::cmp;
+use capnp::traits::{Owned, HasTypeId};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The port methods of `Agent`, for a builtin agent with a field per port
+///
+/// Each field has the name of its port, and is given with the schema of the port in `ports`:
+/// a `MsgReceiver` for an input port, an `Option<MsgSender>` for an output port, and a map of
+/// the elements for an array port. The Msgs waiting on a removed element are dropped.
+///
+/// ```rust,ignore
+/// impl Agent for Throttle {
+///     builtin_ports! {
+///         inputs { input: None },
+///         outputs { output: None },
+///         array_inputs {},
+///         array_outputs {}
+///     }
+///
+///     fn run(&mut self) -> Result<Signal> {
+///         // ...
+///     }
+/// }
+/// ```
+macro_rules! builtin_ports {
+    (
+        inputs { $($input:ident: $input_schema:expr),* },
+        outputs { $($output:ident: $output_schema:expr),* },
+        array_inputs { $($inputs:ident: $inputs_schema:expr),* },
+        array_outputs { $($outputs:ident: $outputs_schema:expr),* }
+    ) => {
+        fn is_input_ports(&self) -> bool {
+            let inputs: &[&str] = &[$(stringify!($input)),*];
+            let array_inputs: &[&str] = &[$(stringify!($inputs)),*];
+            !inputs.is_empty() || !array_inputs.is_empty()
+        }
+
+        fn connect(&mut self, port: &str, _sender: MsgSender) -> Result<()> {
+            $(
+                if port == stringify!($output) {
+                    self.$output = Some(_sender);
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn connect_array(&mut self, port: &str, _element: String, _sender: MsgSender) -> Result<()> {
+            $(
+                if port == stringify!($outputs) {
+                    self.$outputs.insert(_element, _sender);
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn add_inarr_element(&mut self, port: &str, _element: String, _recv: MsgReceiver) -> Result<()> {
+            $(
+                if port == stringify!($inputs) {
+                    self.$inputs.insert(_element, _recv);
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn remove_inarr_element(&mut self, port: &str, _element: &str) -> Result<()> {
+            $(
+                if port == stringify!($inputs) {
+                    if let Some(recv) = self.$inputs.remove(_element) {
+                        // Account for the Msgs that will never be received
+                        while let Ok(_) = recv.try_recv() {}
+                    }
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn disconnect(&mut self, port: &str) -> Result<()> {
+            $(
+                if port == stringify!($output) {
+                    self.$output = None;
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn disconnect_array(&mut self, port: &str, _element: &str) -> Result<()> {
+            $(
+                if port == stringify!($outputs) {
+                    self.$outputs.remove(_element);
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        fn set_receiver(&mut self, port: &str, _recv: MsgReceiver) -> Result<()> {
+            $(
+                if port == stringify!($input) {
+                    self.$input = _recv;
+                    return Ok(());
+                }
+            )*
+            Err(result::Error::PortDontExist(port.into()))
+        }
+
+        #[allow(unused_mut)]
+        fn into_ports(self: Box<Self>) -> AgentPorts {
+            let this = *self;
+            let mut inputs = HashMap::new();
+            $( inputs.insert(stringify!($input).to_string(), this.$input); )*
+            let mut outputs = HashMap::new();
+            $(
+                if let Some(output) = this.$output {
+                    outputs.insert(stringify!($output).to_string(), output);
+                }
+            )*
+            let mut inputs_array = HashMap::new();
+            $( inputs_array.insert(stringify!($inputs).to_string(), this.$inputs.into_iter().collect()); )*
+            let mut outputs_array = HashMap::new();
+            $( outputs_array.insert(stringify!($outputs).to_string(), this.$outputs.into_iter().collect()); )*
+            AgentPorts {
+                inputs: inputs,
+                inputs_array: inputs_array,
+                outputs: outputs,
+                outputs_array: outputs_array,
+                option_msg: None,
+            }
+        }
+
+        fn set_ports(&mut self, mut ports: AgentPorts) {
+            $(
+                if let Some(input) = ports.inputs.remove(stringify!($input)) {
+                    self.$input = input;
+                }
+            )*
+            $( self.$output = ports.outputs.remove(stringify!($output)); )*
+            $(
+                if let Some(elements) = ports.inputs_array.remove(stringify!($inputs)) {
+                    self.$inputs = elements.into_iter().collect();
+                }
+            )*
+            $(
+                if let Some(elements) = ports.outputs_array.remove(stringify!($outputs)) {
+                    self.$outputs = elements.into_iter().collect();
+                }
+            )*
+        }
+
+        fn ports(&self) -> PortList {
+            PortList {
+                inputs: vec![$((stringify!($input).to_string(), $input_schema)),*],
+                outputs: vec![$((stringify!($output).to_string(), $output_schema)),*],
+                array_inputs: vec![$((stringify!($inputs).to_string(), $inputs_schema)),*],
+                array_outputs: vec![$((stringify!($outputs).to_string(), $outputs_schema)),*],
+            }
+        }
+    }
+}
+
+/// How often a blocked `Throttle` checks if the scheduler is stopping, in milliseconds
+const THROTTLE_POLL_MS: u64 = 50;
+
+/// How often a waiting `Clock` checks if the scheduler is stopping, in milliseconds
+const CLOCK_POLL_MS: u64 = 50;
+
+/// How often a waiting `Tee` checks if its outputs have room, in milliseconds
+const TEE_POLL_MS: u64 = 1;
+
+/// Distribute the Msgs of `input` over the elements of the array port `outputs`
+///
+/// Each Msg goes to the next element, in round robin over the element names. With
+/// `spread_on_backpressure`, the elements whose port is full are skipped, and the balancer
+/// only blocks when all the elements are full. An end of stream is sent to all the elements.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut tester = try!(AgentTester::new(load_balancer));
+/// try!(tester.capture_array("outputs", "1"));
+/// try!(tester.capture_array("outputs", "2"));
+/// ```
+pub struct LoadBalancer {
+    input: MsgReceiver,
+    outputs: BTreeMap<String, MsgSender>,
+    /// The last element which received a Msg
+    last: Option<String>,
+    spread_on_backpressure: bool,
+}
+
+/// Create a round robin `LoadBalancer`
+pub fn load_balancer(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+    LoadBalancer::create(id, sched, ctx, false)
+}
+
+/// Create a `LoadBalancer` which skips the full elements
+pub fn load_balancer_spread(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+    LoadBalancer::create(id, sched, ctx, true)
+}
+
+impl LoadBalancer {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, spread_on_backpressure: bool) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = LoadBalancer {
+            input: input,
+            outputs: BTreeMap::new(),
+            last: None,
+            spread_on_backpressure: spread_on_backpressure,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// The elements in the order to try them for the next Msg
+    fn next_elements(&self) -> Vec<&String> {
+        let (after, before): (Vec<&String>, Vec<&String>) = self.outputs.keys()
+            .partition(|k| { self.last.as_ref().map(|l| { *k > l }).unwrap_or(true) });
+        after.into_iter().chain(before.into_iter()).collect()
+    }
+}
+
+impl Agent for LoadBalancer {
+    builtin_ports! {
+        inputs { input: None },
+        outputs {},
+        array_inputs {},
+        array_outputs { outputs: None }
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if msg.is_end_of_stream() {
+            // All the outputs see the end of the stream
+            for output in self.outputs.values() {
+                try!(output.send(msg.clone()));
+            }
+            return Ok(Signal::End);
+        }
+        let element = {
+            let elements = self.next_elements();
+            let free = if self.spread_on_backpressure {
+                elements.iter().cloned().find(|e| { !self.outputs[*e].port().is_full() })
+            } else {
+                None
+            };
+            match free.or(elements.first().map(|e| { *e })) {
+                Some(element) => element.clone(),
+                None => { return Err(result::Error::OutputNotConnected); },
+            }
+        };
+        try!(self.outputs[&element].send(msg));
+        self.last = Some(element);
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// How a `Merger` chooses the next Msg
+pub enum MergeMode<K> {
+    /// Take the Msgs of the elements in round robin
+    Interleave,
+    /// Keep one Msg per element, and send the one with the smallest key
+    ///
+    /// Merges streams that are each sorted by the key. Each stream is a substream: the
+    /// first open bracket of the elements is sent, then the merged Msgs, then a close bracket
+    /// once all the elements sent their close bracket.
+    Ordered(Box<Fn(&mut Msg) -> Result<K> + Send>),
+}
+
+/// Merge the Msgs of the elements of the array port `inputs` on `output`
+///
+/// The order of the Msgs of each element is kept. A single end of stream is sent, once all
+/// the elements sent theirs.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// fn date_key(msg: &mut Msg) -> Result<(i16, u8, u8)> {
+///     let date: time_date::Reader = try!(msg.read_schema());
+///     Ok((date.get_year(), date.get_month(), date.get_day()))
+/// }
+///
+/// fn new(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+///     Merger::create(id, sched, ctx, MergeMode::Ordered(Box::new(date_key)))
+/// }
+/// ```
+pub struct Merger<K> {
+    inputs: BTreeMap<String, MsgReceiver>,
+    output: Option<MsgSender>,
+    mode: MergeMode<K>,
+    /// The last element a Msg was taken from, for `Interleave`
+    last: Option<String>,
+    /// The waiting Msg of each element, for `Ordered`
+    heads: HashMap<String, (K, Msg)>,
+    /// The elements that sent their close bracket, for `Ordered`
+    ended: HashSet<String>,
+    open_sent: bool,
+    /// The elements that sent an end of stream
+    finished: HashSet<String>,
+}
+
+/// Create a `Merger` which interleaves its inputs
+pub fn merger(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+    Merger::<()>::create(id, sched, ctx, MergeMode::Interleave)
+}
+
+impl<K: Ord + Send + 'static> Merger<K> {
+    pub fn create(_id: usize, _sched: Sender<CompMsg>, _ctx: AgentCtx, mode: MergeMode<K>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let agent = Merger {
+            inputs: BTreeMap::new(),
+            output: None,
+            mode: mode,
+            last: None,
+            heads: HashMap::new(),
+            ended: HashSet::new(),
+            open_sent: false,
+            finished: HashSet::new(),
+        };
+        Ok((Box::new(agent) as BoxedComp, HashMap::new()))
+    }
+
+    /// Forget the state of the elements removed since the last run
+    fn forget_removed(&mut self) {
+        let inputs = &self.inputs;
+        self.heads.retain(|element, _| { inputs.contains_key(element) });
+        self.ended.retain(|element| { inputs.contains_key(element) });
+        self.finished.retain(|element| { inputs.contains_key(element) });
+    }
+
+    fn interleave(&mut self) -> Result<()> {
+        let elements: Vec<String> = {
+            let (after, before): (Vec<&String>, Vec<&String>) = self.inputs.keys()
+                .partition(|k| { self.last.as_ref().map(|l| { *k > l }).unwrap_or(true) });
+            after.into_iter().chain(before.into_iter()).cloned().collect()
+        };
+        for element in elements {
+            let res = self.inputs[&element].try_recv();
+            match res {
+                Ok(msg) => {
+                    self.last = Some(element.clone());
+                    if msg.is_end_of_stream() {
+                        return self.end_of_stream(element);
+                    }
+                    return self.output.send(msg);
+                },
+                Err(result::Error::MpscTryRecv(_)) => {},
+                Err(e) => { return Err(e); },
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a single end of stream, once all the elements sent theirs
+    fn end_of_stream(&mut self, element: String) -> Result<()> {
+        self.finished.insert(element);
+        if self.finished.len() >= self.inputs.len() {
+            self.finished.clear();
+            try!(self.output.send(Msg::end_of_stream()));
+        }
+        Ok(())
+    }
+
+    fn ordered(&mut self) -> Result<()> {
+        loop {
+            // Fill the elements without a waiting Msg
+            for (element, input) in &self.inputs {
+                if self.heads.contains_key(element) || self.ended.contains(element) || self.finished.contains(element) {
+                    continue;
+                }
+                loop {
+                    let mut msg = match input.try_recv() {
+                        Ok(msg) => msg,
+                        Err(result::Error::MpscTryRecv(_)) => { break; },
+                        Err(e) => { return Err(e); },
+                    };
+                    match msg.kind {
+                        MsgKind::OpenBracket(_) => {
+                            if !self.open_sent {
+                                self.open_sent = true;
+                                try!(self.output.send(msg));
+                            }
+                        },
+                        MsgKind::CloseBracket => {
+                            self.ended.insert(element.clone());
+                            break;
+                        },
+                        MsgKind::EndOfStream => {
+                            self.finished.insert(element.clone());
+                            break;
+                        },
+                        MsgKind::Data => {
+                            let key = match self.mode {
+                                MergeMode::Ordered(ref key) => try!(key(&mut msg)),
+                                MergeMode::Interleave => { unreachable!(); },
+                            };
+                            self.heads.insert(element.clone(), (key, msg));
+                            break;
+                        },
+                    }
+                }
+            }
+            if self.inputs.len() > 0 && self.ended.len() == self.inputs.len() {
+                self.ended.clear();
+                self.open_sent = false;
+                try!(self.output.send(Msg::close_bracket()));
+                continue;
+            }
+            if self.inputs.len() > 0 && self.finished.len() == self.inputs.len() && self.heads.is_empty() {
+                self.finished.clear();
+                try!(self.output.send(Msg::end_of_stream()));
+                continue;
+            }
+            if self.heads.len() + self.ended.len() + self.finished.len() < self.inputs.len() || self.heads.is_empty() {
+                return Ok(());
+            }
+            let smallest = self.heads.iter()
+                .min_by(|a, b| { (a.1).0.cmp(&(b.1).0) })
+                .map(|(element, _)| { element.clone() })
+                .expect("Merger: no waiting Msg");
+            let (_, msg) = self.heads.remove(&smallest).expect("Merger: no waiting Msg");
+            try!(self.output.send(msg));
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static> Agent for Merger<K> {
+    builtin_ports! {
+        inputs {},
+        outputs { output: None },
+        array_inputs { inputs: None },
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        self.forget_removed();
+        match self.mode {
+            MergeMode::Interleave => try!(self.interleave()),
+            MergeMode::Ordered(_) => try!(self.ordered()),
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+        self.heads.clear();
+        self.ended.clear();
+        self.open_sent = false;
+        self.finished.clear();
+    }
+}
+
+/// The capnp struct `ThrottleConfig { rate @0 :Float64; }`, the config of a `Throttle`
+///
+/// The schemas of the edges are generated in the agents, this reader is written as capnpc
+/// would generate it.
+mod throttle_config {
+    use capnp;
+    use capnp::private::layout::{PointerReader, StructReader};
+    use capnp::traits::FromPointerReader;
+
+    use std::ptr;
+
+    pub struct Reader<'a> {
+        reader: StructReader<'a>,
+    }
+
+    impl<'a> FromPointerReader<'a> for Reader<'a> {
+        fn get_from_pointer(reader: &PointerReader<'a>) -> capnp::Result<Reader<'a>> {
+            Ok(Reader { reader: try!(reader.get_struct(ptr::null())) })
+        }
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn get_rate(&self) -> f64 {
+            self.reader.get_data_field::<f64>(0)
+        }
+    }
+}
+
+/// What a `Throttle` does with a Msg over its rate
+#[derive(Clone, Debug)]
+pub enum ThrottleMode {
+    /// Wait for the next token, the upstream is slowed down by the backpressure
+    Block,
+    /// Drop the Msg, and count it
+    Drop(Arc<AtomicUsize>),
+}
+
+/// Send the Msgs of `input` on `output`, at most `rate` Msgs per second
+///
+/// A token bucket holding one token, refilled at `rate` tokens per second: two Msgs are
+/// at least `1 / rate` seconds apart. The refill is computed from `Instant`, so the waits
+/// don't drift. The brackets and the ends of stream are not throttled.
+///
+/// The rate can be replaced with `Scheduler::configure`, by a capnp message of the struct
+/// `ThrottleConfig { rate @0 :Float64; }`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let dropped = Arc::new(AtomicUsize::new(0));
+/// let mode = ThrottleMode::Drop(dropped.clone());
+/// try!(sched.add_agent_boxed("throttle", move |id, sched, ctx| {
+///     Throttle::create(id, sched, ctx, 10.0, mode)
+/// }));
+/// ```
+pub struct Throttle {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    ctx: AgentCtx,
+    rate: f64,
+    mode: ThrottleMode,
+    tokens: f64,
+    /// The time of the last refill
+    refilled: Instant,
+}
+
+impl Throttle {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, rate: f64, mode: ThrottleMode) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        try!(Throttle::check_rate(rate));
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx.clone(), true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Throttle {
+            input: input,
+            output: None,
+            ctx: ctx,
+            rate: rate,
+            mode: mode,
+            tokens: 1.0,
+            refilled: Instant::now(),
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    fn check_rate(rate: f64) -> Result<()> {
+        if !(rate > 0.0) {
+            return Err(result::Error::Misc(format!("Throttle: the rate {} is not positive", rate)));
+        }
+        Ok(())
+    }
+
+    /// Add the tokens earned since the last refill, the bucket holds one token
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(1.0);
+        self.refilled = now;
+    }
+
+    /// Take a token, waiting for it in `Block` mode. False if the Msg must be dropped
+    fn take_token(&mut self) -> Result<bool> {
+        loop {
+            self.refill();
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return Ok(true);
+            }
+            if let ThrottleMode::Drop(ref dropped) = self.mode {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(false);
+            }
+            if self.ctx.is_stopping() {
+                return Err(result::Error::Stopping);
+            }
+            let wait = ((1.0 - self.tokens) / self.rate * 1e9) as u64;
+            let wait = cmp::min(Duration::new(wait / 1_000_000_000, (wait % 1_000_000_000) as u32),
+                                Duration::from_millis(THROTTLE_POLL_MS));
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Agent for Throttle {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn configure(&mut self, config: &[u8]) -> Result<()> {
+        let reader = try!(capnp::serialize::read_message(&mut &config[..], capnp::message::ReaderOptions::new()));
+        let config: throttle_config::Reader = try!(reader.get_root());
+        let rate = config.get_rate();
+        try!(Throttle::check_rate(rate));
+        self.rate = rate;
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if !msg.is_data() || try!(self.take_token()) {
+            try!(self.output.send(msg));
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.tokens = 1.0;
+        self.refilled = Instant::now();
+    }
+}
+
+/// Send an empty Msg on `output` every `interval`
+///
+/// The ticks are at fixed times from the start of the agent, a late tick doesn't shift the
+/// next ones. No tick is sent while the agent is paused. With a `fire_count`, the clock stops
+/// after that many ticks and closes its output. The clock has no input port, it waits the ticks
+/// in its `run`: add it with `Scheduler::set_own_thread` to keep the threads of the pool free.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("heartbeat", |id, sched, ctx| {
+///     Clock::create(id, sched, ctx, Duration::from_secs(1), None)
+/// }));
+/// try!(sched.set_own_thread("heartbeat"));
+/// ```
+pub struct Clock {
+    output: Option<MsgSender>,
+    ctx: AgentCtx,
+    interval: Duration,
+    fire_count: Option<usize>,
+    fired: usize,
+}
+
+impl Clock {
+    pub fn create(_id: usize, _sched: Sender<CompMsg>, ctx: AgentCtx, interval: Duration, fire_count: Option<usize>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let agent = Clock {
+            output: None,
+            ctx: ctx,
+            interval: interval,
+            fire_count: fire_count,
+            fired: 0,
+        };
+        Ok((Box::new(agent) as BoxedComp, HashMap::new()))
+    }
+
+    /// Sleep until `deadline`, or return `Error::Stopping`
+    fn wait_until(&self, deadline: Instant) -> Result<()> {
+        loop {
+            if self.ctx.is_stopping() {
+                return Err(result::Error::Stopping);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(CLOCK_POLL_MS)));
+        }
+    }
+}
+
+impl Agent for Clock {
+    builtin_ports! {
+        inputs {},
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let start = Instant::now();
+        let mut ticks = 0;
+        loop {
+            if self.fire_count.map(|n| { self.fired >= n }).unwrap_or(false) {
+                // Close the output, the downstream sees the end of the ticks
+                self.output = None;
+                return Ok(Signal::End);
+            }
+            ticks += 1;
+            try!(self.wait_until(start + self.interval * ticks));
+            if !self.ctx.is_paused() {
+                try!(self.output.send(Msg::new()));
+                self.fired += 1;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fired = 0;
+    }
+}
+
+/// Send the Msgs of a `Vec` on `output`, then close it
+///
+/// With `VecSink`, it runs a graph on known Msgs, for a test or a small program. The source
+/// has no input port, it is run once when the scheduler starts.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let collected = Arc::new(Mutex::new(vec![]));
+/// try!(sched.add_agent_boxed("source", |id, sched, ctx| { VecSource::create(id, sched, ctx, msgs) }));
+/// let sink = collected.clone();
+/// try!(sched.add_agent_boxed("sink", move |id, sched, ctx| { VecSink::create(id, sched, ctx, sink) }));
+/// try!(sched.connect("source", "output", "sink", "input"));
+/// try!(sched.start());
+/// try!(sched.run_until_idle());
+/// println!("{} Msgs", collected.lock().unwrap().len());
+/// ```
+pub struct VecSource {
+    output: Option<MsgSender>,
+    msgs: Vec<Msg>,
+}
+
+impl VecSource {
+    pub fn create(_id: usize, _sched: Sender<CompMsg>, _ctx: AgentCtx, msgs: Vec<Msg>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let agent = VecSource {
+            output: None,
+            msgs: msgs,
+        };
+        Ok((Box::new(agent) as BoxedComp, HashMap::new()))
+    }
+}
+
+impl Agent for VecSource {
+    builtin_ports! {
+        inputs {},
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        // A Msg is removed once sent, a restarted source doesn't send it twice
+        while !self.msgs.is_empty() {
+            let msg = self.msgs.remove(0);
+            try!(self.output.send(msg));
+        }
+        // Close the output, the downstream sees the end of the Msgs
+        self.output = None;
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Push the Msgs of `input` in a shared `Vec`, to read them after the run
+///
+/// The brackets and the ends of stream are pushed too. See `VecSource` for an example.
+pub struct VecSink {
+    input: MsgReceiver,
+    collected: Arc<Mutex<Vec<Msg>>>,
+}
+
+impl VecSink {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, collected: Arc<Mutex<Vec<Msg>>>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = VecSink {
+            input: input,
+            collected: collected,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+}
+
+impl Agent for VecSink {
+    builtin_ports! {
+        inputs { input: None },
+        outputs {},
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        self.collected.lock().expect("VecSink: poisoned lock").push(msg.forward());
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Send the capnp messages of a file on `output`, then close it
+///
+/// The file is a stream of messages in the standard framing of `capnp::serialize`, each
+/// message starts with its segment table, as written by `FileSink` or by
+/// `capnp::serialize::write_message`. A capture of Msgs can be replayed. The headers and the
+/// actions are not in the file, and the Msgs sent are data Msgs.
+///
+/// The file is opened by `create`, a missing file is an error when the agent is added. A
+/// truncated or corrupt message stops the source with an error giving its index, the Msgs
+/// before it are sent.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("replay", |id, sched, ctx| { FileSource::create(id, sched, ctx, "capture.bin") }));
+/// try!(sched.connect("replay", "output", "parse", "input"));
+/// ```
+pub struct FileSource {
+    output: Option<MsgSender>,
+    path: PathBuf,
+    file: BufReader<File>,
+    // The index of the next message in the file
+    index: usize,
+}
+
+impl FileSource {
+    pub fn create<P: AsRef<Path>>(_id: usize, _sched: Sender<CompMsg>, _ctx: AgentCtx, path: P) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let file = try!(File::open(path.as_ref()));
+        let agent = FileSource {
+            output: None,
+            path: path.as_ref().to_path_buf(),
+            file: BufReader::new(file),
+            index: 0,
+        };
+        Ok((Box::new(agent) as BoxedComp, HashMap::new()))
+    }
+
+    /// Read the next message, `None` at the end of the file
+    fn read_msg(&mut self) -> Result<Option<Msg>> {
+        if try!(self.file.fill_buf()).is_empty() {
+            return Ok(None);
+        }
+        let reader = match capnp::serialize::read_message(&mut self.file, capnp::message::ReaderOptions::new()) {
+            Ok(reader) => reader,
+            Err(err) => {
+                return Err(result::Error::Misc(format!("{}: truncated or corrupt message {} : {}", self.path.display(), self.index, err)));
+            }
+        };
+        let mut msg = Msg::new();
+        let mut builder = capnp::message::Builder::new_default();
+        try!(builder.set_root(try!(reader.get_root::<capnp::any_pointer::Reader>())));
+        try!(capnp::serialize::write_message(&mut msg.vec, &builder));
+        self.index += 1;
+        Ok(Some(msg))
+    }
+}
+
+impl Agent for FileSource {
+    builtin_ports! {
+        inputs {},
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        // The file is read as it is sent, a restarted source goes on after the last message read
+        while let Some(msg) = try!(self.read_msg()) {
+            try!(self.output.send(msg));
+        }
+        // Close the output, the downstream sees the end of the file
+        self.output = None;
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Append the capnp messages of the Msgs of `input` to a file, to read them with `FileSource`
+///
+/// The file is created if needed, and each message is written as it is received, in the
+/// framing of `capnp::serialize`. The brackets, the ends of stream and the empty Msgs have no
+/// message, they are not written.
+pub struct FileSink {
+    input: MsgReceiver,
+    file: File,
+}
+
+impl FileSink {
+    pub fn create<P: AsRef<Path>>(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, path: P) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let file = try!(OpenOptions::new().append(true).create(true).open(path));
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = FileSink {
+            input: input,
+            file: file,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+}
+
+impl Agent for FileSink {
+    builtin_ports! {
+        inputs { input: None },
+        outputs {},
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if msg.is_data() && !msg.vec.is_empty() {
+            // One write for the whole message, a reader never sees half of it
+            try!(self.file.write_all(&msg.vec));
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// The bytes of a Msg hashed by a `Dedup`
+pub enum DedupKey {
+    /// The whole capn'p payload
+    Payload,
+    /// The bytes returned by the function, to compare only some fields of the schema
+    Bytes(Box<Fn(&mut Msg) -> Result<Vec<u8>> + Send>),
+}
+
+/// Send the Msgs of `input` on `output`, without the ones already seen in the window
+///
+/// The key of each Msg is hashed, a Msg is dropped if its hash is one of the `window` last
+/// distinct hashes seen. A dropped Msg refreshes its hash in the window, as a LRU. The memory is
+/// bounded by the window, but two distinct keys with the same 64 bits hash are taken as the
+/// same. The brackets and the ends of stream are not deduplicated.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// fn date_key(msg: &mut Msg) -> Result<Vec<u8>> {
+///     let date: time_date::Reader = try!(msg.read_schema());
+///     Ok(vec![(date.get_year() >> 8) as u8, date.get_year() as u8, date.get_month(), date.get_day()])
+/// }
+///
+/// try!(sched.add_agent_boxed("dedup", |id, sched, ctx| {
+///     Dedup::create(id, sched, ctx, 1000, DedupKey::Bytes(Box::new(date_key)))
+/// }));
+/// ```
+pub struct Dedup {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    key: DedupKey,
+    window: usize,
+    /// The last time each hash of the window was seen
+    seen: HashMap<u64, u64>,
+    /// The hashes of the window, by the last time they were seen
+    recent: BTreeMap<u64, u64>,
+    /// Counts the Msgs, a time for `seen` and `recent`
+    time: u64,
+}
+
+impl Dedup {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, window: usize, key: DedupKey) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        if window == 0 {
+            return Err(result::Error::Misc("Dedup: the window is empty".into()));
+        }
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Dedup {
+            input: input,
+            output: None,
+            key: key,
+            window: window,
+            seen: HashMap::new(),
+            recent: BTreeMap::new(),
+            time: 0,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// Record the key of `msg` in the window. True if it was not in the window
+    fn is_new(&mut self, msg: &mut Msg) -> Result<bool> {
+        let mut hasher = DefaultHasher::new();
+        match self.key {
+            DedupKey::Payload => msg.vec.hash(&mut hasher),
+            DedupKey::Bytes(ref key) => try!(key(msg)).hash(&mut hasher),
+        }
+        let hash = hasher.finish();
+        self.time += 1;
+        let new = match self.seen.insert(hash, self.time) {
+            Some(last) => {
+                self.recent.remove(&last);
+                false
+            },
+            None => true,
+        };
+        self.recent.insert(self.time, hash);
+        if self.recent.len() > self.window {
+            let oldest = *self.recent.keys().next().expect("Dedup: empty window");
+            if let Some(hash) = self.recent.remove(&oldest) {
+                self.seen.remove(&hash);
+            }
+        }
+        Ok(new)
+    }
+}
+
+impl Agent for Dedup {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let mut msg = try!(self.input.recv());
+        if !msg.is_data() || try!(self.is_new(&mut msg)) {
+            try!(self.output.send(msg));
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.seen.clear();
+        self.recent.clear();
+        self.time = 0;
+    }
+}
+
+/// Send the Msgs of `input` on `output`, without the ones equal to the Msg just before
+///
+/// As `uniq`, a Msg is dropped if its key is the key of the last Msg sent, so only the changes
+/// of a stream are sent. The first Msg is always sent. The key is the same as for a `Dedup`,
+/// the whole payload or the bytes of some fields. The brackets and the ends of stream are
+/// always sent, and are not compared.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("changes", |id, sched, ctx| {
+///     CompactAdjacent::create(id, sched, ctx, DedupKey::Bytes(Box::new(date_key)))
+/// }));
+/// ```
+pub struct CompactAdjacent {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    key: DedupKey,
+    /// The key of the last Msg sent
+    last: Option<Vec<u8>>,
+}
+
+impl CompactAdjacent {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, key: DedupKey) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = CompactAdjacent {
+            input: input,
+            output: None,
+            key: key,
+            last: None,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// Keep the key of `msg` as the last one. True if it differs from the previous one
+    fn is_change(&mut self, msg: &mut Msg) -> Result<bool> {
+        let key = match self.key {
+            DedupKey::Payload => msg.vec.clone(),
+            DedupKey::Bytes(ref key) => try!(key(msg)),
+        };
+        if self.last.as_ref() == Some(&key) {
+            return Ok(false);
+        }
+        self.last = Some(key);
+        Ok(true)
+    }
+}
+
+impl Agent for CompactAdjacent {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let mut msg = try!(self.input.recv());
+        if !msg.is_data() || try!(self.is_change(&mut msg)) {
+            try!(self.output.send(msg));
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Which Msgs a `Sample` sends on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleMode {
+    /// Every nth Msg, from the first one: 0, n, 2n...
+    Nth(usize),
+    /// Each Msg with the probability, drawn from a generator seeded with the u64
+    ///
+    /// The same seed gives the same sample of the same stream.
+    Probability(f64, u64),
+    /// The n first Msgs
+    First(usize),
+}
+
+/// Send a sample of the Msgs of `input` on `output`, and drop the others
+///
+/// Only the data Msgs are counted and sampled, the brackets and the ends of stream are
+/// always sent. A dropped Msg is freed at once. When the agent is restarted after a panic,
+/// the count and the generator start again.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("one_in_100", |id, sched, ctx| { Sample::create(id, sched, ctx, SampleMode::Nth(100)) }));
+/// try!(sched.connect("parse", "output", "one_in_100", "input"));
+/// try!(sched.connect("one_in_100", "output", "display", "input"));
+/// ```
+pub struct Sample {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    mode: SampleMode,
+    /// The number of data Msgs received
+    count: usize,
+    /// The state of the generator of `Probability`
+    rng: u64,
+}
+
+impl Sample {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, mode: SampleMode) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        match mode {
+            SampleMode::Nth(0) => { return Err(result::Error::Misc("Sample: Nth(0) samples nothing".into())); },
+            SampleMode::Probability(p, _) if !(p >= 0.0 && p <= 1.0) => {
+                return Err(result::Error::Misc(format!("Sample: the probability {} is not between 0 and 1", p)));
+            },
+            _ => {},
+        }
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let mut agent = Sample {
+            input: input,
+            output: None,
+            mode: mode,
+            count: 0,
+            rng: 0,
+        };
+        agent.reset();
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// The next number of the generator, in [0, 1)
+    fn next_random(&mut self) -> f64 {
+        // splitmix64
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z = z ^ (z >> 31);
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Count a data Msg, true if it is in the sample
+    fn keep(&mut self) -> bool {
+        let index = self.count;
+        self.count = self.count.saturating_add(1);
+        match self.mode {
+            SampleMode::Nth(n) => index % n == 0,
+            SampleMode::Probability(p, _) => self.next_random() < p,
+            SampleMode::First(n) => index < n,
+        }
+    }
+}
+
+impl Agent for Sample {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if !msg.is_data() || self.keep() {
+            try!(self.output.send(msg));
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.rng = match self.mode {
+            SampleMode::Probability(_, seed) => seed,
+            _ => 0,
+        };
+    }
+}
+
+/// How a `Tee` sends a Msg to its outputs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeeMode {
+    /// Wait until all the elements have room, then send the Msg to all of them
+    AllReady,
+    /// Send the Msg to the elements with room, a full element misses it. Wait while all are full
+    AnyReady,
+}
+
+/// Send a copy of each Msg of `input` to every element of the array port `outputs`
+///
+/// The copies are sent by the agent, not by a broadcasting edge of the scheduler, so the
+/// elements can be connected with the `Replace` fanout policy. With `TeeMode::AllReady`, a slow
+/// element slows down the whole stream; with `TeeMode::AnyReady`, it only misses Msgs. The
+/// brackets and the ends of stream are sent to all the elements in both modes.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("tee", |id, sched, ctx| { Tee::create(id, sched, ctx, TeeMode::AnyReady) }));
+/// try!(sched.connect_array("tee", "outputs", "live", "display", "input"));
+/// try!(sched.connect_array("tee", "outputs", "log", "logger", "input"));
+/// ```
+pub struct Tee {
+    input: MsgReceiver,
+    outputs: BTreeMap<String, MsgSender>,
+    ctx: AgentCtx,
+    mode: TeeMode,
+}
+
+/// Create a `Tee` which waits for all its outputs
+pub fn tee(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+    Tee::create(id, sched, ctx, TeeMode::AllReady)
+}
+
+impl Tee {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, mode: TeeMode) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx.clone(), true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Tee {
+            input: input,
+            outputs: BTreeMap::new(),
+            ctx: ctx,
+            mode: mode,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// Wait before checking the outputs again, or return `Error::Stopping`
+    fn wait(&self) -> Result<()> {
+        if self.ctx.is_stopping() {
+            return Err(result::Error::Stopping);
+        }
+        thread::sleep(Duration::from_millis(TEE_POLL_MS));
+        Ok(())
+    }
+
+    fn send_all(&self, msg: Msg) -> Result<()> {
+        for output in self.outputs.values() {
+            try!(output.send(msg.clone()));
+        }
+        Ok(())
+    }
+
+    fn send_ready(&self, msg: Msg) -> Result<()> {
+        loop {
+            let mut sent = false;
+            for output in self.outputs.values() {
+                if try!(output.try_send(msg.clone())).is_none() {
+                    sent = true;
+                }
+            }
+            if sent {
+                return Ok(());
+            }
+            try!(self.wait());
+        }
+    }
+}
+
+impl Agent for Tee {
+    builtin_ports! {
+        inputs { input: None },
+        outputs {},
+        array_inputs {},
+        array_outputs { outputs: None }
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if self.outputs.is_empty() {
+            return Err(result::Error::OutputNotConnected);
+        }
+        if !msg.is_data() {
+            try!(self.send_all(msg));
+            return Ok(Signal::End);
+        }
+        match self.mode {
+            TeeMode::AllReady => {
+                while self.outputs.values().any(|o| { o.port().is_full() }) {
+                    try!(self.wait());
+                }
+                try!(self.send_all(msg));
+            },
+            TeeMode::AnyReady => try!(self.send_ready(msg)),
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// The predicate of a `Filter`, on the reader of the schema `T`
+pub type FilterPredicate<T> = Box<for<'a> Fn(<T as Owned<'a>>::Reader) -> Result<bool> + Send>;
+
+/// Send the Msgs of `input` matching a predicate on `output`, and the others on `rejected`
+///
+/// The predicate reads the Msgs with the schema `T`, a Msg tagged with another schema is an
+/// error, see `typed`. The rejected Msgs are dropped if `rejected` is not connected. The
+/// brackets and the ends of stream are sent on `output`.
+///
+/// The schemas are generated in each agent, `date_filter!` builds a `Filter` of a date edge
+/// from a predicate on a `Date`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("positive", |id, sched, ctx| {
+///     Filter::<maths_decimal::Owned>::create(id, sched, ctx, Box::new(|d: maths_decimal::Reader| {
+///         Ok(d.get_mantissa() > 0)
+///     }))
+/// }));
+/// ```
+pub struct Filter<T> where T: for<'a> Owned<'a> {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    rejected: Option<MsgSender>,
+    predicate: FilterPredicate<T>,
+}
+
+impl<T> Filter<T> where
+    T: for<'a> Owned<'a> + 'static,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, predicate: FilterPredicate<T>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Filter {
+            input: input,
+            output: None,
+            rejected: None,
+            predicate: predicate,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+}
+
+impl<T> Agent for Filter<T> where
+    T: for<'a> Owned<'a> + 'static,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    builtin_ports! {
+        inputs { input: Some(typed::schema_id::<T>()) },
+        outputs { output: Some(typed::schema_id::<T>()), rejected: Some(typed::schema_id::<T>()) },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if let Some(mut msg) = try!(forward_brackets(msg, &self.output)) {
+            try!(typed::check_schema::<T>(&msg));
+            let matched = {
+                let reader: <T as Owned>::Reader = try!(msg.read_schema());
+                try!((self.predicate)(reader))
+            };
+            if matched {
+                try!(self.output.forward(msg));
+            } else if let Some(ref rejected) = self.rejected {
+                try!(rejected.forward(msg));
+            }
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// The `create` function of a `Filter` of the date edge `$edge`, with a predicate on a `Date`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let today = Date::new(2017, 6, 1);
+/// try!(sched.add_agent_boxed("future", date_filter!(time_date, move |date| { date > today })));
+/// ```
+#[macro_export]
+macro_rules! date_filter {
+    ($edge:ident, $predicate:expr) => {{
+        let predicate = $predicate;
+        move |id, sched, ctx| {
+            rustfbp::builtin::Filter::<$edge::Owned>::create(id, sched, ctx, Box::new(move |date: $edge::Reader| {
+                Ok(predicate(rustfbp::date::Date::new(date.get_year(), date.get_month(), date.get_day())))
+            }))
+        }
+    }}
+}
+
+/// The header of a Msg sent on the `error` port of a `Map`, it holds the text of the error
+pub const ERROR_HEADER: &'static str = "fbp-error";
+
+/// The function of a `Map`, it reads the schema `A` and fills a builder of the schema `B`
+pub type MapFn<A, B> = Box<for<'a, 'b, 'c> Fn(<A as Owned<'a>>::Reader, &'c mut <B as Owned<'b>>::Builder) -> Result<()> + Send>;
+
+/// What a `Map` does when its function fails
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapErrorPolicy {
+    /// Return the error from `run`, the agent is then handled by its `RestartPolicy`
+    Abort,
+    /// Send the input Msg on `error` with the text of the error in `ERROR_HEADER`, and
+    /// continue. The Msg is dropped if `error` is not connected
+    Route,
+}
+
+/// Send on `output` a Msg of the schema `B` for each Msg of the schema `A` of `input`
+///
+/// The function reads the input Msg and fills the builder of the output Msg, which is then
+/// tagged with `B`, see `typed`. A Msg tagged with another schema than `A` fails as the
+/// function does. The brackets and the ends of stream are sent on `output`.
+///
+/// `date_map!` builds a `Map` from a date edge, with a function on a `Date`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("negate", |id, sched, ctx| {
+///     Map::<maths_decimal::Owned, maths_decimal::Owned>::create(id, sched, ctx, MapErrorPolicy::Route,
+///         Box::new(|d: maths_decimal::Reader, out: &mut maths_decimal::Builder| {
+///             out.set_mantissa(-d.get_mantissa());
+///             out.set_scale(d.get_scale());
+///             Ok(())
+///         }))
+/// }));
+/// ```
+pub struct Map<A, B> where A: for<'a> Owned<'a>, B: for<'a> Owned<'a> {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    error: Option<MsgSender>,
+    policy: MapErrorPolicy,
+    map: MapFn<A, B>,
+}
+
+impl<A, B> Map<A, B> where
+    A: for<'a> Owned<'a> + 'static,
+    B: for<'a> Owned<'a> + 'static,
+    <A as Owned<'static>>::Reader: HasTypeId,
+    <B as Owned<'static>>::Reader: HasTypeId,
+{
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, policy: MapErrorPolicy, map: MapFn<A, B>) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Map {
+            input: input,
+            output: None,
+            error: None,
+            policy: policy,
+            map: map,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// Build the output Msg of `msg`
+    fn map(&self, msg: &mut Msg) -> Result<Msg> {
+        try!(typed::check_schema::<A>(msg));
+        let mut out = Msg::new();
+        {
+            let reader: <A as Owned>::Reader = try!(msg.read_schema());
+            let mut builder = out.build_schema::<<B as Owned>::Builder>();
+            try!((self.map)(reader, &mut builder));
+        }
+        typed::set_schema::<B>(&mut out);
+        Ok(out)
+    }
+}
+
+impl<A, B> Agent for Map<A, B> where
+    A: for<'a> Owned<'a> + 'static,
+    B: for<'a> Owned<'a> + 'static,
+    <A as Owned<'static>>::Reader: HasTypeId,
+    <B as Owned<'static>>::Reader: HasTypeId,
+{
+    builtin_ports! {
+        inputs { input: Some(typed::schema_id::<A>()) },
+        outputs { output: Some(typed::schema_id::<B>()), error: Some(typed::schema_id::<A>()) },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let msg = try!(self.input.recv());
+        if let Some(mut msg) = try!(forward_brackets(msg, &self.output)) {
+            match self.map(&mut msg) {
+                Ok(out) => { try!(self.output.forward(out)); },
+                Err(e) => {
+                    if self.policy == MapErrorPolicy::Abort {
+                        return Err(e);
+                    }
+                    if let Some(ref error) = self.error {
+                        msg.set_header(ERROR_HEADER, format!("{}", e).into_bytes());
+                        try!(error.send(msg));
+                    }
+                },
+            }
+        }
+        Ok(Signal::End)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// The `create` function of a `Map` from the date edge `$from`, with a body reading a `Date`
+///
+/// The body fills the builder `$out` of the edge `$to`, and returns a `Result<()>`.
+///
+/// # Example
+///
+/// The day of the year of each date, as a decimal:
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("day_of_year", date_map!(time_date, maths_decimal, MapErrorPolicy::Route, |date, out| {
+///     let day = try!(rustfbp::date::day_of_year(date.year, date.month, date.day)
+///         .ok_or(rustfbp::result::Error::Misc(format!("{} doesn't exist", date))));
+///     out.set_mantissa(day as i64);
+///     out.set_scale(0);
+///     Ok(())
+/// })));
+/// ```
+#[macro_export]
+macro_rules! date_map {
+    ($from:ident, $to:ident, $policy:expr, |$date:ident, $out:ident| $body:expr) => {{
+        move |id, sched, ctx| {
+            rustfbp::builtin::Map::<$from::Owned, $to::Owned>::create(id, sched, ctx, $policy, Box::new(move |reader: $from::Reader, $out: &mut $to::Builder| {
+                let $date = rustfbp::date::Date::new(reader.get_year(), reader.get_month(), reader.get_day());
+                $body
+            }))
+        }
+    }}
+}
+
+/// Send on `output` the last Msg of each burst of `input`, once `input` was quiet for `quiet`
+///
+/// The debounce holds the most recent Msg, and replaces it with each new Msg. The held Msg is
+/// sent when no Msg arrived during `quiet`, when a bracket or an end of stream arrives (which
+/// follows it) and when the scheduler is stopping or the input is closed.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("resize", |id, sched, ctx| {
+///     Debounce::create(id, sched, ctx, Duration::from_millis(200))
+/// }));
+/// ```
+pub struct Debounce {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    quiet: Duration,
+}
+
+impl Debounce {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, quiet: Duration) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        if quiet == Duration::new(0, 0) {
+            return Err(result::Error::Misc("Debounce: the quiet period is zero".into()));
+        }
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Debounce {
+            input: input,
+            output: None,
+            quiet: quiet,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+}
+
+impl Agent for Debounce {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        let mut held = try!(self.input.recv());
+        if !held.is_data() {
+            try!(self.output.send(held));
+            return Ok(Signal::End);
+        }
+        loop {
+            match self.input.recv_timeout(self.quiet) {
+                Ok(Some(msg)) => {
+                    if !msg.is_data() {
+                        try!(self.output.send(held));
+                        try!(self.output.send(msg));
+                        return Ok(Signal::End);
+                    }
+                    held = msg;
+                },
+                Ok(None) => {
+                    try!(self.output.send(held));
+                    return Ok(Signal::End);
+                },
+                Err(e @ result::Error::Stopping) | Err(e @ result::Error::PortClosed(_)) => {
+                    // Flush the held Msg before giving up
+                    try!(self.output.send(held));
+                    return Err(e);
+                },
+                Err(e) => { return Err(e); },
+            }
+        }
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// When a `Window` sends its window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowSpec {
+    /// After `n` Msgs
+    Count(usize),
+    /// After the duration, from the first Msg of the window
+    Time(Duration),
+}
+
+/// Group the Msgs of `input` in windows, each sent on `output` between an open and a close bracket
+///
+/// The windows follow each other, a Msg is in a single window. A time window starts with its
+/// first Msg, so no empty window is sent. A bracket or an end of stream received on `input`
+/// ends the current window, then it is sent. The current window is also sent when the scheduler is stopping
+/// or the input is closed, even if it is not full.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// try!(sched.add_agent_boxed("window", |id, sched, ctx| {
+///     Window::create(id, sched, ctx, WindowSpec::Time(Duration::from_secs(1)))
+/// }));
+/// ```
+pub struct Window {
+    input: MsgReceiver,
+    output: Option<MsgSender>,
+    spec: WindowSpec,
+    members: Vec<Msg>,
+    /// When the first Msg of the current window was received
+    opened: Option<Instant>,
+}
+
+impl Window {
+    pub fn create(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx, spec: WindowSpec) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        match spec {
+            WindowSpec::Count(0) => { return Err(result::Error::Misc("Window: a window of 0 Msgs".into())); },
+            WindowSpec::Time(d) if d == Duration::new(0, 0) => { return Err(result::Error::Misc("Window: a window of no time".into())); },
+            _ => {},
+        }
+        let (input, sender) = MsgReceiver::new(id, "input", sched, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let agent = Window {
+            input: input,
+            output: None,
+            spec: spec,
+            members: vec![],
+            opened: None,
+        };
+        Ok((Box::new(agent) as BoxedComp, senders))
+    }
+
+    /// Send the current window, if it has a Msg
+    fn flush(&mut self) -> Result<()> {
+        self.opened = None;
+        if self.members.is_empty() {
+            return Ok(());
+        }
+        let mut msgs = Vec::with_capacity(self.members.len() + 2);
+        msgs.push(Msg::open_bracket(None));
+        msgs.extend(self.members.drain(..));
+        msgs.push(Msg::close_bracket());
+        self.output.send_batch(msgs)
+    }
+}
+
+impl Agent for Window {
+    builtin_ports! {
+        inputs { input: None },
+        outputs { output: None },
+        array_inputs {},
+        array_outputs {}
+    }
+
+    fn run(&mut self) -> Result<Signal> {
+        loop {
+            // A time window is waited in the run that opened it
+            let received = match (self.spec, self.opened) {
+                (WindowSpec::Time(duration), Some(opened)) => {
+                    let now = Instant::now();
+                    if now >= opened + duration {
+                        Ok(None)
+                    } else {
+                        self.input.recv_timeout(opened + duration - now)
+                    }
+                },
+                _ => self.input.recv().map(Some),
+            };
+            match received {
+                Ok(Some(msg)) => {
+                    if !msg.is_data() {
+                        try!(self.flush());
+                        try!(self.output.send(msg));
+                        return Ok(Signal::End);
+                    }
+                    if self.members.is_empty() {
+                        self.opened = Some(Instant::now());
+                    }
+                    self.members.push(msg);
+                    if let WindowSpec::Count(n) = self.spec {
+                        if self.members.len() >= n {
+                            try!(self.flush());
+                        }
+                        return Ok(Signal::End);
+                    }
+                },
+                Ok(None) => {
+                    try!(self.flush());
+                    return Ok(Signal::End);
+                },
+                Err(e @ result::Error::Stopping) | Err(e @ result::Error::PortClosed(_)) => {
+                    // Send the partial window before giving up
+                    try!(self.flush());
+                    return Err(e);
+                },
+                Err(e) => { return Err(e); },
+            }
+        }
+    }
+
+    fn on_stop(&mut self) -> Result<()> {
+        // The partial window of a count window waits for a run
+        self.flush()
+    }
+
+    fn reset(&mut self) {
+        self.members.clear();
+        self.opened = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use date::Date;
+    use ports::DEFAULT_CAPACITY;
+    use testing::{AgentTester, bytes_msg, msg_bytes, time_date};
+
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc::channel;
+
+    /// The paths of `date_filter!` and `date_map!`, outside of the crate
+    mod rustfbp {
+        pub use builtin;
+        pub use date;
+    }
+
+    /// A data Msg of the bytes
+    fn bytes(bytes: &[u8]) -> Msg {
+        let mut msg = bytes_msg(bytes);
+        msg.before_send().unwrap();
+        msg
+    }
+
+    /// A data Msg of one byte
+    fn byte(b: u8) -> Msg {
+        bytes(&[b])
+    }
+
+    /// The byte of each data Msg, and `None` for a bracket or an end of stream
+    fn bytes_of(msgs: Vec<Msg>) -> Vec<Option<u8>> {
+        msgs.into_iter().map(|mut msg| {
+            if msg.is_data() { Some(msg_bytes(&mut msg).unwrap()[0]) } else { None }
+        }).collect()
+    }
+
+    /// The bytes of the data Msgs
+    fn data_of(msgs: Vec<Msg>) -> Vec<u8> {
+        bytes_of(msgs).into_iter().filter_map(|b| { b }).collect()
+    }
+
+    /// Push the Msgs to `port`, running the agent before the port is full
+    fn feed(tester: &mut AgentTester, port: &str, msgs: Vec<Msg>) {
+        for msg in msgs {
+            if tester.waiting(port, None) >= DEFAULT_CAPACITY - 1 {
+                tester.run(DEFAULT_CAPACITY).unwrap();
+            }
+            tester.push(port, msg).unwrap();
+        }
+        tester.run(DEFAULT_CAPACITY).unwrap();
+    }
+
+    fn sample(mode: SampleMode) -> AgentTester {
+        let mut tester = AgentTester::with(|id, sched, ctx| { Sample::create(id, sched, ctx, mode) }).unwrap();
+        tester.capture("output").unwrap();
+        tester
+    }
+
+    #[test]
+    fn sample_nth_sends_every_nth_msg() {
+        let mut tester = sample(SampleMode::Nth(3));
+        feed(&mut tester, "input", (0..10).map(byte).collect());
+        assert_eq!(data_of(tester.output("output")), vec![0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn sample_first_sends_the_first_msgs() {
+        let mut tester = sample(SampleMode::First(5));
+        let mut msgs: Vec<Msg> = (0..10).map(byte).collect();
+        msgs.insert(7, Msg::open_bracket(None));
+        msgs.push(Msg::end_of_stream());
+        feed(&mut tester, "input", msgs);
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(0), Some(1), Some(2), Some(3), Some(4), None, None]);
+    }
+
+    #[test]
+    fn sample_probability_is_reproducible() {
+        let run = |p: f64, seed: u64| -> Vec<u8> {
+            let mut tester = sample(SampleMode::Probability(p, seed));
+            feed(&mut tester, "input", (0..200).map(|i| { byte(i as u8) }).collect());
+            data_of(tester.output("output"))
+        };
+        let first = run(0.3, 42);
+        assert_eq!(run(0.3, 42), first);
+        assert!(first.len() > 30 && first.len() < 90, "{} Msgs sent", first.len());
+        assert!(run(0.3, 43) != first);
+        assert!(run(0.0, 42).is_empty());
+        assert_eq!(run(1.0, 42).len(), 200);
+    }
+
+    #[test]
+    fn sample_rejects_the_empty_modes() {
+        assert!(Sample::create(0, channel().0, AgentCtx::new(), SampleMode::Nth(0)).is_err());
+        assert!(Sample::create(0, channel().0, AgentCtx::new(), SampleMode::Probability(1.5, 0)).is_err());
+        assert!(Sample::create(0, channel().0, AgentCtx::new(), SampleMode::Probability(-0.1, 0)).is_err());
+    }
+
+    #[test]
+    fn load_balancer_round_robin_over_the_elements() {
+        let mut tester = AgentTester::new(load_balancer).unwrap();
+        for element in &["a", "b", "c"] {
+            tester.capture_array("outputs", element).unwrap();
+        }
+        let mut msgs: Vec<Msg> = (0..7).map(byte).collect();
+        msgs.push(Msg::end_of_stream());
+        feed(&mut tester, "input", msgs);
+        assert_eq!(bytes_of(tester.output_array("outputs", "a")), vec![Some(0), Some(3), Some(6), None]);
+        assert_eq!(bytes_of(tester.output_array("outputs", "b")), vec![Some(1), Some(4), None]);
+        assert_eq!(bytes_of(tester.output_array("outputs", "c")), vec![Some(2), Some(5), None]);
+    }
+
+    #[test]
+    fn load_balancer_needs_an_element() {
+        let mut tester = AgentTester::new(load_balancer_spread).unwrap();
+        tester.push("input", byte(0)).unwrap();
+        match tester.run(1) {
+            Err(result::Error::OutputNotConnected) => {},
+            _ => panic!("the Msg is balanced without element"),
+        }
+    }
+
+    fn push_elements(tester: &mut AgentTester, port: &str, elements: Vec<(&str, Msg)>) {
+        for (element, msg) in elements {
+            tester.push_array(port, element, msg).unwrap();
+        }
+        tester.run(100).unwrap();
+    }
+
+    #[test]
+    fn merger_interleaves_the_elements() {
+        let mut tester = AgentTester::new(merger).unwrap();
+        tester.capture("output").unwrap();
+        push_elements(&mut tester, "inputs", vec![("a", byte(1)), ("a", byte(2)), ("a", byte(3)), ("b", byte(10)), ("b", byte(20)),
+                                                  ("b", Msg::end_of_stream())]);
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(1), Some(10), Some(2), Some(20), Some(3)]);
+        push_elements(&mut tester, "inputs", vec![("a", Msg::end_of_stream())]);
+        let out = tester.output("output");
+        assert_eq!(out.len(), 1);
+        assert!(out[0].is_end_of_stream());
+    }
+
+    #[test]
+    fn merger_orders_the_sorted_elements() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Merger::create(id, sched, ctx, MergeMode::Ordered(Box::new(|msg: &mut Msg| { Ok(try!(msg_bytes(msg))[0]) })))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        let mut elements = vec![];
+        for &(element, ref bytes) in &[("a", vec![1, 4, 7]), ("b", vec![2, 3, 9])] {
+            elements.push((element, Msg::open_bracket(None)));
+            for b in bytes {
+                elements.push((element, byte(*b)));
+            }
+            elements.push((element, Msg::close_bracket()));
+            elements.push((element, Msg::end_of_stream()));
+        }
+        push_elements(&mut tester, "inputs", elements);
+        let out = tester.output("output");
+        assert_eq!(out.len(), 9);
+        assert_eq!(out[0].kind, MsgKind::OpenBracket(None));
+        assert_eq!(out[7].kind, MsgKind::CloseBracket);
+        assert!(out[8].is_end_of_stream());
+        assert_eq!(data_of(out), vec![1, 2, 3, 4, 7, 9]);
+    }
+
+    #[test]
+    fn merger_waits_for_every_element() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Merger::create(id, sched, ctx, MergeMode::Ordered(Box::new(|msg: &mut Msg| { Ok(try!(msg_bytes(msg))[0]) })))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        push_elements(&mut tester, "inputs", vec![("a", byte(5)), ("b", byte(1)), ("b", byte(2))]);
+        assert_eq!(data_of(tester.output("output")), vec![1, 2]);
+        push_elements(&mut tester, "inputs", vec![("b", byte(8))]);
+        assert_eq!(data_of(tester.output("output")), vec![5]);
+    }
+
+    /// A capnp message of `ThrottleConfig { rate @0 :Float64; }`
+    fn throttle_config(rate: f64) -> Vec<u8> {
+        // One segment of two words: the root struct pointer, and the rate
+        let mut config = vec![0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let bits = rate.to_bits();
+        config.extend((0..8).map(|i| { (bits >> (i * 8)) as u8 }));
+        config
+    }
+
+    #[test]
+    fn throttle_blocks_to_the_rate() {
+        let mut tester = AgentTester::with(|id, sched, ctx| { Throttle::create(id, sched, ctx, 100.0, ThrottleMode::Block) }).unwrap();
+        tester.capture("output").unwrap();
+        let started = Instant::now();
+        feed(&mut tester, "input", vec![byte(0), Msg::open_bracket(None), byte(1), byte(2), byte(3), Msg::close_bracket()]);
+        // The first token is there, the three next ones are 10 ms apart
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(0), None, Some(1), Some(2), Some(3), None]);
+    }
+
+    #[test]
+    fn throttle_drops_over_the_rate() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mode = ThrottleMode::Drop(dropped.clone());
+        let mut tester = AgentTester::with(move |id, sched, ctx| { Throttle::create(id, sched, ctx, 0.01, mode) }).unwrap();
+        tester.capture("output").unwrap();
+        feed(&mut tester, "input", vec![byte(0), byte(1), Msg::open_bracket(None), byte(2), Msg::end_of_stream()]);
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(0), None, None]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn throttle_checks_its_rate() {
+        assert!(Throttle::create(0, channel().0, AgentCtx::new(), 0.0, ThrottleMode::Block).is_err());
+        assert!(Throttle::create(0, channel().0, AgentCtx::new(), -1.0, ThrottleMode::Block).is_err());
+        let (mut throttle, _) = Throttle::create(0, channel().0, AgentCtx::new(), 1.0, ThrottleMode::Block).unwrap();
+        assert!(throttle.configure(&throttle_config(50.0)).is_ok());
+        assert!(throttle.configure(&throttle_config(0.0)).is_err());
+        assert!(throttle.configure(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn clock_ticks_at_the_interval() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Clock::create(id, sched, ctx, Duration::from_millis(10), Some(3))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        let started = Instant::now();
+        assert_eq!(tester.run(5).unwrap(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        let ticks = tester.output("output");
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks.iter().all(|tick| { tick.is_data() && tick.vec.is_empty() }));
+        // The clock is done, a new run sends nothing
+        assert_eq!(tester.run(5).unwrap(), 1);
+        assert!(tester.output("output").is_empty());
+    }
+
+    /// The key of `DedupKey::Bytes` on the first byte
+    fn first_byte(msg: &mut Msg) -> Result<Vec<u8>> {
+        Ok(try!(msg_bytes(msg))[..1].to_vec())
+    }
+
+    #[test]
+    fn dedup_drops_the_msgs_in_the_window() {
+        let mut tester = AgentTester::with(|id, sched, ctx| { Dedup::create(id, sched, ctx, 2, DedupKey::Payload) }).unwrap();
+        tester.capture("output").unwrap();
+        feed(&mut tester, "input", vec![byte(1), byte(2), byte(1), byte(3), byte(1), byte(2)]);
+        // The dropped 1 is refreshed, so 2 is the oldest when 3 comes
+        assert_eq!(data_of(tester.output("output")), vec![1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn dedup_compares_the_keys() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Dedup::create(id, sched, ctx, 10, DedupKey::Bytes(Box::new(first_byte)))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        feed(&mut tester, "input", vec![bytes(&[1, 9]), Msg::open_bracket(None), bytes(&[1, 8]), Msg::open_bracket(None),
+                                        bytes(&[2, 9])]);
+        let mut out = tester.output("output");
+        assert_eq!(out.len(), 4);
+        assert_eq!(msg_bytes(&mut out[0]).unwrap(), vec![1, 9]);
+        assert_eq!(out[1].kind, MsgKind::OpenBracket(None));
+        assert_eq!(out[2].kind, MsgKind::OpenBracket(None));
+        assert_eq!(msg_bytes(&mut out[3]).unwrap(), vec![2, 9]);
+        assert!(Dedup::create(0, channel().0, AgentCtx::new(), 0, DedupKey::Payload).is_err());
+    }
+
+    #[test]
+    fn tee_copies_to_every_element() {
+        for mode in &[TeeMode::AllReady, TeeMode::AnyReady] {
+            let mode = *mode;
+            let mut tester = AgentTester::with(move |id, sched, ctx| { Tee::create(id, sched, ctx, mode) }).unwrap();
+            tester.capture_array("outputs", "live").unwrap();
+            tester.capture_array("outputs", "log").unwrap();
+            feed(&mut tester, "input", vec![Msg::open_bracket(None), byte(1), byte(2), Msg::close_bracket()]);
+            let expected = vec![None, Some(1), Some(2), None];
+            assert_eq!(bytes_of(tester.output_array("outputs", "live")), expected);
+            assert_eq!(bytes_of(tester.output_array("outputs", "log")), expected);
+        }
+    }
+
+    #[test]
+    fn tee_needs_an_element() {
+        let mut tester = AgentTester::new(tee).unwrap();
+        tester.push("input", byte(0)).unwrap();
+        match tester.run(1) {
+            Err(result::Error::OutputNotConnected) => {},
+            _ => panic!("the Msg is copied without element"),
+        }
+    }
+
+    /// The year of each date Msg, and `None` for a bracket or an end of stream
+    fn years_of(msgs: Vec<Msg>) -> Vec<Option<i16>> {
+        msgs.into_iter().map(|mut msg| {
+            if msg.is_data() { Some(time_date::read(&mut msg).0) } else { None }
+        }).collect()
+    }
+
+    fn dates() -> Vec<Msg> {
+        vec![Msg::open_bracket(None), time_date::msg(1999, 12, 31), time_date::msg(2000, 1, 1), time_date::msg(-44, 3, 15),
+             time_date::msg(2017, 6, 1), Msg::close_bracket()]
+    }
+
+    #[test]
+    fn filter_splits_on_the_predicate() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Filter::<time_date::Owned>::create(id, sched, ctx, Box::new(|date: time_date::Reader| { Ok(date.get_year() >= 2000) }))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        tester.capture("rejected").unwrap();
+        feed(&mut tester, "input", dates());
+        assert_eq!(years_of(tester.output("output")), vec![None, Some(2000), Some(2017), None]);
+        assert_eq!(years_of(tester.output("rejected")), vec![Some(1999), Some(-44)]);
+    }
+
+    #[test]
+    fn filter_checks_the_schema() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            Filter::<time_date::Owned>::create(id, sched, ctx, Box::new(|_: time_date::Reader| { Ok(true) }))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        // The rejected Msgs are dropped without `rejected`
+        let mut other = time_date::msg(2017, 6, 1);
+        other.set_header(typed::SCHEMA_HEADER, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        tester.push("input", other).unwrap();
+        match tester.run(1) {
+            Err(result::Error::SchemaMismatch(expected, 1)) if expected == time_date::TYPE_ID => {},
+            _ => panic!("a Msg of another schema is filtered"),
+        }
+    }
+
+    #[test]
+    fn date_filter_reads_the_dates() {
+        let today = Date::new(2000, 1, 1);
+        let mut tester = AgentTester::with(date_filter!(time_date, move |date| { date < today })).unwrap();
+        tester.capture("output").unwrap();
+        tester.capture("rejected").unwrap();
+        feed(&mut tester, "input", dates());
+        assert_eq!(years_of(tester.output("output")), vec![None, Some(1999), Some(-44), None]);
+        assert_eq!(years_of(tester.output("rejected")), vec![Some(2000), Some(2017)]);
+    }
+
+    #[test]
+    fn debounce_sends_the_last_msg_of_a_burst() {
+        let mut tester = AgentTester::with(|id, sched, ctx| { Debounce::create(id, sched, ctx, Duration::from_millis(20)) }).unwrap();
+        tester.capture("output").unwrap();
+        let started = Instant::now();
+        feed(&mut tester, "input", vec![byte(1), byte(2), byte(3)]);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(3)]);
+        // A bracket follows the held Msg
+        feed(&mut tester, "input", vec![byte(4), Msg::open_bracket(None), byte(5), byte(6)]);
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(4), None, Some(6)]);
+        assert!(Debounce::create(0, channel().0, AgentCtx::new(), Duration::new(0, 0)).is_err());
+    }
+
+    fn window(spec: WindowSpec) -> AgentTester {
+        let mut tester = AgentTester::with(|id, sched, ctx| { Window::create(id, sched, ctx, spec) }).unwrap();
+        tester.capture("output").unwrap();
+        tester
+    }
+
+    #[test]
+    fn window_counts_the_msgs() {
+        let mut tester = window(WindowSpec::Count(2));
+        feed(&mut tester, "input", vec![byte(1), byte(2), byte(3), Msg::end_of_stream()]);
+        let out = tester.output("output");
+        let kinds: Vec<MsgKind> = out.iter().map(|msg| { msg.kind.clone() }).collect();
+        assert_eq!(kinds, vec![MsgKind::OpenBracket(None), MsgKind::Data, MsgKind::Data, MsgKind::CloseBracket,
+                               MsgKind::OpenBracket(None), MsgKind::Data, MsgKind::CloseBracket, MsgKind::EndOfStream]);
+        assert_eq!(data_of(out), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn window_waits_the_duration() {
+        let mut tester = window(WindowSpec::Time(Duration::from_millis(20)));
+        let started = Instant::now();
+        feed(&mut tester, "input", vec![byte(1), byte(2)]);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(bytes_of(tester.output("output")), vec![None, Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn window_sends_the_partial_window_on_stop() {
+        let mut tester = window(WindowSpec::Count(5));
+        feed(&mut tester, "input", vec![byte(1), byte(2)]);
+        assert!(tester.output("output").is_empty());
+        tester.stop().unwrap();
+        assert_eq!(bytes_of(tester.output("output")), vec![None, Some(1), Some(2), None]);
+        assert!(Window::create(0, channel().0, AgentCtx::new(), WindowSpec::Count(0)).is_err());
+        assert!(Window::create(0, channel().0, AgentCtx::new(), WindowSpec::Time(Duration::new(0, 0))).is_err());
+    }
+
+    /// A `Map` of the dates to the next day, which fails on the last day of a month
+    fn next_day(policy: MapErrorPolicy) -> AgentTester {
+        let mut tester = AgentTester::with(move |id, sched, ctx| {
+            Map::<time_date::Owned, time_date::Owned>::create(id, sched, ctx, policy,
+                Box::new(|date: time_date::Reader, out: &mut time_date::Builder| {
+                    if Some(date.get_day()) == ::date::days_in_month(date.get_year(), date.get_month()) {
+                        return Err(result::Error::Misc("the last day".into()));
+                    }
+                    out.set_year(date.get_year());
+                    out.set_month(date.get_month());
+                    out.set_day(date.get_day() + 1);
+                    Ok(())
+                }))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        tester
+    }
+
+    #[test]
+    fn map_routes_the_failures() {
+        let mut tester = next_day(MapErrorPolicy::Route);
+        tester.capture("error").unwrap();
+        feed(&mut tester, "input", vec![Msg::open_bracket(None), time_date::msg(2017, 6, 1), time_date::msg(2017, 6, 30),
+                                        Msg::close_bracket()]);
+        let mut out = tester.output("output");
+        assert_eq!(out.len(), 3);
+        assert_eq!(time_date::read(&mut out[1]), (2017, 6, 2));
+        assert!(typed::check_schema::<time_date::Owned>(&out[1]).is_ok());
+        let mut errors = tester.output("error");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(time_date::read(&mut errors[0]), (2017, 6, 30));
+        assert!(errors[0].get_header(ERROR_HEADER).map(|e| { e.ends_with(b"the last day") }).unwrap_or(false));
+    }
+
+    #[test]
+    fn map_aborts_on_a_failure() {
+        let mut tester = next_day(MapErrorPolicy::Abort);
+        tester.push("input", time_date::msg(2017, 6, 30)).unwrap();
+        assert!(tester.run(1).is_err());
+        // Without `error`, a routed failure is dropped
+        let mut tester = next_day(MapErrorPolicy::Route);
+        feed(&mut tester, "input", vec![time_date::msg(2017, 6, 30), time_date::msg(2017, 6, 1)]);
+        assert_eq!(years_of(tester.output("output")), vec![Some(2017)]);
+    }
+
+    #[test]
+    fn date_map_reads_the_dates() {
+        let mut tester = AgentTester::with(date_map!(time_date, time_date, MapErrorPolicy::Abort, |date, out| {
+            let next = try!(rustfbp::date::date_add(date, 1, 0, 0).ok_or(result::Error::Misc(format!("{} + 1 year", date))));
+            out.set_year(next.year);
+            out.set_month(next.month);
+            out.set_day(next.day);
+            Ok(())
+        })).unwrap();
+        tester.capture("output").unwrap();
+        feed(&mut tester, "input", vec![time_date::msg(2016, 2, 29)]);
+        let mut out = tester.output("output");
+        assert_eq!(time_date::read(&mut out[0]), (2017, 2, 28));
+    }
+
+    #[test]
+    fn vec_source_sends_its_msgs_once() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            VecSource::create(id, sched, ctx, vec![byte(1), Msg::open_bracket(None), byte(2), Msg::close_bracket()])
+        }).unwrap();
+        tester.capture("output").unwrap();
+        assert_eq!(tester.run(5).unwrap(), 1);
+        assert_eq!(bytes_of(tester.output("output")), vec![Some(1), None, Some(2), None]);
+        // The output is closed, a new run sends nothing
+        assert_eq!(tester.run(5).unwrap(), 1);
+        assert!(tester.output("output").is_empty());
+    }
+
+    #[test]
+    fn vec_sink_collects_every_msg() {
+        let collected = Arc::new(Mutex::new(vec![]));
+        let sink = collected.clone();
+        let mut tester = AgentTester::with(move |id, sched, ctx| { VecSink::create(id, sched, ctx, sink) }).unwrap();
+        feed(&mut tester, "input", vec![byte(1), Msg::open_bracket(Some(b"b".to_vec())), byte(2), Msg::end_of_stream()]);
+        let collected = collected.lock().unwrap().drain(..).collect();
+        assert_eq!(bytes_of(collected), vec![Some(1), None, Some(2), None]);
+    }
+
+    /// A file of the temporary directory, removed first
+    fn temp_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rustfbp-builtin-{}", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn file_sink(path: &Path, msgs: Vec<Msg>) {
+        let path = path.to_path_buf();
+        let mut tester = AgentTester::with(move |id, sched, ctx| { FileSink::create(id, sched, ctx, path) }).unwrap();
+        feed(&mut tester, "input", msgs);
+    }
+
+    fn file_source(path: &Path) -> (Result<usize>, Vec<Msg>) {
+        let path = path.to_path_buf();
+        let mut tester = AgentTester::with(move |id, sched, ctx| { FileSource::create(id, sched, ctx, path) }).unwrap();
+        tester.capture("output").unwrap();
+        let res = tester.run(5);
+        (res, tester.output("output"))
+    }
+
+    #[test]
+    fn file_source_reads_back_the_file_sink() {
+        let path = temp_file("file_source_reads_back_the_file_sink");
+        file_sink(&path, vec![Msg::open_bracket(None), byte(1), Msg::new(), bytes(&[2, 3]), Msg::close_bracket()]);
+        // The sink appends to the file
+        file_sink(&path, vec![byte(4), Msg::end_of_stream()]);
+        let (res, out) = file_source(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap(), 1);
+        assert!(out.iter().all(|msg| { msg.is_data() }));
+        let out: Vec<Vec<u8>> = out.into_iter().map(|mut msg| { msg_bytes(&mut msg).unwrap() }).collect();
+        assert_eq!(out, vec![vec![1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn file_source_stops_at_a_truncated_msg() {
+        let path = temp_file("file_source_stops_at_a_truncated_msg");
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&byte(1).vec).unwrap();
+            let second = byte(2).vec;
+            file.write_all(&second[..second.len() - 1]).unwrap();
+        }
+        let (res, out) = file_source(&path);
+        fs::remove_file(&path).unwrap();
+        match res {
+            Err(result::Error::Misc(ref e)) if e.contains("message 1") => {},
+            _ => panic!("the truncated message is read"),
+        }
+        assert_eq!(data_of(out), vec![1]);
+        assert!(FileSource::create(0, channel().0, AgentCtx::new(), &path).is_err());
+    }
+
+    #[test]
+    fn compact_adjacent_drops_the_repeated_msgs() {
+        let mut tester = AgentTester::with(|id, sched, ctx| { CompactAdjacent::create(id, sched, ctx, DedupKey::Payload) }).unwrap();
+        tester.capture("output").unwrap();
+        feed(&mut tester, "input", vec![byte(1), byte(1), byte(2), byte(2), byte(2), byte(1), byte(3), byte(3)]);
+        assert_eq!(data_of(tester.output("output")), vec![1, 2, 1, 3]);
+    }
+
+    #[test]
+    fn compact_adjacent_compares_the_keys() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            CompactAdjacent::create(id, sched, ctx, DedupKey::Bytes(Box::new(first_byte)))
+        }).unwrap();
+        tester.capture("output").unwrap();
+        // The brackets are sent and are not compared, the 1 after them is still a repeat
+        feed(&mut tester, "input", vec![bytes(&[1, 9]), Msg::close_bracket(), Msg::open_bracket(None), bytes(&[1, 8]), bytes(&[2, 9])]);
+        let mut out = tester.output("output");
+        assert_eq!(out.len(), 4);
+        assert_eq!(msg_bytes(&mut out[0]).unwrap(), vec![1, 9]);
+        assert_eq!(out[1].kind, MsgKind::CloseBracket);
+        assert_eq!(out[2].kind, MsgKind::OpenBracket(None));
+        assert_eq!(msg_bytes(&mut out[3]).unwrap(), vec![2, 9]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Little endian integers, length prefixed bytes and checksums, for the recordings and the network edges
+
+use result;
+use result::Result;
+
+use ports::MsgKind;
+
+use std::io;
+use std::io::{Read, Write};
+
+pub fn write_u32<W: Write + ?Sized>(out: &mut W, n: u32) -> Result<()> {
+    let bytes: Vec<u8> = (0..4).map(|i| { (n >> (i * 8)) as u8 }).collect();
+    try!(out.write_all(&bytes));
+    Ok(())
+}
+
+pub fn write_u64<W: Write + ?Sized>(out: &mut W, n: u64) -> Result<()> {
+    let bytes: Vec<u8> = (0..8).map(|i| { (n >> (i * 8)) as u8 }).collect();
+    try!(out.write_all(&bytes));
+    Ok(())
+}
+
+pub fn write_bytes<W: Write + ?Sized>(out: &mut W, bytes: &[u8]) -> Result<()> {
+    try!(write_u32(out, bytes.len() as u32));
+    try!(out.write_all(bytes));
+    Ok(())
+}
+
+pub fn read_u8<R: Read>(input: &mut R) -> Result<u8> {
+    let mut buf = [0; 1];
+    try!(input.read_exact(&mut buf));
+    Ok(buf[0])
+}
+
+pub fn read_u32<R: Read>(input: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    try!(input.read_exact(&mut buf));
+    Ok(buf.iter().enumerate().fold(0, |acc, (i, b)| { acc | ((*b as u32) << (i * 8)) }))
+}
+
+pub fn read_u64<R: Read>(input: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    try!(input.read_exact(&mut buf));
+    Ok(buf.iter().enumerate().fold(0, |acc, (i, b)| { acc | ((*b as u64) << (i * 8)) }))
+}
+
+/// Read length prefixed bytes
+///
+/// The buffer grows with the bytes read, not with the length, which comes from the input.
+pub fn read_bytes<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let len = try!(read_u32(input));
+    read_exactly(input, len as u64)
+}
+
+/// Read `len` bytes, or fail with `UnexpectedEof`
+fn read_exactly<R: Read>(input: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    try!(input.by_ref().take(len).read_to_end(&mut buf));
+    if (buf.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated bytes").into());
+    }
+    Ok(buf)
+}
+
+/// Write the kind of a Msg: 0 for data, 1 and the optional label for an open bracket, 2 for a close bracket,
+/// 3 for an end of stream
+pub fn write_kind<W: Write + ?Sized>(out: &mut W, kind: &MsgKind) -> Result<()> {
+    match *kind {
+        MsgKind::Data => { try!(out.write_all(&[0])); },
+        MsgKind::OpenBracket(ref label) => {
+            try!(out.write_all(&[1]));
+            match *label {
+                Some(ref label) => {
+                    try!(out.write_all(&[1]));
+                    try!(write_bytes(out, label));
+                },
+                None => { try!(out.write_all(&[0])); },
+            }
+        },
+        MsgKind::CloseBracket => { try!(out.write_all(&[2])); },
+        MsgKind::EndOfStream => { try!(out.write_all(&[3])); },
+    }
+    Ok(())
+}
+
+pub fn read_kind<R: Read>(input: &mut R) -> Result<MsgKind> {
+    match try!(read_u8(input)) {
+        0 => Ok(MsgKind::Data),
+        1 => {
+            match try!(read_u8(input)) {
+                0 => Ok(MsgKind::OpenBracket(None)),
+                _ => Ok(MsgKind::OpenBracket(Some(try!(read_bytes(input))))),
+            }
+        },
+        2 => Ok(MsgKind::CloseBracket),
+        3 => Ok(MsgKind::EndOfStream),
+        _ => Err(result::Error::BadMessageInfo),
+    }
+}
+
+/// The CRC-32 (IEEE) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_of_the_check_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7_be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        let mut out = vec![];
+        write_u32(&mut out, 0x0403_0201).unwrap();
+        write_u64(&mut out, 0x0807_0605_0403_0201).unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut input = &out[..];
+        assert_eq!(read_u32(&mut input).unwrap(), 0x0403_0201);
+        assert_eq!(read_u64(&mut input).unwrap(), 0x0807_0605_0403_0201);
+        assert!(read_u8(&mut input).is_err());
+    }
+
+    #[test]
+    fn bytes_are_length_prefixed() {
+        let mut out = vec![];
+        write_bytes(&mut out, b"abc").unwrap();
+        assert_eq!(out, vec![3, 0, 0, 0, b'a', b'b', b'c']);
+        assert_eq!(read_bytes(&mut &out[..]).unwrap(), b"abc".to_vec());
+        match read_bytes(&mut &out[..5]) {
+            Err(result::Error::IO(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            _ => panic!("truncated bytes are read"),
+        }
+        // A length of 4 GiB, without the bytes
+        let huge = [0xff, 0xff, 0xff, 0xff, 0];
+        assert!(read_bytes(&mut &huge[..]).is_err());
+    }
+
+    #[test]
+    fn kinds_round_trip() {
+        let kinds = vec![MsgKind::Data, MsgKind::OpenBracket(None), MsgKind::OpenBracket(Some(b"label".to_vec())),
+                         MsgKind::OpenBracket(Some(vec![])), MsgKind::CloseBracket, MsgKind::EndOfStream];
+        let mut out = vec![];
+        for kind in &kinds {
+            write_kind(&mut out, kind).unwrap();
+        }
+        let mut input = &out[..];
+        for kind in &kinds {
+            assert_eq!(read_kind(&mut input).unwrap(), *kind);
+        }
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn read_kind_rejects_an_unknown_kind() {
+        match read_kind(&mut &[4u8][..]) {
+            Err(result::Error::BadMessageInfo) => {},
+            _ => panic!("the kind 4 is read"),
+        }
+        assert!(read_kind(&mut &[1u8][..]).is_err());
+        assert!(read_kind(&mut &[][..]).is_err());
+    }
+}
@@ -0,0 +1,791 @@
+//! Calendar computations for the `time_date` edge
+//!
+//! A `time_date` is a `year: Int16`, a `month: UInt8` and a `day: UInt8` of the proleptic
+//! Gregorian calendar. As for `decimal`, the reader is generated in each agent, so the
+//! helpers take the fields.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let date: time_date::Reader = try!(msg.read_schema());
+//! if is_weekend(date.get_year(), date.get_month(), date.get_day()) {
+//!     // No delivery
+//! }
+//! println!("received {}", Date::new(date.get_year(), date.get_month(), date.get_day()));
+//! ```
+//!
+//! `parse_iso8601` and `format_iso8601` convert the fields from and to the `YYYY-MM-DD` text.
+//!
+//! ```rust,ignore
+//! let (year, month, day) = try!(parse_iso8601("2024-02-29"));
+//! {
+//!     let mut builder: time_date::Builder = msg.build_schema();
+//!     builder.set_year(year);
+//!     builder.set_month(month);
+//!     builder.set_day(day);
+//! }
+//! ```
+//!
+//! `date_add` and `date_diff` compute with the `years`, `months` and `days` of a `time_duration`.
+//!
+//! `to_naive_date` and `from_naive_date` convert the fields from and to a `chrono::NaiveDate`.
+//! With the `json` feature, the `json` module converts them from and to a JSON object. The
+//! `msgpack` module encodes them as a MessagePack map.
+//!
+//! A batch of dates can travel in one Msg, a `List(time_date)`, with `build_date_list!` and
+//! `read_date_list!`. They are macros for the same reason: they need the generated schema.
+
+use result;
+use result::Result;
+
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A day of the week
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// True for a leap year of the proleptic Gregorian calendar, the year 0 is a leap year
+pub fn is_leap_year(year: i16) -> bool {
+    let year = year as i32;
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// The number of days of `month` in `year`, `None` if the month is not 1 to 12
+pub fn days_in_month(year: i16, month: u8) -> Option<u8> {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => Some(31),
+        4 | 6 | 9 | 11 => Some(30),
+        2 => Some(if is_leap_year(year) { 29 } else { 28 }),
+        _ => None,
+    }
+}
+
+/// True if the date exists: the month is 1 to 12 and the day is in the month
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let date: time_date::Reader = try!(msg.read_schema());
+/// if !is_valid(date.get_year(), date.get_month(), date.get_day()) {
+///     return Err(result::Error::Misc("no such date".into()));
+/// }
+/// ```
+pub fn is_valid(year: i16, month: u8, day: u8) -> bool {
+    match days_in_month(year, month) {
+        Some(days) => day >= 1 && day <= days,
+        None => false,
+    }
+}
+
+/// The day of the week of a date, `None` if the date doesn't exist
+///
+/// The years before 1 are the astronomical years: 0 is 1 BC, -1 is 2 BC.
+pub fn weekday(year: i16, month: u8, day: u8) -> Option<Weekday> {
+    if !is_valid(year, month, day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    // 1970-01-01 is a Thursday
+    let weekdays = [Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday, Weekday::Thursday,
+                    Weekday::Friday, Weekday::Saturday, Weekday::Sunday];
+    Some(weekdays[(((days + 3) % 7 + 7) % 7) as usize])
+}
+
+/// The number of days between 0001-01-01 and 1970-01-01
+const UNIX_EPOCH_RATA_DIE: i64 = 719162;
+
+/// The days since 1970-01-01 of a valid date, by the algorithm of Howard Hinnant
+fn days_from_civil(year: i16, month: u8, day: u8) -> i64 {
+    // Counted from March so that the leap day ends the year
+    let (y, m, d) = (year as i64 - if month <= 2 { 1 } else { 0 }, month as i64, day as i64);
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The day number of a date, 1 for 0001-01-01, `None` if the date doesn't exist
+///
+/// The number of days between two dates is the difference of their day numbers. The days
+/// are the days of the calendar, there is no leap second in a date.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let start = to_rata_die(start.get_year(), start.get_month(), start.get_day());
+/// let end = to_rata_die(end.get_year(), end.get_month(), end.get_day());
+/// if let (Some(start), Some(end)) = (start, end) {
+///     println!("{} days", end - start);
+/// }
+/// ```
+pub fn to_rata_die(year: i16, month: u8, day: u8) -> Option<i64> {
+    if is_valid(year, month, day) {
+        Some(days_from_civil(year, month, day) + UNIX_EPOCH_RATA_DIE + 1)
+    } else {
+        None
+    }
+}
+
+/// The date of a day number, `None` if its year doesn't fit in the `year: Int16` of `time_date`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let date = try!(from_rata_die(day + 30).ok_or(result::Error::Misc("out of range".into())));
+/// let mut builder: time_date::Builder = msg.build_schema();
+/// builder.set_year(date.year);
+/// builder.set_month(date.month);
+/// builder.set_day(date.day);
+/// ```
+pub fn from_rata_die(n: i64) -> Option<Date> {
+    // Far enough from the bounds of i64 for the computations below
+    if n > i64::max_value() / 1024 || n < -(i64::max_value() / 1024) {
+        return None;
+    }
+    let z = n - UNIX_EPOCH_RATA_DIE - 1 + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if year < i16::min_value() as i64 || year > i16::max_value() as i64 {
+        return None;
+    }
+    Some(Date::new(year as i16, month as u8, day as u8))
+}
+
+/// The dates from `start` to `end` included, in order, none if `start` is after `end`
+///
+/// An invalid `start` or `end` gives no date either.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let start = Date::new(start.get_year(), start.get_month(), start.get_day());
+/// let end = Date::new(end.get_year(), end.get_month(), end.get_day());
+/// for date in dates_between(start, end) {
+///     // Backfill the day
+/// }
+/// ```
+pub fn dates_between(start: Date, end: Date) -> DateRange {
+    let first = to_rata_die(start.year, start.month, start.day);
+    let last = to_rata_die(end.year, end.month, end.day);
+    match (first, last) {
+        (Some(first), Some(last)) => DateRange { next: first, last: last },
+        _ => DateRange { next: 1, last: 0 },
+    }
+}
+
+/// The iterator of `dates_between`
+#[derive(Clone, Debug)]
+pub struct DateRange {
+    next: i64,
+    last: i64,
+}
+
+impl Iterator for DateRange {
+    type Item = Date;
+
+    fn next(&mut self) -> Option<Date> {
+        if self.next > self.last {
+            return None;
+        }
+        let date = from_rata_die(self.next);
+        self.next += 1;
+        date
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = if self.next > self.last { 0 } else { (self.last - self.next + 1) as usize };
+        (left, Some(left))
+    }
+}
+
+/// The chronological order of two dates, by the year, then the month, then the day
+///
+/// The years are signed, -1 is before 0. An invalid date is compared by its fields as well, this
+/// is the order of `Date`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// dates.sort_by(|a, b| {
+///     cmp_dates(a.get_year(), a.get_month(), a.get_day(), b.get_year(), b.get_month(), b.get_day())
+/// });
+/// ```
+pub fn cmp_dates(year_a: i16, month_a: u8, day_a: u8, year_b: i16, month_b: u8, day_b: u8) -> Ordering {
+    (year_a, month_a, day_a).cmp(&(year_b, month_b, day_b))
+}
+
+/// Parse `[-]YYYY-MM-DD` in the fields of a date
+///
+/// The year has at least four digits and fits in a `i16`, the month and the day have two
+/// digits. Returns `BadDate` for another text, or for a date that doesn't exist.
+pub fn parse_iso8601(s: &str) -> Result<(i16, u8, u8)> {
+    let (negative, rest) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        _ => (false, s),
+    };
+    let parts: Vec<&str> = rest.split('-').collect();
+    if parts.len() != 3 || parts[0].len() < 4 || parts[1].len() != 2 || parts[2].len() != 2
+        || !parts.iter().all(|p| { p.chars().all(|c| { c.is_digit(10) }) }) {
+        return Err(result::Error::BadDate(s.into()));
+    }
+    let year = try!(parts[0].parse::<i16>().map_err(|_| { result::Error::BadDate(s.into()) }));
+    let year = if negative { -year } else { year };
+    let month = try!(parts[1].parse::<u8>().map_err(|_| { result::Error::BadDate(s.into()) }));
+    let day = try!(parts[2].parse::<u8>().map_err(|_| { result::Error::BadDate(s.into()) }));
+    if !is_valid(year, month, day) {
+        return Err(result::Error::BadDate(s.into()));
+    }
+    Ok((year, month, day))
+}
+
+/// Write the fields of a date as `[-]YYYY-MM-DD`, as `Date` prints
+pub fn format_iso8601(year: i16, month: u8, day: u8) -> String {
+    Date::new(year, month, day).to_string()
+}
+
+/// The `chrono::NaiveDate` of the fields, `None` if the date doesn't exist
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let date: time_date::Reader = try!(msg.read_schema());
+/// let naive = try!(to_naive_date(date.get_year(), date.get_month(), date.get_day())
+///     .ok_or(result::Error::Misc("no such date".into())));
+/// ```
+pub fn to_naive_date(year: i16, month: u8, day: u8) -> Option<::chrono::NaiveDate> {
+    if !is_valid(year, month, day) {
+        return None;
+    }
+    ::chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+}
+
+/// The fields of a `chrono::NaiveDate`, `None` if its year doesn't fit in the `year: Int16` of `time_date`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let (year, month, day) = try!(from_naive_date(naive).ok_or(result::Error::Misc("out of range".into())));
+/// let mut builder: time_date::Builder = msg.build_schema();
+/// builder.set_year(year);
+/// builder.set_month(month);
+/// builder.set_day(day);
+/// ```
+pub fn from_naive_date(date: ::chrono::NaiveDate) -> Option<(i16, u8, u8)> {
+    use chrono::Datelike;
+    if date.year() < i16::min_value() as i32 || date.year() > i16::max_value() as i32 {
+        return None;
+    }
+    Some((date.year() as i16, date.month() as u8, date.day() as u8))
+}
+
+/// Add a `time_duration` to a date, `None` if the date doesn't exist or the result doesn't fit
+///
+/// The years and the months are added together, as `12 * years + months`. If the month of the
+/// result is shorter, the day is clamped to its last day: January 31 plus one month is the last
+/// day of February, 28 or 29. Then the days are added. Each field can be negative.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let duration: time_duration::Reader = try!(msg.read_schema());
+/// let due = date_add(start, duration.get_years(), duration.get_months(), duration.get_days());
+/// ```
+pub fn date_add(date: Date, years: i32, months: i32, days: i32) -> Option<Date> {
+    if !is_valid(date.year, date.month, date.day) {
+        return None;
+    }
+    let total = date.year as i64 * 12 + date.month as i64 - 1 + years as i64 * 12 + months as i64;
+    let year = if total >= 0 { total / 12 } else { (total - 11) / 12 };
+    let month = (total - year * 12 + 1) as u8;
+    if year < i16::min_value() as i64 || year > i16::max_value() as i64 {
+        return None;
+    }
+    let year = year as i16;
+    let day = match days_in_month(year, month) {
+        Some(last) if date.day > last => last,
+        _ => date.day,
+    };
+    to_rata_die(year, month, day).and_then(|n| { from_rata_die(n + days as i64) })
+}
+
+/// The `time_duration` from `from` to `to`, `None` if one of the dates doesn't exist
+///
+/// The duration has the most whole months that don't pass `to`, then the days. Its fields have
+/// the sign of `to - from`, and `date_add(from, years, months, days)` is `to`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// if let Some((years, months, days)) = date_diff(start, end) {
+///     let mut builder: time_duration::Builder = msg.build_schema();
+///     builder.set_years(years);
+///     builder.set_months(months);
+///     builder.set_days(days);
+/// }
+/// ```
+pub fn date_diff(from: Date, to: Date) -> Option<(i32, i32, i32)> {
+    let start = match to_rata_die(from.year, from.month, from.day) {
+        Some(n) => n,
+        None => { return None; },
+    };
+    let end = match to_rata_die(to.year, to.month, to.day) {
+        Some(n) => n,
+        None => { return None; },
+    };
+    // The date `from + months` is between `from` and `to`, it always exists
+    let plus = |months: i64| {
+        date_add(from, 0, months as i32, 0)
+            .and_then(|d| { to_rata_die(d.year, d.month, d.day) })
+            .expect("date_diff: the middle date is out of range")
+    };
+    let mut months = (to.year as i64 - from.year as i64) * 12 + to.month as i64 - from.month as i64;
+    let mut middle = plus(months);
+    if end >= start && middle > end {
+        months -= 1;
+        middle = plus(months);
+    } else if end < start && middle < end {
+        months += 1;
+        middle = plus(months);
+    }
+    Some(((months / 12) as i32, (months % 12) as i32, (end - middle) as i32))
+}
+
+/// True for a Saturday or a Sunday, false for an invalid date
+pub fn is_weekend(year: i16, month: u8, day: u8) -> bool {
+    match weekday(year, month, day) {
+        Some(Weekday::Saturday) | Some(Weekday::Sunday) => true,
+        _ => false,
+    }
+}
+
+/// The day of the year of a date, from 1 for January 1st, `None` if the date doesn't exist
+pub fn day_of_year(year: i16, month: u8, day: u8) -> Option<u16> {
+    if !is_valid(year, month, day) {
+        return None;
+    }
+    let before: u16 = (1..month).filter_map(|m| { days_in_month(year, m) }).map(|d| { d as u16 }).sum();
+    Some(before + day as u16)
+}
+
+/// An owned date, printed as `YYYY-MM-DD`
+///
+/// The year has at least four digits, a negative year is `-0044-03-15`. `Date::new` keeps the
+/// fields as they are, even if the date doesn't exist, `Date::from_ymd` checks them.
+///
+/// A date can be kept in the state of an agent or as the key of a `HashMap` or a `BTreeMap`.
+/// The order is the chronological order of `cmp_dates`: the year, then the month, then the day.
+/// With the `serde` feature, it is `Serialize` and `Deserialize`, as a struct of the three fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Date {
+    pub year: i16,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// The former name of `Date`
+pub type DatePrinter = Date;
+
+impl Date {
+    pub fn new(year: i16, month: u8, day: u8) -> Self {
+        Date {
+            year: year,
+            month: month,
+            day: day,
+        }
+    }
+
+    /// The date of the fields, `None` if it doesn't exist
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let date = try!(Date::from_ymd(reader.get_year(), reader.get_month(), reader.get_day())
+    ///     .ok_or(result::Error::Misc("no such date".into())));
+    /// ```
+    pub fn from_ymd(year: i16, month: u8, day: u8) -> Option<Self> {
+        if is_valid(year, month, day) {
+            Some(Date::new(year, month, day))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.year < 0 { "-" } else { "" };
+        write!(f, "{}{:04}-{:02}-{:02}", sign, (self.year as i32).abs(), self.month, self.day)
+    }
+}
+
+/// A Msg holding the dates `$dates`, a `&[Date]`, as a list of the date edge `$edge`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let dates: Vec<Date> = dates_between(start, end).collect();
+/// try!(self.output.output.send(build_date_list!(time_date, &dates)));
+/// ```
+#[macro_export]
+macro_rules! build_date_list {
+    ($edge:ident, $dates:expr) => {{
+        let dates: &[rustfbp::date::Date] = $dates;
+        let mut msg = rustfbp::ports::Msg::new();
+        {
+            let mut list: ::capnp::struct_list::Builder<$edge::Owned> = msg.build_list(dates.len() as u32);
+            for (i, date) in dates.iter().enumerate() {
+                let mut builder = list.borrow().get(i as u32);
+                builder.set_year(date.year);
+                builder.set_month(date.month);
+                builder.set_day(date.day);
+            }
+        }
+        msg
+    }}
+}
+
+/// The dates of a Msg built by `build_date_list!`, a `Result<Vec<Date>>`
+///
+/// `$msg` is a `&mut Msg`, as for `Msg::read_schema`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut msg = try!(self.input.input.recv());
+/// for date in try!(read_date_list!(time_date, &mut msg)) {
+///     // Process the date
+/// }
+/// ```
+#[macro_export]
+macro_rules! read_date_list {
+    ($edge:ident, $msg:expr) => {{
+        let msg: &mut rustfbp::ports::Msg = $msg;
+        msg.read_schema::<::capnp::struct_list::Reader<$edge::Owned>>().map(|list| {
+            list.iter()
+                .map(|date| { rustfbp::date::Date::new(date.get_year(), date.get_month(), date.get_day()) })
+                .collect::<Vec<rustfbp::date::Date>>()
+        })
+    }}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use testing::time_date;
+
+    use std::collections::HashMap;
+
+    /// The paths of `build_date_list!` and `read_date_list!`, outside of the crate
+    mod rustfbp {
+        pub use date;
+        pub use ports;
+    }
+
+    #[test]
+    fn is_valid_follows_the_leap_years() {
+        assert!(is_valid(2000, 2, 29));
+        assert!(!is_valid(1900, 2, 29));
+        assert!(is_valid(2004, 2, 29));
+        assert!(!is_valid(2003, 2, 29));
+        assert!(is_valid(0, 2, 29));
+        assert!(is_valid(-4, 2, 29));
+    }
+
+    #[test]
+    fn is_valid_checks_the_month_bounds() {
+        assert!(is_valid(2017, 1, 1));
+        assert!(is_valid(2017, 1, 31));
+        assert!(!is_valid(2017, 1, 32));
+        assert!(is_valid(2017, 4, 30));
+        assert!(!is_valid(2017, 4, 31));
+        assert!(is_valid(2017, 12, 31));
+        assert!(!is_valid(2017, 0, 1));
+        assert!(!is_valid(2017, 13, 1));
+        assert!(!is_valid(2017, 6, 0));
+    }
+
+    #[test]
+    fn weekday_of_known_dates() {
+        assert_eq!(weekday(2000, 1, 1), Some(Weekday::Saturday));
+        assert_eq!(weekday(1970, 1, 1), Some(Weekday::Thursday));
+        assert_eq!(weekday(1969, 7, 20), Some(Weekday::Sunday));
+        assert_eq!(weekday(1900, 1, 1), Some(Weekday::Monday));
+        assert_eq!(weekday(1600, 3, 1), Some(Weekday::Wednesday));
+        assert_eq!(weekday(2016, 2, 29), Some(Weekday::Monday));
+        assert_eq!(weekday(1, 1, 1), Some(Weekday::Monday));
+        // The year 0 has 366 days
+        assert_eq!(weekday(0, 1, 1), Some(Weekday::Saturday));
+        assert_eq!(weekday(-1, 12, 31), Some(Weekday::Friday));
+        assert_eq!(weekday(2017, 2, 29), None);
+        assert_eq!(weekday(2017, 13, 1), None);
+    }
+
+    #[test]
+    fn weekday_follows_the_days() {
+        let weekdays = [Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday, Weekday::Thursday,
+                        Weekday::Friday, Weekday::Saturday, Weekday::Sunday];
+        // 1999-12-27 is a Monday
+        let mut i = 0;
+        for date in dates_between(Date::new(1999, 12, 27), Date::new(2001, 3, 31)) {
+            assert_eq!(weekday(date.year, date.month, date.day), Some(weekdays[i % 7]), "{}", date);
+            i += 1;
+        }
+    }
+
+    #[test]
+    fn is_weekend_is_saturday_and_sunday() {
+        assert!(is_weekend(2017, 6, 3));
+        assert!(is_weekend(2017, 6, 4));
+        assert!(!is_weekend(2017, 6, 5));
+        assert!(!is_weekend(2017, 6, 2));
+        assert!(!is_weekend(2017, 6, 31));
+    }
+
+    #[test]
+    fn day_of_year_counts_from_january_1st() {
+        assert_eq!(day_of_year(2017, 1, 1), Some(1));
+        assert_eq!(day_of_year(2017, 3, 1), Some(60));
+        assert_eq!(day_of_year(2016, 3, 1), Some(61));
+        assert_eq!(day_of_year(2017, 12, 31), Some(365));
+        assert_eq!(day_of_year(2016, 12, 31), Some(366));
+        assert_eq!(day_of_year(2017, 2, 29), None);
+    }
+
+    #[test]
+    fn rata_die_of_known_dates() {
+        assert_eq!(to_rata_die(1, 1, 1), Some(1));
+        assert_eq!(to_rata_die(0, 12, 31), Some(0));
+        assert_eq!(to_rata_die(0, 1, 1), Some(-365));
+        assert_eq!(to_rata_die(1970, 1, 1), Some(UNIX_EPOCH_RATA_DIE + 1));
+        assert_eq!(to_rata_die(1900, 1, 1), Some(693596));
+        assert_eq!(to_rata_die(2000, 1, 1), Some(730120));
+        assert_eq!(to_rata_die(2017, 6, 1), Some(736481));
+        assert_eq!(to_rata_die(2017, 2, 29), None);
+        assert_eq!(to_rata_die(2017, 0, 1), None);
+        assert_eq!(from_rata_die(730120), Some(Date::new(2000, 1, 1)));
+        assert_eq!(from_rata_die(0), Some(Date::new(0, 12, 31)));
+    }
+
+    #[test]
+    fn rata_die_round_trips() {
+        let first = to_rata_die(-2000, 1, 1).unwrap();
+        let last = to_rata_die(2400, 12, 31).unwrap();
+        let mut previous = from_rata_die(first - 1).unwrap();
+        for n in first..last + 1 {
+            let date = from_rata_die(n).unwrap();
+            assert!(is_valid(date.year, date.month, date.day), "{}", date);
+            assert!(date > previous, "{} after {}", date, previous);
+            assert_eq!(to_rata_die(date.year, date.month, date.day), Some(n));
+            previous = date;
+        }
+    }
+
+    #[test]
+    fn rata_die_stops_at_the_bounds_of_the_year() {
+        let max = to_rata_die(i16::max_value(), 12, 31).unwrap();
+        let min = to_rata_die(i16::min_value(), 1, 1).unwrap();
+        assert_eq!(from_rata_die(max), Some(Date::new(i16::max_value(), 12, 31)));
+        assert_eq!(from_rata_die(max + 1), None);
+        assert_eq!(from_rata_die(min), Some(Date::new(i16::min_value(), 1, 1)));
+        assert_eq!(from_rata_die(min - 1), None);
+        assert_eq!(from_rata_die(i64::max_value()), None);
+        assert_eq!(from_rata_die(i64::min_value()), None);
+    }
+
+    #[test]
+    fn dates_between_rolls_the_month_and_the_year() {
+        let dates: Vec<Date> = dates_between(Date::new(2017, 1, 30), Date::new(2017, 2, 2)).collect();
+        assert_eq!(dates, vec![Date::new(2017, 1, 30), Date::new(2017, 1, 31), Date::new(2017, 2, 1), Date::new(2017, 2, 2)]);
+        let dates: Vec<Date> = dates_between(Date::new(2016, 12, 30), Date::new(2017, 1, 2)).collect();
+        assert_eq!(dates, vec![Date::new(2016, 12, 30), Date::new(2016, 12, 31), Date::new(2017, 1, 1), Date::new(2017, 1, 2)]);
+        let dates: Vec<Date> = dates_between(Date::new(0, 12, 31), Date::new(1, 1, 1)).collect();
+        assert_eq!(dates, vec![Date::new(0, 12, 31), Date::new(1, 1, 1)]);
+    }
+
+    #[test]
+    fn dates_between_has_the_leap_day() {
+        let dates: Vec<Date> = dates_between(Date::new(2016, 2, 28), Date::new(2016, 3, 1)).collect();
+        assert_eq!(dates, vec![Date::new(2016, 2, 28), Date::new(2016, 2, 29), Date::new(2016, 3, 1)]);
+        let dates: Vec<Date> = dates_between(Date::new(1900, 2, 28), Date::new(1900, 3, 1)).collect();
+        assert_eq!(dates, vec![Date::new(1900, 2, 28), Date::new(1900, 3, 1)]);
+        assert_eq!(dates_between(Date::new(2016, 1, 1), Date::new(2016, 12, 31)).count(), 366);
+    }
+
+    #[test]
+    fn dates_between_is_empty_backwards() {
+        let one: Vec<Date> = dates_between(Date::new(2017, 6, 1), Date::new(2017, 6, 1)).collect();
+        assert_eq!(one, vec![Date::new(2017, 6, 1)]);
+        assert_eq!(dates_between(Date::new(2017, 6, 2), Date::new(2017, 6, 1)).count(), 0);
+        assert_eq!(dates_between(Date::new(2017, 2, 29), Date::new(2017, 6, 1)).count(), 0);
+        assert_eq!(dates_between(Date::new(2017, 1, 1), Date::new(2017, 2, 30)).count(), 0);
+        let mut range = dates_between(Date::new(2017, 6, 1), Date::new(2017, 6, 30));
+        assert_eq!(range.size_hint(), (30, Some(30)));
+        range.next();
+        assert_eq!(range.size_hint(), (29, Some(29)));
+    }
+
+    #[test]
+    fn date_list_round_trips() {
+        let first = to_rata_die(2015, 1, 1).unwrap();
+        let dates: Vec<Date> = (first..first + 1000).map(|n| { from_rata_die(n).unwrap() }).collect();
+        let mut msg = build_date_list!(time_date, &dates);
+        msg.before_send().unwrap();
+        assert_eq!(read_date_list!(time_date, &mut msg).unwrap(), dates);
+        let mut empty = build_date_list!(time_date, &[]);
+        empty.before_send().unwrap();
+        assert_eq!(read_date_list!(time_date, &mut empty).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn cmp_dates_sorts_chronologically() {
+        let mut dates = vec![(2017, 3, 1), (-1, 12, 31), (2017, 2, 28), (0, 1, 1), (-44, 3, 15), (2016, 12, 31), (2017, 2, 1)];
+        dates.sort_by(|a, b| { cmp_dates(a.0, a.1, a.2, b.0, b.1, b.2) });
+        assert_eq!(dates, vec![(-44, 3, 15), (-1, 12, 31), (0, 1, 1), (2016, 12, 31), (2017, 2, 1), (2017, 2, 28), (2017, 3, 1)]);
+        assert_eq!(cmp_dates(2017, 6, 1, 2017, 6, 1), Ordering::Equal);
+        assert_eq!(Date::new(-1, 12, 31).cmp(&Date::new(0, 1, 1)), cmp_dates(-1, 12, 31, 0, 1, 1));
+    }
+
+    #[test]
+    fn parse_iso8601_reads_the_fields() {
+        assert_eq!(parse_iso8601("2024-02-29").unwrap(), (2024, 2, 29));
+        assert_eq!(parse_iso8601("-0044-03-15").unwrap(), (-44, 3, 15));
+        assert_eq!(parse_iso8601("0000-01-01").unwrap(), (0, 1, 1));
+        assert_eq!(parse_iso8601("12345-12-31").unwrap(), (12345, 12, 31));
+    }
+
+    #[test]
+    fn parse_iso8601_rejects_the_malformed_dates() {
+        for s in &["2024-2-9", "2024/02/29", "", "-", "2024-02", "2024-02-29-", "24-02-29", "2024-02-+9",
+                   "+2024-02-29", "99999-01-01", "2023-02-29", "2024-13-01", "2024-00-10"] {
+            match parse_iso8601(s) {
+                Err(result::Error::BadDate(ref bad)) if bad == s => {},
+                _ => panic!("'{}' is parsed", s),
+            }
+        }
+    }
+
+    #[test]
+    fn format_iso8601_pads_the_fields() {
+        assert_eq!(format_iso8601(2024, 2, 9), "2024-02-09");
+        assert_eq!(format_iso8601(-44, 3, 15), "-0044-03-15");
+        assert_eq!(format_iso8601(7, 1, 1), "0007-01-01");
+        let (y, m, d) = parse_iso8601(&format_iso8601(-1, 12, 31)).unwrap();
+        assert_eq!((y, m, d), (-1, 12, 31));
+    }
+
+    #[test]
+    fn naive_dates_round_trip() {
+        for year in -400..2401 {
+            for month in 1..13 {
+                for day in 1..days_in_month(year, month).unwrap() + 1 {
+                    let naive = to_naive_date(year, month, day).unwrap();
+                    assert_eq!(from_naive_date(naive), Some((year, month, day)));
+                }
+            }
+        }
+        assert_eq!(to_naive_date(2017, 2, 29), None);
+        assert_eq!(from_naive_date(::chrono::NaiveDate::from_ymd_opt(40000, 1, 1).unwrap()), None);
+        assert_eq!(from_naive_date(::chrono::NaiveDate::from_ymd_opt(-40000, 1, 1).unwrap()), None);
+    }
+
+    #[test]
+    fn date_add_clamps_to_the_end_of_the_month() {
+        assert_eq!(date_add(Date::new(2017, 1, 31), 0, 1, 0), Some(Date::new(2017, 2, 28)));
+        assert_eq!(date_add(Date::new(2016, 1, 31), 0, 1, 0), Some(Date::new(2016, 2, 29)));
+        assert_eq!(date_add(Date::new(2016, 2, 29), 1, 0, 0), Some(Date::new(2017, 2, 28)));
+        assert_eq!(date_add(Date::new(2016, 2, 29), 4, 0, 0), Some(Date::new(2020, 2, 29)));
+        assert_eq!(date_add(Date::new(2017, 3, 31), 0, -1, 0), Some(Date::new(2017, 2, 28)));
+        assert_eq!(date_add(Date::new(2017, 1, 31), 0, 1, 1), Some(Date::new(2017, 3, 1)));
+    }
+
+    #[test]
+    fn date_add_rolls_the_months_and_the_days() {
+        assert_eq!(date_add(Date::new(2017, 6, 15), 0, 13, 0), Some(Date::new(2018, 7, 15)));
+        assert_eq!(date_add(Date::new(2017, 6, 15), 1, -18, 0), Some(Date::new(2016, 12, 15)));
+        assert_eq!(date_add(Date::new(2017, 12, 31), 0, 0, 1), Some(Date::new(2018, 1, 1)));
+        assert_eq!(date_add(Date::new(2016, 2, 28), 0, 0, 1), Some(Date::new(2016, 2, 29)));
+        assert_eq!(date_add(Date::new(1, 1, 1), 0, 0, -1), Some(Date::new(0, 12, 31)));
+        assert_eq!(date_add(Date::new(0, 1, 15), 0, -1, 0), Some(Date::new(-1, 12, 15)));
+    }
+
+    #[test]
+    fn date_add_fails_out_of_range() {
+        assert_eq!(date_add(Date::new(2017, 2, 29), 0, 0, 1), None);
+        assert_eq!(date_add(Date::new(i16::max_value(), 12, 31), 0, 0, 1), None);
+        assert_eq!(date_add(Date::new(i16::min_value(), 1, 1), 0, -1, 0), None);
+        assert_eq!(date_add(Date::new(2017, 1, 1), i32::max_value(), i32::max_value(), 0), None);
+        assert_eq!(date_add(Date::new(2017, 1, 1), 0, 0, i32::min_value()), None);
+    }
+
+    #[test]
+    fn date_diff_is_undone_by_date_add() {
+        assert_eq!(date_diff(Date::new(2017, 1, 31), Date::new(2017, 2, 28)), Some((0, 1, 0)));
+        assert_eq!(date_diff(Date::new(2017, 1, 31), Date::new(2017, 3, 1)), Some((0, 1, 1)));
+        assert_eq!(date_diff(Date::new(2016, 2, 29), Date::new(2017, 2, 28)), Some((1, 0, 0)));
+        assert_eq!(date_diff(Date::new(2017, 3, 1), Date::new(2017, 1, 31)), Some((0, -1, -1)));
+        assert_eq!(date_diff(Date::new(2017, 6, 1), Date::new(2015, 3, 1)), Some((-2, -3, 0)));
+        assert_eq!(date_diff(Date::new(2017, 2, 29), Date::new(2017, 3, 1)), None);
+        let dates: Vec<Date> = dates_between(Date::new(2015, 12, 20), Date::new(2016, 3, 10)).collect();
+        for from in &dates {
+            for to in &dates {
+                let (years, months, days) = date_diff(*from, *to).unwrap();
+                assert_eq!(date_add(*from, years, months, days), Some(*to), "{} to {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn from_ymd_checks_the_calendar() {
+        assert_eq!(Date::from_ymd(2016, 2, 29), Some(Date::new(2016, 2, 29)));
+        assert_eq!(Date::from_ymd(2017, 2, 29), None);
+        assert_eq!(Date::from_ymd(2017, 4, 31), None);
+        assert_eq!(Date::from_ymd(2017, 13, 1), None);
+        assert_eq!(Date::from_ymd(2017, 1, 0), None);
+    }
+
+    #[test]
+    fn date_prints_the_sign_and_four_digits() {
+        assert_eq!(Date::new(2017, 6, 1).to_string(), "2017-06-01");
+        assert_eq!(Date::new(-44, 3, 15).to_string(), "-0044-03-15");
+        assert_eq!(Date::new(12345, 12, 31).to_string(), "12345-12-31");
+    }
+
+    #[test]
+    fn date_is_a_key() {
+        let mut dates = vec![Date::new(2017, 1, 2), Date::new(-1, 12, 31), Date::new(2016, 12, 31), Date::new(2017, 1, 1)];
+        dates.sort();
+        assert_eq!(dates, vec![Date::new(-1, 12, 31), Date::new(2016, 12, 31), Date::new(2017, 1, 1), Date::new(2017, 1, 2)]);
+        let mut counts = HashMap::new();
+        *counts.entry(Date::new(2017, 1, 1)).or_insert(0) += 1;
+        *counts.entry(Date::new(2017, 1, 1)).or_insert(0) += 1;
+        assert_eq!(counts[&Date::new(2017, 1, 1)], 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn date_round_trips_through_json() {
+        let date = Date::new(-44, 3, 15);
+        let json = ::serde_json::to_string(&date).unwrap();
+        assert_eq!(json, r#"{"year":-44,"month":3,"day":15}"#);
+        let back: Date = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(back, date);
+    }
+}
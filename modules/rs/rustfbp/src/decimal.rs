@@ -0,0 +1,165 @@
+//! Text conversions of the `maths_decimal` edge
+//!
+//! A `maths_decimal` is a `mantissa: Int64` and a `scale: Int8`, the value is
+//! `mantissa * 10^-scale`. The reader and the builder are generated in each agent, so the
+//! helpers take and return the two fields.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let (mantissa, scale) = try!(decimal_from_str("123.45"));
+//! {
+//!     let mut builder: maths_decimal::Builder = msg.build_schema();
+//!     builder.set_mantissa(mantissa);
+//!     builder.set_scale(scale);
+//! }
+//!
+//! let reader: maths_decimal::Reader = try!(msg.read_schema());
+//! assert_eq!(decimal_to_string(reader.get_mantissa(), reader.get_scale()), "123.45");
+//! ```
+
+use result;
+use result::Result;
+
+use std::i8;
+
+/// Write a decimal as `[-]digits[.digits]`
+///
+/// A negative scale adds zeros after the mantissa, 12 with the scale -2 is `1200`.
+pub fn decimal_to_string(mantissa: i64, scale: i8) -> String {
+    let negative = mantissa < 0;
+    // The absolute value as u64, i64::MIN has no positive i64
+    let digits = if negative { (mantissa as u64).wrapping_neg() } else { mantissa as u64 };
+    let mut digits = format!("{}", digits);
+    if scale < 0 {
+        if digits != "0" {
+            for _ in 0..-(scale as i16) {
+                digits.push('0');
+            }
+        }
+    } else if scale > 0 {
+        let scale = scale as usize;
+        while digits.len() <= scale {
+            digits.insert(0, '0');
+        }
+        let pos = digits.len() - scale;
+        digits.insert(pos, '.');
+    }
+    if negative {
+        digits.insert(0, '-');
+    }
+    digits
+}
+
+/// Parse `[+-]digits[.digits]` in a mantissa and a scale
+///
+/// The scale is the number of fractional digits, trailing zeros included: `1.50` is
+/// `(150, 2)`. Returns `DecimalOverflow` if the mantissa doesn't fit in a `i64`, and
+/// `DecimalTooPrecise` if there are more than 127 fractional digits.
+pub fn decimal_from_str(s: &str) -> Result<(i64, i8)> {
+    let (negative, rest) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (int, frac) = match rest.find('.') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => (rest, ""),
+    };
+    if int.len() + frac.len() == 0 {
+        return Err(result::Error::BadDecimal(s.into()));
+    }
+    if frac.len() > i8::MAX as usize {
+        return Err(result::Error::DecimalTooPrecise(s.into()));
+    }
+    // Accumulate on the negative side, to accept i64::MIN
+    let mut mantissa: i64 = 0;
+    for c in int.chars().chain(frac.chars()) {
+        let digit = match c.to_digit(10) {
+            Some(digit) => digit as i64,
+            None => { return Err(result::Error::BadDecimal(s.into())); },
+        };
+        mantissa = try!(mantissa.checked_mul(10)
+                        .and_then(|m| { m.checked_sub(digit) })
+                        .ok_or(result::Error::DecimalOverflow(s.into())));
+    }
+    if !negative {
+        mantissa = try!(mantissa.checked_neg().ok_or(result::Error::DecimalOverflow(s.into())));
+    }
+    Ok((mantissa, frac.len() as i8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::i64;
+
+    #[test]
+    fn decimal_from_str_reads_the_scale() {
+        assert_eq!(decimal_from_str("123.45").unwrap(), (12345, 2));
+        assert_eq!(decimal_from_str("-123.45").unwrap(), (-12345, 2));
+        assert_eq!(decimal_from_str("+7").unwrap(), (7, 0));
+        assert_eq!(decimal_from_str("-0.05").unwrap(), (-5, 2));
+        assert_eq!(decimal_from_str(".5").unwrap(), (5, 1));
+        assert_eq!(decimal_from_str("5.").unwrap(), (5, 0));
+    }
+
+    #[test]
+    fn decimal_from_str_keeps_the_trailing_zeros() {
+        assert_eq!(decimal_from_str("1.50").unwrap(), (150, 2));
+        assert_eq!(decimal_from_str("100").unwrap(), (100, 0));
+        assert_eq!(decimal_from_str("0.000").unwrap(), (0, 3));
+        assert_eq!(decimal_to_string(150, 2), "1.50");
+    }
+
+    #[test]
+    fn decimal_from_str_detects_the_overflow() {
+        assert_eq!(decimal_from_str("9223372036854775807").unwrap(), (i64::MAX, 0));
+        assert_eq!(decimal_from_str("-9223372036854775808").unwrap(), (i64::MIN, 0));
+        assert_eq!(decimal_from_str("-922337203685477580.8").unwrap(), (i64::MIN, 1));
+        for s in &["9223372036854775808", "-9223372036854775809", "92233720368547758.080"] {
+            match decimal_from_str(s) {
+                Err(result::Error::DecimalOverflow(ref bad)) if bad == s => {},
+                _ => panic!("'{}' fits in a i64", s),
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_from_str_rejects_the_other_strings() {
+        let precise = format!("0.{}", (0..128).map(|_| { "0" }).collect::<String>());
+        match decimal_from_str(&precise) {
+            Err(result::Error::DecimalTooPrecise(_)) => {},
+            _ => panic!("128 fractional digits are accepted"),
+        }
+        let precise = format!("0.{}", (0..127).map(|_| { "0" }).collect::<String>());
+        assert_eq!(decimal_from_str(&precise).unwrap(), (0, 127));
+        for s in &["", "-", "+", ".", "1.2.3", "1,5", "1e5", " 1", "--1", "1-"] {
+            match decimal_from_str(s) {
+                Err(result::Error::BadDecimal(ref bad)) if bad == s => {},
+                _ => panic!("'{}' is parsed", s),
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_to_string_places_the_point() {
+        assert_eq!(decimal_to_string(12345, 2), "123.45");
+        assert_eq!(decimal_to_string(-12345, 2), "-123.45");
+        assert_eq!(decimal_to_string(5, 3), "0.005");
+        assert_eq!(decimal_to_string(-5, 1), "-0.5");
+        assert_eq!(decimal_to_string(12, -2), "1200");
+        assert_eq!(decimal_to_string(0, -2), "0");
+        assert_eq!(decimal_to_string(i64::MIN, 0), "-9223372036854775808");
+        assert_eq!(decimal_to_string(i64::MIN, 19), "-0.9223372036854775808");
+    }
+
+    #[test]
+    fn decimals_round_trip() {
+        for s in &["0", "-1", "123.45", "-0.001", "1.50", "9223372036854775807", "-922337203685477.5808"] {
+            let (mantissa, scale) = decimal_from_str(s).unwrap();
+            assert_eq!(decimal_to_string(mantissa, scale), *s);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! JSON conversions of the `time_date` edge, with the `json` feature
+//!
+//! A date is the object `{"year": -44, "month": 3, "day": 15}`. As in `date`, the reader and the
+//! builder are generated in each agent, so the helpers take and return the fields.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let date: time_date::Reader = try!(msg.read_schema());
+//! let value = date_to_json(date.get_year(), date.get_month(), date.get_day());
+//!
+//! let (year, month, day) = try!(date_from_json(&value));
+//! let mut builder: time_date::Builder = msg.build_schema();
+//! builder.set_year(year);
+//! builder.set_month(month);
+//! builder.set_day(day);
+//! ```
+
+use result;
+use result::Result;
+
+use date::is_valid;
+
+use serde_json::{Map, Value};
+
+/// The JSON object of the fields of a date
+pub fn date_to_json(year: i16, month: u8, day: u8) -> Value {
+    let mut object = Map::new();
+    object.insert("year".to_string(), Value::from(year));
+    object.insert("month".to_string(), Value::from(month));
+    object.insert("day".to_string(), Value::from(day));
+    Value::Object(object)
+}
+
+/// The integer field `name` of `object`, in `min` to `max`
+fn field(object: &Map<String, Value>, name: &str, min: i64, max: i64) -> Result<i64> {
+    let value = try!(object.get(name).ok_or(result::Error::BadJson(format!("the date has no field {}", name))));
+    let n = try!(value.as_i64().ok_or(result::Error::BadJson(format!("the field {} of the date is not an integer : {}", name, value))));
+    if n < min || n > max {
+        return Err(result::Error::BadJson(format!("the field {} of the date is out of range : {}", name, n)));
+    }
+    Ok(n)
+}
+
+/// The fields of a date written by `date_to_json`
+///
+/// Returns `BadJson` if `value` is not an object, if a field is missing, is not an integer or is
+/// out of the range of the edge, or if the date doesn't exist. The other keys are ignored.
+pub fn date_from_json(value: &Value) -> Result<(i16, u8, u8)> {
+    let object = try!(value.as_object().ok_or(result::Error::BadJson(format!("the date is not an object : {}", value))));
+    let year = try!(field(object, "year", i16::min_value() as i64, i16::max_value() as i64)) as i16;
+    let month = try!(field(object, "month", 0, u8::max_value() as i64)) as u8;
+    let day = try!(field(object, "day", 0, u8::max_value() as i64)) as u8;
+    if !is_valid(year, month, day) {
+        return Err(result::Error::BadJson(format!("the date {} doesn't exist", value)));
+    }
+    Ok((year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use date::days_in_month;
+
+    use serde_json;
+
+    #[test]
+    fn dates_round_trip_through_json() {
+        for year in -1000..3001 {
+            for month in 1..13 {
+                for &day in &[1, 15, days_in_month(year, month).unwrap()] {
+                    let text = serde_json::to_string(&date_to_json(year, month, day)).unwrap();
+                    let value: Value = serde_json::from_str(&text).unwrap();
+                    assert_eq!(date_from_json(&value).unwrap(), (year, month, day));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn date_to_json_is_an_object_of_the_fields() {
+        let text = serde_json::to_string(&date_to_json(-44, 3, 15)).unwrap();
+        let value: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["year"].as_i64(), Some(-44));
+        assert_eq!(value["month"].as_i64(), Some(3));
+        assert_eq!(value["day"].as_i64(), Some(15));
+    }
+
+    #[test]
+    fn bad_json_dates_are_rejected() {
+        for text in &[r#"{"month":3,"day":15}"#, r#"{"year":2017,"day":15}"#, r#"{"year":2017,"month":3}"#,
+                      r#"{"year":"2017","month":3,"day":15}"#, r#"{"year":2017.5,"month":3,"day":15}"#,
+                      r#"{"year":40000,"month":3,"day":15}"#, r#"{"year":2017,"month":-3,"day":15}"#,
+                      r#"{"year":2017,"month":13,"day":15}"#, r#"{"year":2017,"month":2,"day":29}"#,
+                      r#"[2017,3,15]"#, r#""2017-03-15""#] {
+            let value: Value = serde_json::from_str(text).unwrap();
+            match date_from_json(&value) {
+                Err(result::Error::BadJson(_)) => {},
+                _ => panic!("{} is read as a date", text),
+            }
+        }
+    }
+}
@@ -1,14 +1,50 @@
 #![feature(question_mark)]
-#![feature(alloc_system)]
+#![cfg_attr(not(feature = "memory-tracking"), feature(alloc_system))]
 
+// The memory-tracking feature installs its own global allocator, see the memory module
+#[cfg(not(feature = "memory-tracking"))]
 extern crate alloc_system;
 
 extern crate libloading;
 extern crate capnp;
+extern crate chrono;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(any(feature = "json", all(test, feature = "serde")))]
+extern crate serde_json;
+
+mod codec;
+mod random;
+mod signal;
 
 pub mod agent;
+#[macro_use]
+pub mod builtin;
+// After builtin, its tests use builtin_ports!
+mod memory;
+pub mod date;
+pub mod decimal;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod msgpack;
+pub mod network;
 
 pub mod scheduler;
 
 pub mod ports;
 pub mod result;
+pub mod retry;
+pub mod record;
+pub mod request;
+pub mod testing;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+pub mod typed;
@@ -0,0 +1,233 @@
+//! Approximate memory accounting per agent
+//!
+//! With the `memory-tracking` feature, rustfbp installs a global allocator wrapping the system
+//! allocator. Once `Scheduler::enable_memory_tracking` is called, each allocation and each
+//! deallocation is counted for the agent running on the thread, and the counts are in
+//! `AgentMetrics`.
+//!
+//! # Toolchain
+//!
+//! The allocator is a `GlobalAlloc`, which the pinned nightly of the nix build doesn't have:
+//! the feature needs a rustc of at least 1.28, built with cargo. The feature replaces
+//! `alloc_system`, which is only used without it. The nix build never enables the feature, and
+//! without it `Scheduler::enable_memory_tracking` only prints a warning.
+//!
+//! The accounting is approximate:
+//!
+//! * the memory is counted for the agent running when it is allocated or freed: a Msg built by
+//!   an agent and dropped by the next one is freed by the second, whose `live_bytes` can be
+//!   negative
+//! * the allocations of `on_start`, `on_stop` and of the scheduler itself are not counted
+//! * the sizes are the sizes asked to the allocator, not the memory used by the system
+//!
+//! # Overhead
+//!
+//! Without the feature, the allocator of std is used and nothing is counted. With the feature,
+//! each allocation reads a thread local and, while the tracking is enabled, updates an atomic
+//! counter shared by the threads running the agent. Expect the allocations to be noticeably
+//! slower in the agents allocating a lot.
+
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT};
+
+/// True once `Scheduler::enable_memory_tracking` is called
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+thread_local!(static CURRENT: Cell<*const MemoryCounters> = Cell::new(ptr::null()));
+
+/// The bytes allocated and freed by an agent
+pub struct MemoryCounters {
+    pub allocated: AtomicUsize,
+    pub freed: AtomicUsize,
+}
+
+impl MemoryCounters {
+    pub fn new() -> Self {
+        MemoryCounters {
+            allocated: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Count the allocations of the thread for `counters`, until the guard is dropped
+pub struct Tracked {
+    previous: *const MemoryCounters,
+}
+
+impl Tracked {
+    /// The counters must outlive the guard
+    pub fn new(counters: &MemoryCounters) -> Tracked {
+        let previous = CURRENT.with(|current| {
+            let previous = current.get();
+            current.set(counters as *const MemoryCounters);
+            previous
+        });
+        Tracked {
+            previous: previous,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        CURRENT.with(|current| { current.set(previous) });
+    }
+}
+
+/// Start counting, returns false if rustfbp is built without the `memory-tracking` feature
+pub fn enable() -> bool {
+    ENABLED.store(true, Ordering::SeqCst);
+    cfg!(feature = "memory-tracking")
+}
+
+#[cfg(feature = "memory-tracking")]
+mod allocator {
+    use super::{CURRENT, ENABLED};
+
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::Ordering;
+
+    /// Count `size` bytes for the agent of the thread, if any
+    fn count(size: usize, allocated: bool) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        // The thread local is gone while the thread exits
+        let _ = CURRENT.try_with(|current| {
+            let counters = current.get();
+            if !counters.is_null() {
+                let counters = unsafe { &*counters };
+                if allocated {
+                    counters.allocated.fetch_add(size, Ordering::Relaxed);
+                } else {
+                    counters.freed.fetch_add(size, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// The system allocator, counting the bytes of the agents
+    struct TrackingAllocator;
+
+    unsafe impl GlobalAlloc for TrackingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                count(layout.size(), true);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            count(layout.size(), false);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = System.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                count(layout.size(), false);
+                count(new_size, true);
+            }
+            new_ptr
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> *const MemoryCounters {
+        CURRENT.with(|current| { current.get() })
+    }
+
+    #[test]
+    fn tracked_guards_restore_the_previous_counters() {
+        let outer = MemoryCounters::new();
+        let inner = MemoryCounters::new();
+        {
+            let _outer = Tracked::new(&outer);
+            {
+                let _inner = Tracked::new(&inner);
+                assert_eq!(current(), &inner as *const MemoryCounters);
+            }
+            assert_eq!(current(), &outer as *const MemoryCounters);
+        }
+        assert!(current().is_null());
+    }
+
+    #[cfg(feature = "memory-tracking")]
+    mod allocator {
+        use agent::{Agent, AgentPorts, PortList};
+        use ports::{MsgReceiver, MsgSender};
+        use result;
+        use result::Result;
+        use scheduler::{AgentCtx, BoxedComp, CompMsg, Scheduler, Signal};
+        use testing::{bytes_msg, msg_bytes};
+
+        use std::collections::HashMap;
+        use std::sync::mpsc::Sender;
+        use std::time::Duration;
+
+        const MB: usize = 1 << 20;
+
+        /// Keeps a MB for each IP 1, frees them all on an IP 0
+        struct Hoarder {
+            input: MsgReceiver,
+            kept: Vec<Vec<u8>>,
+        }
+
+        impl Agent for Hoarder {
+            builtin_ports! {
+                inputs { input: None },
+                outputs {},
+                array_inputs {},
+                array_outputs {}
+            }
+
+            fn run(&mut self) -> Result<Signal> {
+                let mut msg = try!(self.input.recv());
+                if msg_bytes(&mut msg).unwrap()[0] == 1 {
+                    self.kept.push(vec![1; MB]);
+                } else {
+                    self.kept = vec![];
+                }
+                Ok(Signal::End)
+            }
+        }
+
+        fn hoarder(id: usize, sc: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+            let (input, sender) = MsgReceiver::new(id, "input", sc, ctx, true);
+            let mut senders = HashMap::new();
+            senders.insert("input".to_string(), sender);
+            Ok((Box::new(Hoarder { input: input, kept: vec![] }) as BoxedComp, senders))
+        }
+
+        #[test]
+        fn live_bytes_follows_the_allocations_of_an_agent() {
+            let mut sched = Scheduler::new();
+            sched.add_agent_boxed("hoarder", hoarder).unwrap();
+            sched.enable_metrics(true);
+            sched.enable_memory_tracking();
+            sched.start().unwrap();
+            for _ in 0..3 {
+                sched.inject("hoarder", "input", bytes_msg(&[1])).unwrap();
+            }
+            assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+            let live = sched.metrics()["hoarder"].live_bytes;
+            assert!(live >= 3 * MB as i64 && live < 4 * MB as i64, "the hoarder holds {} bytes", live);
+            sched.inject("hoarder", "input", bytes_msg(&[0])).unwrap();
+            assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+            let live = sched.metrics()["hoarder"].live_bytes;
+            assert!(live.abs() < MB as i64, "the hoarder holds {} bytes", live);
+            sched.shutdown(Duration::from_secs(5)).unwrap();
+        }
+    }
+}
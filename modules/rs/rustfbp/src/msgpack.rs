@@ -0,0 +1,238 @@
+//! MessagePack encoding of the `time_date` edge
+//!
+//! A date is the map `{"year": -44, "month": 3, "day": 15}`, keyed by the field names, as a
+//! MessagePack consumer in another language expects. As in `date`, the reader and the builder
+//! are generated in each agent, so the helpers take and return the fields.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let date: time_date::Reader = try!(msg.read_schema());
+//! let bytes = date_to_msgpack(date.get_year(), date.get_month(), date.get_day());
+//!
+//! let (year, month, day) = try!(date_from_msgpack(&bytes));
+//! let mut builder: time_date::Builder = msg.build_schema();
+//! builder.set_year(year);
+//! builder.set_month(month);
+//! builder.set_day(day);
+//! ```
+
+use result;
+use result::Result;
+
+use date::is_valid;
+
+/// Append the shortest MessagePack encoding of `n`
+fn write_int(bytes: &mut Vec<u8>, n: i64) {
+    if n >= 0 && n <= 0x7f {
+        bytes.push(n as u8);
+    } else if n >= -32 && n < 0 {
+        bytes.push(n as i8 as u8);
+    } else if n >= 0 && n <= 0xff {
+        bytes.push(0xcc);
+        bytes.push(n as u8);
+    } else if n >= 0 && n <= 0xffff {
+        bytes.push(0xcd);
+        bytes.push((n >> 8) as u8);
+        bytes.push(n as u8);
+    } else if n >= -0x80 && n < 0 {
+        bytes.push(0xd0);
+        bytes.push(n as i8 as u8);
+    } else {
+        // The fields of a date are at least -0x8000
+        bytes.push(0xd1);
+        bytes.push((n as i16 >> 8) as u8);
+        bytes.push(n as i16 as u8);
+    }
+}
+
+/// Append the MessagePack encoding of the short string `s`
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.push(0xa0 | s.len() as u8);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+/// The MessagePack map `{"year", "month", "day"}` of the fields of a date
+pub fn date_to_msgpack(year: i16, month: u8, day: u8) -> Vec<u8> {
+    let mut bytes = vec![0x83];
+    write_str(&mut bytes, "year");
+    write_int(&mut bytes, year as i64);
+    write_str(&mut bytes, "month");
+    write_int(&mut bytes, month as i64);
+    write_str(&mut bytes, "day");
+    write_int(&mut bytes, day as i64);
+    bytes
+}
+
+/// Reads the MessagePack values of a date from the start of `bytes`
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let b = try!(self.bytes.get(self.pos).cloned()
+                     .ok_or(result::Error::BadMsgpack("the bytes end in the middle of the date".into())));
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// The next `len` bytes as a big endian unsigned integer
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        let mut n = 0;
+        for _ in 0..len {
+            n = n << 8 | try!(self.byte()) as u64;
+        }
+        Ok(n)
+    }
+
+    /// An integer in any of its encodings
+    fn int(&mut self) -> Result<i64> {
+        let b = try!(self.byte());
+        match b {
+            b if b <= 0x7f => Ok(b as i64),
+            b if b >= 0xe0 => Ok(b as i8 as i64),
+            0xcc => self.uint(1).map(|n| { n as i64 }),
+            0xcd => self.uint(2).map(|n| { n as i64 }),
+            0xce => self.uint(4).map(|n| { n as i64 }),
+            0xcf => {
+                let n = try!(self.uint(8));
+                if n > i64::max_value() as u64 {
+                    return Err(result::Error::BadMsgpack(format!("the integer {} is out of range", n)));
+                }
+                Ok(n as i64)
+            }
+            0xd0 => self.uint(1).map(|n| { n as u8 as i8 as i64 }),
+            0xd1 => self.uint(2).map(|n| { n as u16 as i16 as i64 }),
+            0xd2 => self.uint(4).map(|n| { n as u32 as i32 as i64 }),
+            0xd3 => self.uint(8).map(|n| { n as i64 }),
+            b => Err(result::Error::BadMsgpack(format!("0x{:02x} is not an integer", b))),
+        }
+    }
+
+    /// The number of entries of a map
+    fn map_len(&mut self) -> Result<u64> {
+        let b = try!(self.byte());
+        match b {
+            b if b & 0xf0 == 0x80 => Ok((b & 0x0f) as u64),
+            0xde => self.uint(2),
+            0xdf => self.uint(4),
+            b => Err(result::Error::BadMsgpack(format!("0x{:02x} is not a map", b))),
+        }
+    }
+
+    /// The bytes of a string
+    fn str(&mut self) -> Result<&'a [u8]> {
+        let b = try!(self.byte());
+        let len = match b {
+            b if b & 0xe0 == 0xa0 => (b & 0x1f) as u64,
+            0xd9 => try!(self.uint(1)),
+            0xda => try!(self.uint(2)),
+            0xdb => try!(self.uint(4)),
+            b => { return Err(result::Error::BadMsgpack(format!("0x{:02x} is not a string", b))); },
+        };
+        let len = len as usize;
+        if self.bytes.len() - self.pos < len {
+            return Err(result::Error::BadMsgpack("the bytes end in the middle of the date".into()));
+        }
+        let s = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(s)
+    }
+}
+
+/// The field `name` of a date, in `min` to `max`
+fn field(value: Option<i64>, name: &str, min: i64, max: i64) -> Result<i64> {
+    let n = try!(value.ok_or(result::Error::BadMsgpack(format!("the date has no field {}", name))));
+    if n < min || n > max {
+        return Err(result::Error::BadMsgpack(format!("the field {} of the date is out of range : {}", name, n)));
+    }
+    Ok(n)
+}
+
+/// The fields of a date written by `date_to_msgpack`
+///
+/// The keys can be in any order, and the integers and the map in any of their encodings.
+/// Returns `BadMsgpack` if the bytes are not a map of the three fields, if a field is out of
+/// the range of the edge, or if the date doesn't exist.
+pub fn date_from_msgpack(bytes: &[u8]) -> Result<(i16, u8, u8)> {
+    let mut reader = Reader { bytes: bytes, pos: 0 };
+    let (mut year, mut month, mut day) = (None, None, None);
+    for _ in 0..try!(reader.map_len()) {
+        let key = try!(reader.str());
+        if key == b"year" {
+            year = Some(try!(reader.int()));
+        } else if key == b"month" {
+            month = Some(try!(reader.int()));
+        } else if key == b"day" {
+            day = Some(try!(reader.int()));
+        } else {
+            return Err(result::Error::BadMsgpack(format!("the date has a field {}", String::from_utf8_lossy(key))));
+        }
+    }
+    if reader.pos != bytes.len() {
+        return Err(result::Error::BadMsgpack(format!("{} bytes follow the date", bytes.len() - reader.pos)));
+    }
+    let year = try!(field(year, "year", i16::min_value() as i64, i16::max_value() as i64)) as i16;
+    let month = try!(field(month, "month", 0, u8::max_value() as i64)) as u8;
+    let day = try!(field(day, "day", 0, u8::max_value() as i64)) as u8;
+    if !is_valid(year, month, day) {
+        return Err(result::Error::BadMsgpack(format!("the date {}-{}-{} doesn't exist", year, month, day)));
+    }
+    Ok((year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_is_a_map_of_the_field_names() {
+        let bytes = date_to_msgpack(2017, 3, 15);
+        let mut expected = vec![0x83];
+        expected.push(0xa4);
+        expected.extend_from_slice(b"year");
+        expected.extend_from_slice(&[0xcd, 0x07, 0xe1]);
+        expected.push(0xa5);
+        expected.extend_from_slice(b"month");
+        expected.push(0x03);
+        expected.push(0xa3);
+        expected.extend_from_slice(b"day");
+        expected.push(0x0f);
+        assert_eq!(bytes, expected);
+        assert_eq!(&date_to_msgpack(-44, 3, 15)[1..7], &[0xa4, b'y', b'e', b'a', b'r', 0xd0]);
+        assert_eq!(&date_to_msgpack(-1, 3, 15)[1..7], &[0xa4, b'y', b'e', b'a', b'r', 0xff]);
+    }
+
+    #[test]
+    fn dates_round_trip_through_msgpack() {
+        for &(year, month, day) in &[(2017, 3, 15), (-44, 3, 15), (-20, 3, 15), (0, 2, 29), (200, 1, 1), (-200, 1, 1),
+                                     (i16::min_value(), 1, 1), (i16::max_value(), 12, 31)] {
+            let bytes = date_to_msgpack(year, month, day);
+            assert_eq!(date_from_msgpack(&bytes).unwrap(), (year, month, day));
+        }
+        // A map16, the day first, and a year as an int64
+        let mut bytes = vec![0xde, 0x00, 0x03, 0xa3, b'd', b'a', b'y', 0xcc, 0x0f];
+        bytes.extend_from_slice(&[0xa5, b'm', b'o', b'n', b't', b'h', 0x03]);
+        bytes.extend_from_slice(&[0xd9, 0x04, b'y', b'e', b'a', b'r', 0xd3, 0, 0, 0, 0, 0, 0, 0x07, 0xe1]);
+        assert_eq!(date_from_msgpack(&bytes).unwrap(), (2017, 3, 15));
+    }
+
+    #[test]
+    fn bad_msgpack_dates_are_rejected() {
+        let invalid = date_to_msgpack(2017, 2, 29);
+        let mut trailing = date_to_msgpack(2017, 3, 15);
+        trailing.push(0xc0);
+        let out_of_range = [0x83, 0xa4, b'y', b'e', b'a', b'r', 0xce, 0, 1, 0, 0,
+                            0xa5, b'm', b'o', b'n', b't', b'h', 0x03, 0xa3, b'd', b'a', b'y', 0x0f];
+        let missing = [0x82, 0xa4, b'y', b'e', b'a', b'r', 0x01, 0xa5, b'm', b'o', b'n', b't', b'h', 0x03];
+        for bytes in &[&invalid[..], &trailing[..], &out_of_range[..], &missing[..], &invalid[..10],
+                       &[0x93, 0x01, 0x02][..], &[0xc0][..], &[][..]] {
+            match date_from_msgpack(bytes) {
+                Err(result::Error::BadMsgpack(_)) => {},
+                _ => panic!("{:?} is read as a date", bytes),
+            }
+        }
+    }
+}
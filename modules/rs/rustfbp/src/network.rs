@@ -0,0 +1,291 @@
+//! Build a scheduler from a description of its graph
+//!
+//! A `NetworkBuilder` keeps the agents, the edges and the IIPs in the order of the calls,
+//! then `build` creates the scheduler at once. All the errors are collected and returned
+//! together in `Error::InvalidGraph`, instead of stopping at the first one.
+//!
+//! The ports of the agents added with `agent` are checked with `Agent::ports`: the ports
+//! must exist and the type ids of their schemas must match. The ports of the agents of
+//! `node` are checked by `Scheduler::connect`, with their dylib.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut sched = try!(NetworkBuilder::new("pipeline")
+//!     .agent("clock", |id, sched, ctx| { Clock::create(id, sched, ctx, Duration::from_secs(1), None) })
+//!     .agent("throttle", |id, sched, ctx| { Throttle::create(id, sched, ctx, 0.5, ThrottleMode::Block) })
+//!     .node("display", "/nix/store/...-display/lib/libagent.so")
+//!     .connect("clock", "output", "throttle", "input")
+//!     .connect("throttle", "output", "display", "input")
+//!     .iip("display", "option", msg)
+//!     .build());
+//! try!(sched.start());
+//! ```
+
+use agent::PortList;
+use ports::{Msg, MsgSender};
+use result;
+use result::Result;
+use scheduler::{AgentCtx, BoxedComp, CompMsg, Edge, FanoutPolicy, Scheduler};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+
+/// The `create` function of an agent, called once by `build`
+type CreateAgent = Box<FnMut(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)>>;
+
+/// An agent of a `NetworkBuilder`
+enum AgentSpec {
+    /// The complete path to a dylib, see `Scheduler::add_node`
+    Node(String),
+    /// See `Scheduler::add_agent_boxed`
+    Boxed(CreateAgent),
+}
+
+/// The description of a graph, to create a scheduler
+pub struct NetworkBuilder {
+    name: String,
+    agents: Vec<(String, AgentSpec)>,
+    edges: Vec<Edge>,
+    iips: Vec<(String, String, Option<String>, Msg)>,
+    allow_cycles: bool,
+}
+
+impl NetworkBuilder {
+    /// An empty graph, `name` is given in the errors of `build`
+    pub fn new<A: Into<String>>(name: A) -> Self {
+        NetworkBuilder {
+            name: name.into(),
+            agents: vec![],
+            edges: vec![],
+            iips: vec![],
+            allow_cycles: false,
+        }
+    }
+
+    /// Add an agent created in the process, as `Scheduler::add_agent_boxed`
+    pub fn agent<A, F>(mut self, name: A, create: F) -> Self where
+        A: Into<String>,
+        F: FnOnce(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> + 'static,
+    {
+        let mut create = Some(create);
+        let create: CreateAgent = Box::new(move |id, sched, ctx| {
+            (create.take().expect("NetworkBuilder: an agent is created twice"))(id, sched, ctx)
+        });
+        self.agents.push((name.into(), AgentSpec::Boxed(create)));
+        self
+    }
+
+    /// Add an agent of a dylib, as `Scheduler::add_node`
+    pub fn node<A: Into<String>, B: Into<String>>(mut self, name: A, sort: B) -> Self {
+        self.agents.push((name.into(), AgentSpec::Node(sort.into())));
+        self
+    }
+
+    /// Connect a simple output port to a simple input port
+    pub fn connect<A, B, C, D>(self, comp_out: A, port_out: B, comp_in: C, port_in: D) -> Self where
+        A: Into<String>,
+        B: Into<String>,
+        C: Into<String>,
+        D: Into<String>,
+    {
+        self.edge(Edge {
+            out_agent: comp_out.into(), out_port: port_out.into(), out_element: None,
+            in_agent: comp_in.into(), in_port: port_in.into(), in_element: None,
+            feedback: false,
+        })
+    }
+
+    /// Add an edge, with the elements of the array ports, see `Scheduler::connect_edge`
+    ///
+    /// The feedback edges are marked with `Scheduler::mark_feedback`.
+    pub fn edge(mut self, edge: Edge) -> Self {
+        self.edges.push(edge);
+        self
+    }
+
+    /// Add an IIP on an input port, as `Scheduler::add_iip`
+    pub fn iip<A: Into<String>, B: Into<String>>(mut self, comp: A, port: B, msg: Msg) -> Self {
+        self.iips.push((comp.into(), port.into(), None, msg));
+        self
+    }
+
+    /// Add an IIP on an element of an array input port, as `Scheduler::add_iip_array`
+    pub fn iip_array<A, B, C>(mut self, comp: A, port: B, element: C, msg: Msg) -> Self where
+        A: Into<String>,
+        B: Into<String>,
+        C: Into<String>,
+    {
+        self.iips.push((comp.into(), port.into(), Some(element.into()), msg));
+        self
+    }
+
+    /// Accept the cycles without feedback edges, see `Scheduler::allow_cycles`
+    pub fn allow_cycles(mut self, allow: bool) -> Self {
+        self.allow_cycles = allow;
+        self
+    }
+
+    /// Create the scheduler, not started
+    ///
+    /// All the agents are added, then all the edges and the IIPs, even after an error. The
+    /// graph is checked with `Scheduler::validate`, unless the cycles are allowed. The errors
+    /// are returned in `Error::InvalidGraph`, and the scheduler is then stopped.
+    pub fn build(self) -> Result<Scheduler> {
+        let mut sched = Scheduler::new();
+        let mut errors = vec![];
+        let mut ports: HashMap<String, PortList> = HashMap::new();
+        let mut names = HashSet::new();
+        for (name, spec) in self.agents {
+            if !names.insert(name.clone()) {
+                errors.push(format!("{}() : the agent is added twice", name));
+                continue;
+            }
+            let res = match spec {
+                AgentSpec::Node(sort) => sched.add_node(&name as &str, sort),
+                AgentSpec::Boxed(mut create) => {
+                    let ports = &mut ports;
+                    let key = name.clone();
+                    sched.add_agent_boxed(&name as &str, move |id, sched, ctx| {
+                        let (comp, senders) = try!(create(id, sched, ctx));
+                        ports.insert(key, comp.ports());
+                        Ok((comp, senders))
+                    })
+                },
+            };
+            if let Err(e) = res {
+                errors.push(format!("{}() : {}", name, e));
+            }
+        }
+        // Keep all the edges of an output port, `Replace` would keep only the last one
+        sched.set_fanout_policy(FanoutPolicy::Broadcast);
+        for edge in &self.edges {
+            let res = match check_ports(&ports, edge) {
+                Ok(()) => sched.connect_edge(edge),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    if edge.feedback {
+                        if let Err(e) = sched.mark_feedback(&edge.out_agent as &str, &edge.out_port as &str) {
+                            errors.push(format!("{} : {}", describe(edge), e));
+                        }
+                    }
+                },
+                Err(e) => { errors.push(format!("{} : {}", describe(edge), e)); },
+            }
+        }
+        sched.set_fanout_policy(FanoutPolicy::Replace);
+        for (comp, port, element, msg) in self.iips {
+            let res = match element {
+                Some(ref element) => sched.add_iip_array(&comp as &str, &port as &str, element as &str, msg),
+                None => sched.add_iip(&comp as &str, &port as &str, msg),
+            };
+            if let Err(e) = res {
+                errors.push(format!("IIP on {}() {} : {}", comp, label(&port, &element), e));
+            }
+        }
+        if self.allow_cycles {
+            sched.allow_cycles(true);
+        } else if let Err(e) = sched.validate() {
+            errors.push(format!("{}", e));
+        }
+        if errors.is_empty() {
+            Ok(sched)
+        } else {
+            sched.join();
+            Err(result::Error::InvalidGraph(self.name, errors))
+        }
+    }
+}
+
+fn label(port: &str, element: &Option<String>) -> String {
+    match *element {
+        Some(ref e) => format!("{}[{}]", port, e),
+        None => port.into(),
+    }
+}
+
+fn describe(edge: &Edge) -> String {
+    format!("{}() {} -> {} {}()", edge.out_agent, label(&edge.out_port, &edge.out_element),
+            label(&edge.in_port, &edge.in_element), edge.in_agent)
+}
+
+/// The type id of `port` in `ports`, `None` if the port doesn't exist
+fn find_port(ports: &[(String, Option<u64>)], port: &str) -> Option<Option<u64>> {
+    ports.iter().find(|p| { p.0 == port }).map(|p| { p.1 })
+}
+
+/// Check the ports of an edge for the agents created in the process
+fn check_ports(ports: &HashMap<String, PortList>, edge: &Edge) -> Result<()> {
+    let mut out_id = None;
+    if let Some(list) = ports.get(&edge.out_agent) {
+        let outputs = if edge.out_element.is_some() { &list.array_outputs } else { &list.outputs };
+        out_id = try!(find_port(outputs, &edge.out_port).ok_or(result::Error::PortNotFound(edge.out_agent.clone(), edge.out_port.clone())));
+    }
+    let mut in_id = None;
+    if let Some(list) = ports.get(&edge.in_agent) {
+        let inputs = if edge.in_element.is_some() { &list.array_inputs } else { &list.inputs };
+        in_id = try!(find_port(inputs, &edge.in_port).ok_or(result::Error::PortNotFound(edge.in_agent.clone(), edge.in_port.clone())));
+    }
+    if let (Some(expected), Some(found)) = (in_id, out_id) {
+        if expected != found {
+            return Err(result::Error::TypeMismatch(expected, found));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builtin::{Throttle, ThrottleMode, VecSink, VecSource};
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::sync::{Arc, Mutex};
+
+    fn throttle(id: usize, sched: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        Throttle::create(id, sched, ctx, 1000.0, ThrottleMode::Block)
+    }
+
+    #[test]
+    fn pipeline_is_built_and_run() {
+        let collected = Arc::new(Mutex::new(vec![]));
+        let sink = collected.clone();
+        let mut sched = NetworkBuilder::new("pipeline")
+            .agent("source", |id, sched, ctx| {
+                VecSource::create(id, sched, ctx, vec![bytes_msg(&[0]), bytes_msg(&[1]), bytes_msg(&[2])])
+            })
+            .agent("throttle", throttle)
+            .agent("sink", move |id, sched, ctx| { VecSink::create(id, sched, ctx, sink) })
+            .connect("source", "output", "throttle", "input")
+            .connect("throttle", "output", "sink", "input")
+            .build()
+            .unwrap();
+        sched.start().unwrap();
+        sched.run_until_idle().unwrap();
+        let received: Vec<u8> = collected.lock().unwrap().iter_mut().map(|msg| { msg_bytes(msg).unwrap()[0] }).collect();
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn build_reports_every_error() {
+        let res = NetworkBuilder::new("broken")
+            .agent("source", |id, sched, ctx| { VecSource::create(id, sched, ctx, vec![]) })
+            .agent("left", throttle)
+            .agent("right", throttle)
+            .connect("source", "missing", "left", "input")
+            .connect("left", "output", "right", "input")
+            .connect("right", "output", "left", "input")
+            .build();
+        match res {
+            Err(result::Error::InvalidGraph(ref name, ref errors)) if name == "broken" => {
+                assert_eq!(errors.len(), 2, "{:?}", errors);
+                assert!(errors[0].starts_with("source() missing -> input left()"), "{}", errors[0]);
+                assert!(errors[1].contains("cycle between the agents"), "{}", errors[1]);
+            },
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("the broken graph is built"),
+        }
+    }
+}
@@ -9,13 +9,51 @@ use std::io::Read;
 
 use result;
 use result::Result;
+use trace;
+use typed;
 
+use std::cell::RefCell;
+use std::cmp;
 use std::mem;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
-use std::sync::mpsc::{Sender, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver, SyncSender, RecvTimeoutError, TrySendError};
 use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use scheduler::CompMsg;
+use scheduler::{CompMsg, AgentCtx, Blocked};
+
+/// The target of the log records of the ports
+const LOG_TARGET: &'static str = "fractalide::ports";
+
+/// How often a blocked receive or send checks if the scheduler is stopping, in milliseconds
+const STOP_POLL_MS: u64 = 50;
+
+/// The number of Msgs an input port buffers by default
+pub const DEFAULT_CAPACITY: usize = 25;
+
+/// The header holding the sequence number of a Msg, a `u64` little endian, see `OrderedInput`
+pub const SEQUENCE_HEADER: &'static str = "fbp-seq";
+
+/// The kind of an Msg : data, a bracket delimiting a substream, or the end of the stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum MsgKind {
+    /// A normal Msg, with its capn'p representation in `vec`
+    Data,
+    /// Open a substream, with an optional label
+    OpenBracket(Option<Vec<u8>>),
+    /// Close the last opened substream
+    CloseBracket,
+    /// No more Msg will be sent on the stream
+    ///
+    /// Unlike a closed port, the edge stays connected: a source that doesn't own the edge
+    /// tells the downstream agents to finish their work.
+    EndOfStream,
+}
 
 /// Represent an Msg
 pub struct Msg {
@@ -23,6 +61,13 @@ pub struct Msg {
     pub vec: Vec<u8>,
     /// is the action of the Msg
     pub action: String,
+    /// Data or bracket
+    pub kind: MsgKind,
+    headers: Option<Box<BTreeMap<String, Vec<u8>>>>,
+    // When the Msg was first sent, if the scheduler tracks the latency
+    stamp: Option<Instant>,
+    // The traversal limit of the receiving port, in words
+    max_words: Option<u64>,
     reader: Option<capnp::message::Reader<capnp::serialize::OwnedSegments>>,
     builder: Option<capnp::message::Builder<capnp::message::HeapAllocator>>,
 }
@@ -37,11 +82,305 @@ impl Msg {
     pub fn new() -> Self {
         Msg { vec: vec![],
              action: String::new(),
+             kind: MsgKind::Data,
+             headers: None,
+             stamp: None,
+             max_words: None,
              reader: None,
              builder: None,
         }
     }
 
+    /// Return a new open bracket Msg
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(self.output.output.send(Msg::open_bracket(Some(b"batch".to_vec()))));
+    /// ```
+    pub fn open_bracket(label: Option<Vec<u8>>) -> Self {
+        let mut msg = Msg::new();
+        msg.kind = MsgKind::OpenBracket(label);
+        msg
+    }
+
+    /// Return a new close bracket Msg
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(self.output.output.send(Msg::close_bracket()));
+    /// ```
+    pub fn close_bracket() -> Self {
+        let mut msg = Msg::new();
+        msg.kind = MsgKind::CloseBracket;
+        msg
+    }
+
+    /// Return a new end of stream Msg
+    ///
+    /// The agents passing the Msgs through forward it, the agents aggregating the Msgs send
+    /// what they hold then forward it.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(self.output.output.send(Msg::end_of_stream()));
+    /// ```
+    pub fn end_of_stream() -> Self {
+        let mut msg = Msg::new();
+        msg.kind = MsgKind::EndOfStream;
+        msg
+    }
+
+    /// Move the Msg to send it again, without copying its content
+    ///
+    /// The action, the kind and the headers are kept. The capnp `Reader` obtained with
+    /// `read_schema` is dropped, the bytes of the Msg are not.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let msg = try!(self.input.input.recv());
+    /// try!(self.output.output.send(msg.forward()));
+    /// ```
+    pub fn forward(mut self) -> Msg {
+        self.reader = None;
+        self
+    }
+
+    /// Return true if the Msg is an open or a close bracket
+    pub fn is_bracket(&self) -> bool {
+        match self.kind {
+            MsgKind::OpenBracket(_) | MsgKind::CloseBracket => true,
+            _ => false,
+        }
+    }
+
+    /// Return true if the Msg is an end of stream
+    pub fn is_end_of_stream(&self) -> bool {
+        self.kind == MsgKind::EndOfStream
+    }
+
+    /// Return true if the Msg is data, neither a bracket nor an end of stream
+    pub fn is_data(&self) -> bool {
+        self.kind == MsgKind::Data
+    }
+
+    /// Set a header, that travels with the Msg through the ports
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// msg.set_header("correlation_id", id.as_bytes().to_vec());
+    /// ```
+    pub fn set_header<K: Into<String>>(&mut self, key: K, value: Vec<u8>) {
+        if self.headers.is_none() {
+            self.headers = Some(Box::new(BTreeMap::new()));
+        }
+        if let Some(ref mut headers) = self.headers {
+            headers.insert(key.into(), value);
+        }
+    }
+
+    /// Get a header
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// if let Some(id) = msg.get_header("correlation_id") {
+    ///     println!("{:?}", id);
+    /// }
+    /// ```
+    pub fn get_header(&self, key: &str) -> Option<&Vec<u8>> {
+        self.headers.as_ref().and_then(|headers| { headers.get(key) })
+    }
+
+    /// All the headers, sorted by key
+    pub fn headers(&self) -> Vec<(&str, &[u8])> {
+        self.headers.as_ref()
+            .map(|headers| { headers.iter().map(|(k, v)| { (&k[..], &v[..]) }).collect() })
+            .unwrap_or(vec![])
+    }
+
+    /// Remove a header, and return its value
+    pub fn remove_header(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.headers.as_mut().and_then(|headers| { headers.remove(key) })
+    }
+
+    /// When the Msg was first sent, if `Scheduler::enable_latency_tracking` was called
+    ///
+    /// The stamp is kept when the Msg is cloned or forwarded.
+    pub fn stamp(&self) -> Option<Instant> {
+        self.stamp
+    }
+
+    /// Set the stamp of the Msg, for a transport that carries it
+    pub fn set_stamp(&mut self, stamp: Option<Instant>) {
+        self.stamp = stamp;
+    }
+
+    /// The time elapsed since the Msg was first sent, `None` if it has no stamp
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let msg = try!(self.input.input.recv());
+    /// if let Some(age) = msg.age() {
+    ///     self.latencies.push(age);
+    /// }
+    /// ```
+    pub fn age(&self) -> Option<Duration> {
+        self.stamp.map(|stamp| { stamp.elapsed() })
+    }
+
+    /// Give the Msg the sequence number `seq`, for an `OrderedInput`
+    pub fn set_sequence(&mut self, seq: u64) {
+        let bytes = (0..8).map(|i| { (seq >> (i * 8)) as u8 }).collect();
+        self.set_header(SEQUENCE_HEADER, bytes);
+    }
+
+    /// The sequence number of the Msg, `None` if it has none
+    pub fn sequence(&self) -> Option<u64> {
+        match self.get_header(SEQUENCE_HEADER) {
+            Some(bytes) if bytes.len() == 8 => {
+                Some(bytes.iter().enumerate().fold(0u64, |acc, (i, b)| { acc | ((*b as u64) << (i * 8)) }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Create a Msg from its capnp message in the packed encoding
+    ///
+    /// The action, the kind and the headers are not part of the packed bytes, they are the
+    /// ones of `Msg::new`. An empty slice gives an empty Msg.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let bytes = try!(msg.to_packed_bytes());
+    /// let copy = try!(Msg::from_packed_bytes(&bytes));
+    /// ```
+    pub fn from_packed_bytes(bytes: &[u8]) -> Result<Msg> {
+        let mut msg = Msg::new();
+        if !bytes.is_empty() {
+            // The reader of capnp 0.8 assumes aligned words, the message is unpacked in words
+            let words = try!(unpack(bytes));
+            let reader = try!(capnp::serialize::read_message_from_words(&words, capnp::message::ReaderOptions::new()));
+            let mut builder = capnp::message::Builder::new_default();
+            try!(builder.set_root(try!(reader.get_root::<capnp::any_pointer::Reader>())));
+            try!(capnp::serialize::write_message(&mut msg.vec, &builder));
+        }
+        Ok(msg)
+    }
+
+    /// Write the capnp message of the Msg in the packed encoding
+    ///
+    /// The packed encoding compresses the zero bytes, it is smaller for the structs with
+    /// default fields. A Msg being built must be written with `before_send` first.
+    pub fn to_packed_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        if !self.vec.is_empty() {
+            let reader = try!(capnp::serialize::read_message(&mut &self.vec[..], capnp::message::ReaderOptions::new()));
+            let mut builder = capnp::message::Builder::new_default();
+            try!(builder.set_root(try!(reader.get_root::<capnp::any_pointer::Reader>())));
+            let mut unpacked = vec![];
+            try!(capnp::serialize::write_message(&mut unpacked, &builder));
+            bytes = pack(&unpacked);
+        }
+        Ok(bytes)
+    }
+
+    /// The capnp message of the Msg in a canonical form, to compare two messages
+    ///
+    /// The message is copied in a single segment without segment table, the objects in
+    /// preorder. capnp 0.8 has no canonicalization, so unlike the canonical form of capnp,
+    /// the zero words at the end of the structs are kept: two Msgs of the same schema and
+    /// the same content have the same bytes. A Msg being built must be written with
+    /// `before_send` first.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// if try!(a.canonicalize()) == try!(b.canonicalize()) {
+    ///     // Same content
+    /// }
+    /// ```
+    pub fn canonicalize(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        if !self.vec.is_empty() {
+            let reader = try!(capnp::serialize::read_message(&mut &self.vec[..], capnp::message::ReaderOptions::new()));
+            let root = try!(reader.get_root::<capnp::any_pointer::Reader>());
+            // The root pointer and its target, a single segment
+            let words = try!(root.target_size()).word_count + 1;
+            let mut builder = capnp::message::Builder::new(capnp::message::HeapAllocator::new().first_segment_words(words as u32));
+            try!(builder.set_root(root));
+            for segment in builder.get_segments_for_output().iter() {
+                bytes.extend_from_slice(capnp::Word::words_to_bytes(segment));
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// The size of the capnp message, in words
+    pub fn words(&self) -> u64 {
+        (self.vec.len() / 8) as u64
+    }
+
+    /// The type id of the schema of the Msg, if it is tagged, see `typed`
+    ///
+    /// A capnp message doesn't hold its schema, only a `TypedOutput` or `typed::set_schema`
+    /// tag the Msg.
+    pub fn type_id(&self) -> Option<u64> {
+        match self.get_header(typed::SCHEMA_HEADER) {
+            Some(bytes) if bytes.len() == 8 => {
+                Some(bytes.iter().enumerate().fold(0u64, |acc, (i, b)| { acc | ((*b as u64) << (i * 8)) }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Read the Msg without its schema, to walk it with `capnp::any_pointer`
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let root = try!(msg.read_any());
+    /// println!("{} words", try!(root.target_size()).word_count);
+    /// ```
+    pub fn read_any<'a>(&'a mut self) -> Result<capnp::any_pointer::Reader<'a>> {
+        self.read_schema()
+    }
+
+    /// The words of the capnp message in hex, one word per line, with its offset
+    ///
+    /// The first word is the segment table. A Msg being built is dumped as it will be sent.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// println!("{:?} :\n{}", msg.type_id(), msg.hex_dump());
+    /// // 0000: 00 00 00 00 02 00 00 00
+    /// // 0001: 00 00 00 00 01 00 00 00
+    /// // ...
+    /// ```
+    pub fn hex_dump(&self) -> String {
+        let mut built = vec![];
+        let bytes = match self.builder {
+            Some(ref builder) => {
+                if capnp::serialize::write_message(&mut built, builder).is_err() {
+                    built.clear();
+                }
+                &built
+            },
+            None => &self.vec,
+        };
+        let mut dump = String::new();
+        for (i, word) in bytes.chunks(8).enumerate() {
+            let hex: Vec<String> = word.iter().map(|b| { format!("{:02x}", b) }).collect();
+            dump.push_str(&format!("{:04x}: {}\n", i, hex.join(" ")));
+        }
+        dump
+    }
+
+    /// The options to decode the capnp message, with the limit of the receiving port
+    fn reader_options(&self) -> capnp::message::ReaderOptions {
+        let mut options = capnp::message::ReaderOptions::new();
+        if let Some(words) = self.max_words {
+            options.traversal_limit_in_words(words);
+        }
+        options
+    }
+
     /// Return a capnp `Reader`
     ///
     /// # Example
@@ -54,7 +393,7 @@ impl Msg {
     /// }
     /// ```
     pub fn read_schema<'a, T: capnp::traits::FromPointerReader<'a>>(&'a mut self) -> Result<T> {
-        let msg = try!(capnp::serialize::read_message(&mut &self.vec[..], capnp::message::ReaderOptions::new()));
+        let msg = try!(capnp::serialize::read_message(&mut &self.vec[..], self.reader_options()));
         self.reader = Some(msg);
         Ok(try!(self.reader.as_ref().unwrap().get_root()))
     }
@@ -77,6 +416,27 @@ impl Msg {
         self.builder.as_mut().unwrap().init_root()
     }
 
+    /// Return a capnp `Builder` of a list of `len` elements, the root of the Msg
+    ///
+    /// A batch of structs in one Msg, the receiver reads it with `read_schema` as a
+    /// `capnp::struct_list::Reader`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let mut msg = Msg::new();
+    /// {
+    ///     let mut list: capnp::struct_list::Builder<time_date::Owned> = msg.build_list(2);
+    ///     list.borrow().get(0).set_year(2017);
+    ///     list.borrow().get(1).set_year(2018);
+    /// }
+    /// ```
+    pub fn build_list<'a, T: capnp::traits::FromPointerBuilder<'a>>(&'a mut self, len: u32) -> T {
+        let msg = capnp::message::Builder::new_default();
+        self.builder = Some(msg);
+        self.builder.as_mut().unwrap().init_root::<capnp::any_pointer::Builder>().initn_as(len)
+    }
+
     /// Return a capnp `Builder` from a capnp `Reader`
     ///
     /// # Example
@@ -91,7 +451,7 @@ impl Msg {
     pub fn edit_schema<'a, T: capnp::traits::FromPointerBuilder<'a>,
                                  U: capnp::traits::FromPointerReader<'a> + capnp::traits::SetPointerBuilder<T>>
         (&'a mut self) -> Result<T> {
-        let reader = try!(capnp::serialize::read_message(&mut &self.vec[..], capnp::message::ReaderOptions::new()));
+        let reader = try!(capnp::serialize::read_message(&mut &self.vec[..], self.reader_options()));
         self.reader = Some(reader);
         let reader: U = try!(self.reader.as_ref().unwrap().get_root());
 
@@ -124,21 +484,402 @@ impl Msg {
     }
 }
 
+/// Pack a serialized capnp message, its length is a multiple of 8
+///
+/// A word is written as a tag, with a bit set for each nonzero byte, followed by these bytes.
+/// The tag 0x00 is followed by the number of zero words after it, the tag 0xff by the number
+/// of words copied after it, the words with at most one zero byte.
+fn pack(bytes: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(bytes.len() / 2);
+    let words: Vec<&[u8]> = bytes.chunks(8).collect();
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let tag = word.iter().enumerate().fold(0u8, |tag, (bit, b)| { if *b != 0 { tag | 1 << bit } else { tag } });
+        packed.push(tag);
+        packed.extend(word.iter().filter(|b| { **b != 0 }));
+        i += 1;
+        if tag == 0x00 {
+            let run = words[i..].iter().take(255).take_while(|w| { w.iter().all(|b| { *b == 0 }) }).count();
+            packed.push(run as u8);
+            i += run;
+        } else if tag == 0xff {
+            let run = words[i..].iter().take(255).take_while(|w| { w.iter().filter(|b| { **b == 0 }).count() <= 1 }).count();
+            packed.push(run as u8);
+            for word in &words[i..i + run] {
+                packed.extend_from_slice(word);
+            }
+            i += run;
+        }
+    }
+    packed
+}
+
+/// Unpack the words of a packed capnp message
+fn unpack(packed: &[u8]) -> Result<Vec<capnp::Word>> {
+    let truncated = || { result::Error::Capnp(capnp::Error::failed("the packed message is truncated".into())) };
+    let mut bytes = vec![];
+    let mut pos = 0;
+    while pos < packed.len() {
+        let tag = packed[pos];
+        pos += 1;
+        for bit in 0..8 {
+            if tag & 1 << bit != 0 {
+                bytes.push(try!(packed.get(pos).cloned().ok_or_else(&truncated)));
+                pos += 1;
+            } else {
+                bytes.push(0);
+            }
+        }
+        if tag == 0x00 || tag == 0xff {
+            let run = try!(packed.get(pos).cloned().ok_or_else(&truncated)) as usize * 8;
+            pos += 1;
+            if tag == 0x00 {
+                bytes.extend((0..run).map(|_| { 0u8 }));
+            } else {
+                if packed.len() - pos < run {
+                    return Err(truncated());
+                }
+                bytes.extend_from_slice(&packed[pos..pos + run]);
+                pos += run;
+            }
+        }
+    }
+    let mut words = capnp::Word::allocate_zeroed_vec(bytes.len() / 8);
+    capnp::Word::words_to_bytes_mut(&mut words).copy_from_slice(&bytes);
+    Ok(words)
+}
+
 impl Clone for Msg {
     fn clone(&self) -> Self {
         Msg {
             vec: self.vec.clone(),
             action: self.action.clone(),
+            kind: self.kind.clone(),
+            headers: self.headers.clone(),
+            stamp: self.stamp,
+            max_words: self.max_words,
             reader: None,
             builder: None,
         }
     }
 }
 
+/// When the circuit breaker of an input port opens, see `Scheduler::set_circuit_breaker`
+///
+/// A failure is a panic of the agent owning the port, or a send to the closed port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BreakerConfig {
+    /// The number of failures within `window` that open the breaker
+    pub failures: usize,
+    /// The time a failure is remembered
+    pub window: Duration,
+    /// The time the breaker stays open, before a Msg is let through to probe the agent
+    pub cooldown: Duration,
+    /// The agent and the input port receiving the Msgs while the breaker is open, the Msgs
+    /// are dropped if `None`
+    pub fallback: Option<(String, String)>,
+}
+
+/// The state of the circuit breaker of an input port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    /// The Msgs are delivered
+    Closed,
+    /// The Msgs go to the fallback port or are dropped, since the `Instant`
+    Open(Instant),
+    /// The probe Msg was delivered, the next send closes the breaker if no failure happened
+    HalfOpen,
+}
+
+struct Breaker {
+    /// The name of the port, for the logs
+    port: String,
+    config: BreakerConfig,
+    state: BreakerState,
+    /// When the remembered failures happened
+    failures: VecDeque<Instant>,
+    /// The panics of the agent already counted
+    panics: usize,
+    fallback: Option<MsgSender>,
+}
+
+impl Breaker {
+    /// Count the new panics of the agent, and tell if a Msg can be delivered
+    fn allow(&mut self, panics: usize) -> bool {
+        let now = Instant::now();
+        // The panics are counted from 0 again after `Scheduler::reset_metrics`
+        let new = if panics >= self.panics { panics - self.panics } else { panics };
+        self.panics = panics;
+        for _ in 0..new {
+            self.failures.push_back(now);
+        }
+        while self.failures.front().map_or(false, |f| { now.duration_since(*f) > self.config.window }) {
+            self.failures.pop_front();
+        }
+        match self.state {
+            BreakerState::Closed => {
+                if self.failures.len() >= self.config.failures {
+                    self.open(now);
+                    false
+                } else {
+                    true
+                }
+            },
+            BreakerState::Open(since) => {
+                if now.duration_since(since) >= self.config.cooldown {
+                    self.state = BreakerState::HalfOpen;
+                    true
+                } else {
+                    false
+                }
+            },
+            BreakerState::HalfOpen => {
+                if new > 0 {
+                    self.open(now);
+                    false
+                } else {
+                    info!(target: LOG_TARGET, "the circuit breaker of the port {} is closed", self.port);
+                    self.state = BreakerState::Closed;
+                    self.failures.clear();
+                    true
+                }
+            },
+        }
+    }
+
+    fn failed(&mut self) {
+        let now = Instant::now();
+        self.failures.push_back(now);
+        let open = match self.state {
+            BreakerState::Closed => self.failures.len() >= self.config.failures,
+            BreakerState::Open(_) => false,
+            BreakerState::HalfOpen => true,
+        };
+        if open {
+            self.open(now);
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        warn!(target: LOG_TARGET, "the circuit breaker of the port {} is open for {:?}", self.port, self.config.cooldown);
+        self.state = BreakerState::Open(now);
+    }
+}
+
+/// What a MsgSender does with a Msg, according to the circuit breaker
+enum Gate {
+    Deliver,
+    /// The breaker is open, send to the fallback port if any
+    Reject(Option<MsgSender>),
+}
+
+/// The state shared by the MsgSenders and the MsgReceiver of an input port
+pub struct PortState {
+    /// The agent owning the receiver
+    pub agent: usize,
+    /// The name of the port, `port[element]` for an element of an array port
+    pub name: String,
+    /// The number of Msgs the port can buffer
+    pub capacity: usize,
+    depth: AtomicUsize,
+    upstreams: AtomicUsize,
+    connected: AtomicBool,
+    /// The Msgs the senders can still send, `None` without flow control
+    credits: Mutex<Option<usize>>,
+    /// The size limit of a received Msg in words, `None` without limit
+    max_words: Mutex<Option<u64>>,
+    traced: AtomicBool,
+    tracer: Mutex<Option<Box<Fn(&Msg) + Send>>>,
+    has_breaker: AtomicBool,
+    breaker: Mutex<Option<Breaker>>,
+    /// Counts the Msgs taken and the credits granted, the blocked senders wait on it
+    room: (Mutex<usize>, Condvar),
+}
+
+impl PortState {
+    /// The approximate number of Msgs waiting in the port
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// True if a send to the port would block
+    pub fn is_full(&self) -> bool {
+        self.depth() >= self.capacity
+    }
+
+    /// The credits left to the senders, `None` if the port doesn't use credits
+    pub fn credits(&self) -> Option<usize> {
+        self.credits.lock().ok().and_then(|c| { *c })
+    }
+
+    /// Take a credit to send an Msg, false if there is none left
+    fn take_credit(&self) -> bool {
+        match self.credits.lock() {
+            Ok(mut credits) => {
+                match *credits {
+                    None => true,
+                    Some(0) => false,
+                    Some(ref mut n) => {
+                        *n -= 1;
+                        true
+                    },
+                }
+            },
+            Err(_) => true,
+        }
+    }
+
+    /// Let the senders send `n` more Msgs, see `MsgReceiver::grant`
+    pub fn grant(&self, n: usize) {
+        if let Ok(mut credits) = self.credits.lock() {
+            *credits = Some(credits.unwrap_or(0) + n);
+        }
+        self.make_room();
+    }
+
+    /// The number of times room was made, to give to `wait_room`
+    fn room_generation(&self) -> usize {
+        let &(ref lock, _) = &self.room;
+        lock.lock().map(|gen| { *gen }).unwrap_or(0)
+    }
+
+    /// Wake up the senders waiting for room in the port or for a credit
+    fn make_room(&self) {
+        let &(ref lock, ref cvar) = &self.room;
+        if let Ok(mut gen) = lock.lock() {
+            *gen = gen.wrapping_add(1);
+            cvar.notify_all();
+        }
+    }
+
+    /// Block until room is made after `generation`, or `timeout` elapsed
+    fn wait_room(&self, generation: usize, timeout: Duration) {
+        let &(ref lock, ref cvar) = &self.room;
+        if let Ok(gen) = lock.lock() {
+            if *gen == generation {
+                let _ = cvar.wait_timeout(gen, timeout);
+            }
+        }
+    }
+
+    /// The size limit of a received Msg, in capnp words
+    pub fn max_message_size(&self) -> Option<u64> {
+        self.max_words.lock().ok().and_then(|m| { *m })
+    }
+
+    /// Limit the size of the received Msgs to `words` capnp words, or remove the limit
+    ///
+    /// A larger Msg is dropped by the receiver, which returns `Error::MessageTooLarge`.
+    /// The limit is also the traversal limit when the Msg is decoded.
+    pub fn set_max_message_size(&self, words: Option<u64>) {
+        if let Ok(mut max) = self.max_words.lock() {
+            *max = words;
+        }
+    }
+
+    /// Give back the credit of an Msg that was not sent
+    fn refund_credit(&self) {
+        if let Ok(mut credits) = self.credits.lock() {
+            if let Some(ref mut n) = *credits {
+                *n += 1;
+            }
+        }
+    }
+
+    /// True once the port was connected to output ports, and all of them are gone
+    ///
+    /// The output ports of a removed or dead agent are gone, and so is a disconnected edge.
+    /// The MsgSenders given by the scheduler, without an agent sending through them, are not
+    /// counted. The port can be connected again later.
+    pub fn is_closed(&self) -> bool {
+        self.connected.load(Ordering::SeqCst) && self.upstreams.load(Ordering::SeqCst) == 0
+    }
+
+    /// The number of output ports connected to the port
+    pub fn senders(&self) -> usize {
+        self.upstreams.load(Ordering::SeqCst)
+    }
+
+    /// Call `tracer` with each Msg sent to the port, or stop tracing with `None`
+    ///
+    /// The tracer is called by the sending agent, before the Msg is put in the port, so
+    /// it slows down the sender and must be cheap.
+    pub fn set_tracer(&self, tracer: Option<Box<Fn(&Msg) + Send>>) {
+        if let Ok(mut t) = self.tracer.lock() {
+            self.traced.store(tracer.is_some(), Ordering::SeqCst);
+            *t = tracer;
+        }
+    }
+
+    /// Stop delivering the Msgs after failures, or remove the circuit breaker with `None`
+    ///
+    /// The `fallback` is the sender of the port of `config.fallback`, see
+    /// `Scheduler::set_circuit_breaker`.
+    pub fn set_circuit_breaker(&self, config: Option<BreakerConfig>, fallback: Option<MsgSender>, panics: usize) {
+        if let Ok(mut b) = self.breaker.lock() {
+            self.has_breaker.store(config.is_some(), Ordering::SeqCst);
+            *b = config.map(|config| {
+                Breaker {
+                    port: self.name.clone(),
+                    config: config,
+                    state: BreakerState::Closed,
+                    failures: VecDeque::new(),
+                    panics: panics,
+                    fallback: fallback,
+                }
+            });
+        }
+    }
+
+    /// The state of the circuit breaker, `None` without breaker
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.breaker.lock().ok().and_then(|b| { b.as_ref().map(|b| { b.state }) })
+    }
+
+    /// Ask the circuit breaker if a Msg can be delivered
+    fn gate(&self, panics: usize) -> Gate {
+        if !self.has_breaker.load(Ordering::Relaxed) {
+            return Gate::Deliver;
+        }
+        match self.breaker.lock() {
+            Ok(mut b) => {
+                match *b {
+                    Some(ref mut breaker) => {
+                        if breaker.allow(panics) { Gate::Deliver } else { Gate::Reject(breaker.fallback.clone()) }
+                    },
+                    None => Gate::Deliver,
+                }
+            },
+            Err(_) => Gate::Deliver,
+        }
+    }
+
+    /// Count a failed send in the circuit breaker
+    fn failed(&self) {
+        if self.has_breaker.load(Ordering::Relaxed) {
+            if let Ok(mut b) = self.breaker.lock() {
+                if let Some(ref mut breaker) = *b {
+                    breaker.failed();
+                }
+            }
+        }
+    }
+
+    fn trace(&self, msg: &Msg) {
+        if self.traced.load(Ordering::Relaxed) {
+            if let Ok(t) = self.tracer.lock() {
+                if let Some(ref tracer) = *t {
+                    tracer(msg);
+                }
+            }
+        }
+    }
+}
+
 /// A wrapper around `SyncSender<Msg>`
 ///
 /// A specific `SyncSender` for the Msg object. It also sends information to the scheduler.
-#[derive(Clone)]
 pub struct MsgSender {
     /// The SyncSender, connected to a receiver in another agent
     pub sender: SyncSender<Msg>,
@@ -146,81 +887,1336 @@ pub struct MsgSender {
     pub dest: usize,
     /// A Sender to the scheduler, to signal that the receiver must be run
     pub sched: Sender<CompMsg>,
+    origin: Option<AgentCtx>,
+    ctx: AgentCtx,
+    port: Arc<PortState>,
     must_sched: bool,
+    /// The other destinations of a broadcasting output port
+    fanout: Vec<MsgSender>,
 }
 
 impl MsgSender {
     /// Send an Msg to the Receiver
+    ///
+    /// Blocks while the port of the receiver is full, until the receiver takes a Msg. Once
+    /// the scheduler is stopping, `Error::Stopping` is returned instead of blocking.
     pub fn send(&self, mut msg: Msg) -> Result<()> {
         try!(msg.before_send());
-        try!(self.sender.send(msg));
-        if self.must_sched {
-            try!(self.sched.send(CompMsg::Inc(self.dest)));
+        self.stamp(&mut msg);
+        if let Gate::Reject(fallback) = self.port.gate(self.ctx.panics()) {
+            return self.reject(vec![msg], fallback);
         }
-        Ok(())
+        for dest in &self.fanout {
+            try!(dest.send(msg.clone()));
+        }
+        let mut pending = 0;
+        try!(self.push(msg, &mut pending));
+        self.flush(pending)
     }
-}
-
-pub trait OutputSend {
-    fn send(&self, msg:Msg) -> Result<()>;
-}
 
-impl OutputSend for Option<MsgSender> {
-    fn send(&self, msg: Msg) -> Result<()> {
-        if let &Some(ref sender) = self {
-            sender.send(msg)?;
-            Ok(())
-        } else {
-            Err(result::Error::OutputNotConnected)
+    /// Send several Msgs to the Receiver, in order
+    ///
+    /// The receiver and the scheduler are signaled once for the whole batch, or each time
+    /// the port is full.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(self.output.output.send_batch(msgs));
+    /// ```
+    pub fn send_batch(&self, mut msgs: Vec<Msg>) -> Result<()> {
+        for msg in &mut msgs {
+            try!(msg.before_send());
+            self.stamp(msg);
         }
+        if let Gate::Reject(fallback) = self.port.gate(self.ctx.panics()) {
+            return self.reject(msgs, fallback);
+        }
+        for dest in &self.fanout {
+            try!(dest.send_batch(msgs.clone()));
+        }
+        let mut pending = 0;
+        for msg in msgs {
+            if let Err(e) = self.push(msg, &mut pending) {
+                try!(self.flush(pending));
+                return Err(e);
+            }
+        }
+        self.flush(pending)
     }
-}
 
+    /// Send an Msg without blocking
+    ///
+    /// Returns the Msg back if the port of the receiver is full, so the agent can drop it or
+    /// keep it for later. A closed port is still an `Error::PortClosed`.
+    ///
+    /// A broadcasting output port gives the Msg back if one of its destinations is full, and
+    /// sends it to none of them. Once the first destination took it, the copies are sent to
+    /// the others, waiting for the rare destination filled meanwhile by another sender.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// if let Some(msg) = try!(self.output.output.try_send(msg)) {
+    ///     // The downstream is late, drop the frame
+    /// }
+    /// ```
+    pub fn try_send(&self, mut msg: Msg) -> Result<Option<Msg>> {
+        try!(msg.before_send());
+        self.stamp(&mut msg);
+        let late = |port: &PortState| { port.is_full() || port.credits() == Some(0) };
+        if late(&self.port) || self.fanout.iter().any(|dest| { late(&dest.port) }) {
+            return Ok(Some(msg));
+        }
+        if let Gate::Reject(fallback) = self.port.gate(self.ctx.panics()) {
+            return self.reject(vec![msg], fallback).map(|_| { None });
+        }
+        let copies: Vec<Msg> = self.fanout.iter().map(|_| { msg.clone() }).collect();
+        if let Some(msg) = try!(self.try_push(msg)) {
+            return Ok(Some(msg));
+        }
+        for (dest, copy) in self.fanout.iter().zip(copies) {
+            try!(dest.send(copy));
+        }
+        Ok(None)
+    }
+
+    /// Put an Msg in the channel and signal it, or give it back if the port is full
+    fn try_push(&self, msg: Msg) -> Result<Option<Msg>> {
+        if !self.port.take_credit() {
+            return Ok(Some(msg));
+        }
+        self.port.trace(&msg);
+        self.port.depth.fetch_add(1, Ordering::Relaxed);
+        let res = match self.sender.try_send(msg) {
+            Ok(()) => { return self.flush(1).map(|_| { None }); },
+            Err(TrySendError::Full(m)) => Ok(Some(m)),
+            Err(TrySendError::Disconnected(_)) => {
+                self.port.failed();
+                Err(result::Error::PortClosed(self.port.name.clone()))
+            },
+        };
+        self.port.depth.fetch_sub(1, Ordering::Relaxed);
+        self.port.refund_credit();
+        res
+    }
+
+    /// Send the Msgs refused by the open circuit breaker to the fallback port, or drop them
+    fn reject(&self, msgs: Vec<Msg>, fallback: Option<MsgSender>) -> Result<()> {
+        match fallback {
+            Some(fallback) => fallback.send_batch(msgs),
+            None => {
+                debug!(target: LOG_TARGET, "the circuit breaker of the port {} is open, {} Msgs dropped", self.port.name, msgs.len());
+                Ok(())
+            },
+        }
+    }
+
+    /// Stamp an Msg sent for the first time, if the scheduler tracks the latency
+    fn stamp(&self, msg: &mut Msg) {
+        let on = self.ctx.tracks_latency() || self.origin.as_ref().map_or(false, |o| { o.tracks_latency() });
+        if msg.stamp.is_none() && on {
+            msg.stamp = Some(Instant::now());
+        }
+        trace::on_send(msg);
+    }
+
+    /// Put an Msg in the channel, without signaling it
+    fn push(&self, mut msg: Msg, pending: &mut usize) -> Result<()> {
+        let mut blocked = false;
+        if let Err(e) = self.wait_credit(pending, &mut blocked) {
+            if blocked {
+                self.set_blocked(None);
+            }
+            return Err(e);
+        }
+        self.port.trace(&msg);
+        self.port.depth.fetch_add(1, Ordering::Relaxed);
+        let res = loop {
+            // Read before the send, so a Msg taken meanwhile wakes up the wait
+            let generation = self.port.room_generation();
+            match self.sender.try_send(msg) {
+                Ok(()) => { break Ok(()); },
+                Err(TrySendError::Full(m)) => {
+                    if self.ctx.is_stopping() {
+                        break Err(result::Error::Stopping);
+                    }
+                    if *pending > 0 {
+                        // The receiver must know about the Msgs already in the port
+                        if let Err(e) = self.flush(*pending) {
+                            break Err(e);
+                        }
+                        *pending = 0;
+                    }
+                    if !blocked {
+                        blocked = true;
+                        warn!(target: LOG_TARGET, "the port {} is full, the sender waits", self.port.name);
+                        self.set_blocked(Some(Blocked::new(self.port.clone(), true)));
+                    }
+                    msg = m;
+                    self.port.wait_room(generation, Duration::from_millis(STOP_POLL_MS));
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    self.port.failed();
+                    break Err(result::Error::PortClosed(self.port.name.clone()));
+                },
+            }
+        };
+        if blocked {
+            self.set_blocked(None);
+        }
+        match res {
+            Ok(()) => { *pending += 1; },
+            Err(_) => {
+                self.port.depth.fetch_sub(1, Ordering::Relaxed);
+                self.port.refund_credit();
+            },
+        }
+        res
+    }
+
+    /// Block until the receiver grants a credit, if the port uses credits
+    fn wait_credit(&self, pending: &mut usize, blocked: &mut bool) -> Result<()> {
+        loop {
+            let generation = self.port.room_generation();
+            if self.port.take_credit() {
+                return Ok(());
+            }
+            if self.ctx.is_stopping() {
+                return Err(result::Error::Stopping);
+            }
+            if *pending > 0 {
+                // The receiver must see the Msgs already sent to grant more
+                try!(self.flush(*pending));
+                *pending = 0;
+            }
+            if !*blocked {
+                *blocked = true;
+                self.set_blocked(Some(Blocked::new(self.port.clone(), true)));
+            }
+            self.port.wait_room(generation, Duration::from_millis(STOP_POLL_MS));
+        }
+    }
+
+    /// Signal `n` new Msgs to the receiver and the scheduler
+    fn flush(&self, n: usize) -> Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.ctx.notify();
+        if let Some(ref origin) = self.origin {
+            origin.on_sent(n);
+            if origin.metrics_enabled() {
+                trace!(target: LOG_TARGET, "{} Msgs delivered to the port {} of the agent {}", n, self.port.name, self.dest);
+            }
+        }
+        if self.must_sched {
+            if n == 1 {
+                try!(self.sched.send(CompMsg::Inc(self.dest)));
+            } else {
+                try!(self.sched.send(CompMsg::IncBatch(self.dest, n)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the agent that sends through this MsgSender, to count its sent Msgs
+    ///
+    /// The port of the receiver stays open while such a MsgSender exists.
+    pub fn set_origin(&mut self, ctx: AgentCtx) {
+        if self.origin.is_none() {
+            self.port.upstreams.fetch_add(1, Ordering::SeqCst);
+            self.port.connected.store(true, Ordering::SeqCst);
+        }
+        self.origin = Some(ctx);
+    }
+
+    /// The state of the port of the receiver
+    pub fn port(&self) -> &Arc<PortState> {
+        &self.port
+    }
+
+    /// Also send a copy of each Msg to `dest`, for an output port connected to several
+    /// input ports
+    pub fn add_destination(&mut self, dest: MsgSender) {
+        self.fanout.push(dest);
+    }
+
+    fn set_blocked(&self, blocked: Option<Blocked>) {
+        if let Some(ref origin) = self.origin {
+            origin.set_blocked(blocked);
+        }
+    }
+}
+
+impl Clone for MsgSender {
+    fn clone(&self) -> Self {
+        if self.origin.is_some() {
+            self.port.upstreams.fetch_add(1, Ordering::SeqCst);
+        }
+        MsgSender {
+            sender: self.sender.clone(),
+            dest: self.dest,
+            sched: self.sched.clone(),
+            origin: self.origin.clone(),
+            ctx: self.ctx.clone(),
+            port: self.port.clone(),
+            must_sched: self.must_sched,
+            fanout: self.fanout.clone(),
+        }
+    }
+}
+
+impl Drop for MsgSender {
+    fn drop(&mut self) {
+        if self.origin.is_some() {
+            // The Msgs are already in the channel, the receiver sees them before the closing
+            self.port.upstreams.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+pub trait OutputSend {
+    fn send(&self, msg:Msg) -> Result<()>;
+    /// Send without blocking, the Msg is given back if the port is full
+    fn try_send(&self, msg: Msg) -> Result<Option<Msg>>;
+    /// Send a received Msg without copying it, see `Msg::forward`
+    fn forward(&self, msg: Msg) -> Result<()> {
+        self.send(msg.forward())
+    }
+    fn send_batch(&self, msgs: Vec<Msg>) -> Result<()> {
+        for msg in msgs {
+            try!(self.send(msg));
+        }
+        Ok(())
+    }
+}
+
+impl OutputSend for MsgSender {
+    fn send(&self, msg: Msg) -> Result<()> {
+        MsgSender::send(self, msg)
+    }
+
+    fn try_send(&self, msg: Msg) -> Result<Option<Msg>> {
+        MsgSender::try_send(self, msg)
+    }
+
+    fn send_batch(&self, msgs: Vec<Msg>) -> Result<()> {
+        MsgSender::send_batch(self, msgs)
+    }
+}
+
+impl OutputSend for Option<MsgSender> {
+    fn send(&self, msg: Msg) -> Result<()> {
+        if let &Some(ref sender) = self {
+            sender.send(msg)?;
+            Ok(())
+        } else {
+            Err(result::Error::OutputNotConnected)
+        }
+    }
+
+    fn try_send(&self, msg: Msg) -> Result<Option<Msg>> {
+        if let &Some(ref sender) = self {
+            sender.try_send(msg)
+        } else {
+            Err(result::Error::OutputNotConnected)
+        }
+    }
+
+    fn send_batch(&self, msgs: Vec<Msg>) -> Result<()> {
+        if let &Some(ref sender) = self {
+            sender.send_batch(msgs)
+        } else {
+            Err(result::Error::OutputNotConnected)
+        }
+    }
+}
+
+/// What to do with a Msg sent to an element of an array output port that is not connected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Disconnected {
+    /// Drop the Msg, the send succeeds
+    Drop,
+    /// Return `OutputNotConnected`
+    Error,
+}
+
+/// Send on an element of an array output port
+///
+/// An element is named when it is connected, and the name is any string: `"1"` or `"north"`.
+/// A name is unique in a port, `Scheduler::connect_array` doesn't give it to another destination.
+///
+/// The elements can be connected and disconnected while the graph runs, the changes are
+/// seen by the agent between two runs.
+///
+/// # Example
+/// ```rust,ignore
+/// // After sched.connect_array("router", "outputs", "north", "north_sink", "input")
+/// try!(self.outarr.outputs.send_to("north", msg, Disconnected::Error));
+/// ```
+pub trait OutputArraySend {
+    fn send_to(&self, element: &str, msg: Msg, policy: Disconnected) -> Result<()>;
+}
+
+impl OutputArraySend for HashMap<String, MsgSender> {
+    fn send_to(&self, element: &str, msg: Msg, policy: Disconnected) -> Result<()> {
+        match (self.get(element), policy) {
+            (Some(sender), _) => sender.send(msg),
+            (None, Disconnected::Drop) => Ok(()),
+            (None, Disconnected::Error) => Err(result::Error::OutputNotConnected),
+        }
+    }
+}
+
+/// Send the brackets and the ends of stream directly to `output`, and return the data Msgs
+///
+/// Helps the agents that don't care about substreams to keep them.
+///
+/// # Example
+/// ```rust,ignore
+/// let msg = try!(self.input.input.recv());
+/// if let Some(msg) = try!(forward_brackets(msg, &self.output.output)) {
+///     // process the data Msg
+/// }
+/// ```
+pub fn forward_brackets<O: OutputSend + ?Sized>(msg: Msg, output: &O) -> Result<Option<Msg>> {
+    if !msg.is_data() {
+        try!(output.send(msg));
+        Ok(None)
+    } else {
+        Ok(Some(msg))
+    }
+}
+
+pub struct MsgReceiver {
+    id: usize,
+    recv: Receiver<Msg>,
+    sender: MsgSender,
+    sched: Sender<CompMsg>,
+    ctx: AgentCtx,
+    port: Arc<PortState>,
+    must_sched: bool,
+    /// The Msgs given back with `requeue`, received before the channel
+    deferred: RefCell<VecDeque<(Msg, Instant)>>,
+    /// A Msg that arrived while the agent was paused in a receive, received once resumed
+    held: RefCell<Option<Msg>>,
+}
 
-pub struct MsgReceiver {
-    id: usize,
-    recv: Receiver<Msg>,
-    sender: MsgSender,
-    sched: Sender<CompMsg>,
-    must_sched: bool,
-}
-
 impl MsgReceiver {
-    pub fn new(id: usize, sched: Sender<CompMsg>, must_sched: bool) -> (MsgReceiver, MsgSender) {
-        let (s, r) = sync_channel(25);
+    pub fn new(id: usize, name: &str, sched: Sender<CompMsg>, ctx: AgentCtx, must_sched: bool) -> (MsgReceiver, MsgSender) {
+        MsgReceiver::with_capacity(id, name, sched, ctx, must_sched, DEFAULT_CAPACITY)
+    }
+
+    /// Create a receiver that buffers at most `capacity` Msgs
+    pub fn with_capacity(id: usize, name: &str, sched: Sender<CompMsg>, ctx: AgentCtx, must_sched: bool, capacity: usize) -> (MsgReceiver, MsgSender) {
+        let (s, r) = sync_channel(capacity);
+        let port = Arc::new(PortState {
+            agent: id,
+            name: name.into(),
+            capacity: capacity,
+            depth: AtomicUsize::new(0),
+            upstreams: AtomicUsize::new(0),
+            connected: AtomicBool::new(false),
+            credits: Mutex::new(None),
+            max_words: Mutex::new(None),
+            traced: AtomicBool::new(false),
+            tracer: Mutex::new(None),
+            has_breaker: AtomicBool::new(false),
+            breaker: Mutex::new(None),
+            room: (Mutex::new(0), Condvar::new()),
+        });
         let s = MsgSender {
             sender: s,
             dest: id,
+            origin: None,
+            ctx: ctx.clone(),
+            port: port.clone(),
             must_sched: must_sched,
             sched: sched.clone(),
+            fanout: vec![],
         };
         let r = MsgReceiver {
             recv: r,
             id: id,
             sender: s.clone(),
             sched: sched,
+            ctx: ctx,
+            port: port,
             must_sched: must_sched,
+            deferred: RefCell::new(VecDeque::new()),
+            held: RefCell::new(None),
         };
         (r, s)
     }
 
+    /// Receive an Msg, blocking until one is available
+    ///
+    /// Once the scheduler is stopping, the Msgs already in the port are still received,
+    /// then `Error::Stopping` is returned instead of blocking.
+    ///
+    /// Once the port is closed (see `PortState::is_closed`), the Msgs already in the port are
+    /// still received, then `Error::PortClosed` is returned: an empty port with an output port
+    /// still connected blocks, an empty and closed port doesn't.
     pub fn recv(&self) -> Result<Msg> {
-        let msg = try!(self.recv.recv());
+        loop {
+            if let Some(msg) = try!(self.recv_until(None)) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Receive an Msg, blocking at most `dur`
+    ///
+    /// Returns `Ok(None)` if no Msg arrived in time. Like `recv`, it returns
+    /// `Error::Stopping` on shutdown without waiting the end of `dur`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// match try!(self.input.input.recv_timeout(Duration::from_secs(1))) {
+    ///     Some(msg) => { /* process the Msg */ },
+    ///     None => { /* idle tick */ },
+    /// }
+    /// ```
+    pub fn recv_timeout(&self, dur: Duration) -> Result<Option<Msg>> {
+        self.recv_until(Some(Instant::now() + dur))
+    }
+
+    /// Receive an Msg, but wait until `dur` elapsed since a requeued Msg was given back
+    ///
+    /// An agent that is not ready for a Msg can `requeue` it and reconsider it later with
+    /// `recv_after`, without spinning. Without a requeued Msg, it is a `recv`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let msg = try!(self.input.left.recv_after(Duration::from_millis(10)));
+    /// if !self.state.has_key(&msg) {
+    ///     self.input.left.requeue(msg);
+    ///     return Ok(End);
+    /// }
+    /// ```
+    pub fn recv_after(&self, dur: Duration) -> Result<Msg> {
+        let requeued = self.deferred.borrow().front().map(|&(_, at)| { at });
+        if let Some(at) = requeued {
+            let deadline = at + dur;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                if self.ctx.is_stopping() {
+                    return Err(result::Error::Stopping);
+                }
+                thread::sleep(cmp::min(deadline - now, Duration::from_millis(STOP_POLL_MS)));
+            }
+        }
+        self.recv()
+    }
+
+    /// Give back a received Msg, the next receive returns it again
+    ///
+    /// The Msg goes in front of the Msgs not received yet, and of the Msgs requeued before:
+    /// requeuing the Msgs in the reverse order they were received keeps their order. The
+    /// Msg counts as waiting in the port again, so the scheduler runs the agent for it.
+    pub fn requeue(&self, msg: Msg) {
+        self.port.depth.fetch_add(1, Ordering::Relaxed);
+        self.deferred.borrow_mut().push_front((msg, Instant::now()));
         if self.must_sched {
-            try!(self.sched.send(CompMsg::Dec(self.id)));
+            let _ = self.sched.send(CompMsg::Inc(self.id));
+        }
+    }
+
+    /// Take the first requeued Msg, it was already recorded, checked and counted in the metrics
+    fn recv_deferred(&self) -> Result<Option<Msg>> {
+        if self.ctx.is_paused() {
+            return Ok(None);
+        }
+        let msg = self.deferred.borrow_mut().pop_front();
+        match msg {
+            Some((msg, _)) => {
+                try!(self.dequeued(1));
+                Ok(Some(msg))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<Option<Msg>> {
+        if let Some(msg) = try!(self.recv_deferred()) {
+            return Ok(Some(msg));
+        }
+        let poll = Duration::from_millis(STOP_POLL_MS);
+        let mut blocked = false;
+        let res = loop {
+            let now = Instant::now();
+            let wait = match deadline {
+                Some(deadline) if deadline > now => cmp::min(deadline - now, poll),
+                Some(_) => Duration::new(0, 0),
+                None => poll,
+            };
+            let expired = deadline.map(|d| { now >= d }).unwrap_or(false);
+            let generation = self.ctx.generation();
+            if self.ctx.is_paused() {
+                if self.ctx.is_stopping() {
+                    break Err(result::Error::Stopping);
+                }
+                if expired {
+                    break Ok(None);
+                }
+                self.ctx.wait_notify(generation, wait);
+                continue;
+            }
+            let held = self.held.borrow_mut().take();
+            if let Some(msg) = held {
+                break Ok(Some(msg));
+            }
+            match self.recv.recv_timeout(wait) {
+                Ok(msg) => {
+                    if self.ctx.is_paused() {
+                        // Paused during the wait
+                        *self.held.borrow_mut() = Some(msg);
+                        continue;
+                    }
+                    break Ok(Some(msg));
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.ctx.is_stopping() {
+                        break Err(result::Error::Stopping);
+                    }
+                    if self.port.is_closed() {
+                        break self.recv_closed().map(Some);
+                    }
+                    if deadline.map(|d| { Instant::now() >= d }).unwrap_or(false) {
+                        break Ok(None);
+                    }
+                    if !blocked {
+                        blocked = true;
+                        self.ctx.set_blocked(Some(Blocked::new(self.port.clone(), false)));
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(result::Error::PortClosed(self.port.name.clone()));
+                },
+            }
+        };
+        if blocked {
+            self.ctx.set_blocked(None);
+        }
+        match res {
+            Ok(Some(msg)) => {
+                try!(self.received(1));
+                let msg = try!(self.check_size(msg));
+                self.ctx.on_msg_received(&self.port.name, &msg);
+                Ok(Some(msg))
+            },
+            res => res,
+        }
+    }
+
+    /// Receive at most `max` Msgs, blocking until at least one is available
+    ///
+    /// The scheduler is signaled once for the whole batch.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let msgs = try!(self.input.input.recv_batch(100));
+    /// ```
+    pub fn recv_batch(&self, max: usize) -> Result<Vec<Msg>> {
+        let mut msgs = vec![];
+        if max == 0 {
+            return Ok(msgs);
+        }
+        msgs.push(try!(self.recv()));
+        while msgs.len() < max {
+            match try!(self.recv_deferred()) {
+                Some(msg) => { msgs.push(msg); },
+                None => { break; },
+            }
+        }
+        // The requeued Msgs were recorded when first received
+        let first_new = msgs.len();
+        let mut more = 0;
+        while msgs.len() < max && !self.ctx.is_paused() {
+            match self.recv.try_recv() {
+                Ok(msg) => {
+                    more += 1;
+                    match self.check_size(msg) {
+                        Ok(msg) => { msgs.push(msg); },
+                        Err(e) => { warn!(target: LOG_TARGET, "{}, dropped", e); },
+                    }
+                },
+                Err(_) => { break; },
+            }
+        }
+        if more > 0 {
+            try!(self.received(more));
+        }
+        for msg in &msgs[cmp::max(first_new, 1)..] {
+            self.ctx.on_msg_received(&self.port.name, msg);
         }
+        Ok(msgs)
+    }
+
+    /// The port is closed, receive the last Msg sent before the closing, if any
+    fn recv_closed(&self) -> Result<Msg> {
+        self.recv.try_recv().map_err(|_| { result::Error::PortClosed(self.port.name.clone()) })
+    }
+
+    /// Drop a Msg over the size limit of the port, else set the limit for its decoding
+    fn check_size(&self, mut msg: Msg) -> Result<Msg> {
+        let max = self.port.max_message_size();
+        if let Some(max) = max {
+            if msg.words() > max {
+                return Err(result::Error::MessageTooLarge(self.port.name.clone(), msg.words()));
+            }
+        }
+        msg.max_words = max;
         Ok(msg)
     }
 
-    pub fn try_recv(&self) -> Result<Msg> {
-        let msg = self.recv.try_recv()?;
+    fn received(&self, n: usize) -> Result<()> {
+        self.ctx.on_received(n);
+        self.dequeued(n)
+    }
+
+    /// Take `n` Msgs out of the depth of the port, and tell the scheduler
+    fn dequeued(&self, n: usize) -> Result<()> {
+        self.port.depth.fetch_sub(n, Ordering::Relaxed);
+        self.port.make_room();
         if self.must_sched {
-            try!(self.sched.send(CompMsg::Dec(self.id)));
+            if n == 1 {
+                try!(self.sched.send(CompMsg::Dec(self.id)));
+            } else {
+                try!(self.sched.send(CompMsg::DecBatch(self.id, n)));
+            }
         }
+        Ok(())
+    }
+
+    pub fn try_recv(&self) -> Result<Msg> {
+        if self.ctx.is_paused() {
+            return Err(result::Error::MpscTryRecv(mpsc::TryRecvError::Empty));
+        }
+        if let Some(msg) = try!(self.recv_deferred()) {
+            return Ok(msg);
+        }
+        let held = self.held.borrow_mut().take();
+        let msg = match held.map(Ok).unwrap_or_else(|| { self.recv.try_recv() }) {
+            Ok(msg) => msg,
+            Err(mpsc::TryRecvError::Disconnected) => { return Err(result::Error::PortClosed(self.port.name.clone())); },
+            Err(_) if self.port.is_closed() => { try!(self.recv_closed()) },
+            Err(e) => { return Err(e.into()); },
+        };
+        try!(self.received(1));
+        let msg = try!(self.check_size(msg));
+        self.ctx.on_msg_received(&self.port.name, &msg);
         Ok(msg)
     }
 
+    /// Let the senders send `n` more Msgs
+    ///
+    /// The first grant switches the port to credits: from then the senders block once they
+    /// sent all the granted Msgs, so the consumer bounds the Msgs in flight whatever the
+    /// capacity of the port. `try_send` gives the Msg back instead of blocking. The first
+    /// credits are usually granted before the start, with `Scheduler::grant_credits`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// fn run(&mut self) -> Result<Signal> {
+    ///     let msg = try!(self.input.input.recv());
+    ///     // process the Msg, then ask the next one
+    ///     self.input.input.grant(1);
+    ///     Ok(End)
+    /// }
+    /// ```
+    pub fn grant(&self, n: usize) {
+        self.port.grant(n);
+    }
+
     pub fn get_sender(&self) -> MsgSender {
 	self.sender.clone()
     }
+
+    /// The state of the port
+    pub fn port(&self) -> &Arc<PortState> {
+        &self.port
+    }
+}
+
+/// Receive from several input ports at once
+///
+/// `recv` blocks until one of the ports has an Msg. The port checked first changes
+/// after each Msg, so a busy port cannot starve the others.
+///
+/// The ports must belong to the same agent.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut selector = PortSelector::new();
+/// selector.add("left", &self.input.left);
+/// selector.add("right", &self.input.right);
+/// let (port, msg) = try!(selector.recv());
+/// ```
+pub struct PortSelector<'a> {
+    ports: Vec<(&'a str, &'a MsgReceiver)>,
+    next: usize,
+}
+
+impl<'a> PortSelector<'a> {
+    /// Create an empty selector
+    pub fn new() -> Self {
+        PortSelector {
+            ports: vec![],
+            next: 0,
+        }
+    }
+
+    /// Add the input port `name` to the selector
+    pub fn add(&mut self, name: &'a str, port: &'a MsgReceiver) -> &mut Self {
+        self.ports.push((name, port));
+        self
+    }
+
+    /// Receive the next Msg of any port, with the name of this port
+    ///
+    /// Returns `Error::Stopping` once the scheduler is stopping and the ports are empty,
+    /// and an `Error::PortClosed` once all the ports are disconnected.
+    pub fn recv(&mut self) -> Result<(String, Msg)> {
+        let ctx = match self.ports.first() {
+            Some(&(_, port)) => port.ctx.clone(),
+            None => { return Err(result::Error::PortClosed(String::new())); },
+        };
+        loop {
+            let generation = ctx.generation();
+            if let Some(res) = self.try_recv_any() {
+                return res;
+            }
+            if ctx.is_stopping() {
+                return Err(result::Error::Stopping);
+            }
+            ctx.wait_notify(generation, Duration::from_millis(STOP_POLL_MS));
+        }
+    }
+
+    /// Check each port once, starting at `next`
+    fn try_recv_any(&mut self) -> Option<Result<(String, Msg)>> {
+        let len = self.ports.len();
+        let mut disconnected = 0;
+        for i in 0..len {
+            let idx = (self.next + i) % len;
+            let (name, port) = self.ports[idx];
+            match port.try_recv() {
+                Ok(msg) => {
+                    self.next = (idx + 1) % len;
+                    return Some(Ok((name.into(), msg)));
+                },
+                Err(result::Error::MpscTryRecv(mpsc::TryRecvError::Empty)) => {},
+                Err(result::Error::PortClosed(_)) => { disconnected += 1; },
+                Err(e) => { return Some(Err(e)); },
+            }
+        }
+        if disconnected == len {
+            let names: Vec<&str> = self.ports.iter().map(|&(name, _)| { name }).collect();
+            Some(Err(result::Error::PortClosed(names.join(", "))))
+        } else {
+            None
+        }
+    }
+}
+
+/// Receive from several input ports, the ports of higher priority first
+///
+/// `recv` returns an Msg of the highest priority port that is not empty. Among the ports
+/// of the same priority, the first added is checked first. With `max_consecutive`, a
+/// priority gives at most this number of Msgs in a row while a lower priority port has an
+/// Msg, so a busy control port cannot starve the data port.
+///
+/// The ports must belong to the same agent.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut ports = PriorityPortSet::new();
+/// ports.add("control", &self.input.control, 10);
+/// ports.add("data", &self.input.data, 0);
+/// ports.max_consecutive(10, 100);
+/// let (port, msg) = try!(ports.recv());
+/// ```
+pub struct PriorityPortSet<'a> {
+    ports: Vec<(&'a str, &'a MsgReceiver, u32)>,
+    caps: HashMap<u32, usize>,
+    /// The priority of the last Msgs, and their number in a row
+    streak: Option<(u32, usize)>,
+}
+
+impl<'a> PriorityPortSet<'a> {
+    /// Create an empty set
+    pub fn new() -> Self {
+        PriorityPortSet {
+            ports: vec![],
+            caps: HashMap::new(),
+            streak: None,
+        }
+    }
+
+    /// Add the input port `name` with `priority`, the highest number is served first
+    pub fn add(&mut self, name: &'a str, port: &'a MsgReceiver, priority: u32) -> &mut Self {
+        let pos = self.ports.iter().position(|&(_, _, p)| { p < priority }).unwrap_or(self.ports.len());
+        self.ports.insert(pos, (name, port, priority));
+        self
+    }
+
+    /// Give at most `max` Msgs in a row from the ports of `priority`
+    pub fn max_consecutive(&mut self, priority: u32, max: usize) -> &mut Self {
+        self.caps.insert(priority, max);
+        self
+    }
+
+    /// Receive the next Msg, with the name of its port
+    ///
+    /// Returns `Error::Stopping` once the scheduler is stopping and the ports are empty,
+    /// and an `Error::PortClosed` once all the ports are disconnected.
+    pub fn recv(&mut self) -> Result<(String, Msg)> {
+        let ctx = match self.ports.first() {
+            Some(&(_, port, _)) => port.ctx.clone(),
+            None => { return Err(result::Error::PortClosed(String::new())); },
+        };
+        loop {
+            let generation = ctx.generation();
+            if let Some(res) = self.try_recv_any() {
+                return res;
+            }
+            if ctx.is_stopping() {
+                return Err(result::Error::Stopping);
+            }
+            ctx.wait_notify(generation, Duration::from_millis(STOP_POLL_MS));
+        }
+    }
+
+    /// Check the ports in priority order, the priority over its cap last
+    fn try_recv_any(&mut self) -> Option<Result<(String, Msg)>> {
+        let capped = match self.streak {
+            Some((priority, count)) if self.caps.get(&priority).map(|max| { count >= *max }).unwrap_or(false) => Some(priority),
+            _ => None,
+        };
+        let mut disconnected = 0;
+        for pass in 0..2 {
+            if pass == 1 && capped.is_none() {
+                break;
+            }
+            for i in 0..self.ports.len() {
+                let (name, port, priority) = self.ports[i];
+                if (pass == 0) == (Some(priority) == capped) {
+                    continue;
+                }
+                match port.try_recv() {
+                    Ok(msg) => {
+                        self.streak = match self.streak {
+                            Some((p, count)) if p == priority => Some((p, count + 1)),
+                            _ => Some((priority, 1)),
+                        };
+                        return Some(Ok((name.into(), msg)));
+                    },
+                    Err(result::Error::MpscTryRecv(mpsc::TryRecvError::Empty)) => {},
+                    Err(result::Error::PortClosed(_)) => { disconnected += 1; },
+                    Err(e) => { return Some(Err(e)); },
+                }
+            }
+        }
+        if disconnected == self.ports.len() {
+            let names: Vec<&str> = self.ports.iter().map(|&(name, _, _)| { name }).collect();
+            Some(Err(result::Error::PortClosed(names.join(", "))))
+        } else {
+            None
+        }
+    }
+}
+
+/// Receive the Msgs of input ports in the order of their sequence numbers
+///
+/// The producers feeding the ports number their Msgs with `Msg::set_sequence`, from a common
+/// counter. The Msgs are given by `recv`, or by `recv_any` for a fan-in on several ports, in
+/// sequence without gap, the ones arriving early are buffered. A Msg without a sequence
+/// number is given as it arrives, and a Msg whose number was already given is dropped.
+///
+/// A missing number is waited for `gap_timeout` after a later Msg arrived. Then `recv` returns
+/// `Error::SequenceGap`, and continues with the buffered Msgs from the next number received.
+///
+/// The `OrderedInput` is kept in the state of the agent, the early Msgs stay buffered between
+/// two runs.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// // In the state: ordered: OrderedInput::new(1, Duration::from_secs(5))
+/// let msg = match self.ordered.recv(&self.input.input) {
+///     Err(result::Error::SequenceGap(missing, next)) => {
+///         warn!("the Msgs {} to {} are lost", missing, next - 1);
+///         return Ok(End);
+///     },
+///     res => try!(res),
+/// };
+///
+/// // Or from the left and the right producers
+/// let msg = try!(self.ordered.recv_any(&[&self.input.left, &self.input.right]));
+/// ```
+pub struct OrderedInput {
+    next: u64,
+    gap_timeout: Duration,
+    early: BTreeMap<u64, Msg>,
+    /// Since when the next number is missing while later Msgs wait
+    gap_since: Option<Instant>,
+    /// The port `recv_any` checks first
+    turn: usize,
+}
+
+impl OrderedInput {
+    /// Wait for the sequence number `first`, then the following ones
+    pub fn new(first: u64, gap_timeout: Duration) -> Self {
+        OrderedInput {
+            next: first,
+            gap_timeout: gap_timeout,
+            early: BTreeMap::new(),
+            gap_since: None,
+            turn: 0,
+        }
+    }
+
+    /// The sequence number of the next Msg given by `recv`
+    pub fn next_sequence(&self) -> u64 {
+        self.next
+    }
+
+    /// The number of Msgs received before their turn
+    pub fn buffered(&self) -> usize {
+        self.early.len()
+    }
+
+    /// Receive the next Msg in sequence from `port`, blocking until it arrives
+    ///
+    /// The errors of `MsgReceiver::recv` are returned as they are, the buffered Msgs are kept.
+    pub fn recv(&mut self, port: &MsgReceiver) -> Result<Msg> {
+        loop {
+            if let Some(msg) = self.take_next() {
+                return Ok(msg);
+            }
+            let msg = match self.gap_since {
+                None => try!(port.recv()),
+                Some(since) => {
+                    let elapsed = since.elapsed();
+                    if elapsed >= self.gap_timeout {
+                        return Err(self.skip_gap());
+                    }
+                    match try!(port.recv_timeout(self.gap_timeout - elapsed)) {
+                        Some(msg) => msg,
+                        None => { continue; },
+                    }
+                },
+            };
+            if let Some(msg) = self.arrived(msg) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Receive the next Msg in sequence from any of `ports`, blocking until it arrives
+    ///
+    /// The producers send to different ports of the agent, and the numbers are ordered across
+    /// the ports. The ports must belong to the same agent. As `PortSelector::recv`, returns
+    /// `Error::Stopping` once the scheduler is stopping and the ports are empty, and an
+    /// `Error::PortClosed` once all the ports are disconnected.
+    pub fn recv_any(&mut self, ports: &[&MsgReceiver]) -> Result<Msg> {
+        let ctx = match ports.first() {
+            Some(port) => port.ctx.clone(),
+            None => { return Err(result::Error::PortClosed(String::new())); },
+        };
+        loop {
+            if let Some(msg) = self.take_next() {
+                return Ok(msg);
+            }
+            let mut wait = Duration::from_millis(STOP_POLL_MS);
+            if let Some(since) = self.gap_since {
+                let elapsed = since.elapsed();
+                if elapsed >= self.gap_timeout {
+                    return Err(self.skip_gap());
+                }
+                wait = cmp::min(wait, self.gap_timeout - elapsed);
+            }
+            let generation = ctx.generation();
+            match try!(self.try_recv_any(ports)) {
+                Some(msg) => {
+                    if let Some(msg) = self.arrived(msg) {
+                        return Ok(msg);
+                    }
+                },
+                None => {
+                    if ctx.is_stopping() {
+                        return Err(result::Error::Stopping);
+                    }
+                    ctx.wait_notify(generation, wait);
+                },
+            }
+        }
+    }
+
+    /// Check each port once, starting at `turn`
+    fn try_recv_any(&mut self, ports: &[&MsgReceiver]) -> Result<Option<Msg>> {
+        let len = ports.len();
+        let mut disconnected = 0;
+        for i in 0..len {
+            let idx = (self.turn + i) % len;
+            match ports[idx].try_recv() {
+                Ok(msg) => {
+                    self.turn = (idx + 1) % len;
+                    return Ok(Some(msg));
+                },
+                Err(result::Error::MpscTryRecv(mpsc::TryRecvError::Empty)) => {},
+                Err(result::Error::PortClosed(_)) => { disconnected += 1; },
+                Err(e) => { return Err(e); },
+            }
+        }
+        if disconnected == len {
+            let names: Vec<&str> = ports.iter().map(|port| { &port.port.name[..] }).collect();
+            return Err(result::Error::PortClosed(names.join(", ")));
+        }
+        Ok(None)
+    }
+
+    /// The buffered Msg of the next number, if it arrived
+    fn take_next(&mut self) -> Option<Msg> {
+        let msg = self.early.remove(&self.next);
+        if msg.is_some() {
+            self.next += 1;
+            self.gap_since = if self.early.is_empty() { None } else { Some(Instant::now()) };
+        }
+        msg
+    }
+
+    /// Give up the missing numbers, up to the first buffered one
+    fn skip_gap(&mut self) -> result::Error {
+        let missing = self.next;
+        let next = *self.early.keys().next().expect("OrderedInput: a gap without buffered Msg");
+        self.next = next;
+        self.gap_since = None;
+        result::Error::SequenceGap(missing, next)
+    }
+
+    /// Buffer a received Msg, a Msg without number is returned to be given at once
+    fn arrived(&mut self, msg: Msg) -> Option<Msg> {
+        let seq = match msg.sequence() {
+            Some(seq) => seq,
+            None => { return Some(msg); },
+        };
+        if seq < self.next || self.early.contains_key(&seq) {
+            debug!(target: LOG_TARGET, "OrderedInput: drops the Msg {}, already received", seq);
+            return None;
+        }
+        self.early.insert(seq, msg);
+        if seq != self.next && self.gap_since.is_none() {
+            self.gap_since = Some(Instant::now());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scheduler::AgentCtx;
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::sync::mpsc::channel;
+
+    fn port(name: &str, capacity: usize) -> (MsgReceiver, MsgSender) {
+        let (sched, _) = channel();
+        MsgReceiver::with_capacity(0, name, sched, AgentCtx::new(), false, capacity)
+    }
+
+    #[test]
+    fn packing_round_trips() {
+        let mut bytes = vec![0, 0, 0, 0, 1, 0, 2, 0];
+        // 300 zero words, more than a run
+        bytes.extend((0..300 * 8).map(|_| { 0u8 }));
+        // Words without zero, then with one zero byte, then with two
+        bytes.extend((0..16).map(|i| { i as u8 + 1 }));
+        bytes.extend_from_slice(&[1, 2, 3, 0, 5, 6, 7, 8]);
+        bytes.extend_from_slice(&[1, 0, 3, 0, 5, 6, 7, 8]);
+        let packed = pack(&bytes);
+        assert_eq!(&packed[..4], &[0x50, 1, 2, 0x00]);
+        assert!(packed.len() < 60);
+        let words = unpack(&packed).unwrap();
+        assert_eq!(capnp::Word::words_to_bytes(&words), &bytes[..]);
+        // A truncated message
+        for end in 1..packed.len() {
+            if let Ok(words) = unpack(&packed[..end]) {
+                assert!(capnp::Word::words_to_bytes(&words) != &bytes[..]);
+            }
+        }
+        match unpack(&[0xff, 1, 2]) {
+            Err(result::Error::Capnp(_)) => {},
+            _ => panic!("a truncated word is unpacked"),
+        }
+    }
+
+    #[test]
+    fn packed_bytes_round_trip() {
+        let mut data = vec![0; 40];
+        data.push(7);
+        let mut msg = bytes_msg(&data);
+        msg.before_send().unwrap();
+        let packed = msg.to_packed_bytes().unwrap();
+        assert!(packed.len() < msg.vec.len());
+        let mut copy = Msg::from_packed_bytes(&packed).unwrap();
+        assert_eq!(msg_bytes(&mut copy).unwrap(), msg_bytes(&mut msg).unwrap());
+        assert!(Msg::from_packed_bytes(&packed[..packed.len() - 1]).is_err());
+    }
+
+    /// Send `n` Msgs from another thread, counting the sends that returned
+    fn produce(sender: MsgSender, n: u8) -> (Arc<AtomicUsize>, thread::JoinHandle<()>) {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let count = sent.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..n {
+                sender.send(bytes_msg(&[i])).unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        (sent, producer)
+    }
+
+    /// Wait until `sent` reaches `n`, much faster than a receive polls for the shutdown
+    fn wait_sent(sent: &AtomicUsize, n: usize) {
+        let start = Instant::now();
+        while sent.load(Ordering::SeqCst) < n {
+            assert!(start.elapsed() < Duration::from_millis(STOP_POLL_MS / 2), "the sender is not woken up");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn sender_stalls_at_the_capacity_until_a_receive() {
+        let (recv, sender) = port("input", 2);
+        let (sent, producer) = produce(sender, 4);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert!(recv.port().is_full());
+        assert_eq!(msg_bytes(&mut recv.recv().unwrap()).unwrap(), vec![0]);
+        wait_sent(&sent, 3);
+        assert_eq!(msg_bytes(&mut recv.recv().unwrap()).unwrap(), vec![1]);
+        wait_sent(&sent, 4);
+        producer.join().unwrap();
+        let rest: Vec<Vec<u8>> = (0..2).map(|_| { msg_bytes(&mut recv.recv().unwrap()).unwrap() }).collect();
+        assert_eq!(rest, vec![vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn sender_stalls_without_credit_until_a_grant() {
+        let (recv, sender) = port("input", 4);
+        recv.port().grant(1);
+        let (sent, producer) = produce(sender, 2);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        recv.port().grant(1);
+        wait_sent(&sent, 2);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn fanout_sends_the_built_msg_to_every_destination() {
+        let (first, mut sender) = port("first", 4);
+        let (second, other) = port("second", 4);
+        sender.add_destination(other);
+        sender.send(bytes_msg(&[1, 2, 3])).unwrap();
+        sender.send_batch(vec![bytes_msg(&[4]), bytes_msg(&[5])]).unwrap();
+        for recv in &[first, second] {
+            let got: Vec<Vec<u8>> = (0..3).map(|_| { msg_bytes(&mut recv.try_recv().unwrap()).unwrap() }).collect();
+            assert_eq!(got, vec![vec![1, 2, 3], vec![4], vec![5]]);
+        }
+    }
+
+    #[test]
+    fn open_breaker_feeds_no_destination() {
+        let (first, mut sender) = port("first", 4);
+        let (second, other) = port("second", 4);
+        sender.add_destination(other);
+        let config = BreakerConfig { failures: 1, window: Duration::from_secs(60), cooldown: Duration::from_secs(60), fallback: None };
+        sender.port().set_circuit_breaker(Some(config), None, 0);
+        sender.port().failed();
+        sender.send(bytes_msg(&[1])).unwrap();
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_err());
+    }
+
+    #[test]
+    fn try_send_on_a_full_destination_sends_no_copy() {
+        let (first, mut sender) = port("first", 1);
+        let (second, other) = port("second", 4);
+        sender.add_destination(other);
+        assert!(sender.try_send(bytes_msg(&[1])).unwrap().is_none());
+        let back = sender.try_send(bytes_msg(&[2])).unwrap();
+        assert!(back.is_some());
+        assert_eq!(msg_bytes(&mut first.try_recv().unwrap()).unwrap(), vec![1]);
+        assert_eq!(msg_bytes(&mut second.try_recv().unwrap()).unwrap(), vec![1]);
+        assert!(second.try_recv().is_err());
+        assert!(sender.try_send(back.unwrap()).unwrap().is_none());
+        assert_eq!(msg_bytes(&mut first.try_recv().unwrap()).unwrap(), vec![2]);
+        assert_eq!(msg_bytes(&mut second.try_recv().unwrap()).unwrap(), vec![2]);
+    }
+
+    /// The ports `left` and `right` of an agent
+    fn two_ports() -> ((MsgReceiver, MsgSender), (MsgReceiver, MsgSender)) {
+        let (sched, _) = channel();
+        let ctx = AgentCtx::new();
+        (MsgReceiver::with_capacity(0, "left", sched.clone(), ctx.clone(), false, 8),
+         MsgReceiver::with_capacity(0, "right", sched, ctx, false, 8))
+    }
+
+    fn numbered(seq: u64) -> Msg {
+        let mut msg = bytes_msg(&[seq as u8]);
+        msg.set_sequence(seq);
+        msg
+    }
+
+    #[test]
+    fn fan_in_is_received_in_sequence() {
+        let ((left, to_left), (right, to_right)) = two_ports();
+        let producers = thread::spawn(move || {
+            for &(sender, seq) in &[(&to_left, 1), (&to_right, 3), (&to_left, 2), (&to_right, 4)] {
+                sender.send(numbered(seq)).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let mut ordered = OrderedInput::new(1, Duration::from_secs(5));
+        let mut received = vec![];
+        for _ in 0..4 {
+            let mut msg = ordered.recv_any(&[&left, &right]).unwrap();
+            received.push((msg.sequence().unwrap(), msg_bytes(&mut msg).unwrap()[0]));
+        }
+        assert_eq!(received, vec![(1, 1), (2, 2), (3, 3), (4, 4)]);
+        assert_eq!(ordered.buffered(), 0);
+        producers.join().unwrap();
+    }
+
+    #[test]
+    fn missing_number_is_a_gap_after_the_timeout() {
+        let ((left, to_left), (right, to_right)) = two_ports();
+        to_left.send(numbered(1)).unwrap();
+        to_right.send(numbered(3)).unwrap();
+        to_left.send(numbered(4)).unwrap();
+        let mut ordered = OrderedInput::new(1, Duration::from_millis(50));
+        assert_eq!(ordered.recv_any(&[&left, &right]).unwrap().sequence(), Some(1));
+        let start = Instant::now();
+        match ordered.recv_any(&[&left, &right]) {
+            Err(result::Error::SequenceGap(2, 3)) => {},
+            other => panic!("the missing 2 gives {:?}", other.map(|msg| { msg.sequence() })),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(ordered.recv_any(&[&left, &right]).unwrap().sequence(), Some(3));
+        assert_eq!(ordered.recv_any(&[&left, &right]).unwrap().sequence(), Some(4));
+        // A late 2 is dropped
+        to_right.send(numbered(2)).unwrap();
+        to_left.send(numbered(5)).unwrap();
+        assert_eq!(ordered.recv_any(&[&left, &right]).unwrap().sequence(), Some(5));
+        assert_eq!(ordered.next_sequence(), 6);
+    }
 }
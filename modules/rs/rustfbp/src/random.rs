@@ -0,0 +1,14 @@
+//! Random numbers, without a dependency
+//!
+//! They spread the retries and name the spans, they are not for cryptography.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A random number
+pub fn random_u64() -> u64 {
+    // A RandomState has random keys, hashing with it gives a random number
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish()
+}
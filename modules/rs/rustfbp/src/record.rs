@@ -0,0 +1,356 @@
+//! Record the Msgs received by an agent, and replay them
+//!
+//! A `Recorder` is set on the context of an agent, with `Scheduler::record_agent` or
+//! `AgentTester::record`. Each Msg received by the agent, on any of its input ports, is written
+//! with a sequence number, in the order the agent received them. A `Player` reads the recording
+//! back and pushes the Msgs to an `AgentTester` in the same order.
+//!
+//! # Format
+//!
+//! All the integers are little endian, a `bytes` is a `u32` length followed by the bytes.
+//!
+//! * header : the magic `FBPREC`, the version as `u32`, and the layout of a record as `bytes`
+//! * each record : `seq: u64`, `port: bytes`, `action: bytes`, `kind: u8`, `label: u8 bytes?`,
+//!   `data: bytes`
+//!
+//! The kind is 0 for a data Msg, 1 for an open bracket, 2 for a close bracket and 3 for an end
+//! of stream. The label of an open bracket is preceded by 1 if it is present, else by 0. The
+//! headers of the Msgs are not recorded.
+//!
+//! A recording created with `Recorder::create_packed` writes the `data` in the capnp packed
+//! encoding, its layout ends with `data:packed`. The `Player` reads both.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut tester = try!(AgentTester::new(new));
+//! try!(tester.record("/tmp/add.rec"));
+//! // push and run...
+//!
+//! let player = try!(Player::open("/tmp/add.rec"));
+//! let mut replay = try!(AgentTester::new(new));
+//! try!(replay.capture("output"));
+//! try!(player.replay(&mut replay, 100));
+//! ```
+
+use result;
+use result::Result;
+
+use codec::{write_u32, write_u64, write_bytes, write_kind, read_u32, read_u64, read_bytes, read_kind};
+use ports::{Msg, DEFAULT_CAPACITY};
+use testing::AgentTester;
+
+use std::fs::File;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The magic at the start of a recording
+pub const MAGIC: &'static [u8] = b"FBPREC";
+
+/// The version of the format written by the `Recorder`
+pub const VERSION: u32 = 1;
+
+/// The layout of a record, written in the header
+const LAYOUT: &'static str = "seq:u64 port:bytes action:bytes kind:u8 label:u8,bytes? data:bytes";
+
+/// The layout of a record with the data in the packed encoding
+const PACKED_LAYOUT: &'static str = "seq:u64 port:bytes action:bytes kind:u8 label:u8,bytes? data:packed";
+
+/// Write the received Msgs of an agent
+pub struct Recorder {
+    out: Mutex<(Box<Write + Send>, u64)>,
+    packed: bool,
+}
+
+impl Recorder {
+    /// Create a recording in a new file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder> {
+        let file = try!(File::create(path));
+        Recorder::new(Box::new(BufWriter::new(file)))
+    }
+
+    /// Create a recording in a new file, with the data in the packed encoding
+    pub fn create_packed<P: AsRef<Path>>(path: P) -> Result<Recorder> {
+        let file = try!(File::create(path));
+        Recorder::new_packed(Box::new(BufWriter::new(file)))
+    }
+
+    /// Create a recording in `out`, the header is written at once
+    pub fn new(out: Box<Write + Send>) -> Result<Recorder> {
+        Recorder::open(out, false)
+    }
+
+    /// Create a recording in `out`, with the data in the packed encoding
+    pub fn new_packed(out: Box<Write + Send>) -> Result<Recorder> {
+        Recorder::open(out, true)
+    }
+
+    fn open(mut out: Box<Write + Send>, packed: bool) -> Result<Recorder> {
+        let layout = if packed { PACKED_LAYOUT } else { LAYOUT };
+        try!(out.write_all(MAGIC));
+        try!(write_u32(&mut out, VERSION));
+        try!(write_bytes(&mut out, layout.as_bytes()));
+        try!(out.flush());
+        Ok(Recorder {
+            out: Mutex::new((out, 0)),
+            packed: packed,
+        })
+    }
+
+    /// Write a Msg received on `port`
+    pub fn record(&self, port: &str, msg: &Msg) -> Result<()> {
+        let mut guard = try!(self.out.lock().map_err(|_| { result::Error::Misc("Recorder: poisoned lock".into()) }));
+        let &mut (ref mut out, ref mut seq) = &mut *guard;
+        try!(write_u64(out, *seq));
+        try!(write_bytes(out, port.as_bytes()));
+        try!(write_bytes(out, msg.action.as_bytes()));
+        try!(write_kind(out, &msg.kind));
+        if self.packed {
+            try!(write_bytes(out, &try!(msg.to_packed_bytes())));
+        } else {
+            try!(write_bytes(out, &msg.vec));
+        }
+        try!(out.flush());
+        *seq += 1;
+        Ok(())
+    }
+}
+
+/// One received Msg
+pub struct Record {
+    /// The position of the Msg in the recording
+    pub seq: u64,
+    /// The input port, `port[element]` for an element of an array port
+    pub port: String,
+    pub msg: Msg,
+}
+
+impl Record {
+    /// Split the port name in the port and the element of an array port
+    pub fn port_element(&self) -> (&str, Option<&str>) {
+        if self.port.ends_with(']') {
+            if let Some(pos) = self.port.find('[') {
+                return (&self.port[..pos], Some(&self.port[pos + 1..self.port.len() - 1]));
+            }
+        }
+        (&self.port, None)
+    }
+}
+
+/// Read a recording, to replay it
+pub struct Player {
+    records: Vec<Record>,
+}
+
+impl Player {
+    /// Read a recording file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Player> {
+        let file = try!(File::open(path));
+        Player::read(&mut BufReader::new(file))
+    }
+
+    /// Read a recording
+    pub fn read<R: Read>(input: &mut R) -> Result<Player> {
+        let mut magic = [0; 6];
+        try!(input.read_exact(&mut magic));
+        if &magic[..] != MAGIC {
+            return Err(result::Error::BadRecording("not a recording".into()));
+        }
+        let version = try!(read_u32(input));
+        if version != VERSION {
+            return Err(result::Error::BadRecording(format!("version {} is not supported", version)));
+        }
+        let packed = match &try!(read_bytes(input))[..] {
+            layout if layout == LAYOUT.as_bytes() => false,
+            layout if layout == PACKED_LAYOUT.as_bytes() => true,
+            _ => { return Err(result::Error::BadRecording("unknown layout".into())); },
+        };
+
+        let mut records = vec![];
+        loop {
+            let seq = match read_u64(input) {
+                Ok(seq) => seq,
+                Err(result::Error::IO(ref e)) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => { break; },
+                Err(e) => { return Err(e); },
+            };
+            let port = try!(String::from_utf8(try!(read_bytes(input))));
+            let action = try!(String::from_utf8(try!(read_bytes(input))));
+            let kind = try!(read_kind(input));
+            let data = try!(read_bytes(input));
+            let mut msg = if packed {
+                try!(Msg::from_packed_bytes(&data))
+            } else {
+                let mut msg = Msg::new();
+                msg.vec = data;
+                msg
+            };
+            msg.action = action;
+            msg.kind = kind;
+            records.push(Record {
+                seq: seq,
+                port: port,
+                msg: msg,
+            });
+        }
+        Ok(Player {
+            records: records,
+        })
+    }
+
+    /// The recorded Msgs, in the order they were received
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Push the recorded Msgs to `tester` in order, and run the agent at most `max` times
+    ///
+    /// The agent is run before a push if the port is full. Returns the number of runs.
+    pub fn replay(&self, tester: &mut AgentTester, max: usize) -> Result<usize> {
+        let mut runs = 0;
+        for record in &self.records {
+            let (port, element) = record.port_element();
+            if tester.waiting(port, element) >= DEFAULT_CAPACITY {
+                runs += try!(tester.run(max - runs));
+            }
+            match element {
+                Some(element) => { try!(tester.push_array(port, element, record.msg.clone())); },
+                None => { try!(tester.push(port, record.msg.clone())); },
+            }
+        }
+        runs += try!(tester.run(max - runs));
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use builtin::merger;
+    use ports::MsgKind;
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::Arc;
+
+    /// A `Write` whose bytes are read back by the test
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn built(bytes: &[u8]) -> Msg {
+        let mut msg = bytes_msg(bytes);
+        msg.before_send().unwrap();
+        msg
+    }
+
+    /// The kinds and the bytes of the Msgs of a `Merger`
+    fn merged(msgs: Vec<Msg>) -> Vec<(MsgKind, Vec<u8>)> {
+        msgs.into_iter().map(|mut msg| {
+            let bytes = if msg.is_data() { msg_bytes(&mut msg).unwrap() } else { vec![] };
+            (msg.kind, bytes)
+        }).collect()
+    }
+
+    #[test]
+    fn replay_gives_the_same_output() {
+        let path = env::temp_dir().join("rustfbp-record-replay_gives_the_same_output.rec");
+        let mut tester = AgentTester::new(merger).unwrap();
+        tester.record(&path).unwrap();
+        tester.capture("output").unwrap();
+        tester.push_array("inputs", "a", Msg::open_bracket(Some(b"batch".to_vec()))).unwrap();
+        tester.push_array("inputs", "b", built(&[10])).unwrap();
+        tester.push_array("inputs", "a", built(&[1])).unwrap();
+        tester.push_array("inputs", "b", built(&[20])).unwrap();
+        tester.push_array("inputs", "a", Msg::close_bracket()).unwrap();
+        tester.push_array("inputs", "b", Msg::end_of_stream()).unwrap();
+        tester.push_array("inputs", "a", Msg::end_of_stream()).unwrap();
+        tester.run(20).unwrap();
+        let recorded = merged(tester.output("output"));
+        assert_eq!(recorded.len(), 6);
+
+        let player = Player::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let seqs: Vec<u64> = player.records().iter().map(|r| { r.seq }).collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3, 4, 5, 6]);
+        let ports: Vec<(&str, Option<&str>)> = player.records().iter().map(|r| { r.port_element() }).collect();
+        assert_eq!(ports, vec![("inputs", Some("a")), ("inputs", Some("b")), ("inputs", Some("a")), ("inputs", Some("b")),
+                               ("inputs", Some("a")), ("inputs", Some("b")), ("inputs", Some("a"))]);
+
+        let mut replay = AgentTester::new(merger).unwrap();
+        replay.capture("output").unwrap();
+        player.replay(&mut replay, 20).unwrap();
+        assert_eq!(merged(replay.output("output")), recorded);
+    }
+
+    #[test]
+    fn packed_recording_round_trips() {
+        for packed in &[false, true] {
+            let buf = Shared(Arc::new(Mutex::new(vec![])));
+            let recorder = if *packed {
+                Recorder::new_packed(Box::new(buf.clone())).unwrap()
+            } else {
+                Recorder::new(Box::new(buf.clone())).unwrap()
+            };
+            let mut msg = built(&[0, 0, 0, 7]);
+            msg.action = "add".into();
+            recorder.record("input", &msg).unwrap();
+            recorder.record("options", &Msg::open_bracket(None)).unwrap();
+            let bytes = buf.0.lock().unwrap().clone();
+            assert_eq!(&bytes[..6], MAGIC);
+            let player = Player::read(&mut &bytes[..]).unwrap();
+            let records = player.records();
+            assert_eq!(records.len(), 2);
+            assert_eq!((records[0].seq, &records[0].port as &str, &records[0].msg.action as &str), (0, "input", "add"));
+            assert_eq!(records[0].msg.kind, MsgKind::Data);
+            let mut data = records[0].msg.clone();
+            assert_eq!(msg_bytes(&mut data).unwrap(), vec![0, 0, 0, 7]);
+            assert_eq!((records[1].seq, &records[1].port as &str), (1, "options"));
+            assert_eq!(records[1].msg.kind, MsgKind::OpenBracket(None));
+        }
+    }
+
+    #[test]
+    fn player_rejects_the_other_files() {
+        let mut header = vec![];
+        header.extend_from_slice(MAGIC);
+        write_u32(&mut header, VERSION + 1).unwrap();
+        write_bytes(&mut header, LAYOUT.as_bytes()).unwrap();
+        let mut layout = vec![];
+        layout.extend_from_slice(MAGIC);
+        write_u32(&mut layout, VERSION).unwrap();
+        write_bytes(&mut layout, b"seq:u64").unwrap();
+        for bytes in &[b"FBPXXX\x01\0\0\0".to_vec(), header, layout] {
+            match Player::read(&mut &bytes[..]) {
+                Err(result::Error::BadRecording(_)) => {},
+                _ => panic!("the recording is read"),
+            }
+        }
+        let buf = Shared(Arc::new(Mutex::new(vec![])));
+        let recorder = Recorder::new(Box::new(buf.clone())).unwrap();
+        recorder.record("input", &built(&[1, 2, 3])).unwrap();
+        let bytes = buf.0.lock().unwrap().clone();
+        assert!(Player::read(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn port_element_splits_the_array_ports() {
+        let record = |port: &str| { Record { seq: 0, port: port.into(), msg: Msg::new() } };
+        assert_eq!(record("input").port_element(), ("input", None));
+        assert_eq!(record("inputs[a]").port_element(), ("inputs", Some("a")));
+        assert_eq!(record("inputs[]").port_element(), ("inputs", Some("")));
+        assert_eq!(record("input]").port_element(), ("input]", None));
+    }
+}
@@ -0,0 +1,256 @@
+//! Request and reply between agents, matched by a correlation id
+//!
+//! The requesting agent sends the requests with a `RequestReply`, which gives each one an id
+//! in the header `CORRELATION_HEADER`. The responder copies the header in its reply, with
+//! `reply_to`, and sends it to the reply port of the requester. Several requests can be
+//! outstanding: `recv_reply` waits for the reply of one of them, and keeps the replies of the
+//! others until they are asked for.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! // The requester, with the RequestReply in its state
+//! let first = try!(self.requests.send_request(&self.output.request, msg));
+//! let second = try!(self.requests.send_request(&self.output.request, other));
+//! let reply = try!(self.requests.recv_reply(&self.input.reply, &second, Duration::from_secs(1)));
+//! let reply = try!(self.requests.recv_reply(&self.input.reply, &first, Duration::from_secs(1)));
+//!
+//! // The responder
+//! let request = try!(self.input.input.recv());
+//! let mut reply = Msg::new();
+//! // build the reply
+//! try!(self.output.output.send(reply_to(&request, reply)));
+//! ```
+
+use result;
+use result::Result;
+use ports::{Msg, MsgReceiver, OutputSend};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+const LOG_TARGET: &'static str = "fractalide::request";
+
+/// The header holding the correlation id of a request and of its reply
+pub const CORRELATION_HEADER: &'static str = "correlation_id";
+
+/// The correlation id of a Msg, `None` without the header or if it is not UTF-8
+pub fn correlation_id(msg: &Msg) -> Option<&str> {
+    msg.get_header(CORRELATION_HEADER).and_then(|id| { ::std::str::from_utf8(id).ok() })
+}
+
+/// Give to `reply` the correlation id of `request`
+pub fn reply_to(request: &Msg, mut reply: Msg) -> Msg {
+    if let Some(id) = request.get_header(CORRELATION_HEADER) {
+        reply.set_header(CORRELATION_HEADER, id.clone());
+    }
+    reply
+}
+
+/// The outstanding requests of an agent, and the replies received before they were asked for
+///
+/// A reply without a correlation id, or for a request that is not outstanding, is dropped.
+pub struct RequestReply {
+    next_id: u64,
+    pending: HashSet<String>,
+    arrived: HashMap<String, Msg>,
+}
+
+impl RequestReply {
+    pub fn new() -> Self {
+        RequestReply {
+            next_id: 0,
+            pending: HashSet::new(),
+            arrived: HashMap::new(),
+        }
+    }
+
+    /// Send `msg` as a request on `output`, and return its correlation id
+    pub fn send_request<O: OutputSend + ?Sized>(&mut self, output: &O, mut msg: Msg) -> Result<String> {
+        let id = self.next_id.to_string();
+        self.next_id += 1;
+        msg.set_header(CORRELATION_HEADER, id.clone().into_bytes());
+        try!(output.send(msg));
+        self.pending.insert(id.clone());
+        Ok(id)
+    }
+
+    /// Wait at most `timeout` for the reply to the request `id` on the port `reply`
+    ///
+    /// The replies to the other outstanding requests received meanwhile are kept. After an
+    /// `Error::Timeout`, the request is still outstanding: `recv_reply` can wait again, or
+    /// `cancel` forgets it.
+    pub fn recv_reply(&mut self, reply: &MsgReceiver, id: &str, timeout: Duration) -> Result<Msg> {
+        if let Some(msg) = self.arrived.remove(id) {
+            self.pending.remove(id);
+            return Ok(msg);
+        }
+        if !self.pending.contains(id) {
+            return Err(result::Error::Misc(format!("RequestReply: no outstanding request {}", id)));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(result::Error::Timeout(format!("no reply to the request {} after {:?}", id, timeout)));
+            }
+            let msg = match try!(reply.recv_timeout(deadline - now)) {
+                Some(msg) => msg,
+                None => { continue; },
+            };
+            let received = match correlation_id(&msg) {
+                Some(received) => received.to_string(),
+                None => {
+                    warn!(target: LOG_TARGET, "RequestReply: drops a reply without correlation id");
+                    continue;
+                },
+            };
+            if received == id {
+                self.pending.remove(id);
+                return Ok(msg);
+            }
+            if self.pending.contains(&received) {
+                self.arrived.insert(received, msg);
+            } else {
+                debug!(target: LOG_TARGET, "RequestReply: drops the reply to {}, not outstanding", received);
+            }
+        }
+    }
+
+    /// Forget the request `id`, its reply is dropped
+    pub fn cancel(&mut self, id: &str) {
+        self.pending.remove(id);
+        self.arrived.remove(id);
+    }
+
+    /// The number of requests waiting for `recv_reply`
+    pub fn outstanding(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{Agent, AgentPorts, PortList};
+    use ports::MsgSender;
+    use scheduler::{AgentCtx, BoxedComp, CompMsg, Scheduler, Signal};
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Sender};
+
+    type Replies = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// Replies to each request with its bytes
+    struct Echo {
+        input: MsgReceiver,
+        output: Option<MsgSender>,
+    }
+
+    impl Agent for Echo {
+        builtin_ports! {
+            inputs { input: None },
+            outputs { output: None },
+            array_inputs {},
+            array_outputs {}
+        }
+
+        fn run(&mut self) -> Result<Signal> {
+            let mut request = try!(self.input.recv());
+            let reply = bytes_msg(&try!(msg_bytes(&mut request)));
+            try!(self.output.send(reply_to(&request, reply)));
+            Ok(Signal::End)
+        }
+    }
+
+    fn echo(id: usize, sc: Sender<CompMsg>, ctx: AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sc, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        Ok((Box::new(Echo { input: input, output: None }) as BoxedComp, senders))
+    }
+
+    /// On an IP on `start`, sends the requests 1 and 2, and waits for the reply to 2 first
+    struct Requester {
+        start: MsgReceiver,
+        reply: MsgReceiver,
+        request: Option<MsgSender>,
+        requests: RequestReply,
+        replies: Replies,
+    }
+
+    impl Agent for Requester {
+        builtin_ports! {
+            inputs { start: None, reply: None },
+            outputs { request: None },
+            array_inputs {},
+            array_outputs {}
+        }
+
+        fn run(&mut self) -> Result<Signal> {
+            try!(self.start.recv());
+            let first = try!(self.requests.send_request(&self.request, bytes_msg(&[1])));
+            let second = try!(self.requests.send_request(&self.request, bytes_msg(&[2])));
+            assert_eq!(self.requests.outstanding(), 2);
+            for id in &[second, first] {
+                let mut reply = try!(self.requests.recv_reply(&self.reply, id, Duration::from_secs(5)));
+                let received = correlation_id(&reply).map(|id| { id.to_string() });
+                self.replies.lock().unwrap().push((received.unwrap_or_default(), try!(msg_bytes(&mut reply))));
+            }
+            assert_eq!(self.requests.outstanding(), 0);
+            Ok(Signal::End)
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_get_their_own_reply() {
+        let replies = Arc::new(Mutex::new(vec![]));
+        let collected = replies.clone();
+        let mut sched = Scheduler::new();
+        sched.add_agent_boxed("echo", echo).unwrap();
+        sched.add_agent_boxed("requester", move |id, sc, ctx| {
+            let (start, start_sender) = MsgReceiver::new(id, "start", sc.clone(), ctx.clone(), true);
+            let (reply, reply_sender) = MsgReceiver::new(id, "reply", sc, ctx, true);
+            let mut senders = HashMap::new();
+            senders.insert("start".to_string(), start_sender);
+            senders.insert("reply".to_string(), reply_sender);
+            let requester = Requester {
+                start: start,
+                reply: reply,
+                request: None,
+                requests: RequestReply::new(),
+                replies: collected,
+            };
+            Ok((Box::new(requester) as BoxedComp, senders))
+        }).unwrap();
+        sched.connect("requester", "request", "echo", "input").unwrap();
+        sched.connect("echo", "output", "requester", "reply").unwrap();
+        sched.mark_feedback("echo", "output").unwrap();
+        sched.start().unwrap();
+        sched.inject("requester", "start", bytes_msg(&[0])).unwrap();
+        assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+        assert_eq!(*replies.lock().unwrap(), vec![("1".to_string(), vec![2]), ("0".to_string(), vec![1])]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn timed_out_request_stays_outstanding() {
+        let (sched, _) = channel();
+        let (requests_in, request) = MsgReceiver::new(0, "request", sched.clone(), AgentCtx::new(), false);
+        let (reply, reply_sender) = MsgReceiver::new(0, "reply", sched, AgentCtx::new(), false);
+        let mut requests = RequestReply::new();
+        let id = requests.send_request(&request, bytes_msg(&[1])).unwrap();
+        match requests.recv_reply(&reply, &id, Duration::from_millis(50)) {
+            Err(result::Error::Timeout(_)) => {},
+            other => panic!("a reply without responder is {:?}", other.map(|_| { () })),
+        }
+        assert_eq!(requests.outstanding(), 1);
+        let received = requests_in.recv().unwrap();
+        reply_sender.send(reply_to(&received, bytes_msg(&[2]))).unwrap();
+        let mut answer = requests.recv_reply(&reply, &id, Duration::from_secs(5)).unwrap();
+        assert_eq!(msg_bytes(&mut answer).unwrap(), vec![2]);
+        assert_eq!(requests.outstanding(), 0);
+    }
+}
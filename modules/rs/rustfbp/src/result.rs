@@ -10,6 +10,7 @@ use std::sync::mpsc;
 
 use ports::Msg;
 use scheduler::CompMsg;
+use typed;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -33,6 +34,30 @@ pub enum Error {
     ElementNotFound(String, String, String),
     CannotRemove(String),
     BadMessageInfo,
+    Stopping,
+    ShutdownTimeout(Vec<String>),
+    AgentPanicked(String),
+    Cycle(Vec<String>),
+    Deadlock(Vec<String>),
+    StartFailed(String),
+    SchemaMismatch(u64, u64),
+    PortClosed(String),
+    InvalidGraph(String, Vec<String>),
+    BadRecording(String),
+    BadDecimal(String),
+    DecimalOverflow(String),
+    DecimalTooPrecise(String),
+    AlreadyConnected(String, String),
+    MessageTooLarge(String, u64),
+    TypeMismatch(u64, u64),
+    BadTopology(String),
+    IntegrityMismatch(u32, u32),
+    Timeout(String),
+    SequenceGap(u64, u64),
+    FrameTooLarge(u64, u64),
+    BadDate(String),
+    BadJson(String),
+    BadMsgpack(String),
 }
 
 impl fmt::Display for Error {
@@ -56,6 +81,33 @@ impl fmt::Display for Error {
             Error::ElementNotFound(ref c, ref p, ref s) => write!(f, "agent error : Element {} on port {} of agent {} is not found", s, p, c),
             Error::CannotRemove(ref c) => write!(f, "Scheduler error : Cannot remove agent {}", c),
             Error::BadMessageInfo => write!(f, "Ports error : Bad message information"),
+            Error::Stopping => write!(f, "Ports error : the scheduler is stopping"),
+            Error::ShutdownTimeout(ref c) => write!(f, "Scheduler error : agents {} did not stop in time", c.join(", ")),
+            Error::AgentPanicked(ref p) => write!(f, "agent error : panicked with '{}'", p),
+            Error::Cycle(ref c) => write!(f, "Scheduler error : cycle between the agents {}", c.join(" -> ")),
+            Error::Deadlock(ref c) => write!(f, "Scheduler error : suspected deadlock, {}", c.join(", ")),
+            Error::StartFailed(ref e) => write!(f, "agent error : on_start failed, {}", e),
+            Error::SchemaMismatch(ref expected, ref found) => {
+                let name = |id: u64| { typed::describe_schema(id).unwrap_or("unknown") };
+                write!(f, "Msg error : expected the schema {:x} ({}), got {:x} ({})", expected, name(*expected), found, name(*found))
+            },
+            Error::PortClosed(ref p) => write!(f, "Ports error : port {} is closed", p),
+            Error::BadRecording(ref e) => write!(f, "Recorder error : {}", e),
+            Error::BadDecimal(ref s) => write!(f, "Decimal error : '{}' is not a decimal", s),
+            Error::DecimalOverflow(ref s) => write!(f, "Decimal error : the mantissa of '{}' overflows a i64", s),
+            Error::DecimalTooPrecise(ref s) => write!(f, "Decimal error : '{}' has more than 127 fractional digits", s),
+            Error::AlreadyConnected(ref c, ref p) => write!(f, "Scheduler error : Port {} of agent {} is already connected", p, c),
+            Error::MessageTooLarge(ref p, ref w) => write!(f, "Ports error : a Msg of {} words on the port {} is over the size limit", w, p),
+            Error::TypeMismatch(ref expected, ref found) => write!(f, "Scheduler error : the input port expects the schema {:x}, the output port sends {:x}", expected, found),
+            Error::BadTopology(ref e) => write!(f, "Topology error : {}", e),
+            Error::IntegrityMismatch(ref e, ref r) => write!(f, "Transport error : the Msg is corrupted, checksum {:08x} expected, {:08x} received", e, r),
+            Error::Timeout(ref e) => write!(f, "Timeout error : {}", e),
+            Error::FrameTooLarge(ref len, ref max) => write!(f, "Transport error : a frame of {} bytes is over the limit of {} bytes", len, max),
+            Error::SequenceGap(ref missing, ref next) => write!(f, "Ports error : the Msgs {} to {} did not arrive in time", missing, next - 1),
+            Error::InvalidGraph(ref g, ref e) => write!(f, "Scheduler error : the graph {} is invalid\n  {}", g, e.join("\n  ")),
+            Error::BadDate(ref s) => write!(f, "Date error : '{}' is not a YYYY-MM-DD date", s),
+            Error::BadJson(ref e) => write!(f, "JSON error : {}", e),
+            Error::BadMsgpack(ref e) => write!(f, "MessagePack error : {}", e),
         }
     }
 }
@@ -81,6 +133,30 @@ impl error::Error for Error {
             Error::ElementNotFound(..) => "Element not found",
             Error::CannotRemove(..) => "Cannot remove agent",
             Error::BadMessageInfo => "Ports error : cannot receive the message, wrong bit information",
+            Error::Stopping => "The scheduler is stopping",
+            Error::ShutdownTimeout(..) => "Agents did not stop in time",
+            Error::AgentPanicked(..) => "Agent panicked",
+            Error::Cycle(..) => "Cycle in the graph",
+            Error::Deadlock(..) => "Suspected deadlock",
+            Error::StartFailed(..) => "Agent cannot start",
+            Error::SchemaMismatch(..) => "Msg of another schema",
+            Error::PortClosed(..) => "Port closed",
+            Error::InvalidGraph(..) => "Invalid graph",
+            Error::BadRecording(..) => "Bad recording",
+            Error::BadDecimal(..) => "Not a decimal",
+            Error::DecimalOverflow(..) => "Decimal overflow",
+            Error::DecimalTooPrecise(..) => "Too many fractional digits",
+            Error::AlreadyConnected(..) => "Output port already connected",
+            Error::MessageTooLarge(..) => "Msg too large",
+            Error::TypeMismatch(..) => "Schema mismatch between two ports",
+            Error::BadTopology(..) => "Bad topology",
+            Error::IntegrityMismatch(..) => "Corrupted Msg",
+            Error::Timeout(..) => "Timed out",
+            Error::FrameTooLarge(..) => "Frame too large",
+            Error::SequenceGap(..) => "Gap in the sequence of the Msgs",
+            Error::BadDate(..) => "Not a date",
+            Error::BadJson(..) => "Bad JSON",
+            Error::BadMsgpack(..) => "Bad MessagePack",
         }
     }
 
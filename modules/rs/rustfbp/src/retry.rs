@@ -0,0 +1,204 @@
+//! Retry a fallible operation with a backoff, in the `run` of an agent
+//!
+//! An agent calling an external service can retry the transient errors without blocking
+//! the shutdown: the waits between the attempts end as soon as the scheduler is stopping.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! fn run(&mut self) -> Result<Signal> {
+//!     let msg = try!(self.input.input.recv());
+//!     let policy = RetryPolicy::new(5, Duration::from_millis(100));
+//!     let answer = try!(retry::with_backoff(&self.ctx, &policy, || { self.client.query(&msg) }));
+//!     try!(self.output.output.send(answer));
+//!     Ok(End)
+//! }
+//! ```
+
+use result;
+use result::Result;
+use random::random_u64;
+use scheduler::AgentCtx;
+
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOG_TARGET: &'static str = "fractalide::retry";
+
+/// How often a waiting retry checks if the scheduler is stopping, in milliseconds
+const RETRY_POLL_MS: u64 = 50;
+
+/// How the delay grows between two attempts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    /// Wait `base_delay` after each failure
+    Constant,
+    /// Wait `base_delay`, then twice as long after each failure
+    Exponential,
+}
+
+/// When and how long `with_backoff` retries
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The number of calls, the first one included. 0 is taken as 1
+    pub max_attempts: usize,
+    /// The delay after the first failure
+    pub base_delay: Duration,
+    /// The longest delay, the exponential backoff stops growing there
+    pub max_delay: Duration,
+    pub backoff: Backoff,
+    /// Wait a random delay between the half and the whole of the computed delay, so the agents
+    /// failing together don't retry together
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// An exponential backoff without jitter, up to 30 seconds between two attempts
+    pub fn new(max_attempts: usize, base_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts,
+            base_delay: base_delay,
+            max_delay: Duration::from_secs(30),
+            backoff: Backoff::Exponential,
+            jitter: false,
+        }
+    }
+
+    /// The delay after the failure of the attempt `attempt`, from 1
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Constant => self.base_delay,
+            Backoff::Exponential => {
+                let factor = 1u32 << cmp::min(attempt.saturating_sub(1), 31);
+                self.base_delay.checked_mul(factor).unwrap_or(self.max_delay)
+            }
+        };
+        let delay = cmp::min(delay, self.max_delay);
+        if self.jitter {
+            let nanos = delay.as_secs().saturating_mul(1_000_000_000).saturating_add(delay.subsec_nanos() as u64);
+            let half = nanos / 2;
+            let random = random_u64() % (nanos - half + 1);
+            let jittered = half + random;
+            Duration::new(jittered / 1_000_000_000, (jittered % 1_000_000_000) as u32)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Call `f` until it succeeds, at most `policy.max_attempts` times, and return its result
+///
+/// After the last attempt, the last error is returned. An `Error::Stopping` from `f` is
+/// returned at once. While waiting, `Error::Stopping` is returned as soon as the scheduler of
+/// `ctx` is stopping, this is why the context of the agent is needed.
+pub fn with_backoff<T, F>(ctx: &AgentCtx, policy: &RetryPolicy, mut f: F) -> Result<T> where
+    F: FnMut() -> Result<T>
+{
+    let attempts = cmp::max(policy.max_attempts, 1);
+    let mut attempt = 1;
+    loop {
+        let err = match f() {
+            Ok(value) => { return Ok(value); },
+            Err(result::Error::Stopping) => { return Err(result::Error::Stopping); },
+            Err(err) => err,
+        };
+        if attempt >= attempts {
+            return Err(err);
+        }
+        let delay = policy.delay(attempt);
+        debug!(target: LOG_TARGET, "attempt {} of {} fails : {}, retry in {:?}", attempt, attempts, err, delay);
+        try!(wait(ctx, delay));
+        attempt += 1;
+    }
+}
+
+/// Sleep for `delay`, or until the scheduler is stopping
+fn wait(ctx: &AgentCtx, delay: Duration) -> Result<()> {
+    let deadline = Instant::now() + delay;
+    loop {
+        if ctx.is_stopping() {
+            return Err(result::Error::Stopping);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        thread::sleep(cmp::min(deadline - now, Duration::from_millis(RETRY_POLL_MS)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builtin::VecSink;
+    use scheduler::Scheduler;
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn delay_grows_up_to_the_max_delay() {
+        let mut policy = RetryPolicy::new(5, Duration::from_millis(100));
+        policy.max_delay = Duration::from_millis(350);
+        let delays: Vec<Duration> = (1..5).map(|attempt| { policy.delay(attempt) }).collect();
+        assert_eq!(delays, vec![Duration::from_millis(100), Duration::from_millis(200),
+                                Duration::from_millis(350), Duration::from_millis(350)]);
+        policy.backoff = Backoff::Constant;
+        assert_eq!(policy.delay(4), Duration::from_millis(100));
+        policy.jitter = true;
+        for attempt in 1..20 {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn with_backoff_retries_until_a_success() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(1));
+        let mut calls = 0;
+        let res = with_backoff(&AgentCtx::new(), &policy, || {
+            calls += 1;
+            if calls <= 2 {
+                Err(result::Error::Misc("unavailable".into()))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        let mut calls = 0;
+        let res: Result<()> = with_backoff(&AgentCtx::new(), &policy, || {
+            calls += 1;
+            Err(result::Error::Misc(format!("attempt {}", calls)))
+        });
+        match res {
+            Err(result::Error::Misc(ref e)) if e == "attempt 5" => {},
+            _ => panic!("the last error is not returned"),
+        }
+    }
+
+    #[test]
+    fn with_backoff_stops_waiting_on_a_shutdown() {
+        let mut sched = Scheduler::new();
+        let (ctx_s, ctx_r) = channel();
+        sched.add_agent_boxed("sink", move |id, sc, ctx| {
+            ctx_s.send(ctx.clone()).unwrap();
+            VecSink::create(id, sc, ctx, Arc::new(Mutex::new(vec![])))
+        }).unwrap();
+        let ctx = ctx_r.recv().unwrap();
+        sched.start().unwrap();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            sched.shutdown(Duration::from_secs(5)).unwrap();
+        });
+        let policy = RetryPolicy::new(5, Duration::from_secs(10));
+        let started = Instant::now();
+        let res: Result<()> = with_backoff(&ctx, &policy, || { Err(result::Error::Misc("unavailable".into())) });
+        match res {
+            Err(result::Error::Stopping) => {},
+            _ => panic!("the retry doesn't see the shutdown"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        stopper.join().unwrap();
+    }
+}
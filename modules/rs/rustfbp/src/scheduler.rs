@@ -6,39 +6,82 @@
 //!
 //! The interior scheduler is the actual state of the scheduler. It is edited by sending messages.
 //! The messages are send by the exterior scheduler and the agents of the Graph.
+//!
+//! The diagnostics go through the `log` facade, with the targets `fractalide::scheduler`,
+//! `fractalide::ports` and `fractalide::transport`. The Msgs delivered are traced only while
+//! the metrics are enabled.
 
 
 extern crate libloading;
 extern crate threadpool;
+#[cfg(feature = "affinity")]
+extern crate core_affinity;
 
 use self::threadpool::ThreadPool;
 
 use result;
 use result::Result;
 
-use ports::{MsgSender, MsgReceiver, Msg};
+use ports::{BreakerConfig, MsgSender, MsgReceiver, Msg, PortState};
 use agent::Agent;
+use memory;
+use memory::MemoryCounters;
+use signal;
+use trace;
+use record::Recorder;
+use topology::{AgentDesc, Topology};
 
 use std::borrow::Cow;
+use std::path::Path;
 
-use std::collections::HashMap;
-use std::sync::mpsc::{Sender, Receiver};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver, SendError};
 use std::sync::mpsc::channel;
 
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use std::cmp;
+use std::fmt;
 use std::mem;
+use std::panic;
+use std::any::Any;
 
 
 /// A boxed comp is a agent that can be send between thread
 pub type BoxedComp = Box<Agent + Send>;
-// TODO : manage "can_run": allow a user to pause a agent
+
+/// The target of the log records of the scheduler
+const LOG_TARGET: &'static str = "fractalide::scheduler";
+
+/// The sort of the agents added with `add_agent_boxed`, they have no dylib
+pub const BOXED_SORT: &'static str = "boxed";
+
+/// The time between two checks of `run_until_idle`
+const IDLE_POLL_MS: u64 = 20;
+
+/// The time given to the agents to stop, once the graph is idle
+const IDLE_SHUTDOWN_SECS: u64 = 10;
+
+/// The smallest stack accepted by `set_stack_size`, in bytes
+pub const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// The time between two checks of the signals, see `install_signal_handler`
+const SIGNAL_POLL_MS: u64 = 50;
+
+/// The number of threads of the pool shared by the agents
+const POOL_THREADS: usize = 8;
+
+/// The aging rate of a new scheduler, in priority levels per second of wait
+pub const DEFAULT_AGING_RATE: f64 = 10.0;
 
 /// All the messages that can be send between the "exterior scheduler" and the "interior scheduler".
 pub enum CompMsg {
     /// Add a new agent. The String is the name, the BoxedComp is the agent itself
-    NewAgent(usize, String, BoxedComp),
+    NewAgent(usize, String, BoxedComp, AgentCtx),
     /// Stop the scheduler
     Halt,
     /// Try to stop the sheduler state
@@ -62,13 +105,49 @@ pub enum CompMsg {
     /// Signal the end of an execution
     RunEnd(usize, BoxedComp, Result<Signal>),
     /// Set the receiver of an input port
-    SetReceiver(usize, String, Receiver<Msg>),
+    SetReceiver(usize, String, MsgReceiver),
     /// The agent received an Msg
     Inc(usize),
     /// The agent read an Msg
     Dec(usize),
+    /// The agent received several Msgs
+    IncBatch(usize, usize),
+    /// The agent read several Msgs
+    DecBatch(usize, usize),
     /// Remove a agent
     Remove(usize, Sender<SyncMsg>),
+    /// Stop scheduling agents and halt once the running ones are done
+    Shutdown,
+    /// Ask the names of the agents that are not yet stopped
+    Running(Sender<Vec<String>>),
+    /// Ask if no Msg is in flight and all the running agents are blocked on an empty port
+    Idle(Sender<bool>),
+    /// Ask what each agent is doing, with the port it is blocked on
+    Status(Sender<Vec<(usize, AgentStatus, Option<Arc<PortState>>)>>),
+    /// Set what to do when the agent panics
+    SetRestartPolicy(usize, RestartPolicy),
+    /// Run the agent in its own thread, pinned to the CPUs if any
+    OwnThread(usize, Option<Vec<usize>>),
+    /// Run the agent in its own thread, with a stack of the size in bytes
+    StackSize(usize, usize),
+    /// Set the base priority of the agent in the pool
+    BasePriority(usize, i32),
+    /// Set the priority levels gained per second by the agents waiting for the pool
+    AgingRate(f64),
+    /// Check if the running agents are all blocked since the Duration
+    CheckDeadlock(Duration),
+    /// Stop running the agent
+    Pause(usize),
+    /// Run again a paused agent
+    Resume(usize),
+    /// Replace the agent by the given one, once it is not running. The Sender is signaled after the replacement
+    Reload(usize, BoxedComp, Sender<()>),
+    /// Save the state of the agent between two runs, and send it with the name of the agent
+    Checkpoint(usize, Sender<(String, Option<Vec<u8>>)>),
+    /// Load a saved state in the agent, after `on_start`
+    Restore(usize, Vec<u8>),
+    /// Set the config given to the agent before `on_start`
+    Configure(usize, Vec<u8>),
 }
 
 pub enum Signal {
@@ -76,6 +155,466 @@ pub enum Signal {
     Continue,
 }
 
+/// What `connect` does when the output port is already connected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FanoutPolicy {
+    /// Replace the connection, an output port has a single destination
+    ///
+    /// An element of an array output port is not replaced, `connect_array` returns
+    /// `Error::AlreadyConnected`: disconnect it first, or choose another element name.
+    Replace,
+    /// Add a destination, each Msg is copied to all the destinations
+    Broadcast,
+    /// Return `Error::AlreadyConnected`
+    Error,
+}
+
+/// What the scheduler does when the `run` method of an agent panics
+///
+/// On restart, the agent is reset with `Agent::reset` but keeps its connected ports.
+/// Otherwise the agent is dropped, so its senders return an error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartPolicy {
+    /// Drop the agent
+    Never,
+    /// Always restart the agent
+    Always,
+    /// Restart the agent at most n times
+    MaxRetries(usize),
+}
+
+/// The state shared between the scheduler and an agent with its ports
+///
+/// It is given to each agent at creation, and the ports of the agent keep a clone.
+#[derive(Clone)]
+pub struct AgentCtx {
+    stopping: Arc<AtomicBool>,
+    metrics_on: Arc<AtomicBool>,
+    latency_on: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    wakeup: Arc<(Mutex<usize>, Condvar)>,
+    paused: Arc<AtomicBool>,
+    blocked: Arc<Mutex<Option<Blocked>>>,
+    recorder: Arc<Mutex<Option<Arc<Recorder>>>>,
+}
+
+impl AgentCtx {
+    /// Create a new context, not linked to any scheduler
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let ctx = AgentCtx::new();
+    /// assert!(!ctx.is_stopping());
+    /// ```
+    pub fn new() -> Self {
+        AgentCtx {
+            stopping: Arc::new(AtomicBool::new(false)),
+            metrics_on: Arc::new(AtomicBool::new(false)),
+            latency_on: Arc::new(AtomicBool::new(false)),
+            counters: Arc::new(Counters::new()),
+            wakeup: Arc::new((Mutex::new(0), Condvar::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            blocked: Arc::new(Mutex::new(None)),
+            recorder: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Return true once the scheduler is shutting down
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Return true while the agent is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Record that the agent waits on a port, or `None` once it doesn't wait anymore
+    ///
+    /// The time of each wait is added to the blocked time of the metrics.
+    pub fn set_blocked(&self, blocked: Option<Blocked>) {
+        if let Ok(mut b) = self.blocked.lock() {
+            if let Some(ref previous) = *b {
+                if self.metrics_enabled() {
+                    self.counters.add(&self.counters.blocked, previous.since.elapsed());
+                }
+            }
+            *b = blocked;
+        }
+    }
+
+    /// Get the port on which the agent waits
+    pub fn blocked(&self) -> Option<Blocked> {
+        self.blocked.lock().ok().and_then(|b| { b.clone() })
+    }
+
+    /// Return true if the Msgs are stamped when first sent
+    pub fn tracks_latency(&self) -> bool {
+        self.latency_on.load(Ordering::Relaxed)
+    }
+
+    /// Count `n` Msgs received by the agent, if the metrics are enabled
+    pub fn on_received(&self, n: usize) {
+        if self.metrics_on.load(Ordering::Relaxed) {
+            self.counters.received.fetch_add(n, Ordering::Relaxed);
+            self.counters.touch();
+        }
+    }
+
+    /// Return true while the metrics are enabled
+    pub fn metrics_enabled(&self) -> bool {
+        self.metrics_on.load(Ordering::Relaxed)
+    }
+
+    /// Count `n` Msgs sent by the agent, if the metrics are enabled
+    pub fn on_sent(&self, n: usize) {
+        if self.metrics_on.load(Ordering::Relaxed) {
+            self.counters.sent.fetch_add(n, Ordering::Relaxed);
+            self.counters.touch();
+        }
+    }
+
+    /// Add the time of a run of the agent, if the metrics are enabled
+    fn on_run(&self, time: Duration) {
+        if self.metrics_enabled() {
+            self.counters.add(&self.counters.run, time);
+        }
+    }
+
+    /// The number of panics of the agent, counted even without the metrics
+    pub fn panics(&self) -> usize {
+        self.counters.panics.load(Ordering::SeqCst)
+    }
+
+    fn on_panic(&self) {
+        self.counters.panics.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Count the memory allocated on the thread for the agent, until the guard is dropped
+    fn track_memory(&self) -> memory::Tracked {
+        memory::Tracked::new(&self.counters.memory)
+    }
+
+    /// Record the Msgs received by the agent, or stop recording with `None`
+    pub fn set_recorder(&self, recorder: Option<Arc<Recorder>>) {
+        if let Ok(mut r) = self.recorder.lock() {
+            *r = recorder;
+        }
+    }
+
+    /// Give a received Msg to the recorder, if the agent is recorded
+    pub fn on_msg_received(&self, port: &str, msg: &Msg) {
+        trace::on_recv(msg);
+        let recorder = self.recorder.lock().ok().and_then(|r| { r.clone() });
+        if let Some(recorder) = recorder {
+            if let Err(e) = recorder.record(port, msg) {
+                error!(target: LOG_TARGET, "Recorder: cannot record the Msg of the port {} : {}", port, e);
+            }
+        }
+    }
+
+    /// Wake up the agent waiting in `wait_notify`, an Msg was sent to it
+    pub fn notify(&self) {
+        let &(ref lock, ref cvar) = &*self.wakeup;
+        if let Ok(mut gen) = lock.lock() {
+            *gen = gen.wrapping_add(1);
+            cvar.notify_all();
+        }
+    }
+
+    /// The number of notifications received, to give to `wait_notify`
+    pub fn generation(&self) -> usize {
+        let &(ref lock, _) = &*self.wakeup;
+        lock.lock().map(|gen| *gen).unwrap_or(0)
+    }
+
+    /// Block until a notification arrives after `generation`, or `timeout` elapsed
+    pub fn wait_notify(&self, generation: usize, timeout: Duration) {
+        let &(ref lock, ref cvar) = &*self.wakeup;
+        if let Ok(gen) = lock.lock() {
+            if *gen == generation {
+                let _ = cvar.wait_timeout(gen, timeout);
+            }
+        }
+    }
+
+    /// A context for a new agent: the scheduler wide state is shared, the counters are fresh
+    fn for_agent(&self) -> AgentCtx {
+        AgentCtx {
+            stopping: self.stopping.clone(),
+            metrics_on: self.metrics_on.clone(),
+            latency_on: self.latency_on.clone(),
+            counters: Arc::new(Counters::new()),
+            wakeup: Arc::new((Mutex::new(0), Condvar::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            blocked: Arc::new(Mutex::new(None)),
+            recorder: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn metrics(&self) -> AgentMetrics {
+        let run = *self.counters.run.lock().expect("metrics: poisoned lock");
+        let blocked = *self.counters.blocked.lock().expect("metrics: poisoned lock");
+        let allocated = self.counters.memory.allocated.load(Ordering::Relaxed) as u64;
+        let freed = self.counters.memory.freed.load(Ordering::Relaxed) as u64;
+        AgentMetrics {
+            received: self.counters.received.load(Ordering::Relaxed) as u64,
+            sent: self.counters.sent.load(Ordering::Relaxed) as u64,
+            last_active: *self.counters.last_active.lock().expect("metrics: poisoned lock"),
+            running: run.checked_sub(blocked).unwrap_or(Duration::new(0, 0)),
+            blocked: blocked,
+            bytes_allocated: allocated,
+            bytes_freed: freed,
+            live_bytes: allocated as i64 - freed as i64,
+            panics: self.panics() as u64,
+        }
+    }
+}
+
+/// A port on which an agent waits
+#[derive(Clone)]
+pub struct Blocked {
+    /// The input port, full if the agent is sending, empty if the agent is receiving
+    pub port: Arc<PortState>,
+    /// True if the agent waits to send, false if it waits to receive
+    pub sending: bool,
+    /// When the agent started to wait
+    pub since: Instant,
+}
+
+impl Blocked {
+    /// The agent starts waiting now on `port`
+    pub fn new(port: Arc<PortState>, sending: bool) -> Self {
+        Blocked {
+            port: port,
+            sending: sending,
+            since: Instant::now(),
+        }
+    }
+}
+
+struct Counters {
+    received: AtomicUsize,
+    sent: AtomicUsize,
+    last_active: Mutex<Option<Instant>>,
+    /// The time spent in `run`, blocked included
+    run: Mutex<Duration>,
+    blocked: Mutex<Duration>,
+    memory: MemoryCounters,
+    panics: AtomicUsize,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Counters {
+            received: AtomicUsize::new(0),
+            sent: AtomicUsize::new(0),
+            last_active: Mutex::new(None),
+            run: Mutex::new(Duration::new(0, 0)),
+            blocked: Mutex::new(Duration::new(0, 0)),
+            memory: MemoryCounters::new(),
+            panics: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, total: &Mutex<Duration>, time: Duration) {
+        if let Ok(mut total) = total.lock() {
+            *total += time;
+        }
+    }
+
+    fn touch(&self) {
+        if let Ok(mut last) = self.last_active.lock() {
+            *last = Some(Instant::now());
+        }
+    }
+
+    fn reset(&self) {
+        self.received.store(0, Ordering::Relaxed);
+        self.sent.store(0, Ordering::Relaxed);
+        self.memory.allocated.store(0, Ordering::Relaxed);
+        self.memory.freed.store(0, Ordering::Relaxed);
+        self.panics.store(0, Ordering::SeqCst);
+        for total in &[&self.run, &self.blocked] {
+            if let Ok(mut total) = total.lock() {
+                *total = Duration::new(0, 0);
+            }
+        }
+        if let Ok(mut last) = self.last_active.lock() {
+            *last = None;
+        }
+    }
+}
+
+/// A snapshot of the Msgs that went through an agent
+///
+/// Only counted while the metrics are enabled with `Scheduler::enable_metrics`.
+#[derive(Clone, Debug)]
+pub struct AgentMetrics {
+    /// Number of Msgs read on the input ports
+    pub received: u64,
+    /// Number of Msgs sent on the output ports
+    pub sent: u64,
+    /// The last time the agent received or sent an Msg
+    pub last_active: Option<Instant>,
+    /// The time spent in `run`, without the time blocked on a port
+    pub running: Duration,
+    /// The time spent waiting on a full or an empty port
+    ///
+    /// Approximate: a receive is counted as blocked after the first poll of the port.
+    pub blocked: Duration,
+    /// The bytes allocated in `run`, see `Scheduler::enable_memory_tracking`
+    pub bytes_allocated: u64,
+    /// The bytes freed in `run`
+    pub bytes_freed: u64,
+    /// The allocated bytes minus the freed bytes, negative if the agent frees the memory of others
+    pub live_bytes: i64,
+    /// The number of panics in `run`, counted even while the metrics are disabled
+    pub panics: u64,
+}
+
+impl AgentMetrics {
+    /// The counts since `earlier`, an older snapshot of the same agent
+    ///
+    /// A count lower than in `earlier` was reset in between, it is then kept as is.
+    pub fn since(&self, earlier: &AgentMetrics) -> AgentMetrics {
+        fn count(now: u64, earlier: u64) -> u64 {
+            if now >= earlier { now - earlier } else { now }
+        }
+        fn time(now: Duration, earlier: Duration) -> Duration {
+            now.checked_sub(earlier).unwrap_or(now)
+        }
+        let bytes_allocated = count(self.bytes_allocated, earlier.bytes_allocated);
+        let bytes_freed = count(self.bytes_freed, earlier.bytes_freed);
+        AgentMetrics {
+            received: count(self.received, earlier.received),
+            sent: count(self.sent, earlier.sent),
+            last_active: self.last_active,
+            running: time(self.running, earlier.running),
+            blocked: time(self.blocked, earlier.blocked),
+            bytes_allocated: bytes_allocated,
+            bytes_freed: bytes_freed,
+            live_bytes: bytes_allocated as i64 - bytes_freed as i64,
+            panics: count(self.panics, earlier.panics),
+        }
+    }
+}
+
+/// The metrics of an agent at the end of `Scheduler::run_until_idle`
+#[derive(Clone, Debug)]
+pub struct AgentReport {
+    pub name: String,
+    pub received: u64,
+    pub sent: u64,
+    pub running: Duration,
+    pub blocked: Duration,
+}
+
+/// The summary of `Scheduler::run_until_idle`
+///
+/// The agents are sorted by name. `total_ips` is the number of Msgs received by the agents: a
+/// Msg going through three agents is counted three times, the IIPs and the Msgs sent from
+/// outside the graph are counted once. The counts and the times are only kept while the
+/// metrics are enabled, see `Scheduler::enable_metrics`.
+#[derive(Clone, Debug)]
+pub struct RunReport {
+    pub per_agent: Vec<AgentReport>,
+    pub total_ips: u64,
+    /// The time since `Scheduler::start`
+    pub wall_time: Duration,
+}
+
+/// What an agent is doing, in a `NetworkState`
+#[derive(Clone, Debug, PartialEq)]
+pub enum AgentStatus {
+    /// Not running, no Msg is waiting for it
+    Idle,
+    /// Waiting for a thread of the pool
+    Ready,
+    /// In `run`
+    Running,
+    /// In `run`, waiting to send on a full port or to receive on an empty one
+    ///
+    /// The port is `agent.port`, or only the name of the port if its agent is not found.
+    Blocked { port: String, sending: bool, waited: Duration },
+    /// Paused with `Scheduler::pause`, it may end its current run
+    Paused,
+    /// Panicked without restart, or failed to start
+    Dead,
+}
+
+impl fmt::Display for AgentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AgentStatus::Idle => write!(f, "idle"),
+            AgentStatus::Ready => write!(f, "waiting for a thread"),
+            AgentStatus::Running => write!(f, "running"),
+            AgentStatus::Blocked { ref port, sending, waited } => {
+                let action = if sending { "sending to" } else { "receiving on" };
+                write!(f, "blocked {} {} for {:?}", action, port, waited)
+            },
+            AgentStatus::Paused => write!(f, "paused"),
+            AgentStatus::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+/// The state of an agent, in a `NetworkState`
+#[derive(Clone, Debug)]
+pub struct AgentState {
+    pub name: String,
+    pub sort: String,
+    pub status: AgentStatus,
+    /// The approximate number of Msgs waiting in each input port, as `Scheduler::queue_depths`
+    pub depths: Vec<(String, usize)>,
+    pub metrics: AgentMetrics,
+}
+
+/// A snapshot of a running graph, see `Scheduler::dump_state`
+///
+/// The agents are sorted by name. It is displayed as a report, one agent per paragraph then
+/// the edges.
+#[derive(Clone, Debug)]
+pub struct NetworkState {
+    pub agents: Vec<AgentState>,
+    pub edges: Vec<Edge>,
+    pub stopping: bool,
+}
+
+impl fmt::Display for NetworkState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.stopping {
+            try!(writeln!(f, "the scheduler is stopping"));
+        }
+        for agent in &self.agents {
+            try!(writeln!(f, "{} ({}) : {}", agent.name, agent.sort, agent.status));
+            for &(ref port, depth) in &agent.depths {
+                try!(writeln!(f, "    {} : {} Msgs waiting", port, depth));
+            }
+            let m = &agent.metrics;
+            try!(writeln!(f, "    received {}, sent {}, running {:?}, blocked {:?}, panics {}",
+                          m.received, m.sent, m.running, m.blocked, m.panics));
+        }
+        try!(writeln!(f, "edges :"));
+        for edge in &self.edges {
+            try!(writeln!(f, "    {}.{} -> {}.{}{}", edge.out_agent, Edge::label(&edge.out_port, &edge.out_element),
+                          edge.in_agent, Edge::label(&edge.in_port, &edge.in_element),
+                          if edge.feedback { " (feedback)" } else { "" }));
+        }
+        Ok(())
+    }
+}
+
 /// This structure keep all the information for the "exterior scheduler".
 ///
 /// These information must be accessible for the user of the scheduler
@@ -89,6 +628,64 @@ pub struct Comp {
     pub sort: String,
     /// True if a agent had no input port
     pub start: bool,
+    /// The context given to the agent and its ports
+    pub ctx: AgentCtx,
+}
+
+/// A connection between an output port and an input port
+///
+/// The elements are set for the array ports.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Edge {
+    pub out_agent: String,
+    pub out_port: String,
+    pub out_element: Option<String>,
+    pub in_agent: String,
+    pub in_port: String,
+    pub in_element: Option<String>,
+    /// A feedback edge is allowed to close a cycle
+    pub feedback: bool,
+}
+
+impl Edge {
+    fn label(port: &str, element: &Option<String>) -> String {
+        match *element {
+            Some(ref e) => format!("{}[{}]", port, e),
+            None => port.into(),
+        }
+    }
+}
+
+/// A change of the graph, given to the subscribers of `Scheduler::subscribe`
+///
+/// The ports of the edges are `port[element]` for the elements of array ports.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EdgeEvent {
+    AgentAdded(String),
+    AgentRemoved(String),
+    EdgeConnected { src: String, src_port: String, dst: String, dst_port: String },
+    EdgeDisconnected { src: String, src_port: String, dst: String, dst_port: String },
+}
+
+impl EdgeEvent {
+    fn connected(edge: &Edge) -> Self {
+        EdgeEvent::EdgeConnected {
+            src: edge.out_agent.clone(),
+            src_port: Edge::label(&edge.out_port, &edge.out_element),
+            dst: edge.in_agent.clone(),
+            dst_port: Edge::label(&edge.in_port, &edge.in_element),
+        }
+    }
+
+    fn disconnected(edge: &Edge) -> Self {
+        EdgeEvent::EdgeDisconnected {
+            src: edge.out_agent.clone(),
+            src_port: Edge::label(&edge.out_port, &edge.out_element),
+            dst: edge.in_agent.clone(),
+            dst_port: Edge::label(&edge.in_port, &edge.in_element),
+        }
+    }
 }
 
 /// the exterior scheduler. The end user use the methods of this structure.
@@ -97,12 +694,29 @@ pub struct Scheduler {
     pub cache: AgentCache,
     /// Keep the agent
     pub agents: HashMap<String, Comp>,
+    /// Keep the connections between the agents
+    pub edges: Vec<Edge>,
     /// A sender to send message to the scheduler
     pub sender: Sender<CompMsg>,
     /// Received the error from the "interior scheduler"
     pub error_receiver: Receiver<result::Error>,
+    ctx: AgentCtx,
+    allow_cycles: bool,
+    fanout: FanoutPolicy,
+    subscribers: Vec<Box<Fn(EdgeEvent) + Send>>,
+    iips: Vec<(String, String, Option<String>, Msg)>,
+    /// The metrics at the last `metrics_delta`
+    sampled: HashMap<String, AgentMetrics>,
+    /// When `start` was called
+    started: Option<Instant>,
+    /// Changed by each `shutdown_after` and `cancel_scheduled_shutdown`, to cancel the timers before
+    shutdown_timer: Arc<AtomicUsize>,
+    /// Changed by each `enable_deadlock_watchdog` and `disable_deadlock_watchdog`, to stop the
+    /// watchdogs before
+    watchdog: Arc<AtomicUsize>,
     id: usize,
     th: JoinHandle<()>,
+    done: Receiver<()>,
 }
 
 impl Scheduler {
@@ -116,12 +730,14 @@ impl Scheduler {
     pub fn new() -> Self {
         let (s, r) = channel();
         let (error_s, error_r) = channel();
-        let mut sched_s = SchedState::new(s.clone());
+        let (done_s, done_r) = channel();
+        let ctx = AgentCtx::new();
+        let mut sched_s = SchedState::new(s.clone(), ctx.clone());
         let th = thread::spawn(move || {
             loop {
                 let msg = r.recv().unwrap();
                 let res: Result<()> = match msg {
-                    CompMsg::NewAgent(id, name, comp) => { sched_s.new_agent(id, name, comp) },
+                    CompMsg::NewAgent(id, name, comp, ctx) => { sched_s.new_agent(id, name, comp, ctx) },
                     CompMsg::Start(name) => { sched_s.start(name) },
                     CompMsg::Halt => { break; },
                     CompMsg::HaltState => { sched_s.halt() },
@@ -150,22 +766,55 @@ impl Scheduler {
                     CompMsg::DisconnectArray(name, port, element) => {
                         sched_s.edit_agent(name, EditCmp::DisconnectArray(port, element))
                     },
-                    CompMsg::Inc(dest) => { sched_s.inc(dest) },
-                    CompMsg::Dec(dest) => { sched_s.dec(dest) },
+                    CompMsg::Inc(dest) => { sched_s.inc(dest, 1) },
+                    CompMsg::Dec(dest) => { sched_s.dec(dest, 1) },
+                    CompMsg::IncBatch(dest, n) => { sched_s.inc(dest, n as isize) },
+                    CompMsg::DecBatch(dest, n) => { sched_s.dec(dest, n as isize) },
                     CompMsg::Remove(name, sync_sender) => {
                         sched_s.remove(name, sync_sender)
                     }
+                    CompMsg::Shutdown => { sched_s.shutdown() },
+                    CompMsg::Running(sync_sender) => { sched_s.running(sync_sender) },
+                    CompMsg::Idle(sync_sender) => { sched_s.idle(sync_sender) },
+                    CompMsg::Status(sync_sender) => { sched_s.status(sync_sender) },
+                    CompMsg::SetRestartPolicy(name, policy) => { sched_s.set_restart_policy(name, policy) },
+                    CompMsg::OwnThread(name, cpus) => { sched_s.own_thread(name, cpus) },
+                    CompMsg::StackSize(name, size) => { sched_s.stack_size(name, size) },
+                    CompMsg::BasePriority(name, level) => { sched_s.base_priority(name, level) },
+                    CompMsg::AgingRate(rate) => { sched_s.aging_rate(rate) },
+                    CompMsg::Reload(name, comp, sync_sender) => { sched_s.reload(name, comp, sync_sender) },
+                    CompMsg::Pause(name) => { sched_s.pause(name) },
+                    CompMsg::CheckDeadlock(threshold) => { sched_s.check_deadlock(threshold) },
+                    CompMsg::Resume(name) => { sched_s.resume(name) },
+                    CompMsg::Checkpoint(name, sync_sender) => { sched_s.checkpoint(name, sync_sender) },
+                    CompMsg::Restore(name, data) => { sched_s.restore(name, data) },
+                    CompMsg::Configure(name, config) => { sched_s.configure(name, config) },
                 };
                 res.map_err(|e| { error_s.send(e).expect("cannot send the error"); }).ok();
             }
+            for e in sched_s.stop_agents() {
+                let _ = error_s.send(e);
+            }
+            let _ = done_s.send(());
         });
 
         Scheduler {
             cache: AgentCache::new(),
             agents: HashMap::new(),
+            edges: vec![],
             sender: s,
             error_receiver: error_r,
+            ctx: ctx,
+            allow_cycles: false,
+            fanout: FanoutPolicy::Replace,
+            subscribers: vec![],
+            iips: vec![],
+            sampled: HashMap::new(),
+            started: None,
+            shutdown_timer: Arc::new(AtomicUsize::new(0)),
+            watchdog: Arc::new(AtomicUsize::new(0)),
             th: th,
+            done: done_r,
             id: 0,
         }
     }
@@ -185,10 +834,49 @@ impl Scheduler {
     {
         let name = name.into().into_owned();
         let sort = sort.into().into_owned();
-        let (comp, senders) = self.cache.create_comp(&sort, self.id, self.sender.clone()).expect("cannot create comp");
+        let ctx = self.ctx.for_agent();
+        let (comp, senders) = self.cache.create_comp(&sort, self.id, self.sender.clone(), ctx.clone()).expect("cannot create comp");
+        if !senders.contains_key("accumulator") {
+            return Err(result::Error::PortNotFound(name, "accumulator".into()));
+        }
+        self.add_comp(name, sort, comp, senders, ctx);
+        Ok(())
+    }
+
+    /// Add an agent created in the process, without a dylib
+    ///
+    /// `create` has the signature of the `new` function of the agents, for example one of the
+    /// `builtin` agents, or a closure using `Merger::create`. The agent is then used like the
+    /// agents of `add_node`. Its ports have the schema `any`. An input port that the agent
+    /// doesn't have is reported by `get_schema_input`, an output port only when it is connected.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// try!(sched.add_agent_boxed("balancer", builtin::load_balancer));
+    /// try!(sched.add_agent_boxed("merger", |id, sched, ctx| {
+    ///     Merger::create(id, sched, ctx, MergeMode::Ordered(Box::new(date_key)))
+    /// }));
+    /// ```
+    pub fn add_agent_boxed<'a, A, F>(&mut self, name: A, create: F) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        F: FnOnce(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)>,
+    {
+        let name = name.into().into_owned();
+        let ctx = self.ctx.for_agent();
+        let (comp, senders) = try!(create(self.id, self.sender.clone(), ctx.clone()));
+        self.add_comp(name, BOXED_SORT.into(), comp, senders, ctx);
+        Ok(())
+    }
+
+    fn add_comp(&mut self, name: String, sort: String, comp: BoxedComp, senders: HashMap<String, MsgSender>, ctx: AgentCtx) {
         let start = !comp.is_input_ports();
-        self.sender.send(CompMsg::NewAgent(self.id, name.clone(), comp)).expect("Cannot send to sched state");
-        let s_acc = try!(senders.get("accumulator").ok_or(result::Error::PortNotFound(name.clone(), "accumulator".into()))).clone();
+        self.sender.send(CompMsg::NewAgent(self.id, name.clone(), comp, ctx.clone())).expect("Cannot send to sched state");
+        let s_acc = senders.get("accumulator").map(|s| {
+            let mut s = s.clone();
+            s.set_origin(ctx.clone());
+            s
+        });
         self.agents.insert(name.clone(),
                                Comp {
                                    id: self.id,
@@ -196,513 +884,1999 @@ impl Scheduler {
                                    inputs_array: HashMap::new(),
                                    sort: sort,
                                    start: start,
+                                   ctx: ctx,
                                });
-        self.sender.send(CompMsg::ConnectOutputPort(self.id, "accumulator".into(), s_acc)).expect("Cannot send to sched state");
+        if let Some(s_acc) = s_acc {
+            self.sender.send(CompMsg::ConnectOutputPort(self.id, "accumulator".into(), s_acc)).expect("Cannot send to sched state");
+        }
         self.id += 1;
-        Ok(())
+        self.notify(EdgeEvent::AgentAdded(name));
+    }
+
+    /// Call `subscriber` on each change of the graph: added and removed agents, connected and
+    /// disconnected edges
+    ///
+    /// The subscriber is called by the method changing the graph, the IIPs are not edges.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// sched.subscribe(Box::new(|event| { println!("{:?}", event); }));
+    /// ```
+    pub fn subscribe(&mut self, subscriber: Box<Fn(EdgeEvent) + Send>) {
+        self.subscribers.push(subscriber);
+    }
+
+    fn notify(&self, event: EdgeEvent) {
+        debug!(target: LOG_TARGET, "{:?}", event);
+        for subscriber in &self.subscribers {
+            subscriber(event.clone());
+        }
+    }
+
+    /// Remove the edges matching `removed`, and notify the subscribers
+    fn remove_edges<F: Fn(&Edge) -> bool>(&mut self, removed: F) {
+        let (gone, kept): (Vec<Edge>, Vec<Edge>) = self.edges.drain(..).partition(|e| { removed(e) });
+        self.edges = kept;
+        for edge in &gone {
+            self.notify(EdgeEvent::disconnected(edge));
+        }
     }
 
     /// Start the scheduler
     ///
-    /// Start all the agent that have no input ports
+    /// Send the IIPs, then start all the agent that have no input ports. The graph is
+    /// first checked with `validate`, unless the cycles are allowed.
+    ///
+    /// The IIPs that don't fit in their port are sent in the background once the agents are
+    /// started, in order. If a port of an IIP is gone, nothing is sent and the IIPs are kept.
     ///
     /// # Example
     ///
     /// ```rust,ignore
-    /// sched.start();
+    /// try!(sched.start());
     /// ```
-    pub fn start(&self) {
+    pub fn start(&mut self) -> Result<()> {
+        if !self.allow_cycles {
+            try!(self.validate());
+        }
+        let mut senders = vec![];
+        for &(ref comp, ref port, ref element, _) in &self.iips {
+            senders.push(match *element {
+                Some(ref element) => try!(self.get_array_sender(&comp[..], &port[..], &element[..])),
+                None => try!(self.get_sender(&comp[..], &port[..])),
+            });
+        }
+        let iips = mem::replace(&mut self.iips, vec![]);
+        // The IIPs after the first one that doesn't fit in a port wait for the agent
+        let mut late: Vec<(MsgSender, Vec<Msg>)> = vec![];
+        for ((_, _, _, msg), sender) in iips.into_iter().zip(senders) {
+            if let Some(&mut (_, ref mut msgs)) = late.iter_mut().find(|&&mut (ref s, _)| { Arc::ptr_eq(s.port(), sender.port()) }) {
+                msgs.push(msg);
+                continue;
+            }
+            if let Some(msg) = try!(sender.try_send(msg)) {
+                late.push((sender, vec![msg]));
+            }
+        }
         for comp in self.agents.values() {
             if comp.start {
                 self.sender.send(CompMsg::Start(comp.id)).expect("start: unable to send to sched state");
             }
         }
+        for (sender, msgs) in late {
+            thread::spawn(move || {
+                for msg in msgs {
+                    if let Err(e) = sender.send(msg) {
+                        warn!(target: LOG_TARGET, "the IIPs of the port {} are not all sent : {}", sender.port().name, e);
+                        break;
+                    }
+                }
+            });
+        }
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        Ok(())
     }
 
-    /// Start the agent `name` if it has no input port
+    /// Check that the graph has no cycle, except through the feedback edges
     ///
     /// # Example
     ///
     /// ```rust,ignore
-    /// try!(sched.start_if_needed("add"));
+    /// if let Err(result::Error::Cycle(agents)) = sched.validate() {
+    ///     println!("cycle between {:?}", agents);
+    /// }
     /// ```
-    pub fn start_if_needed<'a, A: Into<Cow<'a, str>>>(&self, name: A) -> Result<()> {
-        let name = name.into().into_owned();
-        self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))
-            .and_then(|comp| {
-                if comp.start {
-                    self.sender.send(CompMsg::Start(comp.id)).expect("start_if_needed");
-                }
-                Ok(())
-            })
+    pub fn validate(&self) -> Result<()> {
+        let mut graph: HashMap<&str, Vec<&str>> = HashMap::new();
+        for edge in self.edges.iter().filter(|e| { !e.feedback }) {
+            graph.entry(&edge.out_agent).or_insert(vec![]).push(&edge.in_agent);
+        }
+        let mut names: Vec<&str> = self.agents.keys().map(|n| { n as &str }).collect();
+        names.sort();
+        let mut done = HashSet::new();
+        for name in names {
+            let mut path = vec![];
+            if let Some(cycle) = find_cycle(name, &graph, &mut path, &mut done) {
+                return Err(result::Error::Cycle(cycle));
+            }
+        }
+        Ok(())
     }
 
-    /// Start a agent, even if it has an input port
+    /// Set what `connect` does when the output port is already connected
+    ///
+    /// The default is `FanoutPolicy::Replace`. With `FanoutPolicy::Broadcast`, the sends on
+    /// the output port block while one of the destinations is full.
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// sched.start_agent("add");
+    /// sched.set_fanout_policy(FanoutPolicy::Broadcast);
+    /// try!(sched.connect("clock", "output", "display", "input"));
+    /// try!(sched.connect("clock", "output", "log", "input"));
     /// ```
-    pub fn start_agent<'a, A>(&self, name: A) -> Result<()> where
-        A: Into<Cow<'a, str>>
-    {
-        let name = name.into();
-        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
-        self.sender.send(CompMsg::Start(comp.id)).expect("start: unable to send to sched state");
-        Ok(())
+    pub fn set_fanout_policy(&mut self, policy: FanoutPolicy) {
+        self.fanout = policy;
     }
 
-    /// Remove a agent form the scheduler and retrieve all the information
+    /// Let `start` run a graph with cycles
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// let (boxed_comp, comp) = try!(sched.remove_agent("add"));
-    /// assert!(boxed_comp.is_input_ports());
+    /// sched.allow_cycles(true);
     /// ```
-    pub fn remove_agent<'a, A: Into<Cow<'a, str>>>(&mut self, name: A) -> Result<(BoxedComp, Comp)>{
-        let name = name.into().into_owned();
-        let (s, r) = channel();
-        {
-            let comp = self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))?;
-            self.sender.send(CompMsg::Remove(comp.id, s)).expect("Scheduler remove_agent: cannot send to the state");
-        }
-        let response = try!(r.recv());
-        match response {
-            SyncMsg::Remove(boxed_comp) => {
-                Ok((boxed_comp, try!(self.agents.remove(&name).ok_or(result::Error::AgentNotFound(name.into())))))
-            },
-            SyncMsg::CannotRemove => {
-                Err(result::Error::CannotRemove(name))
-            },
-        }
+    pub fn allow_cycles(&mut self, allow: bool) {
+        self.allow_cycles = allow;
     }
 
-    /// Connect a simple output port to a simple input port
+    /// Mark the edges of an output port as feedback edges, allowed to close a cycle
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// try!(sched.connect("add", "output", "display", "input"));
+    /// try!(sched.mark_feedback("vm", "ask_graph"));
     /// ```
-    pub fn connect<'a, A, B, C, D>(&self, comp_out: A, port_out: B, comp_in: C, port_in: D) -> Result<()> where
+    pub fn mark_feedback<'a, A, B>(&mut self, comp_out: A, port_out: B) -> Result<()> where
         A: Into<Cow<'a, str>>,
         B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
-        D: Into<Cow<'a, str>>
     {
         let comp_out = comp_out.into().into_owned();
         let port_out = port_out.into().into_owned();
-        let comp_in = &*(comp_in.into());
-        let port_in = &*(port_in.into());
-        // Check schema
-        let sort_in = self.agents.get(comp_in).ok_or(result::Error::AgentNotFound(comp_in.into()))?;
-        let sort_out = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        let in_schema = self.cache.get_schema_input(&sort_in.sort, port_in)?;
-        let out_schema = self.cache.get_schema_output(&sort_out.sort, &port_out)?;
-        if in_schema != "any" && out_schema != "any" && in_schema != out_schema {
-            return Err(result::Error::BadSchema(comp_out.clone(), port_out.clone(), out_schema, comp_in.into(), port_in.into(), in_schema));
+        let mut found = false;
+        for edge in self.edges.iter_mut().filter(|e| { e.out_agent == comp_out && e.out_port == port_out }) {
+            edge.feedback = true;
+            found = true;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(result::Error::OutputPortNotConnected(comp_out, port_out))
         }
-
-        let sender = try!(self.get_sender(comp_in, port_in));
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::ConnectOutputPort(comp.id, port_out, sender)).ok().expect("Scheduler connect: unable to send to sched state");
-        Ok(())
     }
 
-    /// Connect a array output port to a simple input port
+    /// Add an initial information packet (IIP) on an input port
+    ///
+    /// The IIP is sent once, when the scheduler starts, before the agents are started.
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// try!(sched.connect_array("add", "outputs", "1", "display", "input"));
+    /// try!(sched.add_iip("add", "option", msg));
     /// ```
-    pub fn connect_array<'a, A, B, C, D, E>(&self, comp_out: A, port_out: B, element_out: C, comp_in: D, port_in: E) -> Result<()> where
+    pub fn add_iip<'a, A, B>(&mut self, comp: A, port: B, msg: Msg) -> Result<()> where
         A: Into<Cow<'a, str>>,
         B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
-        D: Into<Cow<'a, str>>,
-        E: Into<Cow<'a, str>>
     {
-        let comp_out = comp_out.into().into_owned();
-        let port_out = port_out.into().into_owned();
-        let element_out = element_out.into().into_owned();
-        let comp_in = &*(comp_in.into());
-        let port_in = &*(port_in.into());
-        // Check schema
-        let sort_in = self.agents.get(comp_in).ok_or(result::Error::AgentNotFound(comp_in.into()))?;
-        let sort_out = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        let in_schema = self.cache.get_schema_input(&sort_in.sort, port_in)?;
-        let out_schema = self.cache.get_schema_output_array(&sort_out.sort, &port_out)?;
-        if in_schema != "any" && out_schema != "any" && in_schema != out_schema {
-            return Err(result::Error::BadSchema(comp_out.clone(), port_out.clone(), out_schema, comp_in.into(), port_in.into(), in_schema));
-        }
-
-        let sender = try!(self.get_sender(comp_in, port_in));
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::ConnectOutputArrayPort(comp.id, port_out, element_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
+        let comp = comp.into().into_owned();
+        let port = port.into().into_owned();
+        try!(self.get_sender(&comp as &str, &port as &str));
+        self.iips.push((comp, port, None, msg));
         Ok(())
     }
 
-    /// Connect a simple output port to an array input port
+    /// Add an initial information packet (IIP) on an element of an array input port
+    ///
+    /// The element must already exist, see `add_input_array_element`.
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// try!(sched.connect_to_array("add", "output", "display", "inputs", "1"));
+    /// try!(sched.add_iip_array("add", "inputs", "1", msg));
     /// ```
-    pub fn connect_to_array<'a, A, B, C, D, E>(&self, comp_out: A, port_out: B, comp_in: C, port_in: D, element_in: E) -> Result<()> where
+    pub fn add_iip_array<'a, A, B, C>(&mut self, comp: A, port: B, element: C, msg: Msg) -> Result<()> where
         A: Into<Cow<'a, str>>,
         B: Into<Cow<'a, str>>,
         C: Into<Cow<'a, str>>,
-        D: Into<Cow<'a, str>>,
-        E: Into<Cow<'a, str>>
     {
-        let comp_out = comp_out.into().into_owned();
-        let port_out = port_out.into().into_owned();
-        let comp_in = &*(comp_in.into());
-        let port_in = &*(port_in.into());
-        let element_in = &*(element_in.into());
-        // Check schema
-        let sort_in = self.agents.get(comp_in).ok_or(result::Error::AgentNotFound(comp_in.into()))?;
-        let sort_out = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        let in_schema = self.cache.get_schema_input_array(&sort_in.sort, port_in)?;
-        let out_schema = self.cache.get_schema_output(&sort_out.sort, &port_out)?;
-        if in_schema != "any" && out_schema != "any" && in_schema != out_schema {
-            return Err(result::Error::BadSchema(comp_out.clone(), port_out.clone(), out_schema, comp_in.into(), port_in.into(), in_schema));
-        }
-
-        let sender = try!(self.get_array_sender(comp_in, port_in, element_in));
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::ConnectOutputPort(comp.id, port_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
+        let comp = comp.into().into_owned();
+        let port = port.into().into_owned();
+        let element = element.into().into_owned();
+        try!(self.get_array_sender(&comp as &str, &port as &str, &element as &str));
+        self.iips.push((comp, port, Some(element), msg));
         Ok(())
     }
 
-    /// Connect an array output port to an array input port
+    /// Send a Msg on an input port of a running graph, from outside the graph
+    ///
+    /// Unlike an IIP, the Msg is sent at once and can be sent at any time. It is received
+    /// between the Msgs of the edge connected to the port, if any. Like `MsgSender::send`,
+    /// it blocks while the port is full.
     ///
     /// # Example
+    ///
     /// ```rust,ignore
-    /// try!(sched.connect_array_to_array("add", "outputs", "1", "display", "inputs", "1"));
+    /// try!(sched.start());
+    /// try!(sched.inject("add", "input", msg));
     /// ```
-    pub fn connect_array_to_array<'a, A, B, C, D, E, F>(&self, comp_out: A, port_out: B, element_out: C, comp_in: D, port_in: E, element_in: F) -> Result<()> where
+    pub fn inject<'a, A, B>(&self, comp: A, port: B, msg: Msg) -> Result<()> where
         A: Into<Cow<'a, str>>,
         B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
-        D: Into<Cow<'a, str>>,
-        E: Into<Cow<'a, str>>,
-        F: Into<Cow<'a, str>>
     {
-        let comp_out = comp_out.into().into_owned();
-        let port_out = port_out.into().into_owned();
-        let element_out = element_out.into().into_owned();
-        let comp_in = &*(comp_in.into());
-        let port_in = &*(port_in.into());
-        let element_in = &*(element_in.into());
-        // Check schema
-        let sort_in = self.agents.get(comp_in).ok_or(result::Error::AgentNotFound(comp_in.into()))?;
-        let sort_out = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        let in_schema = self.cache.get_schema_input_array(&sort_in.sort, port_in)?;
-        let out_schema = self.cache.get_schema_output_array(&sort_out.sort, &port_out)?;
-        if in_schema != "any" && out_schema != "any" && in_schema != out_schema {
-            return Err(result::Error::BadSchema(comp_out.clone(), port_out.clone(), out_schema, comp_in.into(), port_in.into(), in_schema));
-        }
+        let sender = try!(self.get_sender(comp, port));
+        sender.send(msg)
+    }
 
-        let sender = try!(self.get_array_sender(comp_in, port_in, element_in));
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::ConnectOutputArrayPort(comp.id, port_out, element_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
-        Ok(())
+    /// Start the agent `name` if it has no input port
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// try!(sched.start_if_needed("add"));
+    /// ```
+    pub fn start_if_needed<'a, A: Into<Cow<'a, str>>>(&self, name: A) -> Result<()> {
+        let name = name.into().into_owned();
+        self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))
+            .and_then(|comp| {
+                if comp.start {
+                    self.sender.send(CompMsg::Start(comp.id)).expect("start_if_needed");
+                }
+                Ok(())
+            })
     }
 
-    /// disconnect an output port
+    /// Start a agent, even if it has an input port
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.disconnect("add", "output"));
+    /// sched.start_agent("add");
     /// ```
-    pub fn disconnect<'a, A, B>(&self, comp_out: A, port_out: B) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
+    pub fn start_agent<'a, A>(&self, name: A) -> Result<()> where
+        A: Into<Cow<'a, str>>
     {
-        let comp_out = comp_out.into().into_owned();
-        let port_out = port_out.into().into_owned();
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::Disconnect(comp.id, port_out)).ok().expect("Scheduler disconnect: unable to send to scheduler state");
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        self.sender.send(CompMsg::Start(comp.id)).expect("start: unable to send to sched state");
         Ok(())
     }
 
-    /// disconnect an array output port
+    /// Set what to do when the agent `name` panics. The default is `RestartPolicy::Never`
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.disconnect_array("add", "outputs", "1"));
+    /// try!(sched.set_restart_policy("add", RestartPolicy::MaxRetries(5)));
     /// ```
-    pub fn disconnect_array<'a, A, B, C>(&self, comp_out: A, port_out: B, element: C) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
+    pub fn set_restart_policy<'a, A>(&self, name: A, policy: RestartPolicy) -> Result<()> where
+        A: Into<Cow<'a, str>>
     {
-        let comp_out = comp_out.into().into_owned();
-        let port_out = port_out.into().into_owned();
-        let element = element.into().into_owned();
-        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
-        self.sender.send(CompMsg::DisconnectArray(comp.id, port_out, element)).ok().expect("Scheduler disconnect_array: unable to send to scheduler state");
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        self.sender.send(CompMsg::SetRestartPolicy(comp.id, policy)).expect("set_restart_policy: unable to send to sched state");
         Ok(())
     }
 
-    /// Add a element in an input array port
+    /// Run the agent in its own thread, named after the agent
+    ///
+    /// By default the agents share a pool of threads named `rustfbp`. An agent with its own
+    /// thread is easier to find in a profiler.
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.add_input_array_element("add".into(), "inputs".into(), "1".into()));
+    /// try!(sched.set_own_thread("add"));
     /// ```
-    pub fn add_input_array_element<'a, A, B, C>(&mut self, comp_name: A, port: B, element: C) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
+    pub fn set_own_thread<'a, A>(&self, name: A) -> Result<()> where
+        A: Into<Cow<'a, str>>
     {
-        let comp_name = comp_name.into().into_owned();
-        let port = port.into().into_owned();
-        let element = element.into().into_owned();
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        self.sender.send(CompMsg::OwnThread(comp.id, None)).expect("set_own_thread: unable to send to sched state");
+        Ok(())
+    }
 
-        let comp_id = self.agents.get(&comp_name).ok_or(result::Error::AgentNotFound(comp_name.clone()))?.id;
-        let (r, s) = MsgReceiver::new(
-            comp_id,
-            self.sender.clone(),
-            true
-        );
-        try!(self.agents.get_mut(&comp_name).ok_or(result::Error::AgentNotFound(comp_name.clone()))
-            .and_then(|mut comp| {
-                if !comp.inputs_array.contains_key(&port) {
-                    comp.inputs_array.insert(port.clone(), HashMap::new());
-                }
-                comp.inputs_array.get_mut(&port).ok_or(result::Error::ElementNotFound(comp_name.clone(), port.clone(), element.clone()))
-                    .and_then(|mut port| {
-                        port.insert(element.clone(), s);
-                        Ok(())
-                    })
-            }));
-        self.sender.send(CompMsg::AddInputArrayElement(comp_id, port, element, r)).ok().expect("Scheduler add_input_array_element : Unable to send to scheduler state");
+    /// Run the agent in its own thread, pinned to the first available CPU of `cpus`
+    ///
+    /// Needs the `affinity` feature. Without it, or on a platform where the CPUs cannot be
+    /// listed, the agent gets its own thread and a warning is printed.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_affinity("add", vec![2, 3]));
+    /// ```
+    pub fn set_affinity<'a, A>(&self, name: A, cpus: Vec<usize>) -> Result<()> where
+        A: Into<Cow<'a, str>>
+    {
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        self.sender.send(CompMsg::OwnThread(comp.id, Some(cpus))).expect("set_affinity: unable to send to sched state");
         Ok(())
     }
 
-    // pub fn remove_input_array_element(&mut self, comp: String, port: String, element: String) -> Result<()> {
-    //     // TODO
-    // }
+    /// Run the agent in its own thread, with a stack of `bytes`
+    ///
+    /// The other agents keep the default stack of the threads of Rust. The size must be at
+    /// least `MIN_STACK_SIZE`. The pinning of `set_affinity` is kept.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_stack_size("parser", 64 * 1024 * 1024));
+    /// ```
+    pub fn set_stack_size<'a, A>(&self, name: A, bytes: usize) -> Result<()> where
+        A: Into<Cow<'a, str>>
+    {
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.to_string()))?;
+        if bytes < MIN_STACK_SIZE {
+            return Err(result::Error::Misc(format!("{}() : a stack of {} bytes is below the minimum of {} bytes", name, bytes, MIN_STACK_SIZE)));
+        }
+        self.sender.send(CompMsg::StackSize(comp.id, bytes)).expect("set_stack_size: unable to send to sched state");
+        Ok(())
+    }
 
-    /// Add a element in an input array port, only if this element exists not yet
+    /// Set the base priority of an agent of the shared pool, 0 by default
+    ///
+    /// The pool has a fixed number of threads. When they are all busy, the agents ready to run
+    /// wait, and the next one to run is the one with the highest effective priority: its base
+    /// priority plus the aging rate times the seconds it has waited. A latency sensitive agent
+    /// gets a higher base priority than a bulk one, and the aging keeps the bulk agent from
+    /// waiting forever. The agents in their own thread don't wait for the pool.
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.soft_add_input_array_element("add".into(), "inputs".into(), "1".into()));
+    /// try!(sched.set_base_priority("quotes", 100));
+    /// try!(sched.set_base_priority("archive", -10));
     /// ```
-    pub fn soft_add_input_array_element<'a, A, B, C>(&mut self, comp: A, port: B, element: C) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>
+    pub fn set_base_priority<'a, A>(&self, name: A, level: i32) -> Result<()> where
+        A: Into<Cow<'a, str>>
     {
-        let mut res = true;
-        let comp = comp.into();
-        let port = port.into();
-        let element = element.into();
-        {
-            let c = &(comp) as &str;
-            let p = &(port) as &str;
-            let e = &(element) as &str;
-            if let Some(comp) = self.agents.get(c) {
-                if let Some(port) = comp.inputs_array.get(p) {
-                    if let Some(_) = port.get(e) {
-                        res = false;
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.to_string()))?;
+        self.sender.send(CompMsg::BasePriority(comp.id, level)).expect("set_base_priority: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Set the priority levels gained per second by the agents waiting for the pool
+    ///
+    /// `DEFAULT_AGING_RATE` by default, with 0 the base priorities are strict. See
+    /// `set_base_priority`.
+    pub fn set_aging_rate(&self, levels_per_second: f64) -> Result<()> {
+        if !levels_per_second.is_finite() || levels_per_second < 0.0 {
+            return Err(result::Error::Misc(format!("set_aging_rate() : the rate {} is not a positive number", levels_per_second)));
+        }
+        self.sender.send(CompMsg::AgingRate(levels_per_second)).expect("set_aging_rate: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Save the state of all the agents, see `Agent::checkpoint`
+    ///
+    /// The state of an agent is saved between two runs, so this waits the end of the running
+    /// agents, at most `timeout`. The agents without state are not in the map, neither are the
+    /// agents still running after `timeout`: they are logged, and their states are partial.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let states = sched.checkpoint_all(Duration::from_secs(5));
+    /// // Later, in a new scheduler with the same graph
+    /// try!(sched.restore_all(states));
+    /// ```
+    pub fn checkpoint_all(&self, timeout: Duration) -> HashMap<String, Vec<u8>> {
+        let (s, r) = channel();
+        for comp in self.agents.values() {
+            self.sender.send(CompMsg::Checkpoint(comp.id, s.clone())).expect("checkpoint_all: unable to send to sched state");
+        }
+        // A dropped agent never answers
+        drop(s);
+        let deadline = Instant::now() + timeout;
+        let mut answered = HashSet::new();
+        let mut states = HashMap::new();
+        while answered.len() < self.agents.len() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match r.recv_timeout(deadline - now) {
+                Ok((name, data)) => {
+                    if let Some(data) = data {
+                        states.insert(name.clone(), data);
                     }
-                }
+                    answered.insert(name);
+                },
+                Err(_) => { break; },
             }
         }
-        if res {
-            self.add_input_array_element(comp, port, element)
-        } else {
-            Ok(())
+        let mut missing: Vec<&String> = self.agents.keys().filter(|name| { !answered.contains(*name) }).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            warn!(target: LOG_TARGET, "checkpoint_all: no state from {:?} after {:?}", missing, timeout);
         }
+        states
     }
 
-    /// Add a element in an output array port
+    /// Load the states saved by `checkpoint_all`, see `Agent::restore`
+    ///
+    /// An agent not yet started loads its state after `on_start`, a started one between two
+    /// runs. The states of unknown agents are ignored.
+    pub fn restore_all(&self, states: HashMap<String, Vec<u8>>) -> Result<()> {
+        for (name, data) in states {
+            if let Some(comp) = self.agents.get(&name) {
+                self.sender.send(CompMsg::Restore(comp.id, data)).expect("restore_all: unable to send to sched state");
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the config of an agent, given to `Agent::configure` before `on_start`
+    ///
+    /// The config is usually a capnp message. It is given at the start of the agent, and
+    /// again when the agent is reloaded: an agent already started keeps its config until then.
+    /// If `configure` fails, the agent doesn't start, as when `on_start` fails.
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.add_output_array_element("add".into(), "inputs".into(), "1".into()));
+    /// try!(sched.configure("throttle", config));
+    /// try!(sched.start());
     /// ```
-    pub fn add_output_array_element<'a, A, B, C>(&self, comp: A, port: B, element: C) -> Result<()> where
+    pub fn configure<'a, A>(&self, name: A, config: Vec<u8>) -> Result<()> where
         A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>
     {
-        let comp = comp.into();
-        let port = port.into().into_owned();
-        let element = element.into().into_owned();
-        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
-        self.sender.send(CompMsg::AddOutputArrayElement(comp.id, port, element)).ok().expect("Scheduler add_output_array_element : Unable to send to scheduler state");
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        self.sender.send(CompMsg::Configure(comp.id, config)).expect("configure: unable to send to sched state");
         Ok(())
     }
 
-    /// Change the receiver of an input port.
+    /// Pause an agent: it is not run anymore and stops reading its input ports
     ///
-    /// Usefull for replacing a agent
+    /// The Msgs wait in the input ports, and the senders block once the ports are full.
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.set_receiver("add".into(), "input".into(), recv));
+    /// try!(sched.pause("add"));
     /// ```
-    pub fn set_receiver<'a, A, B>(&self, comp: A, port: B, receiver: Receiver<Msg>) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
+    pub fn pause<'a, A>(&self, name: A) -> Result<()> where
+        A: Into<Cow<'a, str>>
     {
-        let comp = comp.into();
-        let port = port.into().into_owned();
-        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
-        self.sender.send(CompMsg::SetReceiver(comp.id, port, receiver)).expect("scheduler cannot send");
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        comp.ctx.set_paused(true);
+        self.sender.send(CompMsg::Pause(comp.id)).expect("pause: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Resume a paused agent
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.resume("add"));
+    /// ```
+    pub fn resume<'a, A>(&self, name: A) -> Result<()> where
+        A: Into<Cow<'a, str>>
+    {
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        comp.ctx.set_paused(false);
+        self.sender.send(CompMsg::Resume(comp.id)).expect("resume: unable to send to sched state");
         Ok(())
     }
 
-    /// Change the receiver of an array input port.
-    ///
-    /// Usefull for replacing a agent
+    /// Remove a agent form the scheduler and retrieve all the information
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let (boxed_comp, comp) = try!(sched.remove_agent("add"));
+    /// assert!(boxed_comp.is_input_ports());
+    /// ```
+    pub fn remove_agent<'a, A: Into<Cow<'a, str>>>(&mut self, name: A) -> Result<(BoxedComp, Comp)>{
+        let name = name.into().into_owned();
+        let (s, r) = channel();
+        {
+            let comp = self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))?;
+            self.sender.send(CompMsg::Remove(comp.id, s)).expect("Scheduler remove_agent: cannot send to the state");
+        }
+        let response = try!(r.recv());
+        match response {
+            SyncMsg::Remove(boxed_comp) => {
+                let outputs = self.outputs_to(&name, &|e| { e.in_agent == name });
+                self.remove_edges(|e| { e.out_agent == name || e.in_agent == name });
+                try!(self.reconnect_outputs(outputs));
+                self.notify(EdgeEvent::AgentRemoved(name.clone()));
+                Ok((boxed_comp, try!(self.agents.remove(&name).ok_or(result::Error::AgentNotFound(name.into())))))
+            },
+            SyncMsg::CannotRemove => {
+                Err(result::Error::CannotRemove(name))
+            },
+        }
+    }
+
+    /// Replace the agent `name` by a new agent loaded from `path`, without stopping the graph
+    ///
+    /// Waits for the end of the current run of the agent, then moves its ports, with the
+    /// Msgs waiting on them, to the new agent. The state of the agent is not kept. The old
+    /// dylib is unloaded if no other agent uses it.
+    ///
+    /// The dylib stays loaded while it is used, so a new version must have a new path.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.reload_agent("add", "/home/xxx/agents/add_v2.so"));
+    /// ```
+    pub fn reload_agent<'a, A, B>(&mut self, name: A, path: B) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let name = name.into().into_owned();
+        let path = path.into().into_owned();
+        let (id, ctx) = {
+            let comp = self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))?;
+            (comp.id, comp.ctx.clone())
+        };
+        let (comp, _) = try!(self.cache.create_comp(&path, id, self.sender.clone(), ctx));
+        self.swap_comp(name, path, comp)
+    }
+
+    /// Replace the agent `name` by a new agent created in the process, like `reload_agent`
+    ///
+    /// `create` is called like for `add_agent_boxed`, and the ports it returns are replaced
+    /// by the ports of the agent.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.reload_agent_boxed("balancer", |id, sched, ctx| {
+    ///     LoadBalancer::create(id, sched, ctx, true)
+    /// }));
+    /// ```
+    pub fn reload_agent_boxed<'a, A, F>(&mut self, name: A, create: F) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        F: FnOnce(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)>,
+    {
+        let name = name.into().into_owned();
+        let (id, ctx) = {
+            let comp = self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))?;
+            (comp.id, comp.ctx.clone())
+        };
+        let (comp, _) = try!(create(id, self.sender.clone(), ctx));
+        self.swap_comp(name, BOXED_SORT.into(), comp)
+    }
+
+    /// Give the ports of the agent `name` to `comp`, once its run ended
+    fn swap_comp(&mut self, name: String, sort: String, comp: BoxedComp) -> Result<()> {
+        let (id, old_sort) = {
+            let comp = self.agents.get(&name).ok_or(result::Error::AgentNotFound(name.clone()))?;
+            (comp.id, comp.sort.clone())
+        };
+        let (s, r) = channel();
+        self.sender.send(CompMsg::Reload(id, comp, s)).expect("Scheduler reload_agent: cannot send to the state");
+        try!(r.recv());
+        if let Some(comp) = self.agents.get_mut(&name) {
+            comp.sort = sort;
+        }
+        if old_sort != BOXED_SORT && !self.agents.values().any(|comp| { comp.sort == old_sort }) {
+            self.cache.remove(&old_sort);
+        }
+        Ok(())
+    }
+
+    /// Connect a simple output port to a simple input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.connect("add", "output", "display", "input"));
+    /// ```
+    pub fn connect<'a, A, B, C, D>(&mut self, comp_out: A, port_out: B, comp_in: C, port_in: D) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+        D: Into<Cow<'a, str>>
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let comp_in = &*(comp_in.into());
+        let port_in = &*(port_in.into());
+        try!(self.check_schema(&comp_out, &port_out, false, comp_in, port_in, false));
+        self.connect_checked(comp_out, port_out, comp_in, port_in)
+    }
+
+    /// Connect as `connect`, once `check_schema` accepted the edge
+    fn connect_checked(&mut self, comp_out: String, port_out: String, comp_in: &str, port_in: &str) -> Result<()> {
+        let sender = try!(self.get_sender(comp_in, port_in));
+        let sender = try!(self.add_edge(Edge {
+            out_agent: comp_out.clone(), out_port: port_out.clone(), out_element: None,
+            in_agent: comp_in.into(), in_port: port_in.into(), in_element: None,
+            feedback: false,
+        }, sender));
+        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
+        self.sender.send(CompMsg::ConnectOutputPort(comp.id, port_out, sender)).ok().expect("Scheduler connect: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Connect as `connect`, unless the edge is already in the graph
+    ///
+    /// Returns `Ok(true)` if the edge is new, and `Ok(false)` if it was already there: the
+    /// output port is left as it is, the Msgs are not delivered twice. The errors are the
+    /// errors of `connect`, for an unknown agent or port or for schemas that don't match.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// if try!(sched.connect_idempotent("add", "output", "display", "input")) {
+    ///     println!("add -> display");
+    /// }
+    /// ```
+    pub fn connect_idempotent<'a, A, B, C, D>(&mut self, comp_out: A, port_out: B, comp_in: C, port_in: D) -> Result<bool> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+        D: Into<Cow<'a, str>>
+    {
+        let comp_out = comp_out.into();
+        let port_out = port_out.into();
+        let comp_in = comp_in.into();
+        let port_in = port_in.into();
+        try!(self.check_schema(&comp_out, &port_out, false, &comp_in, &port_in, false));
+        let exists = self.edges.iter().any(|e| {
+            e.out_agent == *comp_out && e.out_port == *port_out && e.out_element.is_none()
+                && e.in_agent == *comp_in && e.in_port == *port_in && e.in_element.is_none()
+        });
+        if exists {
+            return Ok(false);
+        }
+        try!(self.connect_checked(comp_out.into_owned(), port_out.into_owned(), &comp_in, &port_in));
+        Ok(true)
+    }
+
+    /// Check that the edges of an output port and of an input port match
+    ///
+    /// The type ids of the capnp schemas are compared when both agents declare them, else the
+    /// names of the edges. An `any` port matches every edge.
+    fn check_schema(&self, comp_out: &str, port_out: &str, out_array: bool, comp_in: &str, port_in: &str, in_array: bool) -> Result<()> {
+        let sort_in = &self.agents.get(comp_in).ok_or(result::Error::AgentNotFound(comp_in.into()))?.sort;
+        let sort_out = &self.agents.get(comp_out).ok_or(result::Error::AgentNotFound(comp_out.into()))?.sort;
+        let (in_schema, in_id) = if in_array {
+            (self.cache.get_schema_input_array(sort_in, port_in)?, self.cache.get_type_id_input_array(sort_in, port_in)?)
+        } else {
+            (self.cache.get_schema_input(sort_in, port_in)?, self.cache.get_type_id_input(sort_in, port_in)?)
+        };
+        let (out_schema, out_id) = if out_array {
+            (self.cache.get_schema_output_array(sort_out, port_out)?, self.cache.get_type_id_output_array(sort_out, port_out)?)
+        } else {
+            (self.cache.get_schema_output(sort_out, port_out)?, self.cache.get_type_id_output(sort_out, port_out)?)
+        };
+        if let (Some(expected), Some(found)) = (in_id, out_id) {
+            if expected != found {
+                return Err(result::Error::TypeMismatch(expected, found));
+            }
+            return Ok(());
+        }
+        if in_schema != "any" && out_schema != "any" && in_schema != out_schema {
+            return Err(result::Error::BadSchema(comp_out.into(), port_out.into(), out_schema, comp_in.into(), port_in.into(), in_schema));
+        }
+        Ok(())
+    }
+
+    /// Add `edge` following the `FanoutPolicy`, and return the sender of its output port
+    ///
+    /// `sender` is the sender of the input port of the edge.
+    fn add_edge(&mut self, edge: Edge, mut sender: MsgSender) -> Result<MsgSender> {
+        let ctx = self.agents.get(&edge.out_agent).ok_or(result::Error::AgentNotFound(edge.out_agent.clone()))?.ctx.clone();
+        sender.set_origin(ctx.clone());
+        let others: Vec<Edge> = self.edges.iter()
+            .filter(|e| { e.out_agent == edge.out_agent && e.out_port == edge.out_port && e.out_element == edge.out_element })
+            .filter(|e| { !(e.in_agent == edge.in_agent && e.in_port == edge.in_port && e.in_element == edge.in_element) })
+            .cloned()
+            .collect();
+        match self.fanout {
+            FanoutPolicy::Replace => {
+                // The name of an element is its label, it is not given to another port
+                if edge.out_element.is_some() && !others.is_empty() {
+                    return Err(result::Error::AlreadyConnected(edge.out_agent.clone(), Edge::label(&edge.out_port, &edge.out_element)));
+                }
+                self.remove_edges(|e| { others.contains(e) });
+                self.edges.retain(|e| { *e != edge });
+            },
+            FanoutPolicy::Broadcast => {
+                for other in &others {
+                    sender.add_destination(try!(self.edge_sender(other, &ctx)));
+                }
+                self.edges.retain(|e| { *e != edge });
+            },
+            FanoutPolicy::Error => {
+                if !others.is_empty() {
+                    return Err(result::Error::AlreadyConnected(edge.out_agent.clone(), Edge::label(&edge.out_port, &edge.out_element)));
+                }
+                self.edges.retain(|e| { *e != edge });
+            },
+        }
+        self.notify(EdgeEvent::connected(&edge));
+        self.edges.push(edge);
+        Ok(sender)
+    }
+
+    /// The sender of the input port of `edge`, sending for the agent of `ctx`
+    fn edge_sender(&self, edge: &Edge, ctx: &AgentCtx) -> Result<MsgSender> {
+        let mut sender = match edge.in_element {
+            Some(ref element) => try!(self.get_array_sender(&edge.in_agent as &str, &edge.in_port as &str, element as &str)),
+            None => try!(self.get_sender(&edge.in_agent as &str, &edge.in_port as &str)),
+        };
+        sender.set_origin(ctx.clone());
+        Ok(sender)
+    }
+
+    /// Connect again the output ports of `outputs` to their remaining destinations
+    ///
+    /// After the removal of an input port, a broadcasting output port keeps sending to the
+    /// other destinations.
+    fn reconnect_outputs(&mut self, outputs: Vec<(String, String, Option<String>)>) -> Result<()> {
+        for (agent, port, element) in outputs {
+            let edges: Vec<Edge> = self.edges.iter()
+                .filter(|e| { e.out_agent == agent && e.out_port == port && e.out_element == element })
+                .cloned()
+                .collect();
+            let (comp_id, ctx) = match self.agents.get(&agent) {
+                Some(comp) => (comp.id, comp.ctx.clone()),
+                None => { continue; },
+            };
+            let mut edges = edges.iter();
+            let mut sender = match edges.next() {
+                Some(edge) => try!(self.edge_sender(edge, &ctx)),
+                None => { continue; },
+            };
+            for edge in edges {
+                sender.add_destination(try!(self.edge_sender(edge, &ctx)));
+            }
+            let msg = match element {
+                Some(element) => CompMsg::ConnectOutputArrayPort(comp_id, port, element, sender),
+                None => CompMsg::ConnectOutputPort(comp_id, port, sender),
+            };
+            self.sender.send(msg).ok().expect("Scheduler reconnect_outputs: unable to send to scheduler state");
+        }
+        Ok(())
+    }
+
+    /// The output ports sending to the input ports matching `removed`, outside of `agent`
+    fn outputs_to(&self, agent: &str, removed: &Fn(&Edge) -> bool) -> Vec<(String, String, Option<String>)> {
+        let mut outputs = vec![];
+        for e in self.edges.iter().filter(|e| { e.out_agent != agent && removed(e) }) {
+            let output = (e.out_agent.clone(), e.out_port.clone(), e.out_element.clone());
+            if !outputs.contains(&output) {
+                outputs.push(output);
+            }
+        }
+        outputs
+    }
+
+    /// Connect an output port to a sender that is not an agent of this scheduler
+    ///
+    /// For example the sender of a `transport::NetworkSink`. The edge is not part of the graph.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.connect_sender("add", "output", sink.sender()));
+    /// ```
+    pub fn connect_sender<'a, A, B>(&mut self, comp_out: A, port_out: B, mut sender: MsgSender) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let comp_id = {
+            let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
+            try!(self.cache.get_schema_output(&comp.sort, &port_out));
+            sender.set_origin(comp.ctx.clone());
+            comp.id
+        };
+        self.remove_edges(|e| { e.out_agent == comp_out && e.out_port == port_out && e.out_element.is_none() });
+        self.sender.send(CompMsg::ConnectOutputPort(comp_id, port_out, sender)).ok().expect("Scheduler connect_sender: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Connect a array output port to a simple input port
+    ///
+    /// The element name is unique in the port: if the element is already connected to another
+    /// input port, returns `Error::AlreadyConnected`, except with `FanoutPolicy::Broadcast`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.connect_array("add", "outputs", "1", "display", "input"));
+    /// ```
+    pub fn connect_array<'a, A, B, C, D, E>(&mut self, comp_out: A, port_out: B, element_out: C, comp_in: D, port_in: E) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+        D: Into<Cow<'a, str>>,
+        E: Into<Cow<'a, str>>
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let element_out = element_out.into().into_owned();
+        let comp_in = &*(comp_in.into());
+        let port_in = &*(port_in.into());
+        try!(self.check_schema(&comp_out, &port_out, true, comp_in, port_in, false));
+
+        let sender = try!(self.get_sender(comp_in, port_in));
+        let sender = try!(self.add_edge(Edge {
+            out_agent: comp_out.clone(), out_port: port_out.clone(), out_element: Some(element_out.clone()),
+            in_agent: comp_in.into(), in_port: port_in.into(), in_element: None,
+            feedback: false,
+        }, sender));
+        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
+        self.sender.send(CompMsg::ConnectOutputArrayPort(comp.id, port_out, element_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// Connect a simple output port to an array input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.connect_to_array("add", "output", "display", "inputs", "1"));
+    /// ```
+    pub fn connect_to_array<'a, A, B, C, D, E>(&mut self, comp_out: A, port_out: B, comp_in: C, port_in: D, element_in: E) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+        D: Into<Cow<'a, str>>,
+        E: Into<Cow<'a, str>>
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let comp_in = &*(comp_in.into());
+        let port_in = &*(port_in.into());
+        let element_in = &*(element_in.into());
+        try!(self.check_schema(&comp_out, &port_out, false, comp_in, port_in, true));
+
+        let sender = try!(self.get_array_sender(comp_in, port_in, element_in));
+        let sender = try!(self.add_edge(Edge {
+            out_agent: comp_out.clone(), out_port: port_out.clone(), out_element: None,
+            in_agent: comp_in.into(), in_port: port_in.into(), in_element: Some(element_in.into()),
+            feedback: false,
+        }, sender));
+        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
+        self.sender.send(CompMsg::ConnectOutputPort(comp.id, port_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// Connect an array output port to an array input port, the element names are unique as for `connect_array`
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.connect_array_to_array("add", "outputs", "1", "display", "inputs", "1"));
+    /// ```
+    pub fn connect_array_to_array<'a, A, B, C, D, E, F>(&mut self, comp_out: A, port_out: B, element_out: C, comp_in: D, port_in: E, element_in: F) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+        D: Into<Cow<'a, str>>,
+        E: Into<Cow<'a, str>>,
+        F: Into<Cow<'a, str>>
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let element_out = element_out.into().into_owned();
+        let comp_in = &*(comp_in.into());
+        let port_in = &*(port_in.into());
+        let element_in = &*(element_in.into());
+        try!(self.check_schema(&comp_out, &port_out, true, comp_in, port_in, true));
+
+        let sender = try!(self.get_array_sender(comp_in, port_in, element_in));
+        let sender = try!(self.add_edge(Edge {
+            out_agent: comp_out.clone(), out_port: port_out.clone(), out_element: Some(element_out.clone()),
+            in_agent: comp_in.into(), in_port: port_in.into(), in_element: Some(element_in.into()),
+            feedback: false,
+        }, sender));
+        let comp = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?;
+        self.sender.send(CompMsg::ConnectOutputArrayPort(comp.id, port_out, element_out, sender)).ok().expect("Scheduler connect: unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// disconnect an output port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.disconnect("add", "output"));
+    /// ```
+    pub fn disconnect<'a, A, B>(&mut self, comp_out: A, port_out: B) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let comp_id = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?.id;
+        self.remove_edges(|e| { e.out_agent == comp_out && e.out_port == port_out && e.out_element.is_none() });
+        self.sender.send(CompMsg::Disconnect(comp_id, port_out)).ok().expect("Scheduler disconnect: unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// disconnect an array output port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.disconnect_array("add", "outputs", "1"));
+    /// ```
+    pub fn disconnect_array<'a, A, B, C>(&mut self, comp_out: A, port_out: B, element: C) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+    {
+        let comp_out = comp_out.into().into_owned();
+        let port_out = port_out.into().into_owned();
+        let element = element.into().into_owned();
+        let comp_id = self.agents.get(&comp_out).ok_or(result::Error::AgentNotFound(comp_out.clone()))?.id;
+        self.remove_edges(|e| { e.out_agent == comp_out && e.out_port == port_out && e.out_element.as_ref() == Some(&element) });
+        self.sender.send(CompMsg::DisconnectArray(comp_id, port_out, element)).ok().expect("Scheduler disconnect_array: unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// Add a element in an input array port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.add_input_array_element("add".into(), "inputs".into(), "1".into()));
+    /// ```
+    pub fn add_input_array_element<'a, A, B, C>(&mut self, comp_name: A, port: B, element: C) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+    {
+        let comp_name = comp_name.into().into_owned();
+        let port = port.into().into_owned();
+        let element = element.into().into_owned();
+
+        let (comp_id, ctx) = {
+            let comp = self.agents.get(&comp_name).ok_or(result::Error::AgentNotFound(comp_name.clone()))?;
+            (comp.id, comp.ctx.clone())
+        };
+        let (r, s) = MsgReceiver::new(
+            comp_id,
+            &format!("{}[{}]", port, element),
+            self.sender.clone(),
+            ctx,
+            true
+        );
+        try!(self.agents.get_mut(&comp_name).ok_or(result::Error::AgentNotFound(comp_name.clone()))
+            .and_then(|mut comp| {
+                if !comp.inputs_array.contains_key(&port) {
+                    comp.inputs_array.insert(port.clone(), HashMap::new());
+                }
+                comp.inputs_array.get_mut(&port).ok_or(result::Error::ElementNotFound(comp_name.clone(), port.clone(), element.clone()))
+                    .and_then(|mut port| {
+                        port.insert(element.clone(), s);
+                        Ok(())
+                    })
+            }));
+        self.sender.send(CompMsg::AddInputArrayElement(comp_id, port, element, r)).ok().expect("Scheduler add_input_array_element : Unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// Remove a element of an input array port, and the edges going to it
+    ///
+    /// The Msgs still waiting on the element are dropped.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.remove_input_array_element("add", "inputs", "1"));
+    /// ```
+    pub fn remove_input_array_element<'a, A, B, C>(&mut self, comp_name: A, port: B, element: C) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+    {
+        let comp_name = comp_name.into().into_owned();
+        let port = port.into().into_owned();
+        let element = element.into().into_owned();
+        let comp_id = {
+            let comp = self.agents.get_mut(&comp_name).ok_or(result::Error::AgentNotFound(comp_name.clone()))?;
+            let removed = comp.inputs_array.get_mut(&port).and_then(|elements| { elements.remove(&element) });
+            if removed.is_none() {
+                return Err(result::Error::ElementNotFound(comp_name.clone(), port, element));
+            }
+            comp.id
+        };
+        let outputs = {
+            let removed = |e: &Edge| { e.in_agent == comp_name && e.in_port == port && e.in_element.as_ref() == Some(&element) };
+            let outputs = self.outputs_to(&comp_name, &removed);
+            self.remove_edges(&removed);
+            outputs
+        };
+        self.sender.send(CompMsg::RemoveInputArrayElement(comp_id, port, element)).ok().expect("Scheduler remove_input_array_element : Unable to send to scheduler state");
+        try!(self.reconnect_outputs(outputs));
+        Ok(())
+    }
+
+    /// Add a element in an input array port, only if this element exists not yet
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.soft_add_input_array_element("add".into(), "inputs".into(), "1".into()));
+    /// ```
+    pub fn soft_add_input_array_element<'a, A, B, C>(&mut self, comp: A, port: B, element: C) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>
+    {
+        let mut res = true;
+        let comp = comp.into();
+        let port = port.into();
+        let element = element.into();
+        {
+            let c = &(comp) as &str;
+            let p = &(port) as &str;
+            let e = &(element) as &str;
+            if let Some(comp) = self.agents.get(c) {
+                if let Some(port) = comp.inputs_array.get(p) {
+                    if let Some(_) = port.get(e) {
+                        res = false;
+                    }
+                }
+            }
+        }
+        if res {
+            self.add_input_array_element(comp, port, element)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add a element in an output array port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.add_output_array_element("add".into(), "inputs".into(), "1".into()));
+    /// ```
+    pub fn add_output_array_element<'a, A, B, C>(&self, comp: A, port: B, element: C) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>
+    {
+        let comp = comp.into();
+        let port = port.into().into_owned();
+        let element = element.into().into_owned();
+        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
+        self.sender.send(CompMsg::AddOutputArrayElement(comp.id, port, element)).ok().expect("Scheduler add_output_array_element : Unable to send to scheduler state");
+        Ok(())
+    }
+
+    /// Set the number of Msgs that an input port can buffer
+    ///
+    /// Once the port is full, the senders block until the agent reads an Msg.
+    /// The port gets a new channel, so it must be set before connecting the port:
+    /// `Error::AlreadyConnected` if an output port is connected to it. The Msgs not yet
+    /// read, as the IIPs, are lost.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_port_capacity("add", "input", 1));
+    /// ```
+    pub fn set_port_capacity<'a, A, B>(&mut self, comp: A, port: B, capacity: usize) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into().into_owned();
+        let port = port.into().into_owned();
+        let sender = self.sender.clone();
+        // The senders of the connected output ports would keep the old channel
+        let connected = self.edges.iter().any(|e| { e.in_agent == comp && e.in_port == port && e.in_element.is_none() });
+        let comp_state = self.agents.get_mut(&comp).ok_or(result::Error::AgentNotFound(comp.clone()))?;
+        if port == "accumulator" || !comp_state.inputs.contains_key(&port) {
+            return Err(result::Error::PortNotFound(comp, port));
+        }
+        if connected || comp_state.inputs[&port].port().senders() > 0 {
+            return Err(result::Error::AlreadyConnected(comp, port));
+        }
+        let must_sched = port != "option";
+        let (r, s) = MsgReceiver::with_capacity(comp_state.id, &port, sender, comp_state.ctx.clone(), must_sched, capacity);
+        comp_state.inputs.insert(port.clone(), s);
+        self.sender.send(CompMsg::SetReceiver(comp_state.id, port, r)).expect("set_port_capacity: unable to send to sched state");
+        Ok(())
+    }
+
+    /// Grant `n` credits to the senders of an input port, see `MsgReceiver::grant`
+    ///
+    /// The first grant switches the port to credits, before the start it bounds the Msgs in
+    /// flight from the first Msg.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.grant_credits("add", "input", 10));
+    /// ```
+    pub fn grant_credits<'a, A, B>(&self, comp: A, port: B, n: usize) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let sender = try!(self.get_sender(comp, port));
+        sender.port().grant(n);
+        Ok(())
+    }
+
+    /// Limit the size of the Msgs received on an input port, see `PortState::set_max_message_size`
+    ///
+    /// The size is in capnp words of 8 bytes. A larger Msg is dropped, the `recv` of the
+    /// agent returns `Error::MessageTooLarge` and the agent keeps running.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_max_message_size("parse", "input", 64 * 1024));
+    /// ```
+    pub fn set_max_message_size<'a, A, B>(&self, comp: A, port: B, words: u64) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let sender = try!(self.get_sender(comp, port));
+        sender.port().set_max_message_size(Some(words));
+        Ok(())
+    }
+
+    /// Stop sending to an input port of a failing agent, or remove the breaker with `None`
+    ///
+    /// After `config.failures` panics of the agent or sends to the closed port within
+    /// `config.window`, the breaker opens: the Msgs sent to the port go to `config.fallback`,
+    /// or are dropped. After `config.cooldown`, one Msg is delivered to probe the agent, and
+    /// the breaker closes at the next send if the agent didn't fail. The state is given by
+    /// `PortState::breaker_state`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_circuit_breaker("parse", "input", Some(BreakerConfig {
+    ///     failures: 3,
+    ///     window: Duration::from_secs(10),
+    ///     cooldown: Duration::from_secs(30),
+    ///     fallback: Some(("dead_letters".into(), "input".into())),
+    /// })));
+    /// ```
+    pub fn set_circuit_breaker<'a, A, B>(&self, comp: A, port: B, config: Option<BreakerConfig>) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let panics = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))?.ctx.panics();
+        let sender = try!(self.get_sender(comp, port));
+        let fallback = match config.as_ref().and_then(|c| { c.fallback.as_ref() }) {
+            Some(&(ref agent, ref port)) => Some(try!(self.get_sender(agent as &str, port as &str))),
+            None => None,
+        };
+        sender.port().set_circuit_breaker(config, fallback, panics);
+        Ok(())
+    }
+
+    /// Change the receiver of an input port.
+    ///
+    /// Usefull for replacing a agent
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_receiver("add".into(), "input".into(), recv));
+    /// ```
+    pub fn set_receiver<'a, A, B>(&self, comp: A, port: B, receiver: MsgReceiver) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into().into_owned();
+        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
+        self.sender.send(CompMsg::SetReceiver(comp.id, port, receiver)).expect("scheduler cannot send");
+        Ok(())
+    }
+
+    /// Change the receiver of an array input port.
+    ///
+    /// Usefull for replacing a agent
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.set_array_receiver("add".into(), "inputs".into(), "1".into(), recv));
+    /// ```
+    pub fn set_array_receiver<'a, A, B, C>(&self, comp: A, port: B, element: C, receiver: MsgReceiver) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>
+    {
+        let comp = comp.into();
+        let port = port.into().into_owned();
+        let element = element.into().into_owned();
+        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
+        self.sender.send(CompMsg::AddInputArrayElement(comp.id, port, element, receiver)).expect("scheduler cannot send");
+        Ok(())
+    }
+
+    /// Get the sender of a input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let sender = try!(sched.get_sender("add", "input"));
+    /// ```
+    pub fn get_sender<'a, A, B>(&self, comp: A, port: B) -> Result<MsgSender> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                c.inputs.get(&port as &str).ok_or(result::Error::PortNotFound(comp.to_string(), port.to_string()))
+                    .map(|s| { s.clone() })
+            })
+    }
+
+    /// Get the sender of an array input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let sender = try!(sched.get_array_sender("add", "input", "1"));
+    /// ```
+    pub fn get_array_sender<'a, A, B, C>(&self, comp: A, port: B, element: C) -> Result<MsgSender> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+        C: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        let element = element.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                c.inputs_array.get(&port as &str).ok_or(result::Error::PortNotFound(comp.to_string(), port.to_string()))
+                    .and_then(|p| {
+                        p.get(&element as &str).ok_or(result::Error::ElementNotFound(comp.to_string(), port.to_string(), element.to_string()))
+                            .map(|s| { s.clone() })
+                    })
+            })
+    }
+
+    /// Get the edge of an input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let edge = try!(sched.get_schema_input("add", "input"));
+    /// ```
+    pub fn get_schema_input<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                // The input ports of a boxed agent are known from its creation
+                if c.sort == BOXED_SORT && !c.inputs.contains_key(&port as &str) {
+                    return Err(result::Error::PortNotFound(comp.to_string(), port.to_string()));
+                }
+                self.cache.get_schema_input(&c.sort, &port as &str)
+            })
+    }
+
+    /// Get the edge of an array input port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let edge = try!(sched.get_schema_input_array("add", "inputs"));
+    /// ```
+    pub fn get_schema_input_array<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                self.cache.get_schema_input_array(&c.sort, &port as &str)
+            })
+    }
+
+    /// Get the edge of an output port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let edge = try!(sched.get_schema_output("add", "output"));
+    /// ```
+    pub fn get_schema_output<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                self.cache.get_schema_output(&c.sort, &port as &str)
+            })
+    }
+
+    /// Get the edge of an array output port
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let edge = try!(sched.get_schema_output_array("add", "outputs"));
+    /// ```
+    pub fn get_schema_output_array<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let comp = comp.into();
+        let port = port.into();
+        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
+            .and_then(|c| {
+                self.cache.get_schema_output_array(&c.sort, &port as &str)
+            })
+    }
+
+    /// Get the graph of the agents and their connections, in the Graphviz DOT format
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let dot = sched.to_dot();
+    /// try!(file.write_all(dot.as_bytes()));
+    /// ```
+    pub fn to_dot(&self) -> String {
+        let mut names: Vec<&String> = self.agents.keys().collect();
+        names.sort();
+        let mut dot = String::from("digraph fbp {\n");
+        for name in names {
+            dot.push_str(&format!("    \"{}\";\n", dot_escape(name)));
+        }
+        for edge in &self.edges {
+            dot.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{} -> {}\"];\n",
+                                  dot_escape(&edge.out_agent),
+                                  dot_escape(&edge.in_agent),
+                                  dot_escape(&Edge::label(&edge.out_port, &edge.out_element)),
+                                  dot_escape(&Edge::label(&edge.in_port, &edge.in_element))));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Get the agents and the edges of the graph, to build it again with `from_topology`
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let topology = sched.topology();
+    /// try!(file.write_all(topology.to_string().as_bytes()));
+    /// ```
+    pub fn topology(&self) -> Topology {
+        let mut agents: Vec<AgentDesc> = self.agents.iter()
+            .map(|(name, comp)| { AgentDesc { name: name.clone(), sort: comp.sort.clone() } })
+            .collect();
+        agents.sort_by(|a, b| { a.name.cmp(&b.name) });
+        Topology {
+            agents: agents,
+            edges: self.edges.clone(),
+        }
+    }
+
+    /// Create a new scheduler with the agents and the edges of `topology`
+    ///
+    /// The agents are loaded from their dylib, the agents of `add_agent_boxed` cannot be built
+    /// again. The elements of the array input ports are added for the edges going to them.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// let topology: Topology = try!(text.parse());
+    /// let mut sched = try!(Scheduler::from_topology(topology));
+    /// try!(sched.start());
+    /// ```
+    pub fn from_topology(topology: Topology) -> Result<Scheduler> {
+        let mut sched = Scheduler::new();
+        match sched.build_topology(&topology) {
+            Ok(()) => Ok(sched),
+            Err(e) => {
+                sched.join();
+                Err(e)
+            },
+        }
+    }
+
+    fn build_topology(&mut self, topology: &Topology) -> Result<()> {
+        for agent in &topology.agents {
+            if agent.sort == BOXED_SORT {
+                return Err(result::Error::BadTopology(format!("the agent {} was added in the process, it has no dylib", agent.name)));
+            }
+            try!(self.add_node(&agent.name as &str, &agent.sort as &str));
+        }
+        // Keep all the edges of an output port, `Replace` would keep only the last one
+        let fanout = mem::replace(&mut self.fanout, FanoutPolicy::Broadcast);
+        for edge in &topology.edges {
+            if let Err(e) = self.connect_edge(edge) {
+                self.fanout = fanout;
+                return Err(e);
+            }
+        }
+        self.fanout = fanout;
+        for edge in topology.edges.iter().filter(|e| { e.feedback }) {
+            try!(self.mark_feedback(&edge.out_agent as &str, &edge.out_port as &str));
+        }
+        Ok(())
+    }
+
+    /// Connect `edge`, with the `connect` method matching its elements
+    ///
+    /// The element of an array input port is added if it doesn't exist. The `feedback` of the
+    /// edge is ignored, see `mark_feedback`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// for edge in &topology.edges {
+    ///     try!(sched.connect_edge(edge));
+    /// }
+    /// ```
+    pub fn connect_edge(&mut self, edge: &Edge) -> Result<()> {
+        let (out_agent, out_port, in_agent, in_port) = (&edge.out_agent as &str, &edge.out_port as &str, &edge.in_agent as &str, &edge.in_port as &str);
+        match (edge.out_element.as_ref(), edge.in_element.as_ref()) {
+            (None, None) => self.connect(out_agent, out_port, in_agent, in_port),
+            (Some(out_element), None) => self.connect_array(out_agent, out_port, out_element as &str, in_agent, in_port),
+            (None, Some(in_element)) => {
+                try!(self.soft_add_input_array_element(in_agent, in_port, in_element as &str));
+                self.connect_to_array(out_agent, out_port, in_agent, in_port, in_element as &str)
+            },
+            (Some(out_element), Some(in_element)) => {
+                try!(self.soft_add_input_array_element(in_agent, in_port, in_element as &str));
+                self.connect_array_to_array(out_agent, out_port, out_element as &str, in_agent, in_port, in_element as &str)
+            },
+        }
+    }
+
+    /// Connect simple ports as `connect`, all of them or none
+    ///
+    /// All the edges are tried. If some fail, the output ports connected by the others get
+    /// back their connections of before the call, and the failures are returned with the
+    /// index of their edge.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// if let Err(failures) = sched.connect_many(&[("add", "output", "display", "input"),
+    ///                                            ("clock", "output", "add", "input")]) {
+    ///     for (i, e) in failures {
+    ///         println!("edge {} : {}", i, e);
+    ///     }
+    /// }
+    /// ```
+    pub fn connect_many(&mut self, edges: &[(&str, &str, &str, &str)]) -> ::std::result::Result<(), Vec<(usize, result::Error)>> {
+        let before = self.edges.clone();
+        let mut connected: Vec<(String, String)> = vec![];
+        let mut failures = vec![];
+        for (i, &(comp_out, port_out, comp_in, port_in)) in edges.iter().enumerate() {
+            match self.connect(comp_out, port_out, comp_in, port_in) {
+                Ok(()) => {
+                    let output = (comp_out.to_string(), port_out.to_string());
+                    if !connected.contains(&output) {
+                        connected.push(output);
+                    }
+                },
+                Err(e) => { failures.push((i, e)); },
+            }
+        }
+        if failures.is_empty() {
+            return Ok(());
+        }
+        for &(ref agent, ref port) in &connected {
+            let on_port = |e: &Edge| { &e.out_agent == agent && &e.out_port == port && e.out_element.is_none() };
+            self.remove_edges(&on_port);
+            let previous: Vec<Edge> = before.iter().filter(|e| { on_port(e) }).cloned().collect();
+            if previous.is_empty() {
+                if let Some(comp) = self.agents.get(agent) {
+                    self.sender.send(CompMsg::Disconnect(comp.id, port.clone())).ok().expect("Scheduler connect_many: unable to send to scheduler state");
+                }
+            }
+            for edge in previous {
+                self.notify(EdgeEvent::connected(&edge));
+                self.edges.push(edge);
+            }
+        }
+        let outputs = connected.into_iter().map(|(agent, port)| { (agent, port, None) }).collect();
+        if let Err(e) = self.reconnect_outputs(outputs) {
+            warn!(target: LOG_TARGET, "connect_many : cannot restore the connections : {}", e);
+        }
+        Err(failures)
+    }
+
+    /// Report a suspected deadlock when all the running agents are blocked on a port for longer than `threshold`
+    ///
+    /// A thread checks the agents periodically. The blocked agents are printed and an
+    /// `Error::Deadlock` is sent on the `error_receiver`, once per deadlock. A new call replaces
+    /// the previous watchdog, and the watchdog ends with the scheduler.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// sched.enable_deadlock_watchdog(Duration::from_secs(5));
+    /// ```
+    pub fn enable_deadlock_watchdog(&self, threshold: Duration) {
+        let generation = self.watchdog.fetch_add(1, Ordering::SeqCst) + 1;
+        let watchdog = self.watchdog.clone();
+        let ctx = self.ctx.clone();
+        let sender = self.sender.clone();
+        let period = cmp::max(threshold / 2, Duration::from_millis(10));
+        thread::spawn(move || {
+            let mut next = Instant::now() + period;
+            loop {
+                if ctx.is_stopping() || watchdog.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= next {
+                    if sender.send(CompMsg::CheckDeadlock(threshold)).is_err() {
+                        return;
+                    }
+                    next = now + period;
+                    continue;
+                }
+                thread::sleep(cmp::min(next - now, Duration::from_millis(SIGNAL_POLL_MS)));
+            }
+        });
+    }
+
+    /// Stop the watchdog of `enable_deadlock_watchdog`
+    pub fn disable_deadlock_watchdog(&self) {
+        self.watchdog.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Get the approximate number of Msgs waiting in each input port
+    ///
+    /// The elements of the array input ports are named `port[element]`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// for (agent, port, depth) in sched.queue_depths() {
+    ///     println!("{}.{} : {}", agent, port, depth);
+    /// }
+    /// ```
+    pub fn queue_depths(&self) -> Vec<(String, String, usize)> {
+        let mut depths = vec![];
+        for (name, comp) in &self.agents {
+            for (port, sender) in &comp.inputs {
+                depths.push((name.clone(), port.clone(), sender.port().depth()));
+            }
+            for elements in comp.inputs_array.values() {
+                for sender in elements.values() {
+                    depths.push((name.clone(), sender.port().name.clone(), sender.port().depth()));
+                }
+            }
+        }
+        depths.sort();
+        depths
+    }
+
+    /// Get a snapshot of the whole graph, to see where it hangs
+    ///
+    /// Gathers the status of each agent with the port it is blocked on, the depths of its
+    /// input ports, its metrics and the edges. The parts are read one after the other from
+    /// a running graph, they can be slightly inconsistent. The metrics are only counted while
+    /// they are enabled, see `enable_metrics`.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// println!("{}", sched.dump_state());
+    /// ```
+    pub fn dump_state(&self) -> NetworkState {
+        let (s, r) = channel();
+        self.sender.send(CompMsg::Status(s)).ok().expect("Scheduler dump_state : Cannot send Status");
+        let mut statuses: HashMap<usize, AgentStatus> = HashMap::new();
+        for (id, mut status, blocked_on) in r.recv().unwrap_or(vec![]) {
+            if let (&mut AgentStatus::Blocked { ref mut port, .. }, Some(blocked_on)) = (&mut status, blocked_on) {
+                if let Some(owner) = self.port_owner(&blocked_on) {
+                    *port = format!("{}.{}", owner, port);
+                }
+            }
+            statuses.insert(id, status);
+        }
+        let mut depths: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+        for (agent, port, depth) in self.queue_depths() {
+            depths.entry(agent).or_insert(vec![]).push((port, depth));
+        }
+        let mut agents: Vec<AgentState> = self.agents.iter()
+            .map(|(name, comp)| {
+                AgentState {
+                    name: name.clone(),
+                    sort: comp.sort.clone(),
+                    status: statuses.get(&comp.id).cloned().unwrap_or(AgentStatus::Dead),
+                    depths: depths.remove(name).unwrap_or(vec![]),
+                    metrics: comp.ctx.metrics(),
+                }
+            })
+            .collect();
+        agents.sort_by(|a, b| { a.name.cmp(&b.name) });
+        NetworkState {
+            agents: agents,
+            edges: self.edges.clone(),
+            stopping: self.ctx.is_stopping(),
+        }
+    }
+
+    /// The agent of an input port
+    fn port_owner(&self, port: &Arc<PortState>) -> Option<&str> {
+        self.agents.iter()
+            .find(|&(_, comp)| {
+                comp.inputs.values().chain(comp.inputs_array.values().flat_map(|elements| { elements.values() }))
+                    .any(|sender| { Arc::ptr_eq(sender.port(), port) })
+            })
+            .map(|(name, _)| { name as &str })
+    }
+
+    /// Call `callback` with each Msg sent to an input port, before the agent receives it
+    ///
+    /// The callback runs in the thread of the sending agent, it must be cheap. Tracing a port
+    /// again replaces the callback.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.trace_port("display", "input", Box::new(|msg: &Msg| {
+    ///     println!("display input: {} bytes", msg.vec.len());
+    /// })));
+    /// ```
+    pub fn trace_port<'a, A, B>(&self, comp: A, port: B, callback: Box<Fn(&Msg) + Send>) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let sender = try!(self.get_sender(comp, port));
+        sender.port().set_tracer(Some(callback));
+        Ok(())
+    }
+
+    /// Stop tracing an input port
+    pub fn untrace_port<'a, A, B>(&self, comp: A, port: B) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        B: Into<Cow<'a, str>>,
+    {
+        let sender = try!(self.get_sender(comp, port));
+        sender.port().set_tracer(None);
+        Ok(())
+    }
+
+    /// Record the Msgs received by an agent in a file, to replay them with a `Player`
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// try!(sched.record_agent("add", "/tmp/add.rec"));
+    /// ```
+    pub fn record_agent<'a, A, P>(&self, name: A, path: P) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+        P: AsRef<Path>,
+    {
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        let recorder = try!(Recorder::create(path));
+        comp.ctx.set_recorder(Some(Arc::new(recorder)));
+        Ok(())
+    }
+
+    /// Stop recording an agent, the recording file is closed
+    pub fn stop_recording<'a, A>(&self, name: A) -> Result<()> where
+        A: Into<Cow<'a, str>>,
+    {
+        let name = name.into();
+        let comp = self.agents.get(&name as &str).ok_or(result::Error::AgentNotFound(name.into_owned()))?;
+        comp.ctx.set_recorder(None);
+        Ok(())
+    }
+
+    /// Enable or disable the counting of the Msgs received and sent by each agent
+    ///
+    /// The metrics are disabled by default.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// sched.enable_metrics(true);
+    /// ```
+    pub fn enable_metrics(&self, on: bool) {
+        self.ctx.metrics_on.store(on, Ordering::SeqCst);
+    }
+
+    /// Stamp each Msg with the time of its first send, for `Msg::age`
+    ///
+    /// A forwarded Msg keeps its stamp, so a sink sees the time spent since the Msg entered
+    /// the graph. Only the Msgs sent after the call are stamped.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// sched.enable_latency_tracking();
+    /// ```
+    pub fn enable_latency_tracking(&self) {
+        self.ctx.latency_on.store(true, Ordering::SeqCst);
+    }
+
+    /// Count the memory allocated and freed by each agent in its `run`, see `AgentMetrics`
+    ///
+    /// Needs the `memory-tracking` feature, which replaces the global allocator and a rustc of
+    /// at least 1.28. Without it, a warning is printed and the counts stay at 0. The tracking is
+    /// for the whole process, and it slows down the allocations: it is a diagnostic for a
+    /// leaking agent, see the `memory` module for its approximations.
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// sched.enable_memory_tracking();
+    /// // ...
+    /// println!("add holds {} bytes", sched.metrics()["add"].live_bytes);
+    /// ```
+    pub fn enable_memory_tracking(&self) {
+        if !memory::enable() {
+            warn!(target: LOG_TARGET, "cannot track the memory of the agents, rustfbp is built without the memory-tracking feature");
+        }
+    }
+
+    /// Get a snapshot of the metrics of all the agents
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(sched.set_array_receiver("add".into(), "inputs".into(), "1".into(), recv));
+    /// let metrics = sched.metrics();
+    /// println!("add received {} Msgs", metrics["add"].received);
     /// ```
-    pub fn set_array_receiver<'a, A, B, C>(&self, comp: A, port: B, element: C, receiver: MsgReceiver) -> Result<()> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>
-    {
-        let comp = comp.into();
-        let port = port.into().into_owned();
-        let element = element.into().into_owned();
-        let comp = self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.into_owned()))?;
-        self.sender.send(CompMsg::AddInputArrayElement(comp.id, port, element, receiver)).expect("scheduler cannot send");
-        Ok(())
+    pub fn metrics(&self) -> HashMap<String, AgentMetrics> {
+        self.agents.iter()
+            .map(|(name, comp)| (name.clone(), comp.ctx.metrics()))
+            .collect()
     }
 
-    /// Get the sender of a input port
+    /// Get the metrics of all the agents since the last call, or since the start
+    ///
+    /// Each call keeps the metrics of the agents, the next call returns the difference. Divided
+    /// by the time between the calls, it gives the rates of the agents. There is one sampler
+    /// per scheduler: two pollers calling it would each see a part of the counts. A poller
+    /// needing its own rates keeps the `metrics` of its last call and uses `AgentMetrics::since`.
     ///
     /// # Example
     /// ```rust,ignore
-    /// let sender = try!(sched.get_sender("add", "input"));
+    /// let delta = sched.metrics_delta();
+    /// thread::sleep(Duration::from_secs(10));
+    /// let delta = sched.metrics_delta();
+    /// println!("add received {} Msgs/s", delta["add"].received as f64 / 10.0);
     /// ```
-    pub fn get_sender<'a, A, B>(&self, comp: A, port: B) -> Result<MsgSender> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                c.inputs.get(&port as &str).ok_or(result::Error::PortNotFound(comp.to_string(), port.to_string()))
-                    .map(|s| { s.clone() })
+    pub fn metrics_delta(&mut self) -> HashMap<String, AgentMetrics> {
+        let now = self.metrics();
+        let delta = now.iter()
+            .map(|(name, metrics)| {
+                let delta = match self.sampled.get(name) {
+                    Some(earlier) => metrics.since(earlier),
+                    None => metrics.clone(),
+                };
+                (name.clone(), delta)
             })
+            .collect();
+        self.sampled = now;
+        delta
     }
 
-    /// Get the sender of an array input port
+    /// Set the metrics of all the agents to 0, and forget the last `metrics_delta`
+    ///
+    /// A Msg counted during the reset can be lost, the counts are not reset at once.
+    pub fn reset_metrics(&mut self) {
+        for comp in self.agents.values() {
+            comp.ctx.counters.reset();
+        }
+        self.sampled.clear();
+    }
+
+    /// Wait for the end of the scheduler
     ///
     /// # Example
     /// ```rust,ignore
-    /// let sender = try!(sched.get_array_sender("add", "input", "1"));
+    /// sched.join();
+    /// // The sched is terminated
     /// ```
-    pub fn get_array_sender<'a, A, B, C>(&self, comp: A, port: B, element: C) -> Result<MsgSender> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-        C: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        let element = element.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                c.inputs_array.get(&port as &str).ok_or(result::Error::PortNotFound(comp.to_string(), port.to_string()))
-                    .and_then(|p| {
-                        p.get(&element as &str).ok_or(result::Error::ElementNotFound(comp.to_string(), port.to_string(), element.to_string()))
-                            .map(|s| { s.clone() })
-                    })
-            })
+    pub fn join(self) {
+        self.sender.send(CompMsg::HaltState).ok().expect("Scheduler join : Cannot send HaltState");
+        self.th.join().ok().expect("Scheduelr join : Cannot join the thread");
     }
 
-    /// Get the edge of an input port
+    /// Stop the scheduler gracefully
+    ///
+    /// No new run of an agent is started. The running agents can still receive the IPs already
+    /// in their input ports, then a receive on an empty port returns `Error::Stopping`.
+    ///
+    /// If some agents are still running after `timeout`, the scheduler is halted without them,
+    /// and their names are returned in `Error::ShutdownTimeout`. They are left to their
+    /// threads, and the end of their run is ignored.
     ///
     /// # Example
     /// ```rust,ignore
-    /// let edge = try!(sched.get_schema_input("add", "input"));
+    /// try!(sched.shutdown(Duration::from_secs(5)));
+    /// // The sched is terminated
     /// ```
-    pub fn get_schema_input<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                self.cache.get_schema_input(&c.sort, &port as &str)
-            })
+    pub fn shutdown(self, timeout: Duration) -> Result<()> {
+        self.ctx.stop();
+        // The scheduler thread already halted if a signal or a timer shut it down
+        if self.sender.send(CompMsg::Shutdown).is_ok() && self.done.recv_timeout(timeout).is_err() {
+            let (s, r) = channel();
+            let running = match self.sender.send(CompMsg::Running(s)) {
+                Ok(()) => r.recv().unwrap_or(vec![]),
+                Err(_) => vec![],
+            };
+            if !running.is_empty() {
+                let _ = self.sender.send(CompMsg::Halt);
+                self.th.join().ok().expect("Scheduler shutdown : Cannot join the thread");
+                return Err(result::Error::ShutdownTimeout(running));
+            }
+        }
+        self.th.join().ok().expect("Scheduler shutdown : Cannot join the thread");
+        Ok(())
     }
 
-    /// Get the edge of an array input port
+    /// Shut down the scheduler gracefully on SIGTERM or SIGINT, as `shutdown`
+    ///
+    /// Needs the `signals` feature, on Unix. The signal handler only sets a flag: a thread
+    /// checks it every 50ms, then stops the scheduler. As with `shutdown`, the running agents
+    /// drain the IPs already in their input ports, and the agents still running after `timeout`
+    /// are logged. The handler is for the whole process: all the schedulers with a handler are
+    /// shut down by the signal. Only the first signal counts, a second SIGTERM or SIGINT
+    /// doesn't cut the shutdown short.
     ///
     /// # Example
     /// ```rust,ignore
-    /// let edge = try!(sched.get_schema_input_array("add", "inputs"));
+    /// try!(sched.install_signal_handler(Duration::from_secs(10)));
+    /// try!(sched.start());
+    /// sched.wait_shutdown();
+    /// // The sched is terminated
     /// ```
-    pub fn get_schema_input_array<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                self.cache.get_schema_input_array(&c.sort, &port as &str)
-            })
+    pub fn install_signal_handler(&self, timeout: Duration) -> Result<()> {
+        try!(signal::install());
+        let ctx = self.ctx.clone();
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let signum;
+            loop {
+                thread::sleep(Duration::from_millis(SIGNAL_POLL_MS));
+                if let Some(s) = signal::received() {
+                    signum = s;
+                    break;
+                }
+                if ctx.is_stopping() {
+                    return;
+                }
+            }
+            info!(target: LOG_TARGET, "signal {} received, shutting down the scheduler", signum);
+            ctx.stop();
+            if sender.send(CompMsg::Shutdown).is_err() {
+                return;
+            }
+            thread::sleep(timeout);
+            let (s, r) = channel();
+            if sender.send(CompMsg::Running(s)).is_ok() {
+                if let Ok(names) = r.recv() {
+                    if !names.is_empty() {
+                        warn!(target: LOG_TARGET, "agents {} did not stop {:?} after the signal", names.join(", "), timeout);
+                    }
+                }
+            }
+        });
+        Ok(())
     }
 
-    /// Get the edge of an output port
+    /// Shut down the scheduler gracefully once `duration` elapsed, as a signal does
+    ///
+    /// A thread stops the scheduler at the end of `duration`, unless it is already stopping or
+    /// `cancel_scheduled_shutdown` was called. A new call replaces the previous timer. The end
+    /// of the scheduler is waited with `wait_shutdown`.
     ///
     /// # Example
     /// ```rust,ignore
-    /// let edge = try!(sched.get_schema_output("add", "output"));
+    /// try!(sched.start());
+    /// sched.shutdown_after(Duration::from_secs(60));
+    /// sched.wait_shutdown();
     /// ```
-    pub fn get_schema_output<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                self.cache.get_schema_output(&c.sort, &port as &str)
-            })
+    pub fn shutdown_after(&self, duration: Duration) {
+        let generation = self.shutdown_timer.fetch_add(1, Ordering::SeqCst) + 1;
+        let timer = self.shutdown_timer.clone();
+        let ctx = self.ctx.clone();
+        let sender = self.sender.clone();
+        let deadline = Instant::now() + duration;
+        thread::spawn(move || {
+            loop {
+                if ctx.is_stopping() || timer.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                thread::sleep(cmp::min(deadline - now, Duration::from_millis(SIGNAL_POLL_MS)));
+            }
+            info!(target: LOG_TARGET, "shutting down the scheduler after {:?}", duration);
+            ctx.stop();
+            let _ = sender.send(CompMsg::Shutdown);
+        });
     }
 
-    /// Get the edge of an array output port
+    /// Cancel the shutdown of `shutdown_after`, if it didn't start yet
+    pub fn cancel_scheduled_shutdown(&self) {
+        self.shutdown_timer.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Return true once the scheduler is shutting down
+    pub fn is_stopping(&self) -> bool {
+        self.ctx.is_stopping()
+    }
+
+    /// Wait for the end of the scheduler, after a shutdown by a signal or a timer
+    ///
+    /// Blocks until all the agents are stopped, see `install_signal_handler` and
+    /// `shutdown_after`.
+    pub fn wait_shutdown(self) {
+        let _ = self.done.recv();
+        self.th.join().ok().expect("Scheduler wait_shutdown : Cannot join the thread");
+    }
+
+    /// Ask the scheduler thread if the graph is idle now
+    fn is_idle(&self) -> Result<bool> {
+        let (s, r) = channel();
+        self.sender.send(CompMsg::Idle(s)).ok().expect("Scheduler is_idle : Cannot send Idle");
+        Ok(try!(r.recv()))
+    }
+
+    /// Wait until the graph is idle, and keep it running
+    ///
+    /// The graph is idle as for `run_until_idle`, but it is not stopped: more Msgs can be
+    /// injected once it returns, to run a graph step by step. Returns false if the graph is
+    /// still busy after `timeout`.
     ///
     /// # Example
     /// ```rust,ignore
-    /// let edge = try!(sched.get_schema_output_array("add", "outputs"));
+    /// try!(sched.inject("parse", "input", first));
+    /// if try!(sched.wait_for_idle(Duration::from_secs(5))) {
+    ///     try!(sched.inject("parse", "input", second));
+    /// }
     /// ```
-    pub fn get_schema_output_array<'a, A, B>(&self, comp: A, port: B) -> Result<String> where
-        A: Into<Cow<'a, str>>,
-        B: Into<Cow<'a, str>>,
-    {
-        let comp = comp.into();
-        let port = port.into();
-        self.agents.get(&comp as &str).ok_or(result::Error::AgentNotFound(comp.to_string()))
-            .and_then(|c| {
-                self.cache.get_schema_output_array(&c.sort, &port as &str)
-            })
+    pub fn wait_for_idle(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut idle = 0;
+        while idle < 2 {
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(IDLE_POLL_MS));
+            idle = if try!(self.is_idle()) { idle + 1 } else { 0 };
+        }
+        Ok(true)
     }
 
-    /// Wait for the end of the scheduler
+    /// Run a finite graph until all the Msgs are processed, then stop it gracefully
+    ///
+    /// The graph is idle when no Msg is in flight, sent to a port but not yet received, and
+    /// each running agent is blocked on an empty input port. It must be seen idle twice in a
+    /// row, 20ms apart, as an Msg sent from outside the graph is counted shortly
+    /// after it is in the port. A paused agent with Msgs in its ports keeps the graph busy.
+    ///
+    /// Returns the metrics of the agents once they are stopped.
     ///
     /// # Example
     /// ```rust,ignore
-    /// sched.join();
+    /// sched.enable_metrics(true);
+    /// try!(sched.start());
+    /// let report = try!(sched.run_until_idle());
     /// // The sched is terminated
+    /// println!("{} Msgs in {:?}", report.total_ips, report.wall_time);
     /// ```
-    pub fn join(self) {
-        self.sender.send(CompMsg::HaltState).ok().expect("Scheduler join : Cannot send HaltState");
-        self.th.join().ok().expect("Scheduelr join : Cannot join the thread");
+    pub fn run_until_idle(self) -> Result<RunReport> {
+        let started = self.started.unwrap_or(Instant::now());
+        let mut idle = 0;
+        while idle < 2 {
+            thread::sleep(Duration::from_millis(IDLE_POLL_MS));
+            idle = if try!(self.is_idle()) { idle + 1 } else { 0 };
+        }
+        let mut agents: Vec<(String, AgentCtx)> = self.agents.iter()
+            .map(|(name, comp)| { (name.clone(), comp.ctx.clone()) })
+            .collect();
+        agents.sort_by(|a, b| { a.0.cmp(&b.0) });
+        try!(self.shutdown(Duration::from_secs(IDLE_SHUTDOWN_SECS)));
+        let per_agent: Vec<AgentReport> = agents.into_iter()
+            .map(|(name, ctx)| {
+                let metrics = ctx.metrics();
+                AgentReport {
+                    name: name,
+                    received: metrics.received,
+                    sent: metrics.sent,
+                    running: metrics.running,
+                    blocked: metrics.blocked,
+                }
+            })
+            .collect();
+        Ok(RunReport {
+            total_ips: per_agent.iter().map(|a| { a.received }).sum(),
+            per_agent: per_agent,
+            wall_time: started.elapsed(),
+        })
     }
 }
 
@@ -712,9 +2886,11 @@ enum EditCmp {
     AddOutputArrayElement(String, String),
     ConnectOutputPort(String, MsgSender),
     ConnectOutputArrayPort(String, String, MsgSender),
-    SetReceiver(String, Receiver<Msg>),
+    SetReceiver(String, MsgReceiver),
     Disconnect(String),
     DisconnectArray(String, String),
+    Checkpoint(String, Sender<(String, Option<Vec<u8>>)>),
+    Restore(Vec<u8>),
 }
 
 /// To be removed, replace by async msg
@@ -727,11 +2903,32 @@ pub enum SyncMsg {
 struct CompState {
     comp: Option<BoxedComp>,
     name: String,
+    ctx: AgentCtx,
     // TODO : manage can_run
     is_run: bool,
     can_run: bool,
     edit_msgs: Vec<EditCmp>,
     ips: isize,
+    restart: RestartPolicy,
+    restarts: usize,
+    dead: bool,
+    paused: bool,
+    started: bool,
+    reload: Option<(BoxedComp, Sender<()>)>,
+    /// The own thread of the agent, it receives the agent to run
+    thread: Option<Sender<(BoxedComp, bool, Option<Vec<u8>>, Option<Vec<u8>>)>>,
+    /// The CPUs of the own thread
+    cpus: Option<Vec<usize>>,
+    /// The stack size of the own thread, in bytes
+    stack_size: Option<usize>,
+    /// The base priority in the pool
+    priority: i32,
+    /// Running in the pool
+    in_pool: bool,
+    /// The state to load after `on_start`
+    restore: Option<Vec<u8>>,
+    /// The config given before each `on_start`
+    config: Option<Vec<u8>>,
 }
 
 /// The state of the internal scheduler
@@ -740,47 +2937,74 @@ struct SchedState {
     agents: HashMap<usize, CompState>,
     running: usize,
     can_halt: bool,
+    ctx: AgentCtx,
+    deadlock: bool,
     pool: ThreadPool,
+    /// The runs in the pool
+    pool_running: usize,
+    /// The agents waiting for a thread of the pool, since the Instant
+    ready: Vec<(usize, Instant)>,
+    aging: f64,
 }
 
 impl SchedState {
-    fn new(s: Sender<CompMsg>) -> Self {
+    fn new(s: Sender<CompMsg>, ctx: AgentCtx) -> Self {
         SchedState {
             sched_sender: s,
             agents: HashMap::new(),
             running: 0,
             can_halt: false,
-            pool: ThreadPool::new(8),
+            ctx: ctx,
+            deadlock: false,
+            pool: ThreadPool::new_with_name("rustfbp".into(), POOL_THREADS),
+            pool_running: 0,
+            ready: vec![],
+            aging: DEFAULT_AGING_RATE,
         }
     }
 
-    fn inc(&mut self, id: usize) -> Result<()> {
+    fn inc(&mut self, id: usize, n: isize) -> Result<()> {
         // silent error for exterior ports
         let mut start = false;
+        let stopping = self.ctx.is_stopping();
         if let Some(ref mut comp) = self.agents.get_mut(&id) {
-            comp.ips += 1;
-            start = comp.ips > 0 && comp.comp.is_some();
+            comp.ips += n;
+            start = comp.ips > 0 && comp.comp.is_some() && !comp.paused && !stopping;
         }
         if start { self.run(id); }
         Ok(())
     }
 
-    fn dec(&mut self, id: usize) -> Result<()> {
+    fn dec(&mut self, id: usize, n: isize) -> Result<()> {
         // silent error for exterior ports
         if let Some(ref mut comp) = self.agents.get_mut(&id) {
-            comp.ips -= 1;
+            comp.ips -= n;
         }
         Ok(())
     }
 
-    fn new_agent(&mut self, id: usize, name: String, comp: BoxedComp) -> Result<()> {
+    fn new_agent(&mut self, id: usize, name: String, comp: BoxedComp, ctx: AgentCtx) -> Result<()> {
         self.agents.insert(id, CompState {
             comp: Some(comp),
             name: name,
+            ctx: ctx,
             is_run: false,
             can_run: false,
             edit_msgs: vec![],
             ips: 0,
+            restart: RestartPolicy::Never,
+            restarts: 0,
+            dead: false,
+            paused: false,
+            started: false,
+            reload: None,
+            thread: None,
+            cpus: None,
+            stack_size: None,
+            priority: 0,
+            in_pool: false,
+            restore: None,
+            config: None,
         });
         Ok(())
     }
@@ -802,10 +3026,11 @@ impl SchedState {
     }
 
     fn start(&mut self, id: usize) -> Result<()> {
+        let stopping = self.ctx.is_stopping();
         let start = {
             let mut comp = self.agents.get_mut(&id).expect("SchedState start : agent not found");
             comp.can_run = true;
-            comp.comp.is_some()
+            comp.comp.is_some() && !comp.paused && !stopping
         };
         if start {
             self.run(id);
@@ -821,45 +3046,410 @@ impl SchedState {
         Ok(())
     }
 
+    fn shutdown(&mut self) -> Result<()> {
+        // The idle agents will not be run anymore
+        for comp in self.agents.values_mut() {
+            if comp.comp.is_some() && comp.is_run {
+                self.running -= 1;
+                comp.is_run = false;
+            }
+        }
+        self.halt()
+    }
+
+    fn running(&mut self, sync_sender: Sender<Vec<String>>) -> Result<()> {
+        let names = self.agents.values()
+            .filter(|comp| { !comp.dead && (comp.comp.is_none() || comp.is_run) })
+            .map(|comp| { comp.name.clone() })
+            .collect();
+        sync_sender.send(names).expect("SchedState running : cannot send to the channel");
+        Ok(())
+    }
+
+    fn status(&mut self, sync_sender: Sender<Vec<(usize, AgentStatus, Option<Arc<PortState>>)>>) -> Result<()> {
+        let statuses = self.agents.iter()
+            .map(|(id, comp)| {
+                let mut blocked_on = None;
+                let status = if comp.dead {
+                    AgentStatus::Dead
+                } else if comp.paused {
+                    AgentStatus::Paused
+                } else if comp.comp.is_none() {
+                    match comp.ctx.blocked() {
+                        Some(b) => {
+                            let status = AgentStatus::Blocked { port: b.port.name.clone(), sending: b.sending, waited: b.since.elapsed() };
+                            blocked_on = Some(b.port);
+                            status
+                        },
+                        None => AgentStatus::Running,
+                    }
+                } else if self.ready.iter().any(|&(ready, _)| { ready == *id }) {
+                    AgentStatus::Ready
+                } else {
+                    AgentStatus::Idle
+                };
+                (*id, status, blocked_on)
+            })
+            .collect();
+        sync_sender.send(statuses).expect("SchedState status : cannot send to the channel");
+        Ok(())
+    }
+
+    fn idle(&mut self, sync_sender: Sender<bool>) -> Result<()> {
+        let in_flight: isize = self.agents.values().filter(|comp| { !comp.dead }).map(|comp| { comp.ips }).sum();
+        let busy = self.agents.values()
+            .filter(|comp| { !comp.dead && comp.comp.is_none() })
+            .any(|comp| {
+                match comp.ctx.blocked() {
+                    Some(ref b) => b.sending,
+                    None => true,
+                }
+            }) || !self.ready.is_empty();
+        sync_sender.send(in_flight == 0 && !busy).expect("SchedState idle : cannot send to the channel");
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, id: usize, sync_sender: Sender<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let dead = {
+            let comp = self.agents.get(&id).expect("SchedState checkpoint : agent doesn't exist");
+            if comp.dead {
+                let _ = sync_sender.send((comp.name.clone(), None));
+            }
+            comp.dead
+        };
+        if !dead {
+            let name = self.agents[&id].name.clone();
+            try!(self.edit_agent(id, EditCmp::Checkpoint(name, sync_sender)));
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, id: usize, data: Vec<u8>) -> Result<()> {
+        let started = {
+            let comp = self.agents.get_mut(&id).expect("SchedState restore : agent doesn't exist");
+            if !comp.started {
+                comp.restore = Some(data.clone());
+            }
+            comp.started
+        };
+        if started {
+            try!(self.edit_agent(id, EditCmp::Restore(data)));
+        }
+        Ok(())
+    }
+
+    fn configure(&mut self, id: usize, config: Vec<u8>) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState configure : agent doesn't exist");
+        comp.config = Some(config);
+        Ok(())
+    }
+
+    fn reload(&mut self, id: usize, new_comp: BoxedComp, sync_sender: Sender<()>) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState reload : agent doesn't exist");
+        if let Some(old_comp) = mem::replace(&mut comp.comp, None) {
+            comp.comp = Some(Self::swap_agent(old_comp, new_comp, &mut comp.started, &comp.name));
+            let _ = sync_sender.send(());
+        } else {
+            // Running, replaced at RunEnd
+            comp.reload = Some((new_comp, sync_sender));
+        }
+        Ok(())
+    }
+
+    /// Move the ports of `old_comp` to `new_comp`, and drop `old_comp`
+    fn swap_agent(mut old_comp: BoxedComp, mut new_comp: BoxedComp, started: &mut bool, name: &str) -> BoxedComp {
+        if *started {
+            match old_comp.on_stop() {
+                Ok(()) => { info!(target: LOG_TARGET, "{} stopped", name); },
+                Err(e) => { error!(target: LOG_TARGET, "{} fails to stop : {}", name, e); },
+            }
+            *started = false;
+        }
+        let ports = old_comp.into_ports();
+        new_comp.set_ports(ports);
+        new_comp
+    }
+
+    fn check_deadlock(&mut self, threshold: Duration) -> Result<()> {
+        let now = Instant::now();
+        let mut running = 0;
+        let mut all_blocked = true;
+        let mut states = vec![];
+        for comp in self.agents.values().filter(|comp| { comp.comp.is_none() && !comp.dead }) {
+            running += 1;
+            match comp.ctx.blocked() {
+                Some(ref b) if now.duration_since(b.since) >= threshold => {
+                    let dest = self.agents.get(&b.port.agent).map(|c| { &c.name as &str }).unwrap_or("?");
+                    states.push(format!("{} is {} {}.{} ({} Msgs waiting)",
+                                        comp.name,
+                                        if b.sending { "sending to" } else { "receiving on" },
+                                        dest,
+                                        b.port.name,
+                                        b.port.depth()));
+                },
+                _ => { all_blocked = false; },
+            }
+        }
+        let deadlock = running > 0 && all_blocked;
+        let report = deadlock && !self.deadlock;
+        self.deadlock = deadlock;
+        if report {
+            states.sort();
+            warn!(target: LOG_TARGET, "Suspected deadlock :\n    {}", states.join("\n    "));
+            return Err(result::Error::Deadlock(states));
+        }
+        Ok(())
+    }
+
+    /// Call `on_stop` on the started agents that are not running
+    fn stop_agents(&mut self) -> Vec<result::Error> {
+        let mut errors = vec![];
+        for comp in self.agents.values_mut() {
+            if !comp.started {
+                continue;
+            }
+            if let Some(ref mut c) = comp.comp {
+                comp.started = false;
+                match c.on_stop() {
+                    Ok(()) => { info!(target: LOG_TARGET, "{} stopped", comp.name); },
+                    Err(e) => {
+                        error!(target: LOG_TARGET, "{} fails to stop : {}", comp.name, e);
+                        errors.push(e);
+                    },
+                }
+            }
+        }
+        errors
+    }
+
+    fn pause(&mut self, id: usize) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState pause : agent doesn't exist");
+        comp.paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self, id: usize) -> Result<()> {
+        let stopping = self.ctx.is_stopping();
+        let start = {
+            let comp = self.agents.get_mut(&id).expect("SchedState resume : agent doesn't exist");
+            comp.paused = false;
+            comp.ips > 0 && comp.comp.is_some() && !stopping
+        };
+        if start {
+            self.run(id);
+        }
+        Ok(())
+    }
+
+    fn own_thread(&mut self, id: usize, cpus: Option<Vec<usize>>) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState own_thread : agent doesn't exist");
+        let (s, r) = channel::<(BoxedComp, bool, Option<Vec<u8>>, Option<Vec<u8>>)>();
+        let sched_s = self.sched_sender.clone();
+        let name = comp.name.clone();
+        let ctx = comp.ctx.clone();
+        comp.cpus = cpus.clone();
+        let mut builder = thread::Builder::new().name(comp.name.clone());
+        if let Some(size) = comp.stack_size {
+            builder = builder.stack_size(size);
+        }
+        try!(builder.spawn(move || {
+            if let Some(cpus) = cpus {
+                pin_thread(&name, &cpus);
+            }
+            for (mut b_comp, first, config, restore) in r {
+                let start = Instant::now();
+                let res = {
+                    let _memory = ctx.track_memory();
+                    trace::in_span(&name, || { run_agent(&mut b_comp, first, config, restore) })
+                };
+                ctx.on_run(start.elapsed());
+                if sched_s.send(CompMsg::RunEnd(id, b_comp, res)).is_err() {
+                    break;
+                }
+            }
+        }));
+        comp.thread = Some(s);
+        Ok(())
+    }
+
+    fn stack_size(&mut self, id: usize, size: usize) -> Result<()> {
+        let cpus = {
+            let comp = self.agents.get_mut(&id).expect("SchedState stack_size : agent doesn't exist");
+            comp.stack_size = Some(size);
+            comp.cpus.clone()
+        };
+        // The previous own thread ends once its sender is replaced
+        self.own_thread(id, cpus)
+    }
+
+    fn base_priority(&mut self, id: usize, level: i32) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState base_priority : agent doesn't exist");
+        comp.priority = level;
+        Ok(())
+    }
+
+    fn aging_rate(&mut self, rate: f64) -> Result<()> {
+        self.aging = rate;
+        Ok(())
+    }
+
+    fn set_restart_policy(&mut self, id: usize, policy: RestartPolicy) -> Result<()> {
+        let comp = self.agents.get_mut(&id).expect("SchedState set_restart_policy : agent doesn't exist");
+        comp.restart = policy;
+        Ok(())
+    }
+
     fn run_end(&mut self, id: usize, mut box_comp: BoxedComp, res: Result<Signal>) -> Result<()>{
+        let stopping = self.ctx.is_stopping();
         let must_restart = {
             let mut comp = self.agents.get_mut(&id).expect("SchedState RunEnd : agent doesn't exist");
+            if comp.in_pool {
+                comp.in_pool = false;
+                self.pool_running -= 1;
+            }
             for msg in comp.edit_msgs.drain(..) {
                 try!(Self::edit_one_comp(&mut box_comp, msg));
             }
-            let must_restart = comp.ips > 0;
-            comp.comp = Some(box_comp);
-            if let Ok(Signal::End) = res {
-                if comp.is_run {
-                    self.running -= 1;
-                    comp.is_run = false;
+            if let Some((new_comp, sync_sender)) = comp.reload.take() {
+                box_comp = Self::swap_agent(box_comp, new_comp, &mut comp.started, &comp.name);
+                let _ = sync_sender.send(());
+            }
+            let mut must_restart = comp.ips > 0 && !comp.paused && !stopping;
+            let ended = match res {
+                Ok(Signal::End) => true,
+                Ok(Signal::Continue) => stopping,
+                Err(result::Error::StartFailed(e)) => {
+                    error!(target: LOG_TARGET, "{} cannot start : {}", comp.name, e);
+                    comp.dead = true;
+                    must_restart = false;
+                    true
+                },
+                Err(result::Error::AgentPanicked(p)) => {
+                    comp.ctx.on_panic();
+                    let restart = match comp.restart {
+                        RestartPolicy::Never => false,
+                        RestartPolicy::Always => true,
+                        RestartPolicy::MaxRetries(n) => comp.restarts < n,
+                    };
+                    if restart && !stopping {
+                        error!(target: LOG_TARGET, "{} panicked : {}, restarting", comp.name, p);
+                        comp.restarts += 1;
+                        box_comp.reset();
+                        false
+                    } else {
+                        error!(target: LOG_TARGET, "{} panicked : {}", comp.name, p);
+                        comp.dead = true;
+                        must_restart = false;
+                        true
+                    }
+                },
+                Err(e) => {
+                    if !stopping {
+                        warn!(target: LOG_TARGET, "{} fails : {}", comp.name, e);
+                    }
+                    stopping
                 }
-            } else if let Err(e) = res {
-                println!("{} fails : {}", comp.name, e);
+            };
+            if !comp.dead {
+                comp.comp = Some(box_comp);
+            }
+            if ended && comp.is_run {
+                self.running -= 1;
+                comp.is_run = false;
             }
             must_restart
         };
         if must_restart {
             self.run(id);
         } else {
-            if self.running <= 0 && self.can_halt {
-                self.sched_sender.send(CompMsg::Halt).ok().expect("SchedState RunEnd : Cannot send Halt");
+            self.dispatch_ready();
+            if self.running <= 0 && self.can_halt {
+                self.sched_sender.send(CompMsg::Halt).ok().expect("SchedState RunEnd : Cannot send Halt");
+            }
+        }
+        Ok(())
+    }
+
+    /// Run an agent, once a thread of the pool is free if it runs in the pool
+    fn run(&mut self, id: usize) {
+        let pooled = {
+            let comp = self.agents.get(&id).expect("SchedState run : agent doesn't exist");
+            comp.thread.is_none() && comp.comp.is_some()
+        };
+        if pooled {
+            if !self.ready.iter().any(|&(ready, _)| { ready == id }) {
+                self.ready.push((id, Instant::now()));
+            }
+            self.dispatch_ready();
+        } else {
+            self.dispatch(id);
+        }
+    }
+
+    /// Run the waiting agents by effective priority, while the pool has free threads
+    fn dispatch_ready(&mut self) {
+        while self.pool_running < POOL_THREADS && !self.ready.is_empty() {
+            let best = {
+                let now = Instant::now();
+                let agents = &self.agents;
+                let aging = self.aging;
+                let priority = |&(id, since): &(usize, Instant)| {
+                    let waited = now - since;
+                    let base = agents.get(&id).map_or(0, |comp| { comp.priority });
+                    base as f64 + aging * (waited.as_secs() as f64 + waited.subsec_nanos() as f64 / 1e9)
+                };
+                // The first of the best is the one waiting for the longest
+                (1..self.ready.len()).fold(0, |best, i| {
+                    if priority(&self.ready[i]) > priority(&self.ready[best]) { i } else { best }
+                })
+            };
+            let (id, _) = self.ready.remove(best);
+            let runnable = self.agents.get(&id).map_or(false, |comp| { comp.comp.is_some() && !comp.paused });
+            if runnable {
+                self.dispatch(id);
             }
         }
-        Ok(())
     }
+
     #[allow(unused_must_use)]
-    fn run(&mut self, id: usize) {
-        let mut o_comp = self.agents.get_mut(&id).expect("SchedSate run : agent doesn't exist");
+    fn dispatch(&mut self, id: usize) {
+        let o_comp = self.agents.get_mut(&id).expect("SchedState dispatch : agent doesn't exist");
         if let Some(mut b_comp) = mem::replace(&mut o_comp.comp, None) {
             if !o_comp.is_run {
                 self.running += 1;
                 o_comp.is_run = true;
             }
+            let first = !o_comp.started;
+            o_comp.started = true;
+            if first {
+                info!(target: LOG_TARGET, "{} starts", o_comp.name);
+            }
+            let mut restore = if first { o_comp.restore.take() } else { None };
+            let mut config = if first { o_comp.config.clone() } else { None };
+            if let Some(ref thread) = o_comp.thread {
+                match thread.send((b_comp, first, config, restore)) {
+                    Ok(()) => { return; },
+                    Err(SendError((comp, _, cfg, data))) => {
+                        b_comp = comp;
+                        config = cfg;
+                        restore = data;
+                    },
+                }
+            }
             let sched_s = self.sched_sender.clone();
+            let ctx = o_comp.ctx.clone();
+            let name = o_comp.name.clone();
+            o_comp.in_pool = true;
+            self.pool_running += 1;
             self.pool.execute(move || {
-                let res = b_comp.run();
-                sched_s.send(CompMsg::RunEnd(id, b_comp, res)).expect("SchedState run : unable to send RunEnd");
+                let start = Instant::now();
+                let res = {
+                    let _memory = ctx.track_memory();
+                    trace::in_span(&name, || { run_agent(&mut b_comp, first, config, restore) })
+                };
+                ctx.on_run(start.elapsed());
+                // The scheduler is gone if its shutdown timed out
+                let _ = sched_s.send(CompMsg::RunEnd(id, b_comp, res));
             });
         };
     }
@@ -882,13 +3472,11 @@ impl SchedState {
                 // try!(c.add_input_receiver(&port, element, recv));
                 c.add_inarr_element(&port, element, recv)?;
             },
-            EditCmp::RemoveInputArrayElement(_port, _element) => {
-                unimplemented!();
-                // try!(c.remove_array_receiver(&port, &element));
+            EditCmp::RemoveInputArrayElement(port, element) => {
+                c.remove_inarr_element(&port, &element)?;
             }
             EditCmp::AddOutputArrayElement(_port, _element) => {
-                unimplemented!();
-                //try!(c.add_output_element(&port, element));
+                // An element of an output array port exists once it is connected
             },
             EditCmp::ConnectOutputPort(port_out, his) => {
                 c.connect(&port_out, his)?;
@@ -896,32 +3484,115 @@ impl SchedState {
             EditCmp::ConnectOutputArrayPort(port_out, element_out, his) => {
                 c.connect_array(&port_out, element_out, his)?;
             },
-            EditCmp::SetReceiver(_port, _hir) => {
-                unimplemented!();
-                //c.set_receiver(port, hir);
+            EditCmp::SetReceiver(port, hir) => {
+                c.set_receiver(&port, hir)?;
             }
-            EditCmp::Disconnect(_port) => {
-                unimplemented!();
-                //try!(c.disconnect(port));
+            EditCmp::Disconnect(port) => {
+                c.disconnect(&port)?;
             },
-            EditCmp::DisconnectArray(_port, _element) => {
-                unimplemented!();
-                //try!(c.disconnect_array(port, element));
+            EditCmp::DisconnectArray(port, element) => {
+                c.disconnect_array(&port, &element)?;
+            },
+            EditCmp::Checkpoint(name, sync_sender) => {
+                let _ = sync_sender.send((name, c.checkpoint()));
+            },
+            EditCmp::Restore(data) => {
+                c.restore(&data)?;
             },
         }
         Ok(())
     }
 }
 
+/// Run an agent once, calling `configure`, `on_start` and loading `restore` before the first run
+fn run_agent(b_comp: &mut BoxedComp, first: bool, config: Option<Vec<u8>>, restore: Option<Vec<u8>>) -> Result<Signal> {
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        if first {
+            if let Some(config) = config {
+                if let Err(e) = b_comp.configure(&config) {
+                    return Err(result::Error::StartFailed(format!("cannot configure, {}", e)));
+                }
+            }
+            if let Err(e) = b_comp.on_start() {
+                return Err(result::Error::StartFailed(format!("{}", e)));
+            }
+            if let Some(data) = restore {
+                if let Err(e) = b_comp.restore(&data) {
+                    return Err(result::Error::StartFailed(format!("cannot restore, {}", e)));
+                }
+            }
+        }
+        b_comp.run()
+    })).unwrap_or_else(|p| { Err(result::Error::AgentPanicked(panic_message(p))) })
+}
+
+#[cfg(feature = "affinity")]
+fn pin_thread(name: &str, cpus: &[usize]) {
+    let core = core_affinity::get_core_ids()
+        .and_then(|ids| { ids.into_iter().find(|core| { cpus.contains(&core.id) }) });
+    match core {
+        Some(core) => { core_affinity::set_for_current(core); },
+        None => { warn!(target: LOG_TARGET, "{} : cannot pin the thread to the CPUs {:?}, not available", name, cpus); },
+    }
+}
+
+#[cfg(not(feature = "affinity"))]
+fn pin_thread(name: &str, cpus: &[usize]) {
+    warn!(target: LOG_TARGET, "{} : cannot pin the thread to the CPUs {:?}, rustfbp is built without the affinity feature", name, cpus);
+}
+
+
+/// Depth first search of a cycle from `node`, returns the agents of the cycle
+fn find_cycle<'a>(node: &'a str, graph: &HashMap<&'a str, Vec<&'a str>>, path: &mut Vec<&'a str>, done: &mut HashSet<&'a str>) -> Option<Vec<String>> {
+    if let Some(pos) = path.iter().position(|n| { *n == node }) {
+        return Some(path[pos..].iter().map(|n| { n.to_string() }).collect());
+    }
+    if done.contains(node) {
+        return None;
+    }
+    path.push(node);
+    if let Some(nexts) = graph.get(node) {
+        for next in nexts {
+            if let Some(cycle) = find_cycle(next, graph, path, done) {
+                return Some(cycle);
+            }
+        }
+    }
+    path.pop();
+    done.insert(node);
+    None
+}
+
+/// Escape a name to put it in a quoted DOT identifier
+fn dot_escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"")
+}
+
+/// Get the message given to `panic!`
+fn panic_message(p: Box<Any + Send>) -> String {
+    if let Some(s) = p.downcast_ref::<&str>() {
+        (*s).into()
+    } else if let Some(s) = p.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown payload".into()
+    }
+}
+
 /// Contains all the information of a dylib agents
 #[allow(dead_code)]
 pub struct AgentLoader {
     lib: libloading::Library,
-    create: extern "C" fn(usize, Sender<CompMsg>) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)>,
+    create: extern "C" fn(usize, Sender<CompMsg>, AgentCtx) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)>,
     get_schema_input: extern "C" fn(&str) -> Result<String>,
     get_schema_input_array: extern "C" fn(&str) -> Result<String>,
     get_schema_output: extern "C" fn(&str) -> Result<String>,
     get_schema_output_array: extern "C" fn(&str) -> Result<String>,
+    // Missing in the agents built before the type ids
+    get_type_id_input: Option<extern "C" fn(&str) -> Result<Option<u64>>>,
+    get_type_id_input_array: Option<extern "C" fn(&str) -> Result<Option<u64>>>,
+    get_type_id_output: Option<extern "C" fn(&str) -> Result<Option<u64>>>,
+    get_type_id_output_array: Option<extern "C" fn(&str) -> Result<Option<u64>>>,
 }
 
 /// Keep all the dylib agents and load them
@@ -942,17 +3613,27 @@ impl AgentCache {
         }
     }
 
+    /// Unload the dylib of an agent. All the agents created from it must be dropped before
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// cc.remove("/home/xxx/agents/add.so");
+    /// ```
+    pub fn remove(&mut self, path: &str) {
+        self.cache.remove(path);
+    }
+
     /// Load a new agent from the system file
     ///
     /// # Example
     /// ```rust,ignore
-    /// try!(cc.create_comp("/home/xxx/agents/add.so", "add", sched_sender));
+    /// try!(cc.create_comp("/home/xxx/agents/add.so", 0, sched_sender, ctx));
     /// ```
-    pub fn create_comp(&mut self, path: &str, id: usize, sender: Sender<CompMsg>) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
+    pub fn create_comp(&mut self, path: &str, id: usize, sender: Sender<CompMsg>, ctx: AgentCtx) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> {
         if !self.cache.contains_key(path) {
             let lib_comp = libloading::Library::new(path).expect("cannot load");
 
-            let new_comp: extern fn(usize, Sender<CompMsg>) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> = unsafe {
+            let new_comp: extern fn(usize, Sender<CompMsg>, AgentCtx) -> Result<(Box<Agent + Send>, HashMap<String, MsgSender>)> = unsafe {
                 *(lib_comp.get(b"create_agent\0").expect("cannot find create method"))
             };
 
@@ -972,6 +3653,14 @@ impl AgentCache {
                 *(lib_comp.get(b"get_schema_output_array\0").expect("cannot find get output method"))
             };
 
+            let type_id = |symbol: &[u8]| -> Option<extern fn(&str) -> Result<Option<u64>>> {
+                unsafe { lib_comp.get(symbol).ok().map(|f| { *f }) }
+            };
+            let id_in = type_id(b"get_type_id_input\0");
+            let id_in_a = type_id(b"get_type_id_input_array\0");
+            let id_out = type_id(b"get_type_id_output\0");
+            let id_out_a = type_id(b"get_type_id_output_array\0");
+
             self.cache.insert(path.into(),
                               AgentLoader {
                                   lib: lib_comp,
@@ -980,10 +3669,14 @@ impl AgentCache {
                                   get_schema_input_array: get_in_a,
                                   get_schema_output: get_out,
                                   get_schema_output_array: get_out_a,
+                                  get_type_id_input: id_in,
+                                  get_type_id_input_array: id_in_a,
+                                  get_type_id_output: id_out,
+                                  get_type_id_output_array: id_out_a,
                               });
         }
         if let Some(loader) = self.cache.get(path){
-            (loader.create)(id, sender)
+            (loader.create)(id, sender, ctx)
         } else {
             unreachable!()
         }
@@ -996,9 +3689,12 @@ impl AgentCache {
     /// cc.get_schema_input("add", "input");
     /// ```
     pub fn get_schema_input(&self, comp: &str, port: &str) -> Result<String> {
+        if comp == BOXED_SORT {
+            return Ok("any".into());
+        }
         self.cache.get(comp).ok_or(result::Error::AgentNotFound(comp.into()))
-            .map(|comp| {
-                (comp.get_schema_input)(port).expect("cannot get")
+            .and_then(|comp| {
+                (comp.get_schema_input)(port)
             })
     }
 
@@ -1009,9 +3705,12 @@ impl AgentCache {
     /// cc.get_schema_input_array("add", "inputs");
     /// ```
     pub fn get_schema_input_array(&self, comp: &str, port: &str) -> Result<String> {
+        if comp == BOXED_SORT {
+            return Ok("any".into());
+        }
         self.cache.get(comp).ok_or(result::Error::AgentNotFound(comp.into()))
-            .map(|comp| {
-                (comp.get_schema_input_array)(port).expect("cannot get")
+            .and_then(|comp| {
+                (comp.get_schema_input_array)(port)
             })
     }
 
@@ -1022,9 +3721,12 @@ impl AgentCache {
     /// cc.get_schema_output("add", "output");
     /// ```
     pub fn get_schema_output(&self, comp: &str, port: &str) -> Result<String> {
+        if comp == BOXED_SORT {
+            return Ok("any".into());
+        }
         self.cache.get(comp).ok_or(result::Error::AgentNotFound(comp.into()))
-            .map(|comp| {
-                (comp.get_schema_output)(port).expect("cannot get")
+            .and_then(|comp| {
+                (comp.get_schema_output)(port)
             })
     }
 
@@ -1035,11 +3737,929 @@ impl AgentCache {
     /// cc.get_schema_output_array("add", "outputs");
     /// ```
     pub fn get_schema_output_array(&self, comp: &str, port: &str) -> Result<String> {
+        if comp == BOXED_SORT {
+            return Ok("any".into());
+        }
+        self.cache.get(comp).ok_or(result::Error::AgentNotFound(comp.into()))
+            .and_then(|comp| {
+                (comp.get_schema_output_array)(port)
+            })
+    }
+}
+
+impl AgentCache {
+    /// Get the type id of the edge of an input port, `None` for `any`
+    ///
+    /// # Example
+    /// ```rust,ignore
+    /// cc.get_type_id_input("add", "input");
+    /// ```
+    pub fn get_type_id_input(&self, comp: &str, port: &str) -> Result<Option<u64>> {
+        self.get_type_id(comp, port, |loader| { loader.get_type_id_input })
+    }
+
+    /// Get the type id of the edge of an array input port, `None` for `any`
+    pub fn get_type_id_input_array(&self, comp: &str, port: &str) -> Result<Option<u64>> {
+        self.get_type_id(comp, port, |loader| { loader.get_type_id_input_array })
+    }
+
+    /// Get the type id of the edge of an output port, `None` for `any`
+    pub fn get_type_id_output(&self, comp: &str, port: &str) -> Result<Option<u64>> {
+        self.get_type_id(comp, port, |loader| { loader.get_type_id_output })
+    }
+
+    /// Get the type id of the edge of an array output port, `None` for `any`
+    pub fn get_type_id_output_array(&self, comp: &str, port: &str) -> Result<Option<u64>> {
+        self.get_type_id(comp, port, |loader| { loader.get_type_id_output_array })
+    }
+
+    fn get_type_id<F>(&self, comp: &str, port: &str, get: F) -> Result<Option<u64>> where
+        F: Fn(&AgentLoader) -> Option<extern "C" fn(&str) -> Result<Option<u64>>>
+    {
+        if comp == BOXED_SORT {
+            return Ok(None);
+        }
         self.cache.get(comp).ok_or(result::Error::AgentNotFound(comp.into()))
-            .map(|comp| {
-                (comp.get_schema_output_array)(port).expect("cannot get")
+            .and_then(|loader| {
+                match get(loader) {
+                    Some(get_type_id) => get_type_id(port),
+                    None => Ok(None),
+                }
             })
     }
 }
 
 unsafe impl Send for AgentCache {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{AgentPorts, PortList};
+    use builtin::{LoadBalancer, Throttle, ThrottleMode, VecSink, VecSource};
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type Collected = Arc<Mutex<Vec<Msg>>>;
+
+    type ProbeRun = Box<FnMut(&mut MsgReceiver, &Option<MsgSender>) -> Result<Signal> + Send>;
+
+    /// An agent with an `input` and an `output`, running a closure
+    struct Probe {
+        input: MsgReceiver,
+        output: Option<MsgSender>,
+        run: ProbeRun,
+    }
+
+    impl Agent for Probe {
+        builtin_ports! {
+            inputs { input: None },
+            outputs { output: None },
+            array_inputs {},
+            array_outputs {}
+        }
+
+        fn run(&mut self) -> Result<Signal> {
+            (self.run)(&mut self.input, &self.output)
+        }
+    }
+
+    fn probe(id: usize, sc: Sender<CompMsg>, ctx: AgentCtx, run: ProbeRun) -> Result<(BoxedComp, HashMap<String, MsgSender>)> {
+        let (input, sender) = MsgReceiver::new(id, "input", sc, ctx, true);
+        let mut senders = HashMap::new();
+        senders.insert("input".to_string(), sender);
+        let probe = Probe {
+            input: input,
+            output: None,
+            run: run,
+        };
+        Ok((Box::new(probe) as BoxedComp, senders))
+    }
+
+    /// An agent counting its IPs, the count is its state
+    struct Counter {
+        input: MsgReceiver,
+        count: u8,
+        counts: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Agent for Counter {
+        builtin_ports! {
+            inputs { input: None },
+            outputs {},
+            array_inputs {},
+            array_outputs {}
+        }
+
+        fn run(&mut self) -> Result<Signal> {
+            try!(self.input.recv());
+            self.count += 1;
+            self.counts.lock().unwrap().push(self.count);
+            Ok(Signal::End)
+        }
+
+        fn checkpoint(&self) -> Option<Vec<u8>> {
+            Some(vec![self.count])
+        }
+
+        fn restore(&mut self, data: &[u8]) -> Result<()> {
+            self.count = data[0];
+            Ok(())
+        }
+    }
+
+    fn add_counter(sched: &mut Scheduler, name: &str) -> Arc<Mutex<Vec<u8>>> {
+        let counts = Arc::new(Mutex::new(vec![]));
+        let seen = counts.clone();
+        sched.add_agent_boxed(name, move |id, sc, ctx| {
+            let (input, sender) = MsgReceiver::new(id, "input", sc, ctx, true);
+            let mut senders = HashMap::new();
+            senders.insert("input".to_string(), sender);
+            Ok((Box::new(Counter { input: input, count: 0, counts: seen }) as BoxedComp, senders))
+        }).unwrap();
+        counts
+    }
+
+    fn add_probe<F>(sched: &mut Scheduler, name: &str, run: F) where
+        F: FnMut(&mut MsgReceiver, &Option<MsgSender>) -> Result<Signal> + Send + 'static
+    {
+        sched.add_agent_boxed(name, move |id, sc, ctx| { probe(id, sc, ctx, Box::new(run)) }).unwrap();
+    }
+
+    /// Forward each IP plus `n`
+    fn adder(n: u8) -> ProbeRun {
+        Box::new(move |input: &mut MsgReceiver, output: &Option<MsgSender>| {
+            let mut msg = try!(input.recv());
+            let b = msg_bytes(&mut msg).unwrap()[0];
+            try!(output.as_ref().expect("the adder has no output").send(bytes_msg(&[b + n])));
+            Ok(Signal::End)
+        })
+    }
+
+    /// A source sending an IP each millisecond until the scheduler stops
+    struct Endless {
+        output: Option<MsgSender>,
+    }
+
+    impl Agent for Endless {
+        builtin_ports! {
+            inputs {},
+            outputs { output: None },
+            array_inputs {},
+            array_outputs {}
+        }
+
+        fn run(&mut self) -> Result<Signal> {
+            loop {
+                match self.output.as_ref().expect("the source has no output").send(bytes_msg(&[0])) {
+                    Ok(()) => {},
+                    Err(result::Error::Stopping) => { return Ok(Signal::End); },
+                    Err(e) => { return Err(e); },
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    /// An endless source `source` feeding a sink `sink`
+    fn add_endless(sched: &mut Scheduler) -> Collected {
+        sched.add_agent_boxed("source", |_, _, _| {
+            Ok((Box::new(Endless { output: None }) as BoxedComp, HashMap::new()))
+        }).unwrap();
+        let sink = add_sink(sched, "sink");
+        sched.connect("source", "output", "sink", "input").unwrap();
+        sink
+    }
+
+    fn add_source(sched: &mut Scheduler, name: &str, bytes: Vec<u8>) {
+        sched.add_agent_boxed(name, move |id, sc, ctx| {
+            VecSource::create(id, sc, ctx, bytes.iter().map(|b| { bytes_msg(&[*b]) }).collect())
+        }).unwrap();
+    }
+
+    fn add_sink(sched: &mut Scheduler, name: &str) -> Collected {
+        let collected = Arc::new(Mutex::new(vec![]));
+        let sink = collected.clone();
+        sched.add_agent_boxed(name, move |id, sc, ctx| { VecSink::create(id, sc, ctx, sink) }).unwrap();
+        collected
+    }
+
+    fn bytes(collected: &Collected) -> Vec<u8> {
+        collected.lock().unwrap().iter_mut().map(|msg| { msg_bytes(msg).unwrap()[0] }).collect()
+    }
+
+    /// Wait up to 5 seconds for `done`
+    fn wait_until<F: Fn() -> bool>(done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "the network doesn't get there in time");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// The status of the agent `name`
+    fn status(sched: &Scheduler, name: &str) -> Option<AgentStatus> {
+        sched.dump_state().agents.iter().find(|a| { a.name == name }).map(|a| { a.status.clone() })
+    }
+
+    /// Take all the threads of the pool with agents blocked in `run`, returns their gates
+    ///
+    /// A run ends when its gate gets a message, which frees one thread.
+    fn fill_pool(sched: &mut Scheduler) -> Vec<Sender<()>> {
+        let mut gates = vec![];
+        for i in 0..POOL_THREADS {
+            let (gate_s, gate_r) = channel();
+            add_probe(sched, &format!("blocker{}", i), move |input, _| {
+                try!(input.recv());
+                let _ = gate_r.recv();
+                Ok(Signal::End)
+            });
+            gates.push(gate_s);
+        }
+        gates
+    }
+
+    fn start_blockers(sched: &Scheduler) {
+        for i in 0..POOL_THREADS {
+            sched.inject(format!("blocker{}", i), "input", bytes_msg(&[0])).unwrap();
+        }
+        wait_until(|| {
+            (0..POOL_THREADS).all(|i| { status(sched, &format!("blocker{}", i)) == Some(AgentStatus::Running) })
+        });
+    }
+
+    /// Add an agent writing its name in `order` on each run
+    fn add_named(sched: &mut Scheduler, name: &str, order: &Arc<Mutex<Vec<String>>>) {
+        let order = order.clone();
+        let own = name.to_string();
+        add_probe(sched, name, move |input, _| {
+            try!(input.recv());
+            order.lock().unwrap().push(own.clone());
+            Ok(Signal::End)
+        });
+    }
+
+    /// Make `name` wait for a thread of the pool
+    fn make_ready(sched: &Scheduler, name: &str) {
+        sched.inject(name, "input", bytes_msg(&[0])).unwrap();
+        wait_until(|| { status(sched, name) == Some(AgentStatus::Ready) });
+    }
+
+    /// Add an agent that panics on the IPs below 2, and forwards the others
+    fn add_flaky(sched: &mut Scheduler, name: &str) {
+        add_probe(sched, name, |input, output| {
+            let mut msg = try!(input.recv());
+            if msg_bytes(&mut msg).unwrap()[0] < 2 {
+                panic!("flaky");
+            }
+            try!(output.as_ref().expect("flaky has no output").send(msg));
+            Ok(Signal::End)
+        });
+    }
+
+    #[test]
+    fn array_elements_route_by_name() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3]);
+        sched.add_agent_boxed("balancer", |id, sc, ctx| { LoadBalancer::create(id, sc, ctx, false) }).unwrap();
+        let north = add_sink(&mut sched, "north");
+        let south = add_sink(&mut sched, "south");
+        sched.connect("source", "output", "balancer", "input").unwrap();
+        sched.connect_array("balancer", "outputs", "north", "north", "input").unwrap();
+        sched.connect_array("balancer", "outputs", "south", "south", "input").unwrap();
+        match sched.connect_array("balancer", "outputs", "north", "south", "input") {
+            Err(result::Error::AlreadyConnected(ref agent, ref port)) if agent == "balancer" && port == "outputs[north]" => {},
+            _ => panic!("the element north is given to another port"),
+        }
+        sched.connect_array("balancer", "outputs", "north", "north", "input").unwrap();
+        assert_eq!(sched.topology().edges.iter().filter(|e| { e.out_agent == "balancer" }).count(), 2);
+        sched.start().unwrap();
+        sched.run_until_idle().unwrap();
+        assert_eq!(bytes(&north), vec![0, 2]);
+        assert_eq!(bytes(&south), vec![1, 3]);
+    }
+
+    #[test]
+    fn checkpoint_all_gives_up_on_a_running_agent() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        sched.add_agent_boxed("throttle", |id, sc, ctx| { Throttle::create(id, sc, ctx, 0.01, ThrottleMode::Block) }).unwrap();
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "throttle", "input").unwrap();
+        sched.connect("throttle", "output", "sink", "input").unwrap();
+        sched.start().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sink.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The throttle waits 100 seconds for its second token
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let states = sched.checkpoint_all(Duration::from_millis(200));
+        assert!(states.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn connect_idempotent_delivers_once() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        let sink = add_sink(&mut sched, "sink");
+        assert!(sched.connect_idempotent("source", "output", "sink", "input").unwrap());
+        assert!(!sched.connect_idempotent("source", "output", "sink", "input").unwrap());
+        assert!(sched.connect_idempotent("source", "output", "missing", "input").is_err());
+        sched.start().unwrap();
+        sched.run_until_idle().unwrap();
+        assert_eq!(bytes(&sink), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn validate_reports_a_cycle_until_it_is_marked_feedback() {
+        let mut sched = Scheduler::new();
+        for name in &["a", "b", "c"] {
+            add_probe(&mut sched, name, |_, _| { Ok(Signal::End) });
+        }
+        sched.connect("a", "output", "b", "input").unwrap();
+        sched.connect("b", "output", "c", "input").unwrap();
+        sched.connect("c", "output", "a", "input").unwrap();
+        match sched.validate() {
+            Err(result::Error::Cycle(ref agents)) if *agents == vec!["a", "b", "c"] => {},
+            _ => panic!("the cycle a -> b -> c is not reported"),
+        }
+        match sched.start() {
+            Err(result::Error::Cycle(_)) => {},
+            _ => panic!("a graph with a cycle starts"),
+        }
+        sched.mark_feedback("c", "output").unwrap();
+        sched.validate().unwrap();
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn iips_beyond_the_capacity_are_sent_in_order() {
+        let mut sched = Scheduler::new();
+        let sink = add_sink(&mut sched, "sink");
+        sched.set_port_capacity("sink", "input", 2).unwrap();
+        for b in 0..6 {
+            sched.add_iip("sink", "input", bytes_msg(&[b])).unwrap();
+        }
+        sched.start().unwrap();
+        wait_until(|| { sink.lock().unwrap().len() == 6 });
+        assert_eq!(bytes(&sink), vec![0, 1, 2, 3, 4, 5]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn iips_are_kept_if_a_port_is_gone() {
+        let mut sched = Scheduler::new();
+        add_sink(&mut sched, "sink");
+        add_sink(&mut sched, "gone");
+        sched.add_iip("sink", "input", bytes_msg(&[0])).unwrap();
+        sched.add_iip("gone", "input", bytes_msg(&[1])).unwrap();
+        sched.remove_agent("gone").unwrap();
+        match sched.start() {
+            Err(result::Error::AgentNotFound(ref name)) if name == "gone" => {},
+            _ => panic!("the IIP of a removed agent is sent"),
+        }
+        assert_eq!(sched.iips.len(), 2);
+        assert_eq!(sched.get_sender("sink", "input").unwrap().port().depth(), 0);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn reload_agent_keeps_the_ports_and_their_msgs() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        sched.add_agent_boxed("add", |id, sc, ctx| { probe(id, sc, ctx, adder(10)) }).unwrap();
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "add", "input").unwrap();
+        sched.connect("add", "output", "sink", "input").unwrap();
+        sched.start().unwrap();
+        wait_until(|| { sink.lock().unwrap().len() == 3 });
+        sched.pause("add").unwrap();
+        sched.inject("add", "input", bytes_msg(&[3])).unwrap();
+        sched.inject("add", "input", bytes_msg(&[4])).unwrap();
+        sched.reload_agent_boxed("add", |id, sc, ctx| { probe(id, sc, ctx, adder(20)) }).unwrap();
+        sched.resume("add").unwrap();
+        wait_until(|| { sink.lock().unwrap().len() == 5 });
+        assert_eq!(bytes(&sink), vec![10, 11, 12, 23, 24]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn paused_agent_receives_once_resumed() {
+        let mut sched = Scheduler::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let received = seen.clone();
+        add_probe(&mut sched, "drain", move |input, _| {
+            loop {
+                let mut msg = try!(input.recv());
+                received.lock().unwrap().push(msg_bytes(&mut msg).unwrap()[0]);
+            }
+        });
+        sched.start().unwrap();
+        sched.inject("drain", "input", bytes_msg(&[0])).unwrap();
+        wait_until(|| { seen.lock().unwrap().len() == 1 });
+        // Paused in the middle of its run
+        sched.pause("drain").unwrap();
+        sched.inject("drain", "input", bytes_msg(&[1])).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(*seen.lock().unwrap(), vec![0]);
+        sched.resume("drain").unwrap();
+        wait_until(|| { seen.lock().unwrap().len() == 2 });
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn paused_agent_is_not_run() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "sink", "input").unwrap();
+        sched.pause("sink").unwrap();
+        sched.start().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(sink.lock().unwrap().is_empty());
+        sched.resume("sink").unwrap();
+        wait_until(|| { sink.lock().unwrap().len() == 3 });
+        assert_eq!(bytes(&sink), vec![0, 1, 2]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn deadlock_of_a_blocked_pair_is_reported_once() {
+        let mut sched = Scheduler::new();
+        for name in &["a", "b"] {
+            add_probe(&mut sched, name, |input, _| {
+                try!(input.recv());
+                try!(input.recv());
+                Ok(Signal::End)
+            });
+        }
+        sched.connect("a", "output", "b", "input").unwrap();
+        sched.connect("b", "output", "a", "input").unwrap();
+        sched.allow_cycles(true);
+        sched.enable_deadlock_watchdog(Duration::from_millis(500));
+        // Replaces the first watchdog
+        sched.enable_deadlock_watchdog(Duration::from_millis(20));
+        sched.start().unwrap();
+        sched.inject("a", "input", bytes_msg(&[0])).unwrap();
+        sched.inject("b", "input", bytes_msg(&[0])).unwrap();
+        let report = sched.error_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        match report {
+            result::Error::Deadlock(ref states) => {
+                assert_eq!(states.len(), 2);
+                assert!(states[0].starts_with("a is receiving on a.input"));
+                assert!(states[1].starts_with("b is receiving on b.input"));
+            },
+            _ => panic!("the deadlock is not reported"),
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert!(sched.error_receiver.try_recv().is_err());
+        sched.disable_deadlock_watchdog();
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn run_until_idle_reports_the_totals() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3, 4]);
+        sched.add_agent_boxed("add", |id, sc, ctx| { probe(id, sc, ctx, adder(1)) }).unwrap();
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "add", "input").unwrap();
+        sched.connect("add", "output", "sink", "input").unwrap();
+        sched.enable_metrics(true);
+        sched.start().unwrap();
+        let report = sched.run_until_idle().unwrap();
+        assert_eq!(bytes(&sink), vec![1, 2, 3, 4, 5]);
+        let counts: Vec<(&str, u64, u64)> = report.per_agent.iter()
+            .map(|a| { (&a.name as &str, a.received, a.sent) })
+            .collect();
+        assert_eq!(counts, vec![("add", 5, 5), ("sink", 5, 0), ("source", 0, 5)]);
+        assert_eq!(report.total_ips, 10);
+    }
+
+    #[test]
+    fn run_report_times_the_running_and_the_blocked_agents() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1]);
+        add_probe(&mut sched, "slow", |input, output| {
+            let msg = try!(input.recv());
+            thread::sleep(Duration::from_millis(100));
+            try!(output.as_ref().expect("slow has no output").send(msg));
+            Ok(Signal::End)
+        });
+        let pairs = Arc::new(Mutex::new(vec![]));
+        let seen = pairs.clone();
+        // Started by the first Msg, it waits for the second one
+        add_probe(&mut sched, "pair", move |input, _| {
+            let mut first = try!(input.recv());
+            let mut second = try!(input.recv());
+            seen.lock().unwrap().push((msg_bytes(&mut first).unwrap()[0], msg_bytes(&mut second).unwrap()[0]));
+            Ok(Signal::End)
+        });
+        sched.connect("source", "output", "slow", "input").unwrap();
+        sched.connect("slow", "output", "pair", "input").unwrap();
+        sched.enable_metrics(true);
+        sched.start().unwrap();
+        let report = sched.run_until_idle().unwrap();
+        assert_eq!(*pairs.lock().unwrap(), vec![(0, 1)]);
+        let names: Vec<&str> = report.per_agent.iter().map(|a| { &a.name as &str }).collect();
+        assert_eq!(names, vec!["pair", "slow", "source"]);
+        assert_eq!(report.total_ips, 4);
+        let slow = &report.per_agent[1];
+        assert!(slow.running >= Duration::from_millis(200), "slow ran {:?}", slow.running);
+        let pair = &report.per_agent[0];
+        assert!(pair.blocked > Duration::new(0, 0), "pair is blocked {:?}", pair.blocked);
+        assert!(pair.running >= pair.blocked);
+        assert!(report.wall_time >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn metrics_delta_counts_since_the_last_sample() {
+        let mut sched = Scheduler::new();
+        let sink = add_sink(&mut sched, "sink");
+        sched.enable_metrics(true);
+        sched.start().unwrap();
+        let inject = |sched: &Scheduler, n: u8| {
+            for i in 0..n {
+                sched.inject("sink", "input", bytes_msg(&[i])).unwrap();
+            }
+            assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+        };
+        inject(&sched, 10);
+        assert_eq!(sched.metrics_delta()["sink"].received, 10);
+        inject(&sched, 5);
+        assert_eq!(sched.metrics_delta()["sink"].received, 5);
+        // A second sampler would only see what the first one left
+        assert_eq!(sched.metrics_delta()["sink"].received, 0);
+        assert_eq!(sched.metrics()["sink"].received, 15);
+        sched.reset_metrics();
+        inject(&sched, 3);
+        assert_eq!(sched.metrics()["sink"].received, 3);
+        assert_eq!(sched.metrics_delta()["sink"].received, 3);
+        assert_eq!(sink.lock().unwrap().len(), 18);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn wait_for_idle_keeps_the_graph_running() {
+        let mut sched = Scheduler::new();
+        sched.add_agent_boxed("add", |id, sc, ctx| { probe(id, sc, ctx, adder(1)) }).unwrap();
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("add", "output", "sink", "input").unwrap();
+        sched.start().unwrap();
+        sched.inject("add", "input", bytes_msg(&[0])).unwrap();
+        sched.inject("add", "input", bytes_msg(&[1])).unwrap();
+        assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+        assert_eq!(bytes(&sink), vec![1, 2]);
+        sched.inject("add", "input", bytes_msg(&[2])).unwrap();
+        assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+        assert_eq!(bytes(&sink), vec![1, 2, 3]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn wait_for_idle_times_out_on_a_busy_graph() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0]);
+        add_probe(&mut sched, "busy", |input, _| {
+            try!(input.recv());
+            thread::sleep(Duration::from_millis(1000));
+            Ok(Signal::End)
+        });
+        sched.connect("source", "output", "busy", "input").unwrap();
+        sched.start().unwrap();
+        let started = Instant::now();
+        assert!(!sched.wait_for_idle(Duration::from_millis(200)).unwrap());
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(200) && waited < Duration::from_millis(600), "waited {:?}", waited);
+        assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn high_priority_agent_overtakes_a_bulk_agent() {
+        let mut sched = Scheduler::new();
+        let gates = fill_pool(&mut sched);
+        let order = Arc::new(Mutex::new(vec![]));
+        add_named(&mut sched, "bulk", &order);
+        add_named(&mut sched, "quotes", &order);
+        sched.set_aging_rate(0.0).unwrap();
+        sched.set_base_priority("quotes", 100).unwrap();
+        sched.start().unwrap();
+        start_blockers(&sched);
+        // The bulk agent waits for longer, the first free thread still goes to quotes
+        make_ready(&sched, "bulk");
+        make_ready(&sched, "quotes");
+        gates[0].send(()).unwrap();
+        wait_until(|| { order.lock().unwrap().len() == 2 });
+        assert_eq!(*order.lock().unwrap(), vec!["quotes", "bulk"]);
+        for gate in &gates[1..] {
+            gate.send(()).unwrap();
+        }
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn aging_runs_a_low_priority_agent_that_waited() {
+        let mut sched = Scheduler::new();
+        let gates = fill_pool(&mut sched);
+        let order = Arc::new(Mutex::new(vec![]));
+        add_named(&mut sched, "bulk", &order);
+        add_named(&mut sched, "quotes", &order);
+        sched.set_aging_rate(20.0).unwrap();
+        sched.set_base_priority("bulk", -5).unwrap();
+        sched.start().unwrap();
+        start_blockers(&sched);
+        make_ready(&sched, "bulk");
+        // 10 levels gained, more than the 5 below quotes
+        thread::sleep(Duration::from_millis(500));
+        make_ready(&sched, "quotes");
+        gates[0].send(()).unwrap();
+        wait_until(|| { order.lock().unwrap().len() == 2 });
+        assert_eq!(*order.lock().unwrap(), vec!["bulk", "quotes"]);
+        for gate in &gates[1..] {
+            gate.send(()).unwrap();
+        }
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn dump_state_shows_the_blocked_ports() {
+        let mut sched = Scheduler::new();
+        // Receives two IPs per run, it waits for the second one
+        add_probe(&mut sched, "pair", |input, _| {
+            try!(input.recv());
+            try!(input.recv());
+            Ok(Signal::End)
+        });
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        add_sink(&mut sched, "sink");
+        sched.set_port_capacity("sink", "input", 1).unwrap();
+        sched.connect("source", "output", "sink", "input").unwrap();
+        sched.pause("sink").unwrap();
+        sched.start().unwrap();
+        sched.inject("pair", "input", bytes_msg(&[0])).unwrap();
+        let blocked = |name: &str| {
+            match status(&sched, name) {
+                Some(AgentStatus::Blocked { port, sending, .. }) => Some((port, sending)),
+                _ => None,
+            }
+        };
+        wait_until(|| { blocked("pair").is_some() && blocked("source").is_some() });
+        assert_eq!(blocked("pair"), Some(("pair.input".to_string(), false)));
+        assert_eq!(blocked("source"), Some(("sink.input".to_string(), true)));
+        let report = format!("{}", sched.dump_state());
+        assert!(report.contains("pair (boxed) : blocked receiving on pair.input for "), "{}", report);
+        assert!(report.contains("source (boxed) : blocked sending to sink.input for "), "{}", report);
+        assert!(report.contains("sink (boxed) : paused\n    input : "), "{}", report);
+        assert!(report.contains("edges :\n    source.output -> sink.input\n"), "{}", report);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn requeued_msg_is_received_first_and_counted_once() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        let mut requeued = false;
+        add_probe(&mut sched, "requeue", move |input, output| {
+            let msg = try!(input.recv());
+            if !requeued {
+                requeued = true;
+                input.requeue(msg);
+                return Ok(Signal::End);
+            }
+            try!(output.as_ref().expect("requeue has no output").send(msg));
+            Ok(Signal::End)
+        });
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "requeue", "input").unwrap();
+        sched.connect("requeue", "output", "sink", "input").unwrap();
+        sched.enable_metrics(true);
+        sched.start().unwrap();
+        let report = sched.run_until_idle().unwrap();
+        assert_eq!(bytes(&sink), vec![0, 1, 2]);
+        let counts: Vec<(&str, u64, u64)> = report.per_agent.iter()
+            .map(|a| { (&a.name as &str, a.received, a.sent) })
+            .collect();
+        assert_eq!(counts, vec![("requeue", 3, 3), ("sink", 3, 0), ("source", 0, 3)]);
+        assert_eq!(report.total_ips, 6);
+    }
+
+    #[test]
+    fn checkpoint_all_is_restored_by_restore_all() {
+        let mut sched = Scheduler::new();
+        let counts = add_counter(&mut sched, "counter");
+        add_sink(&mut sched, "stateless");
+        sched.start().unwrap();
+        for _ in 0..3 {
+            sched.inject("counter", "input", bytes_msg(&[0])).unwrap();
+        }
+        wait_until(|| { counts.lock().unwrap().len() == 3 });
+        let states = sched.checkpoint_all(Duration::from_secs(5));
+        assert_eq!(states.len(), 1);
+        assert_eq!(states["counter"], vec![3]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+
+        let mut sched = Scheduler::new();
+        let counts = add_counter(&mut sched, "counter");
+        sched.restore_all(states).unwrap();
+        sched.start().unwrap();
+        sched.inject("counter", "input", bytes_msg(&[0])).unwrap();
+        wait_until(|| { counts.lock().unwrap().len() == 1 });
+        assert_eq!(*counts.lock().unwrap(), vec![4]);
+        // Restored again once started
+        let mut states = HashMap::new();
+        states.insert("counter".to_string(), vec![10]);
+        sched.restore_all(states).unwrap();
+        sched.inject("counter", "input", bytes_msg(&[0])).unwrap();
+        wait_until(|| { counts.lock().unwrap().len() == 2 });
+        assert_eq!(*counts.lock().unwrap(), vec![4, 11]);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn panicking_agent_restarts_up_to_max_retries() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3]);
+        add_flaky(&mut sched, "flaky");
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "flaky", "input").unwrap();
+        sched.connect("flaky", "output", "sink", "input").unwrap();
+        sched.set_restart_policy("flaky", RestartPolicy::MaxRetries(2)).unwrap();
+        sched.start().unwrap();
+        wait_until(|| { sink.lock().unwrap().len() == 2 });
+        assert_eq!(bytes(&sink), vec![2, 3]);
+        assert_eq!(sched.metrics()["flaky"].panics, 2);
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn panicking_agent_dies_past_max_retries() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3]);
+        add_flaky(&mut sched, "flaky");
+        let sink = add_sink(&mut sched, "sink");
+        sched.connect("source", "output", "flaky", "input").unwrap();
+        sched.connect("flaky", "output", "sink", "input").unwrap();
+        sched.set_restart_policy("flaky", RestartPolicy::MaxRetries(1)).unwrap();
+        sched.start().unwrap();
+        wait_until(|| { sched.metrics()["flaky"].panics == 2 });
+        thread::sleep(Duration::from_millis(100));
+        assert!(sink.lock().unwrap().is_empty());
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_lets_a_running_agent_drain_its_port() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3, 4]);
+        let drained = Arc::new(Mutex::new(vec![]));
+        let seen = drained.clone();
+        let (started_s, started_r) = channel();
+        add_probe(&mut sched, "drain", move |input, _| {
+            let _ = started_s.send(());
+            // The source fills the port meanwhile
+            thread::sleep(Duration::from_millis(100));
+            loop {
+                let mut msg = try!(input.recv());
+                seen.lock().unwrap().push(msg_bytes(&mut msg).unwrap()[0]);
+            }
+        });
+        sched.connect("source", "output", "drain", "input").unwrap();
+        sched.start().unwrap();
+        started_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(*drained.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[cfg(all(unix, feature = "signals"))]
+    #[test]
+    fn sigterm_lets_a_running_agent_drain_its_port() {
+        extern crate libc;
+
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3, 4]);
+        let drained = Arc::new(Mutex::new(vec![]));
+        let seen = drained.clone();
+        let (started_s, started_r) = channel();
+        add_probe(&mut sched, "drain", move |input, _| {
+            let _ = started_s.send(());
+            // The source fills the port meanwhile
+            thread::sleep(Duration::from_millis(200));
+            loop {
+                let mut msg = try!(input.recv());
+                seen.lock().unwrap().push(msg_bytes(&mut msg).unwrap()[0]);
+            }
+        });
+        sched.connect("source", "output", "drain", "input").unwrap();
+        sched.install_signal_handler(Duration::from_secs(5)).unwrap();
+        sched.start().unwrap();
+        started_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+        let started = Instant::now();
+        sched.wait_shutdown();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(*drained.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(signal::received(), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn shutdown_halts_without_a_stuck_agent() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0]);
+        let (started_s, started_r) = channel();
+        add_probe(&mut sched, "stuck", move |input, _| {
+            try!(input.recv());
+            let _ = started_s.send(());
+            // Deaf to the shutdown
+            thread::sleep(Duration::from_secs(1));
+            Ok(Signal::End)
+        });
+        sched.connect("source", "output", "stuck", "input").unwrap();
+        sched.start().unwrap();
+        started_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        let started = Instant::now();
+        match sched.shutdown(Duration::from_millis(100)) {
+            Err(result::Error::ShutdownTimeout(ref names)) if *names == vec!["stuck".to_string()] => {},
+            _ => panic!("the shutdown doesn't time out on the stuck agent"),
+        }
+        assert!(started.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn shutdown_wakes_up_a_blocked_sender() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2, 3, 4]);
+        add_sink(&mut sched, "sink");
+        sched.set_port_capacity("sink", "input", 1).unwrap();
+        sched.connect("source", "output", "sink", "input").unwrap();
+        sched.pause("sink").unwrap();
+        sched.start().unwrap();
+        wait_until(|| {
+            match sched.dump_state().agents.iter().find(|a| { a.name == "source" }).map(|a| { a.status.clone() }) {
+                Some(AgentStatus::Blocked { .. }) => true,
+                _ => false,
+            }
+        });
+        let started = Instant::now();
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn shutdown_after_a_timer_is_complete() {
+        let sched = Scheduler::new();
+        sched.shutdown_after(Duration::from_millis(0));
+        thread::sleep(Duration::from_millis(200));
+        sched.shutdown(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_after_stops_an_endless_source() {
+        let mut sched = Scheduler::new();
+        let sink = add_endless(&mut sched);
+        sched.start().unwrap();
+        let start = Instant::now();
+        sched.shutdown_after(Duration::from_millis(100));
+        sched.wait_shutdown();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1),
+                "the scheduler stops after {:?}", elapsed);
+        assert!(!sink.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancelled_shutdown_keeps_the_graph_running() {
+        let mut sched = Scheduler::new();
+        let sink = add_endless(&mut sched);
+        sched.start().unwrap();
+        sched.shutdown_after(Duration::from_millis(100));
+        sched.cancel_scheduled_shutdown();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!sched.is_stopping());
+        let received = sink.lock().unwrap().len();
+        wait_until(|| { sink.lock().unwrap().len() > received });
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn set_port_capacity_before_connect() {
+        let mut sched = Scheduler::new();
+        add_source(&mut sched, "source", vec![0, 1, 2]);
+        let sink = add_sink(&mut sched, "sink");
+        sched.set_port_capacity("sink", "input", 1).unwrap();
+        sched.connect("source", "output", "sink", "input").unwrap();
+        match sched.set_port_capacity("sink", "input", 4) {
+            Err(result::Error::AlreadyConnected(ref agent, ref port)) if agent == "sink" && port == "input" => {},
+            _ => panic!("the capacity of a connected port is set"),
+        }
+        sched.start().unwrap();
+        sched.run_until_idle().unwrap();
+        assert_eq!(bytes(&sink), vec![0, 1, 2]);
+    }
+}
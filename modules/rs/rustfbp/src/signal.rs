@@ -0,0 +1,60 @@
+//! Catch SIGTERM and SIGINT, to shut down the scheduler gracefully
+//!
+//! The handler only stores the number of the signal in an atomic, which is safe in a signal
+//! handler. The scheduler polls it from a thread of its own, see
+//! `Scheduler::install_signal_handler`.
+
+#[cfg(all(unix, feature = "signals"))]
+extern crate libc;
+
+use result;
+use result::Result;
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The number of the first signal received, 0 if none
+///
+/// It is never reset: once a signal is received, the schedulers installing a handler later are
+/// shut down at once.
+static RECEIVED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Keep the first signal only, `compare_and_swap` ignores the next ones: a SIGINT after a
+/// SIGTERM doesn't change the signal logged, and doesn't hurry the shutdown
+#[cfg(all(unix, feature = "signals"))]
+extern "C" fn on_signal(signum: libc::c_int) {
+    let _ = RECEIVED.compare_and_swap(0, signum as usize, Ordering::SeqCst);
+}
+
+/// Install the handler of SIGTERM and SIGINT
+#[cfg(all(unix, feature = "signals"))]
+pub fn install() -> Result<()> {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    for &signum in &[libc::SIGTERM, libc::SIGINT] {
+        let res = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signum, &action, ptr::null_mut())
+        };
+        if res != 0 {
+            return Err(result::Error::Misc(format!("cannot handle the signal {} : {}", signum, io::Error::last_os_error())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "signals")))]
+pub fn install() -> Result<()> {
+    Err(result::Error::Misc("cannot handle the signals, rustfbp is built without the signals feature or not for Unix".into()))
+}
+
+/// The number of the first signal received, if any, the next signals are ignored
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum as i32),
+    }
+}
@@ -0,0 +1,435 @@
+//! Run a single agent, without a scheduler
+//!
+//! The `AgentTester` creates the agent from its `new` function, sends Msgs to its input ports
+//! and collects what the agent sends on its output ports. The agent is run in the current thread.
+
+
+use result;
+use result::Result;
+
+use ports::{Msg, MsgSender, MsgReceiver};
+use record::Recorder;
+use scheduler::{AgentCtx, BoxedComp, CompMsg, Signal};
+
+use capnp;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::channel;
+
+/// The `new` function generated by the `agent!` macro
+pub type CreateAgent = fn(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)>;
+
+/// The number of Msgs an output port can keep during one run of the agent
+const CAPTURE_CAPACITY: usize = 1024;
+
+/// A Msg whose capnp message is the `List(UInt8)` of `bytes`
+///
+/// For the tests of the agents that don't read the schema of their Msgs.
+pub fn bytes_msg(bytes: &[u8]) -> Msg {
+    let mut msg = Msg::new();
+    {
+        let mut list: capnp::primitive_list::Builder<u8> = msg.build_list(bytes.len() as u32);
+        for (i, byte) in bytes.iter().enumerate() {
+            list.set(i as u32, *byte);
+        }
+    }
+    msg
+}
+
+/// The bytes of a Msg built by `bytes_msg`
+pub fn msg_bytes(msg: &mut Msg) -> Result<Vec<u8>> {
+    let list: capnp::primitive_list::Reader<u8> = try!(msg.read_schema());
+    Ok((0..list.len()).map(|i| { list.get(i) }).collect())
+}
+
+/// Drive an agent in isolation
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut tester = try!(AgentTester::new(new));
+/// try!(tester.capture("output"));
+/// try!(tester.push("input", msg));
+/// try!(tester.run(10));
+/// let out = tester.output("output");
+/// assert_eq!(out.len(), 1);
+/// ```
+pub struct AgentTester {
+    comp: BoxedComp,
+    inputs: HashMap<String, MsgSender>,
+    inputs_array: HashMap<(String, String), MsgSender>,
+    outputs: HashMap<(String, Option<String>), MsgReceiver>,
+    collected: HashMap<(String, Option<String>), Vec<Msg>>,
+    sched: Sender<CompMsg>,
+    sched_r: Receiver<CompMsg>,
+    ctx: AgentCtx,
+    capture_ctx: AgentCtx,
+    ips: isize,
+    started: bool,
+}
+
+impl AgentTester {
+    /// Create the agent with its `new` function
+    pub fn new(create: CreateAgent) -> Result<Self> {
+        AgentTester::with(create)
+    }
+
+    /// Create the agent with a closure, as `Scheduler::add_agent_boxed`, for the builtin agents
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let mut tester = try!(AgentTester::with(|id, sched, ctx| {
+    ///     Throttle::create(id, sched, ctx, 10.0, ThrottleMode::Block)
+    /// }));
+    /// ```
+    pub fn with<F>(create: F) -> Result<Self> where
+        F: FnOnce(usize, Sender<CompMsg>, AgentCtx) -> Result<(BoxedComp, HashMap<String, MsgSender>)>,
+    {
+        let (s, r) = channel();
+        let ctx = AgentCtx::new();
+        let (comp, inputs) = try!(create(0, s.clone(), ctx.clone()));
+        Ok(AgentTester {
+            comp: comp,
+            inputs: inputs,
+            inputs_array: HashMap::new(),
+            outputs: HashMap::new(),
+            collected: HashMap::new(),
+            sched: s,
+            sched_r: r,
+            ctx: ctx,
+            capture_ctx: AgentCtx::new(),
+            ips: 0,
+            started: false,
+        })
+    }
+
+    /// Send an Msg to an input port
+    ///
+    /// At most 25 Msgs can wait on a port, run the agent between the pushes.
+    pub fn push(&mut self, port: &str, msg: Msg) -> Result<()> {
+        {
+            let sender = try!(self.inputs.get(port).ok_or(result::Error::PortNotFound("tester".into(), port.into())));
+            try!(sender.send(msg));
+        }
+        self.count_ips();
+        Ok(())
+    }
+
+    /// Send an Msg to an element of an array input port, the element is created if needed
+    pub fn push_array(&mut self, port: &str, element: &str, msg: Msg) -> Result<()> {
+        let key = (port.to_string(), element.to_string());
+        if !self.inputs_array.contains_key(&key) {
+            let (r, s) = MsgReceiver::new(0, &format!("{}[{}]", port, element), self.sched.clone(), self.ctx.clone(), true);
+            try!(self.comp.add_inarr_element(port, element.into(), r));
+            self.inputs_array.insert(key.clone(), s);
+        }
+        try!(self.inputs_array[&key].send(msg));
+        self.count_ips();
+        Ok(())
+    }
+
+    /// The number of Msgs waiting on an input port, or on an element of an array input port
+    pub fn waiting(&self, port: &str, element: Option<&str>) -> usize {
+        let sender = match element {
+            Some(element) => self.inputs_array.get(&(port.to_string(), element.to_string())),
+            None => self.inputs.get(port),
+        };
+        sender.map(|s| { s.port().depth() }).unwrap_or(0)
+    }
+
+    /// Record the Msgs received by the agent in a file, see `record::Player` to replay them
+    pub fn record<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let recorder = try!(Recorder::create(path));
+        self.ctx.set_recorder(Some(Arc::new(recorder)));
+        Ok(())
+    }
+
+    /// Connect an output port, to collect the Msgs sent on it
+    pub fn capture(&mut self, port: &str) -> Result<()> {
+        let (r, s) = MsgReceiver::with_capacity(1, port, self.sched.clone(), self.capture_ctx.clone(), false, CAPTURE_CAPACITY);
+        try!(self.comp.connect(port, s));
+        self.outputs.insert((port.into(), None), r);
+        Ok(())
+    }
+
+    /// Connect an element of an array output port, to collect the Msgs sent on it
+    pub fn capture_array(&mut self, port: &str, element: &str) -> Result<()> {
+        let (r, s) = MsgReceiver::with_capacity(1, &format!("{}[{}]", port, element), self.sched.clone(), self.capture_ctx.clone(), false, CAPTURE_CAPACITY);
+        try!(self.comp.connect_array(port, element.into(), s));
+        self.outputs.insert((port.into(), Some(element.into())), r);
+        Ok(())
+    }
+
+    /// Run the agent at most `max` times, and return the number of runs
+    ///
+    /// An agent with input ports is run while Msgs wait on them. An agent without input
+    /// ports is run until it returns `End`.
+    pub fn run(&mut self, max: usize) -> Result<usize> {
+        let has_inputs = self.comp.is_input_ports();
+        let mut runs = 0;
+        while runs < max {
+            self.count_ips();
+            if has_inputs && self.ips <= 0 {
+                break;
+            }
+            if !self.started {
+                self.started = true;
+                try!(self.comp.on_start());
+            }
+            let signal = try!(self.comp.run());
+            runs += 1;
+            self.collect();
+            if let Signal::End = signal {
+                if !has_inputs { break; }
+            }
+        }
+        self.count_ips();
+        Ok(runs)
+    }
+
+    /// Call `on_stop` on the agent, if it was started
+    pub fn stop(&mut self) -> Result<()> {
+        if self.started {
+            self.started = false;
+            try!(self.comp.on_stop());
+            self.collect();
+        }
+        Ok(())
+    }
+
+    /// Take the Msgs collected on an output port
+    pub fn output(&mut self, port: &str) -> Vec<Msg> {
+        self.collect();
+        self.collected.remove(&(port.to_string(), None)).unwrap_or(vec![])
+    }
+
+    /// Take the Msgs collected on an element of an array output port
+    pub fn output_array(&mut self, port: &str, element: &str) -> Vec<Msg> {
+        self.collect();
+        self.collected.remove(&(port.to_string(), Some(element.to_string()))).unwrap_or(vec![])
+    }
+
+    fn collect(&mut self) {
+        for (key, recv) in &self.outputs {
+            while let Ok(msg) = recv.try_recv() {
+                self.collected.entry(key.clone()).or_insert(vec![]).push(msg);
+            }
+        }
+    }
+
+    fn count_ips(&mut self) {
+        while let Ok(msg) = self.sched_r.try_recv() {
+            match msg {
+                CompMsg::Inc(_) => { self.ips += 1; },
+                CompMsg::Dec(_) => { self.ips -= 1; },
+                CompMsg::IncBatch(_, n) => { self.ips += n as isize; },
+                CompMsg::DecBatch(_, n) => { self.ips -= n as isize; },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// The struct `TimeDate { year @0 :Int16; month @1 :UInt8; day @2 :UInt8; }` of the edge
+/// `time_date`, for the tests of the crate
+///
+/// The schemas of the edges are generated in the agents, this module is written as capnpc
+/// would generate it.
+#[cfg(test)]
+pub mod time_date {
+    use capnp;
+    use capnp::private::layout::{PointerBuilder, PointerReader, StructBuilder, StructReader, StructSize};
+    use capnp::traits::{FromPointerBuilder, FromPointerReader, FromStructBuilder, FromStructReader, HasStructSize,
+                        HasTypeId, OwnedStruct, SetPointerBuilder};
+
+    use std::ptr;
+
+    pub const TYPE_ID: u64 = 0xc7a4_77d0_d7b5_0c3e;
+
+    pub struct Owned;
+
+    impl<'a> capnp::traits::Owned<'a> for Owned {
+        type Reader = Reader<'a>;
+        type Builder = Builder<'a>;
+    }
+
+    impl<'a> OwnedStruct<'a> for Owned {
+        type Reader = Reader<'a>;
+        type Builder = Builder<'a>;
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Reader<'a> {
+        reader: StructReader<'a>,
+    }
+
+    impl<'a> FromStructReader<'a> for Reader<'a> {
+        fn new(reader: StructReader<'a>) -> Reader<'a> {
+            Reader { reader: reader }
+        }
+    }
+
+    impl<'a> FromPointerReader<'a> for Reader<'a> {
+        fn get_from_pointer(reader: &PointerReader<'a>) -> capnp::Result<Reader<'a>> {
+            Ok(Reader { reader: try!(reader.get_struct(ptr::null())) })
+        }
+    }
+
+    impl<'a, 'b> SetPointerBuilder<Builder<'a>> for Reader<'b> {
+        fn set_pointer_builder<'c>(pointer: PointerBuilder<'c>, value: Reader<'b>) -> capnp::Result<()> {
+            pointer.set_struct(&value.reader)
+        }
+    }
+
+    impl<'a> HasTypeId for Reader<'a> {
+        fn type_id() -> u64 {
+            TYPE_ID
+        }
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn get_year(&self) -> i16 {
+            self.reader.get_data_field::<i16>(0)
+        }
+        pub fn get_month(&self) -> u8 {
+            self.reader.get_data_field::<u8>(2)
+        }
+        pub fn get_day(&self) -> u8 {
+            self.reader.get_data_field::<u8>(3)
+        }
+    }
+
+    pub struct Builder<'a> {
+        builder: StructBuilder<'a>,
+    }
+
+    impl<'a> FromStructBuilder<'a> for Builder<'a> {
+        fn new(builder: StructBuilder<'a>) -> Builder<'a> {
+            Builder { builder: builder }
+        }
+    }
+
+    impl<'a> FromPointerBuilder<'a> for Builder<'a> {
+        fn init_pointer(builder: PointerBuilder<'a>, _len: u32) -> Builder<'a> {
+            Builder { builder: builder.init_struct(Builder::struct_size()) }
+        }
+        fn get_from_pointer(builder: PointerBuilder<'a>) -> capnp::Result<Builder<'a>> {
+            Ok(Builder { builder: try!(builder.get_struct(Builder::struct_size(), ptr::null())) })
+        }
+    }
+
+    impl<'a> HasStructSize for Builder<'a> {
+        fn struct_size() -> StructSize {
+            StructSize { data: 1, pointers: 0 }
+        }
+    }
+
+    impl<'a> Builder<'a> {
+        pub fn set_year(&mut self, value: i16) {
+            self.builder.set_data_field::<i16>(0, value);
+        }
+        pub fn set_month(&mut self, value: u8) {
+            self.builder.set_data_field::<u8>(2, value);
+        }
+        pub fn set_day(&mut self, value: u8) {
+            self.builder.set_data_field::<u8>(3, value);
+        }
+    }
+
+    /// A Msg holding the date
+    pub fn msg(year: i16, month: u8, day: u8) -> ::ports::Msg {
+        let mut msg = ::ports::Msg::new();
+        {
+            let mut date: Builder = msg.build_schema();
+            date.set_year(year);
+            date.set_month(month);
+            date.set_day(day);
+        }
+        msg.before_send().expect("time_date: unable to build the Msg");
+        msg
+    }
+
+    /// The date of a Msg
+    pub fn read(msg: &mut ::ports::Msg) -> (i16, u8, u8) {
+        let date: Reader = msg.read_schema().expect("time_date: not a date");
+        (date.get_year(), date.get_month(), date.get_day())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use builtin::{Map, MapErrorPolicy, VecSource};
+    use ports::MsgKind;
+    use typed;
+
+    fn increment_year() -> Result<AgentTester> {
+        AgentTester::with(|id, sched, ctx| {
+            Map::<time_date::Owned, time_date::Owned>::create(id, sched, ctx, MapErrorPolicy::Abort,
+                Box::new(|date: time_date::Reader, out: &mut time_date::Builder| {
+                    out.set_year(date.get_year() + 1);
+                    out.set_month(date.get_month());
+                    out.set_day(date.get_day());
+                    Ok(())
+                }))
+        })
+    }
+
+    #[test]
+    fn increment_year_agent() {
+        let mut tester = increment_year().unwrap();
+        tester.capture("output").unwrap();
+        tester.push("input", time_date::msg(2016, 2, 29)).unwrap();
+        tester.push("input", time_date::msg(-1, 12, 31)).unwrap();
+        assert_eq!(tester.run(10).unwrap(), 2);
+        let mut out = tester.output("output");
+        assert_eq!(out.len(), 2);
+        assert_eq!(time_date::read(&mut out[0]), (2017, 2, 29));
+        assert_eq!(time_date::read(&mut out[1]), (0, 12, 31));
+        assert!(typed::check_schema::<time_date::Owned>(&out[0]).is_ok());
+        assert!(tester.output("output").is_empty());
+    }
+
+    #[test]
+    fn run_stops_once_the_inputs_are_read() {
+        let mut tester = increment_year().unwrap();
+        tester.capture("output").unwrap();
+        assert_eq!(tester.run(10).unwrap(), 0);
+        tester.push("input", Msg::open_bracket(None)).unwrap();
+        tester.push("input", time_date::msg(2017, 6, 1)).unwrap();
+        tester.push("input", Msg::close_bracket()).unwrap();
+        assert_eq!(tester.waiting("input", None), 3);
+        assert_eq!(tester.run(2).unwrap(), 2);
+        assert_eq!(tester.waiting("input", None), 1);
+        assert_eq!(tester.run(10).unwrap(), 1);
+        let kinds: Vec<MsgKind> = tester.output("output").into_iter().map(|msg| { msg.kind }).collect();
+        assert_eq!(kinds, vec![MsgKind::OpenBracket(None), MsgKind::Data, MsgKind::CloseBracket]);
+        tester.stop().unwrap();
+    }
+
+    #[test]
+    fn run_a_source_until_its_end() {
+        let mut tester = AgentTester::with(|id, sched, ctx| {
+            VecSource::create(id, sched, ctx, vec![bytes_msg(&[1]), bytes_msg(&[2])])
+        }).unwrap();
+        tester.capture("output").unwrap();
+        assert!(tester.run(10).unwrap() < 10);
+        let bytes: Vec<Vec<u8>> = tester.output("output").iter_mut().map(|msg| { msg_bytes(msg).unwrap() }).collect();
+        assert_eq!(bytes, vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn push_to_an_unknown_port() {
+        let mut tester = increment_year().unwrap();
+        match tester.push("other", time_date::msg(2017, 6, 1)) {
+            Err(result::Error::PortNotFound(_, ref port)) if port == "other" => {},
+            _ => panic!("the Msg is pushed to an unknown port"),
+        }
+        assert!(tester.capture("other").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! The shape of a graph: its agents and the edges between them
+//!
+//! `Scheduler::topology` describes a scheduler, `Scheduler::from_topology` builds a new
+//! scheduler with the same agents and edges. The Msgs in flight, the IIPs and the state of the
+//! agents are not part of a topology.
+//!
+//! # Format
+//!
+//! A topology is printed in the syntax of the fbp graphs, a line per agent with its dylib, then
+//! a line per edge. A feedback edge ends with the comment `// feedback`.
+//!
+//! ```text
+//! add(/nix/store/...-add/lib/libagent.so)
+//! display(/nix/store/...-display/lib/libagent.so)
+//! add() output -> input display()
+//! add() outputs[1] -> inputs[a] add() // feedback
+//! ```
+//!
+//! The empty lines and the other comments are skipped when parsing.
+//!
+//! With the `serde` feature, a topology is also `Serialize` and `Deserialize`, to be saved in
+//! JSON or in another format of serde.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let topology = sched.topology();
+//! try!(file.write_all(topology.to_string().as_bytes()));
+//!
+//! let topology: Topology = try!(text.parse());
+//! let sched = try!(Scheduler::from_topology(topology));
+//!
+//! // With the serde feature
+//! let json = try!(serde_json::to_string(&sched.topology()));
+//! ```
+
+use result;
+use result::Result;
+use scheduler::Edge;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The comment marking a feedback edge
+const FEEDBACK: &'static str = "// feedback";
+
+/// An agent of a topology
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AgentDesc {
+    pub name: String,
+    /// The complete path to the dylib
+    pub sort: String,
+}
+
+/// The agents of a graph, sorted by name, and its edges
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Topology {
+    pub agents: Vec<AgentDesc>,
+    pub edges: Vec<Edge>,
+}
+
+fn port_label(port: &str, element: &Option<String>) -> String {
+    match *element {
+        Some(ref e) => format!("{}[{}]", port, e),
+        None => port.into(),
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for agent in &self.agents {
+            try!(writeln!(f, "{}({})", agent.name, agent.sort));
+        }
+        for edge in &self.edges {
+            try!(write!(f, "{}() {} -> {} {}()", edge.out_agent, port_label(&edge.out_port, &edge.out_element),
+                        port_label(&edge.in_port, &edge.in_element), edge.in_agent));
+            if edge.feedback {
+                try!(write!(f, " {}", FEEDBACK));
+            }
+            try!(writeln!(f, ""));
+        }
+        Ok(())
+    }
+}
+
+/// Parse `name()`
+fn parse_name<'a>(s: &'a str, line: &str) -> Result<&'a str> {
+    if s.len() > 2 && s.ends_with("()") {
+        Ok(&s[..s.len() - 2])
+    } else {
+        Err(result::Error::BadTopology(format!("expected an agent in '{}'", line)))
+    }
+}
+
+/// Parse `port` or `port[element]`
+fn parse_port(s: &str, line: &str) -> Result<(String, Option<String>)> {
+    match s.find('[') {
+        Some(pos) if pos > 0 && s.ends_with(']') => Ok((s[..pos].into(), Some(s[pos + 1..s.len() - 1].into()))),
+        None if !s.is_empty() && !s.contains(']') => Ok((s.into(), None)),
+        _ => Err(result::Error::BadTopology(format!("expected a port in '{}'", line))),
+    }
+}
+
+fn parse_edge(edge: &str, feedback: bool, line: &str) -> Result<Edge> {
+    let pos = edge.find("->").expect("parse_edge: not an edge");
+    let out: Vec<&str> = edge[..pos].split_whitespace().collect();
+    let inp: Vec<&str> = edge[pos + 2..].split_whitespace().collect();
+    if out.len() != 2 || inp.len() != 2 {
+        return Err(result::Error::BadTopology(format!("expected 'agent() port -> port agent()' in '{}'", line)));
+    }
+    let out_agent = try!(parse_name(out[0], line));
+    let (out_port, out_element) = try!(parse_port(out[1], line));
+    let (in_port, in_element) = try!(parse_port(inp[0], line));
+    let in_agent = try!(parse_name(inp[1], line));
+    Ok(Edge {
+        out_agent: out_agent.into(), out_port: out_port, out_element: out_element,
+        in_agent: in_agent.into(), in_port: in_port, in_element: in_element,
+        feedback: feedback,
+    })
+}
+
+impl FromStr for Topology {
+    type Err = result::Error;
+
+    fn from_str(s: &str) -> Result<Topology> {
+        let mut topology = Topology { agents: vec![], edges: vec![] };
+        for line in s.lines() {
+            let l = line.trim();
+            let (l, feedback) = if l.ends_with(FEEDBACK) {
+                (l[..l.len() - FEEDBACK.len()].trim(), true)
+            } else {
+                (l, false)
+            };
+            if l.is_empty() || l.starts_with("//") {
+                continue;
+            }
+            if l.contains("->") {
+                topology.edges.push(try!(parse_edge(l, feedback, line)));
+                continue;
+            }
+            match l.find('(') {
+                Some(pos) if pos > 0 && l.len() > pos + 2 && l.ends_with(')') && !l[..pos].contains(char::is_whitespace) => {
+                    topology.agents.push(AgentDesc {
+                        name: l[..pos].into(),
+                        sort: l[pos + 1..l.len() - 1].into(),
+                    });
+                },
+                _ => { return Err(result::Error::BadTopology(format!("expected 'agent(sort)' in '{}'", line))); },
+            }
+        }
+        Ok(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builtin::{VecSink, VecSource};
+    use scheduler::Scheduler;
+
+    use std::sync::{Arc, Mutex};
+
+    fn graph() -> Topology {
+        let mut sched = Scheduler::new();
+        sched.add_agent_boxed("source", |id, sc, ctx| { VecSource::create(id, sc, ctx, vec![]) }).unwrap();
+        sched.add_agent_boxed("sink", |id, sc, ctx| { VecSink::create(id, sc, ctx, Arc::new(Mutex::new(vec![]))) }).unwrap();
+        sched.connect("source", "output", "sink", "input").unwrap();
+        let topology = sched.topology();
+        sched.join();
+        topology
+    }
+
+    #[test]
+    fn topology_round_trips_through_the_text_format() {
+        let topology = graph();
+        assert_eq!(topology.agents.len(), 2);
+        assert_eq!(topology.edges.len(), 1);
+        let parsed: Topology = topology.to_string().parse().unwrap();
+        assert_eq!(parsed, topology);
+    }
+
+    #[test]
+    fn feedback_and_array_edges_are_parsed() {
+        let text = "add(/lib/libadd.so)\nadd() outputs[1] -> inputs[a] add() // feedback\n";
+        let topology: Topology = text.parse().unwrap();
+        let edge = &topology.edges[0];
+        assert_eq!(edge.out_element, Some("1".to_string()));
+        assert_eq!(edge.in_element, Some("a".to_string()));
+        assert!(edge.feedback);
+        assert_eq!(topology.to_string(), text);
+        assert!("add() output".parse::<Topology>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn topology_round_trips_through_json() {
+        let topology = graph();
+        let json = ::serde_json::to_string(&topology).unwrap();
+        let back: Topology = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(back.edges, topology.edges);
+        assert_eq!(back, topology);
+    }
+}
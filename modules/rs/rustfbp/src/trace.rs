@@ -0,0 +1,301 @@
+//! Spans per run of an agent, linked by a trace context carried in the Msgs
+//!
+//! With the `tracing` feature, each run of an agent is in a span `agent::process` of the
+//! `tracing` crate, with the fields `agent`, `span_id`, `trace_id` and `parent_id`. The Msgs
+//! sent during the run carry the trace in the header `TRACE_HEADER`, in the W3C
+//! `traceparent` format: `00-<trace id>-<span id>-01`. The run receiving such a Msg takes its
+//! trace and is the child of the span that sent it. The headers go through the TCP
+//! transport, so the spans are linked across the processes too.
+//!
+//! A run that receives no traced Msg starts a new trace when it sends its first Msg. A run
+//! that sends a Msg already traced, as a `NetworkSource` does, continues that trace.
+//!
+//! Without the feature, nothing is traced and the Msgs have no trace header.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! // With rustfbp = { features = ["tracing"] } and a subscriber exporting the spans
+//! tracing::subscriber::set_global_default(subscriber).expect("cannot set the subscriber");
+//! try!(sched.start());
+//! ```
+
+use ports::Msg;
+
+/// The header holding the trace context of a Msg
+pub const TRACE_HEADER: &'static str = "traceparent";
+
+/// Run `f` in the span of a run of `agent`
+pub fn in_span<T, F: FnOnce() -> T>(agent: &str, f: F) -> T {
+    imp::in_span(agent, f)
+}
+
+/// Continue the trace of a received Msg, called by the input ports
+pub fn on_recv(msg: &Msg) {
+    imp::on_recv(msg)
+}
+
+/// Put the trace context of the current run in a sent Msg, called by the output ports
+pub fn on_send(msg: &mut Msg) {
+    imp::on_send(msg)
+}
+
+/// Read a `traceparent` header, returns the trace id and the id of the parent span
+pub fn parse_header(value: &[u8]) -> Option<((u64, u64), u64)> {
+    let value = match ::std::str::from_utf8(value) {
+        Ok(value) => value,
+        Err(_) => { return None; },
+    };
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() != 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+    let high = u64::from_str_radix(&parts[1][..16], 16);
+    let low = u64::from_str_radix(&parts[1][16..], 16);
+    let span = u64::from_str_radix(parts[2], 16);
+    match (high, low, span) {
+        (Ok(high), Ok(low), Ok(span)) if (high, low) != (0, 0) && span != 0 => Some(((high, low), span)),
+        _ => None,
+    }
+}
+
+/// Write a `traceparent` header
+pub fn format_header(trace_id: (u64, u64), span_id: u64) -> String {
+    format!("00-{:016x}{:016x}-{:016x}-01", trace_id.0, trace_id.1, span_id)
+}
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use tracing;
+
+    use ports::Msg;
+    use random::random_u64;
+
+    use std::cell::RefCell;
+    use std::mem;
+
+    use super::{TRACE_HEADER, parse_header, format_header};
+
+    /// The trace of the run on the thread
+    struct Context {
+        trace_id: Option<(u64, u64)>,
+        span_id: u64,
+        span: tracing::Span,
+    }
+
+    impl Context {
+        fn set_trace(&mut self, trace_id: (u64, u64), parent: Option<u64>) {
+            self.trace_id = Some(trace_id);
+            self.span.record("trace_id", &tracing::field::display(format!("{:016x}{:016x}", trace_id.0, trace_id.1)));
+            if let Some(parent) = parent {
+                self.span.record("parent_id", &tracing::field::display(format!("{:016x}", parent)));
+            }
+        }
+    }
+
+    thread_local!(static CURRENT: RefCell<Option<Context>> = RefCell::new(None));
+
+    /// A random id, never 0 as 0 is invalid in a `traceparent`
+    fn random_id() -> u64 {
+        loop {
+            let id = random_u64();
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    pub fn in_span<T, F: FnOnce() -> T>(agent: &str, f: F) -> T {
+        let span_id = random_id();
+        let span = tracing::info_span!("agent::process",
+                                       agent = agent,
+                                       span_id = %format!("{:016x}", span_id),
+                                       trace_id = tracing::field::Empty,
+                                       parent_id = tracing::field::Empty);
+        let context = Context {
+            trace_id: None,
+            span_id: span_id,
+            span: span.clone(),
+        };
+        let previous = CURRENT.with(|current| { mem::replace(&mut *current.borrow_mut(), Some(context)) });
+        let res = span.in_scope(f);
+        CURRENT.with(|current| { *current.borrow_mut() = previous; });
+        res
+    }
+
+    pub fn on_recv(msg: &Msg) {
+        let parent = match msg.get_header(TRACE_HEADER).and_then(|value| { parse_header(value) }) {
+            Some(parent) => parent,
+            None => { return; },
+        };
+        CURRENT.with(|current| {
+            if let Some(ref mut context) = *current.borrow_mut() {
+                // The first traced Msg of the run gives its parent
+                if context.trace_id.is_none() {
+                    context.set_trace(parent.0, Some(parent.1));
+                }
+            }
+        });
+    }
+
+    pub fn on_send(msg: &mut Msg) {
+        let header = CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            let context = match *current {
+                Some(ref mut context) => context,
+                None => { return None; },
+            };
+            if context.trace_id.is_none() {
+                match msg.get_header(TRACE_HEADER).and_then(|value| { parse_header(value) }) {
+                    Some(parent) => { context.set_trace(parent.0, Some(parent.1)); },
+                    None => { context.set_trace((random_id(), random_id()), None); },
+                }
+            }
+            context.trace_id.map(|trace_id| { format_header(trace_id, context.span_id) })
+        });
+        if let Some(header) = header {
+            msg.set_header(TRACE_HEADER, header.into_bytes());
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use ports::Msg;
+
+    pub fn in_span<T, F: FnOnce() -> T>(_agent: &str, f: F) -> T {
+        f()
+    }
+
+    pub fn on_recv(_msg: &Msg) {}
+
+    pub fn on_send(_msg: &mut Msg) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = format_header((0x0123456789abcdef, 0xfedcba9876543210), 0x1122334455667788);
+        assert_eq!(header, "00-0123456789abcdeffedcba9876543210-1122334455667788-01");
+        assert_eq!(parse_header(header.as_bytes()), Some(((0x0123456789abcdef, 0xfedcba9876543210), 0x1122334455667788)));
+        // The ids keep their leading zeros
+        let header = format_header((0, 1), 2);
+        assert_eq!(header, "00-00000000000000000000000000000001-0000000000000002-01");
+        assert_eq!(parse_header(header.as_bytes()), Some(((0, 1), 2)));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let headers: Vec<&[u8]> = vec![
+            // The ids 0 are invalid
+            b"00-00000000000000000000000000000000-1122334455667788-01",
+            b"00-0123456789abcdeffedcba9876543210-0000000000000000-01",
+            // Wrong lengths
+            b"00-0123456789abcdeffedcba987654321-1122334455667788-01",
+            b"00-0123456789abcdeffedcba98765432100-1122334455667788-01",
+            b"00-0123456789abcdeffedcba9876543210-112233445566778-01",
+            b"00-0123456789abcdeffedcba9876543210-1122334455667788",
+            b"00-0123456789abcdeffedcba9876543210-1122334455667788-01-00",
+            b"",
+            // Not hexadecimal, not UTF-8
+            b"00-0123456789abcdeffedcba987654321g-1122334455667788-01",
+            b"00-0123456789abcdeffedcba9876543210-11223344556677\xff\xff-01",
+        ];
+        for header in headers {
+            assert_eq!(parse_header(header), None, "{:?} is parsed", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    mod capture {
+        use builtin::{Throttle, ThrottleMode, VecSink};
+        use scheduler::Scheduler;
+        use testing::bytes_msg;
+
+        use tracing;
+        use tracing::{Event, Id, Metadata};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Record};
+
+        use std::collections::HashMap;
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        type Spans = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+        struct Fields(HashMap<String, String>);
+
+        impl Visit for Fields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().into(), value.into());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &fmt::Debug) {
+                self.0.insert(field.name().into(), format!("{:?}", value));
+            }
+        }
+
+        /// Keeps the fields of every span, the id of a span is its index plus 1
+        struct Capture {
+            spans: Spans,
+        }
+
+        impl tracing::Subscriber for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, attrs: &Attributes) -> Id {
+                let mut fields = Fields(HashMap::new());
+                attrs.record(&mut fields);
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(fields.0);
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, id: &Id, values: &Record) {
+                let mut fields = Fields(HashMap::new());
+                values.record(&mut fields);
+                self.spans.lock().unwrap()[id.into_u64() as usize - 1].extend(fields.0);
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, _: &Event) {}
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        #[test]
+        fn chained_agents_have_linked_spans() {
+            let spans: Spans = Arc::new(Mutex::new(vec![]));
+            // The agents run in the threads of the pool, the subscriber is global
+            tracing::subscriber::set_global_default(Capture { spans: spans.clone() }).unwrap();
+            let mut sched = Scheduler::new();
+            sched.add_agent_boxed("traced_first", |id, sc, ctx| { Throttle::create(id, sc, ctx, 1000.0, ThrottleMode::Block) }).unwrap();
+            sched.add_agent_boxed("traced_second", |id, sc, ctx| { VecSink::create(id, sc, ctx, Arc::new(Mutex::new(vec![]))) }).unwrap();
+            sched.connect("traced_first", "output", "traced_second", "input").unwrap();
+            sched.start().unwrap();
+            sched.inject("traced_first", "input", bytes_msg(&[0])).unwrap();
+            assert!(sched.wait_for_idle(Duration::from_secs(5)).unwrap());
+            sched.shutdown(Duration::from_secs(5)).unwrap();
+            let spans = spans.lock().unwrap();
+            let agent = |name: &str| -> Vec<&HashMap<String, String>> {
+                spans.iter().filter(|s| { s.get("agent").map(|a| { a == name }).unwrap_or(false) }).collect()
+            };
+            let (first, second) = (agent("traced_first"), agent("traced_second"));
+            assert_eq!((first.len(), second.len()), (1, 1), "{:?}", *spans);
+            // The first run starts the trace, the second is its child
+            assert!(first[0].get("trace_id").is_some());
+            assert_eq!(first[0].get("parent_id"), None);
+            assert_eq!(second[0].get("trace_id"), first[0].get("trace_id"));
+            assert_eq!(second[0].get("parent_id"), first[0].get("span_id"));
+        }
+    }
+}
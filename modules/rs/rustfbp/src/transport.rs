@@ -0,0 +1,771 @@
+//! Edges between two processes, over TCP
+//!
+//! A `NetworkSink` gives a `MsgSender`: the Msgs sent on it are written on a TCP connection.
+//! A `NetworkSource` listens for these connections and sends the Msgs it reads to a local
+//! input port. Together they make an edge between two schedulers, on two machines or in
+//! two processes.
+//!
+//! Each Msg is a frame, a `u32` little endian length followed by the action, the kind, the
+//! headers and the capnp message of the Msg. With `NetworkSink::connect_packed` and
+//! `NetworkSource::listen_packed`, the capnp message is in the packed encoding, both sides
+//! must agree.
+//!
+//! With `FrameFormat::verify_integrity`, the frame ends with the CRC-32 of the canonical form
+//! of the capnp message (see `Msg::canonicalize`). The source computes it again on the Msg it
+//! decoded, and drops the Msg with `Error::IntegrityMismatch` if they differ.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! // Machine B
+//! let sender = try!(sched_b.get_sender("display", "input"));
+//! let source = try!(NetworkSource::listen("0.0.0.0:7000", sender));
+//!
+//! // Machine A
+//! let sink = try!(NetworkSink::connect("machine_b:7000", 5));
+//! try!(sched_a.connect_sender("add", "output", sink.sender()));
+//!
+//! // Both sides check the Msgs
+//! let format = FrameFormat { packed: false, verify_integrity: true };
+//! let source = try!(NetworkSource::listen_with("0.0.0.0:7000", sender, format));
+//! let sink = try!(NetworkSink::connect_with("machine_b:7000", 5, format));
+//! ```
+//!
+//! A `SpillingChannel` is a local edge with the same frames: once it buffers more than its
+//! memory limit, it writes the oldest Msgs in files, and reads them back when the consumer
+//! catches up. Its files also keep the stamp of each Msg, see `Msg::stamp`.
+//!
+//! ```rust,ignore
+//! let input = try!(sched.get_sender("slow", "input"));
+//! let spill = try!(SpillingChannel::new(input, 64 * 1024 * 1024, "/var/tmp/fractalide/slow"));
+//! try!(sched.connect_sender("bursty", "output", spill.sender()));
+//! ```
+
+use result;
+use result::Result;
+
+use codec::{write_u32, write_u64, write_bytes, write_kind, read_u8, read_u32, read_u64, read_bytes, read_kind, crc32};
+use ports::{Msg, MsgSender, MsgReceiver};
+use scheduler::AgentCtx;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The target of the log records of the network edges
+const LOG_TARGET: &'static str = "fractalide::transport";
+
+/// The time between two attempts to connect
+const RETRY_MS: u64 = 200;
+
+/// The time between two checks of the closing of a sink
+const CLOSE_POLL_MS: u64 = 50;
+
+/// The time between two attempts to deliver to a full port, for a `SpillingChannel`
+const SPILL_POLL_MS: u64 = 5;
+
+/// The bytes of a frame besides the capnp message: the action, the kind, the headers and the checksum
+const FRAME_OVERHEAD: u64 = 64 * 1024;
+
+/// How the Msgs are written in the frames, both sides of an edge must agree
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameFormat {
+    /// The capnp message is in the packed encoding
+    pub packed: bool,
+    /// The frame ends with the checksum of the canonical form of the capnp message
+    pub verify_integrity: bool,
+}
+
+/// Write a Msg as a frame
+pub fn write_frame<W: Write>(out: &mut W, msg: &Msg) -> Result<()> {
+    write_frame_with(out, msg, FrameFormat::default())
+}
+
+/// Write a Msg as a frame, with the capnp message in the packed encoding
+pub fn write_packed_frame<W: Write>(out: &mut W, msg: &Msg) -> Result<()> {
+    write_frame_with(out, msg, FrameFormat { packed: true, verify_integrity: false })
+}
+
+/// Write a Msg as a frame of the format `format`
+pub fn write_frame_with<W: Write>(out: &mut W, msg: &Msg, format: FrameFormat) -> Result<()> {
+    let mut frame = vec![];
+    try!(write_bytes(&mut frame, msg.action.as_bytes()));
+    try!(write_kind(&mut frame, &msg.kind));
+    let headers = msg.headers();
+    try!(write_u32(&mut frame, headers.len() as u32));
+    for (key, value) in headers {
+        try!(write_bytes(&mut frame, key.as_bytes()));
+        try!(write_bytes(&mut frame, value));
+    }
+    if format.packed {
+        try!(write_bytes(&mut frame, &try!(msg.to_packed_bytes())));
+    } else {
+        try!(write_bytes(&mut frame, &msg.vec));
+    }
+    if format.verify_integrity {
+        try!(write_u32(&mut frame, crc32(&try!(msg.canonicalize()))));
+    }
+    try!(write_bytes(out, &frame));
+    try!(out.flush());
+    Ok(())
+}
+
+/// Read a frame, waiting until all of it is received
+///
+/// Returns `None` if the connection is closed before the start of a frame.
+pub fn read_frame<R: Read>(input: &mut R) -> Result<Option<Msg>> {
+    read_frame_with(input, FrameFormat::default())
+}
+
+/// Read a frame written by `write_packed_frame`
+pub fn read_packed_frame<R: Read>(input: &mut R) -> Result<Option<Msg>> {
+    read_frame_with(input, FrameFormat { packed: true, verify_integrity: false })
+}
+
+/// Read a frame of the format `format`
+///
+/// With `verify_integrity`, a Msg whose checksum differs is `Error::IntegrityMismatch`, and the
+/// whole frame is read: the next frame can still be read.
+pub fn read_frame_with<R: Read>(input: &mut R, format: FrameFormat) -> Result<Option<Msg>> {
+    read_frame_limited(input, format, None)
+}
+
+/// Read a frame of the format `format`, of at most `max_bytes` bytes
+///
+/// A larger frame is `Error::FrameTooLarge`: it is skipped without being kept in memory, and
+/// the next frame can still be read.
+pub fn read_frame_limited<R: Read>(input: &mut R, format: FrameFormat, max_bytes: Option<u64>) -> Result<Option<Msg>> {
+    let len = match read_u32(input) {
+        Ok(len) => len as u64,
+        Err(result::Error::IO(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => { return Ok(None); },
+        Err(e) => { return Err(e); },
+    };
+    if let Some(max) = max_bytes {
+        if len > max {
+            let skipped = try!(io::copy(&mut input.by_ref().take(len), &mut io::sink()));
+            if skipped < len {
+                return Ok(None);
+            }
+            return Err(result::Error::FrameTooLarge(len, max));
+        }
+    }
+    let mut frame = vec![];
+    try!(input.by_ref().take(len).read_to_end(&mut frame));
+    if (frame.len() as u64) < len {
+        return Ok(None);
+    }
+    let mut frame = &frame[..];
+    let action = try!(String::from_utf8(try!(read_bytes(&mut frame))));
+    let kind = try!(read_kind(&mut frame));
+    let mut headers = vec![];
+    for _ in 0..try!(read_u32(&mut frame)) {
+        let key = try!(String::from_utf8(try!(read_bytes(&mut frame))));
+        let value = try!(read_bytes(&mut frame));
+        headers.push((key, value));
+    }
+    let data = try!(read_bytes(&mut frame));
+    let mut msg = if format.packed {
+        try!(Msg::from_packed_bytes(&data))
+    } else {
+        let mut msg = Msg::new();
+        msg.vec = data;
+        msg
+    };
+    if format.verify_integrity {
+        let expected = try!(read_u32(&mut frame));
+        let received = crc32(&try!(msg.canonicalize()));
+        if expected != received {
+            return Err(result::Error::IntegrityMismatch(expected, received));
+        }
+    }
+    msg.action = action;
+    msg.kind = kind;
+    for (key, value) in headers {
+        msg.set_header(key, value);
+    }
+    Ok(Some(msg))
+}
+
+/// The sending side of a network edge
+pub struct NetworkSink {
+    sender: MsgSender,
+    closed: Arc<AtomicBool>,
+    th: JoinHandle<Result<()>>,
+}
+
+impl NetworkSink {
+    /// Connect to a `NetworkSource`, trying `retries` more times if the connection fails
+    ///
+    /// When the connection is lost, the sink reconnects with the same number of retries.
+    /// Once it gives up, the Msgs sent to the sink return `Error::PortClosed`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, retries: usize) -> Result<NetworkSink> {
+        NetworkSink::connect_with(addr, retries, FrameFormat::default())
+    }
+
+    /// Connect to a `NetworkSource` created with `listen_packed`, as `connect`
+    pub fn connect_packed<A: ToSocketAddrs>(addr: A, retries: usize) -> Result<NetworkSink> {
+        NetworkSink::connect_with(addr, retries, FrameFormat { packed: true, verify_integrity: false })
+    }
+
+    /// Connect to a `NetworkSource` listening with the same `format`, as `connect`
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, retries: usize, format: FrameFormat) -> Result<NetworkSink> {
+        let addrs: Vec<SocketAddr> = try!(addr.to_socket_addrs()).collect();
+        let stream = try!(connect_retry(&addrs, retries));
+        let (sched, _) = channel();
+        let (recv, sender) = MsgReceiver::new(0, "network", sched, AgentCtx::new(), false);
+        let closed = Arc::new(AtomicBool::new(false));
+        let closing = closed.clone();
+        let th = thread::spawn(move || {
+            let mut out = BufWriter::new(stream);
+            loop {
+                let msg = match try!(recv.recv_timeout(Duration::from_millis(CLOSE_POLL_MS))) {
+                    Some(msg) => msg,
+                    None => {
+                        if closing.load(Ordering::SeqCst) {
+                            return Ok(());
+                        }
+                        continue;
+                    },
+                };
+                while let Err(e) = write_frame_with(&mut out, &msg, format) {
+                    warn!(target: LOG_TARGET, "NetworkSink: connection lost ({}), reconnecting", e);
+                    out = BufWriter::new(try!(connect_retry(&addrs, retries)));
+                }
+            }
+        });
+        Ok(NetworkSink {
+            sender: sender,
+            closed: closed,
+            th: th,
+        })
+    }
+
+    /// The sender to connect to an output port
+    pub fn sender(&self) -> MsgSender {
+        self.sender.clone()
+    }
+
+    /// Send the Msgs already given to the sink, and close the connection
+    pub fn close(self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        drop(self.sender);
+        self.th.join().unwrap_or_else(|_| { Err(result::Error::Misc("NetworkSink: the thread panicked".into())) })
+    }
+}
+
+fn connect_retry(addrs: &[SocketAddr], retries: usize) -> Result<TcpStream> {
+    let mut attempt = 0;
+    loop {
+        match TcpStream::connect(addrs) {
+            Ok(stream) => {
+                try!(stream.set_nodelay(true));
+                return Ok(stream);
+            },
+            Err(e) => {
+                if attempt >= retries {
+                    return Err(result::Error::IO(e));
+                }
+                attempt += 1;
+                thread::sleep(Duration::from_millis(RETRY_MS));
+            },
+        }
+    }
+}
+
+/// The receiving side of a network edge
+///
+/// The connections are accepted one after the other, so a sink that reconnects continues
+/// the same stream of Msgs.
+pub struct NetworkSource {
+    addr: SocketAddr,
+}
+
+impl NetworkSource {
+    /// Listen on `addr`, and send the received Msgs to `sender`
+    ///
+    /// The source stops once `sender` returns an error, the local port is closed.
+    pub fn listen<A: ToSocketAddrs>(addr: A, sender: MsgSender) -> Result<NetworkSource> {
+        NetworkSource::listen_with(addr, sender, FrameFormat::default())
+    }
+
+    /// Listen on `addr` for a `NetworkSink` created with `connect_packed`, as `listen`
+    pub fn listen_packed<A: ToSocketAddrs>(addr: A, sender: MsgSender) -> Result<NetworkSource> {
+        NetworkSource::listen_with(addr, sender, FrameFormat { packed: true, verify_integrity: false })
+    }
+
+    /// Listen on `addr` for a `NetworkSink` connected with the same `format`, as `listen`
+    ///
+    /// With `verify_integrity`, the corrupted Msgs are dropped and logged, the connection is kept.
+    ///
+    /// If the port of `sender` has a `max_message_size`, a frame larger than this size, plus
+    /// `FRAME_OVERHEAD` bytes, is skipped without being read in memory, and logged.
+    pub fn listen_with<A: ToSocketAddrs>(addr: A, sender: MsgSender, format: FrameFormat) -> Result<NetworkSource> {
+        let listener = try!(TcpListener::bind(addr));
+        let addr = try!(listener.local_addr());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!(target: LOG_TARGET, "NetworkSource: cannot accept a connection : {}", e);
+                        continue;
+                    },
+                };
+                let mut input = BufReader::new(stream);
+                loop {
+                    let max_bytes = sender.port().max_message_size().map(|words| { words.saturating_mul(8).saturating_add(FRAME_OVERHEAD) });
+                    match read_frame_limited(&mut input, format, max_bytes) {
+                        Ok(Some(msg)) => {
+                            if let Err(e) = sender.send(msg) {
+                                info!(target: LOG_TARGET, "NetworkSource: stops, {}", e);
+                                return;
+                            }
+                        },
+                        Ok(None) => { break; },
+                        Err(e @ result::Error::IntegrityMismatch(..)) | Err(e @ result::Error::FrameTooLarge(..)) => {
+                            warn!(target: LOG_TARGET, "NetworkSource: drops a Msg, {}", e);
+                        },
+                        Err(e) => {
+                            warn!(target: LOG_TARGET, "NetworkSource: connection lost, {}", e);
+                            break;
+                        },
+                    }
+                }
+            }
+        });
+        Ok(NetworkSource {
+            addr: addr,
+        })
+    }
+
+    /// The address the source listens on, useful after a bind on the port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// A local edge that spills the oldest Msgs to disk once it buffers more than `mem_limit` bytes
+///
+/// The Msgs sent on `sender()` are delivered to the destination port in the order they were
+/// sent. When the destination is full, they wait in memory; past `mem_limit`, the oldest in
+/// memory are written as frames in a new file of the spill directory, until the memory holds
+/// half of the limit. The files are read back, and removed, before the Msgs still in memory.
+///
+/// Once the output ports sending to the channel are all gone, or after `close`, the Msgs
+/// still buffered, in memory or on disk, are delivered before the thread of the channel ends.
+/// An output port still connected after `close` is read until it is dropped.
+///
+/// The Msgs read back from disk keep their headers, as their sequence number, and their stamp
+/// for the latency tracking.
+///
+/// The size of a Msg is the size of its capnp message and of its headers. The spill directory
+/// is for the channel only, the files left there by a failed channel are removed.
+pub struct SpillingChannel {
+    sender: MsgSender,
+    closed: Arc<AtomicBool>,
+    spilled: Arc<AtomicUsize>,
+    th: JoinHandle<Result<()>>,
+}
+
+impl SpillingChannel {
+    /// Deliver to `dest`, spilling in the directory `dir`, created if needed
+    pub fn new<P: AsRef<Path>>(dest: MsgSender, mem_limit: usize, dir: P) -> Result<SpillingChannel> {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+        let (sched, _) = channel();
+        let (recv, sender) = MsgReceiver::new(0, "spill", sched, AgentCtx::new(), false);
+        let closed = Arc::new(AtomicBool::new(false));
+        let closing = closed.clone();
+        let spilled = Arc::new(AtomicUsize::new(0));
+        let mut buffer = SpillBuffer::new(dir, mem_limit, spilled.clone());
+        let th = thread::spawn(move || {
+            let mut pending: Option<Msg> = None;
+            // Once the upstream agents are gone, what is buffered is still delivered
+            let mut input_closed = false;
+            loop {
+                // Read before the port, a Msg sent by the last output port is then received
+                let alone = closing.load(Ordering::SeqCst) && recv.port().senders() == 0;
+                let mut received = false;
+                while !input_closed {
+                    match recv.try_recv() {
+                        Ok(msg) => {
+                            try!(buffer.push(msg));
+                            received = true;
+                        },
+                        Err(result::Error::MpscTryRecv(_)) => { break; },
+                        Err(result::Error::PortClosed(_)) => { input_closed = true; },
+                        Err(e) => { return Err(e); },
+                    }
+                }
+                if pending.is_none() {
+                    pending = try!(buffer.pop());
+                }
+                let mut delivered = false;
+                if let Some(msg) = pending.take() {
+                    pending = try!(dest.try_send(msg));
+                    delivered = pending.is_none();
+                }
+                if !delivered && !received {
+                    if pending.is_none() && (input_closed || alone) {
+                        return Ok(());
+                    }
+                    if input_closed {
+                        thread::sleep(Duration::from_millis(SPILL_POLL_MS));
+                        continue;
+                    }
+                    // Wait for a Msg, or for some room in the destination
+                    match recv.recv_timeout(Duration::from_millis(SPILL_POLL_MS)) {
+                        Ok(Some(msg)) => { try!(buffer.push(msg)); },
+                        Ok(None) => {},
+                        Err(result::Error::PortClosed(_)) => { input_closed = true; },
+                        Err(e) => { return Err(e); },
+                    }
+                }
+            }
+        });
+        Ok(SpillingChannel {
+            sender: sender,
+            closed: closed,
+            spilled: spilled,
+            th: th,
+        })
+    }
+
+    /// The sender to connect to an output port
+    pub fn sender(&self) -> MsgSender {
+        self.sender.clone()
+    }
+
+    /// The number of Msgs written to disk since the creation of the channel
+    pub fn spilled(&self) -> usize {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    /// Deliver the Msgs given to the channel, waiting for the destination, and stop
+    ///
+    /// The senders connected to an output port, see `MsgSender::set_origin`, may still send:
+    /// `close` returns once they are all dropped and their Msgs delivered.
+    pub fn close(self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        drop(self.sender);
+        self.th.join().unwrap_or_else(|_| { Err(result::Error::Misc("SpillingChannel: the thread panicked".into())) })
+    }
+}
+
+/// The Msgs waiting in a `SpillingChannel`, all the Msgs on disk are older than the ones in memory
+struct SpillBuffer {
+    dir: PathBuf,
+    mem_limit: usize,
+    memory: VecDeque<Msg>,
+    bytes: usize,
+    /// The spill files not read yet, oldest first, with their number of Msgs
+    files: VecDeque<(PathBuf, usize)>,
+    /// The file being read back, and the number of Msgs left in it
+    reading: Option<(PathBuf, BufReader<File>, usize)>,
+    next_file: u64,
+    spilled: Arc<AtomicUsize>,
+    /// The stamps of the Msgs are written relative to this Instant
+    epoch: Instant,
+}
+
+impl SpillBuffer {
+    fn new(dir: PathBuf, mem_limit: usize, spilled: Arc<AtomicUsize>) -> Self {
+        SpillBuffer {
+            dir: dir,
+            mem_limit: mem_limit,
+            memory: VecDeque::new(),
+            bytes: 0,
+            files: VecDeque::new(),
+            reading: None,
+            next_file: 0,
+            spilled: spilled,
+            epoch: Instant::now(),
+        }
+    }
+
+    fn size(msg: &Msg) -> usize {
+        msg.headers().iter().fold(msg.vec.len(), |size, &(key, value)| { size + key.len() + value.len() })
+    }
+
+    fn push(&mut self, msg: Msg) -> Result<()> {
+        self.bytes += SpillBuffer::size(&msg);
+        self.memory.push_back(msg);
+        if self.bytes > self.mem_limit {
+            try!(self.spill());
+        }
+        Ok(())
+    }
+
+    /// Write the oldest Msgs in memory to a new file, until half of the limit is left
+    fn spill(&mut self) -> Result<()> {
+        let path = self.dir.join(format!("spill-{:08}.frames", self.next_file));
+        self.next_file += 1;
+        let mut out = BufWriter::new(try!(File::create(&path)));
+        let mut count = 0;
+        while self.bytes > self.mem_limit / 2 {
+            let msg = match self.memory.pop_front() {
+                Some(msg) => msg,
+                None => { break; },
+            };
+            self.bytes -= SpillBuffer::size(&msg);
+            try!(self.write_stamp(&mut out, msg.stamp()));
+            try!(write_frame(&mut out, &msg));
+            count += 1;
+        }
+        try!(out.flush());
+        debug!(target: LOG_TARGET, "SpillingChannel: {} Msgs spilled to {}", count, path.display());
+        self.spilled.fetch_add(count, Ordering::Relaxed);
+        self.files.push_back((path, count));
+        Ok(())
+    }
+
+    /// Write the stamp of a Msg before its frame: 0 without stamp, 1 or 2 for a stamp after or
+    /// before the epoch, then the nanoseconds from the epoch
+    fn write_stamp<W: Write>(&self, out: &mut W, stamp: Option<Instant>) -> Result<()> {
+        let (tag, offset) = match stamp {
+            None => (0, Duration::new(0, 0)),
+            Some(stamp) if stamp >= self.epoch => (1, stamp - self.epoch),
+            Some(stamp) => (2, self.epoch - stamp),
+        };
+        try!(out.write_all(&[tag]));
+        write_u64(out, offset.as_secs() * 1_000_000_000 + offset.subsec_nanos() as u64)
+    }
+
+    fn read_stamp<R: Read>(&self, input: &mut R) -> Result<Option<Instant>> {
+        let tag = try!(read_u8(input));
+        let nanos = try!(read_u64(input));
+        let offset = Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32);
+        match tag {
+            0 => Ok(None),
+            1 => Ok(Some(self.epoch + offset)),
+            2 => Ok(Some(self.epoch - offset)),
+            tag => Err(result::Error::Misc(format!("SpillingChannel: the stamp tag {} is invalid", tag))),
+        }
+    }
+
+    /// The oldest Msg, from the disk first
+    fn pop(&mut self) -> Result<Option<Msg>> {
+        loop {
+            if let Some((path, mut input, left)) = self.reading.take() {
+                if left == 0 {
+                    try!(fs::remove_file(&path));
+                    continue;
+                }
+                let stamp = try!(self.read_stamp(&mut input));
+                match try!(read_frame(&mut input)) {
+                    Some(mut msg) => {
+                        msg.set_stamp(stamp);
+                        self.reading = Some((path, input, left - 1));
+                        return Ok(Some(msg));
+                    },
+                    None => {
+                        return Err(result::Error::Misc(format!("{}: truncated spill file", path.display())));
+                    },
+                }
+            }
+            match self.files.pop_front() {
+                Some((path, count)) => {
+                    let input = BufReader::new(try!(File::open(&path)));
+                    self.reading = Some((path, input, count));
+                },
+                None => { break; },
+            }
+        }
+        let msg = self.memory.pop_front();
+        if let Some(ref msg) = msg {
+            self.bytes -= SpillBuffer::size(msg);
+        }
+        Ok(msg)
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        let reading = self.reading.take().map(|(path, _, _)| { path });
+        for path in reading.into_iter().chain(self.files.drain(..).map(|(path, _)| { path })) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!(target: LOG_TARGET, "SpillingChannel: cannot remove {} : {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{bytes_msg, msg_bytes};
+
+    use std::env;
+    use std::thread;
+    use std::time::Instant;
+
+    fn spill_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustfbp-spill-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn numbered(i: u32) -> Msg {
+        let mut msg = bytes_msg(&[0; 32]);
+        msg.set_header("n", i.to_string().into_bytes());
+        msg
+    }
+
+    fn built(mut msg: Msg) -> Msg {
+        msg.before_send().unwrap();
+        msg
+    }
+
+    fn number(msg: &Msg) -> u32 {
+        String::from_utf8(msg.get_header("n").unwrap().clone()).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn spilling_channel_keeps_the_order() {
+        let (sched, _) = channel();
+        let (recv, dest) = MsgReceiver::with_capacity(0, "input", sched, AgentCtx::new(), false, 4);
+        let dir = spill_dir("order");
+        let spill = SpillingChannel::new(dest, 1024, &dir).unwrap();
+        let sender = spill.sender();
+        for i in 0..500 {
+            sender.send(numbered(i)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while spill.spilled() < 400 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(spill.spilled() >= 400);
+        assert!(fs::read_dir(&dir).unwrap().count() > 0);
+        let closing = thread::spawn(move || { spill.close() });
+        for i in 0..500 {
+            let mut msg = recv.recv().unwrap();
+            assert_eq!(number(&msg), i);
+            assert_eq!(msg_bytes(&mut msg).unwrap().len(), 32);
+        }
+        closing.join().unwrap().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn spilling_channel_delivers_after_the_upstream_is_gone() {
+        let (sched, _) = channel();
+        let (recv, dest) = MsgReceiver::with_capacity(0, "input", sched, AgentCtx::new(), false, 4);
+        let dir = spill_dir("closed");
+        let spill = SpillingChannel::new(dest, 1024, &dir).unwrap();
+        let mut sender = spill.sender();
+        sender.set_origin(AgentCtx::new());
+        for i in 0..200 {
+            sender.send(numbered(i)).unwrap();
+        }
+        drop(sender);
+        thread::sleep(Duration::from_millis(100));
+        for i in 0..200 {
+            assert_eq!(number(&recv.recv().unwrap()), i);
+        }
+        spill.close().unwrap();
+    }
+
+    #[test]
+    fn spilling_channel_close_waits_for_the_other_senders() {
+        let (sched, _) = channel();
+        let (recv, dest) = MsgReceiver::with_capacity(0, "input", sched, AgentCtx::new(), false, 4);
+        let dir = spill_dir("close");
+        let spill = SpillingChannel::new(dest, 1024, &dir).unwrap();
+        let mut upstream = spill.sender();
+        upstream.set_origin(AgentCtx::new());
+        let closing = thread::spawn(move || { spill.close() });
+        thread::sleep(Duration::from_millis(100));
+        upstream.send(numbered(0)).unwrap();
+        drop(upstream);
+        closing.join().unwrap().unwrap();
+        assert_eq!(number(&recv.try_recv().unwrap()), 0);
+    }
+
+    #[test]
+    fn spilled_msgs_keep_their_stamp_and_sequence() {
+        let (sched, _) = channel();
+        let (recv, dest) = MsgReceiver::with_capacity(0, "input", sched, AgentCtx::new(), false, 4);
+        let dir = spill_dir("stamp");
+        let early = Instant::now();
+        thread::sleep(Duration::from_millis(10));
+        let spill = SpillingChannel::new(dest, 1024, &dir).unwrap();
+        let sender = spill.sender();
+        let mut stamps = vec![];
+        for i in 0..100 {
+            let mut msg = numbered(i);
+            msg.set_sequence(i as u64);
+            // Stamped before the channel, after it, or not at all
+            let stamp = match i % 3 {
+                0 => Some(early),
+                1 => Some(Instant::now()),
+                _ => None,
+            };
+            msg.set_stamp(stamp);
+            stamps.push(stamp);
+            sender.send(msg).unwrap();
+        }
+        drop(sender);
+        thread::sleep(Duration::from_millis(100));
+        assert!(spill.spilled() > 0);
+        let closing = thread::spawn(move || { spill.close() });
+        for i in 0..100 {
+            let msg = recv.recv().unwrap();
+            assert_eq!((number(&msg), msg.sequence(), msg.stamp()), (i, Some(i as u64), stamps[i as usize]));
+        }
+        closing.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn frame_over_the_limit_is_skipped() {
+        let mut frames = vec![];
+        write_frame(&mut frames, &built(numbered(0))).unwrap();
+        let mut large = bytes_msg(&[0; 4096]);
+        large.set_header("n", b"1".to_vec());
+        write_frame(&mut frames, &built(large)).unwrap();
+        write_frame(&mut frames, &built(numbered(2))).unwrap();
+        let mut input = &frames[..];
+        let max = Some(1024);
+        assert_eq!(number(&read_frame_limited(&mut input, FrameFormat::default(), max).unwrap().unwrap()), 0);
+        match read_frame_limited(&mut input, FrameFormat::default(), max) {
+            Err(result::Error::FrameTooLarge(_, 1024)) => {},
+            _ => panic!("the large frame is not rejected"),
+        }
+        assert_eq!(number(&read_frame_limited(&mut input, FrameFormat::default(), max).unwrap().unwrap()), 2);
+        assert!(read_frame_limited(&mut input, FrameFormat::default(), max).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame_with_a_huge_length_ends_the_stream() {
+        let mut frames = vec![];
+        write_u32(&mut frames, 0xffff_fff0).unwrap();
+        frames.extend_from_slice(&[0; 16]);
+        assert!(read_frame(&mut &frames[..]).unwrap().is_none());
+        match read_frame_limited(&mut &frames[..], FrameFormat::default(), Some(1024)) {
+            Ok(None) => {},
+            _ => panic!("the truncated frame is not the end of the stream"),
+        }
+    }
+
+    #[test]
+    fn network_source_skips_the_msgs_over_the_size_limit() {
+        let (sched, _) = channel();
+        let (recv, dest) = MsgReceiver::with_capacity(0, "input", sched, AgentCtx::new(), false, 4);
+        dest.port().set_max_message_size(Some(16));
+        let source = NetworkSource::listen("127.0.0.1:0", dest).unwrap();
+        let mut stream = TcpStream::connect(source.local_addr()).unwrap();
+        write_frame(&mut stream, &built(bytes_msg(&[0; 1024 * 1024]))).unwrap();
+        write_frame(&mut stream, &built(numbered(1))).unwrap();
+        let msg = recv.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(number(&msg), 1);
+    }
+}
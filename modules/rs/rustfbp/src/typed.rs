@@ -0,0 +1,269 @@
+//! Ports that read and build a capnp schema
+//!
+//! `TypedInput` and `TypedOutput` are thin wrappers over a `MsgReceiver` and an output port.
+//! The `TypedOutput` tags each Msg with the type id of its schema, and the `TypedInput` checks
+//! this tag, so a Msg of the wrong schema is an error instead of a reader returning garbage.
+//! A Msg without tag, sent by an agent using the raw `Msg`, is read as before.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! agent! {
+//!     input(input: time_date),
+//!     output(output: time_date),
+//!     fn run(&mut self) -> Result<Signal> {
+//!         let (year, month, day) = {
+//!             let mut input = TypedInput::<time_date::Owned>::new(&self.input.input);
+//!             let date = try!(input.recv());
+//!             (date.get_year(), date.get_month(), date.get_day())
+//!         };
+//!         let output = TypedOutput::<time_date::Owned, _>::new(&self.output.output);
+//!         try!(output.send_with(|mut date: time_date::Builder| {
+//!             date.set_year(year + 1);
+//!             date.set_month(month);
+//!             date.set_day(day);
+//!             Ok(())
+//!         }));
+//!         Ok(End)
+//!     }
+//! }
+//! ```
+
+extern crate capnp;
+
+use result;
+use result::Result;
+use ports::{Msg, MsgReceiver, OutputSend};
+
+use capnp::traits::{Owned, HasTypeId};
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::RwLock;
+
+/// The header holding the type id of the schema of a Msg
+pub const SCHEMA_HEADER: &'static str = "fbp-schema";
+
+/// The type id of the schema `T`
+pub fn schema_id<T>() -> u64 where
+    T: for<'a> Owned<'a>,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    <<T as Owned<'static>>::Reader as HasTypeId>::type_id()
+}
+
+/// Tag a Msg with the type id of the schema `T`
+pub fn set_schema<T>(msg: &mut Msg) where
+    T: for<'a> Owned<'a>,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    let id = schema_id::<T>();
+    let bytes = (0..8).map(|i| { (id >> (i * 8)) as u8 }).collect();
+    msg.set_header(SCHEMA_HEADER, bytes);
+}
+
+/// Check that a Msg is not tagged with another schema than `T`
+pub fn check_schema<T>(msg: &Msg) -> Result<()> where
+    T: for<'a> Owned<'a>,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    if let Some(bytes) = msg.get_header(SCHEMA_HEADER) {
+        let expected = schema_id::<T>();
+        let found = bytes.iter().take(8).enumerate().fold(0u64, |acc, (i, b)| { acc | ((*b as u64) << (i * 8)) });
+        if bytes.len() != 8 || found != expected {
+            return Err(result::Error::SchemaMismatch(expected, found));
+        }
+    }
+    Ok(())
+}
+
+/// The names of the known schemas, by type id
+///
+/// The `agent!` macro registers the edges of the ports of an agent when it is created, with
+/// the name of the edge in the macro, as `time_date`. A `SchemaMismatch` error names its
+/// schemas from the registry of the process, see `registry`.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// typed::registry().write().unwrap().register(0xd9ccffea5a9cf423, "time_date");
+/// assert_eq!(typed::describe_schema(0xd9ccffea5a9cf423), Some("time_date"));
+/// ```
+pub struct SchemaRegistry {
+    names: HashMap<u64, &'static str>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        SchemaRegistry {
+            names: HashMap::new(),
+        }
+    }
+
+    /// Name the schema of type id `id`, a second name replaces the first
+    pub fn register(&mut self, id: u64, name: &'static str) {
+        self.names.insert(id, name);
+    }
+
+    /// The name of the schema of type id `id`, if it is known
+    pub fn describe(&self, id: u64) -> Option<&'static str> {
+        self.names.get(&id).cloned()
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<SchemaRegistry> = RwLock::new(SchemaRegistry::new());
+}
+
+/// The registry of the process
+pub fn registry() -> &'static RwLock<SchemaRegistry> {
+    &REGISTRY
+}
+
+/// Name a schema in the registry of the process
+pub fn register_schema(id: u64, name: &'static str) {
+    // The agents register their edges each time they are created, most are known already
+    let known = registry().read().map(|registry| { registry.describe(id) == Some(name) }).unwrap_or(false);
+    if !known {
+        if let Ok(mut registry) = registry().write() {
+            registry.register(id, name);
+        }
+    }
+}
+
+/// The name of a schema in the registry of the process
+pub fn describe_schema(id: u64) -> Option<&'static str> {
+    registry().read().ok().and_then(|registry| { registry.describe(id) })
+}
+
+/// An input port returning readers of the schema `T`
+pub struct TypedInput<'p, T> {
+    port: &'p MsgReceiver,
+    msg: Option<Msg>,
+    marker: PhantomData<T>,
+}
+
+impl<'p, T> TypedInput<'p, T> where
+    T: for<'a> Owned<'a>,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    pub fn new(port: &'p MsgReceiver) -> Self {
+        TypedInput {
+            port: port,
+            msg: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Receive a Msg and read it
+    ///
+    /// The reader borrows the `TypedInput`, it lives until the next `recv`.
+    pub fn recv<'a>(&'a mut self) -> Result<<T as Owned<'a>>::Reader> {
+        let msg = try!(self.port.recv());
+        try!(check_schema::<T>(&msg));
+        self.msg = Some(msg);
+        self.msg.as_mut().unwrap().read_schema()
+    }
+
+    /// Take the last received Msg, for example to forward it
+    pub fn take_msg(&mut self) -> Option<Msg> {
+        self.msg.take()
+    }
+}
+
+/// An output port building Msgs of the schema `T`
+pub struct TypedOutput<'p, T, O: 'p + OutputSend + ?Sized> {
+    port: &'p O,
+    marker: PhantomData<T>,
+}
+
+impl<'p, T, O: 'p + OutputSend + ?Sized> TypedOutput<'p, T, O> where
+    T: for<'a> Owned<'a>,
+    <T as Owned<'static>>::Reader: HasTypeId,
+{
+    pub fn new(port: &'p O) -> Self {
+        TypedOutput {
+            port: port,
+            marker: PhantomData,
+        }
+    }
+
+    /// Build a Msg with `build` and send it
+    pub fn send_with<F>(&self, build: F) -> Result<()> where
+        F: for<'a> FnOnce(<T as Owned<'a>>::Builder) -> Result<()>,
+    {
+        let mut msg = Msg::new();
+        try!(build(msg.build_schema::<<T as Owned>::Builder>()));
+        set_schema::<T>(&mut msg);
+        self.port.send(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ports::MsgSender;
+    use scheduler::AgentCtx;
+    use testing::{bytes_msg, time_date};
+
+    use std::sync::mpsc::channel;
+
+    fn port() -> (MsgReceiver, MsgSender) {
+        let (sched, _) = channel();
+        MsgReceiver::new(0, "input", sched, AgentCtx::new(), false)
+    }
+
+    #[test]
+    fn send_with_is_read_by_recv() {
+        let (recv, sender) = port();
+        let output = TypedOutput::<time_date::Owned, _>::new(&sender);
+        output.send_with(|mut date: time_date::Builder| {
+            date.set_year(-44);
+            date.set_month(3);
+            date.set_day(15);
+            Ok(())
+        }).unwrap();
+        let mut input = TypedInput::<time_date::Owned>::new(&recv);
+        {
+            let date = input.recv().unwrap();
+            assert_eq!((date.get_year(), date.get_month(), date.get_day()), (-44, 3, 15));
+        }
+        let msg = input.take_msg().unwrap();
+        let tag: Vec<u8> = (0..8).map(|i| { (time_date::TYPE_ID >> (i * 8)) as u8 }).collect();
+        assert_eq!(msg.get_header(SCHEMA_HEADER), Some(&tag));
+    }
+
+    #[test]
+    fn registry_names_the_schemas_of_a_mismatch() {
+        let mut registry = SchemaRegistry::new();
+        registry.register(time_date::TYPE_ID, "date");
+        registry.register(time_date::TYPE_ID, "time_date");
+        assert_eq!(registry.describe(time_date::TYPE_ID), Some("time_date"));
+        assert_eq!(registry.describe(0x1234), None);
+        register_schema(time_date::TYPE_ID, "time_date");
+        assert_eq!(describe_schema(time_date::TYPE_ID), Some("time_date"));
+        let e = result::Error::SchemaMismatch(time_date::TYPE_ID, 0x1234);
+        assert_eq!(format!("{}", e), "Msg error : expected the schema c7a477d0d7b50c3e (time_date), got 1234 (unknown)");
+    }
+
+    #[test]
+    fn wrong_struct_is_an_error_of_recv() {
+        let (recv, sender) = port();
+        let mut tagged = time_date::msg(2017, 3, 15);
+        tagged.set_header(SCHEMA_HEADER, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        sender.send(tagged).unwrap();
+        sender.send(bytes_msg(&[1, 2, 3])).unwrap();
+        let mut input = TypedInput::<time_date::Owned>::new(&recv);
+        match input.recv() {
+            Err(result::Error::SchemaMismatch(expected, found)) => {
+                assert_eq!((expected, found), (time_date::TYPE_ID, 1));
+            },
+            _ => panic!("a Msg tagged with another schema is read"),
+        }
+        // Not tagged, but the list of bytes is not a struct
+        match input.recv() {
+            Err(result::Error::Capnp(_)) => {},
+            _ => panic!("a list is read as a date"),
+        }
+    }
+}
@@ -156,6 +156,13 @@ fn add_graph(mut agent: &mut ThisAgent, name: &str) -> Result<()> {
         agent.state.sched.add_node(n.get_name()?, n.get_sort()?);
     }
 
+    if let Err(e) = validate(&agent.state.sched, i_graph.get_path()?, &port_uses(&i_graph)?) {
+        for node in &subnet.nodes {
+            let _ = agent.state.sched.remove_agent(node as &str);
+        }
+        return Err(e);
+    }
+
     for e in i_graph.borrow().get_edges()?.get_list()?.iter() {
         let o_name = e.get_o_name()?;
         let o_port = e.get_o_port()?;
@@ -218,6 +225,103 @@ fn add_graph(mut agent: &mut ThisAgent, name: &str) -> Result<()> {
     Ok(())
 }
 
+/// A port used by a graph
+struct PortUse {
+    /// The use, as `sender() output -> input receiver()` for an edge
+    what: String,
+    comp: String,
+    port: String,
+    selection: String,
+    output: bool,
+}
+
+/// The ports used by the edges, the external ports and the imsgs of the graph
+fn port_uses(graph: &core_graph::Reader) -> Result<Vec<PortUse>> {
+    let mut uses = vec![];
+    for e in graph.borrow().get_edges()?.get_list()?.iter() {
+        let what = format!("{}() {} -> {} {}()", e.get_o_name()?, e.get_o_port()?, e.get_i_port()?, e.get_i_name()?);
+        uses.push(PortUse {
+            what: what.clone(),
+            comp: e.get_o_name()?.into(),
+            port: e.get_o_port()?.into(),
+            selection: e.get_o_selection()?.into(),
+            output: true,
+        });
+        uses.push(PortUse {
+            what: what,
+            comp: e.get_i_name()?.into(),
+            port: e.get_i_port()?.into(),
+            selection: e.get_i_selection()?.into(),
+            output: false,
+        });
+    }
+    for ext in graph.borrow().get_external_inputs()?.get_list()?.iter() {
+        uses.push(PortUse {
+            what: format!("{} => {} {}()", ext.get_name()?, ext.get_port()?, ext.get_comp()?),
+            comp: ext.get_comp()?.into(),
+            port: ext.get_port()?.into(),
+            selection: ext.get_selection()?.into(),
+            output: false,
+        });
+    }
+    for ext in graph.borrow().get_external_outputs()?.get_list()?.iter() {
+        uses.push(PortUse {
+            what: format!("{}() {} => {}", ext.get_comp()?, ext.get_port()?, ext.get_name()?),
+            comp: ext.get_comp()?.into(),
+            port: ext.get_port()?.into(),
+            selection: ext.get_selection()?.into(),
+            output: true,
+        });
+    }
+    for imsg in graph.borrow().get_imsgs()?.get_list()?.iter() {
+        uses.push(PortUse {
+            what: format!("'{}' -> {} {}()", imsg.get_imsg()?, imsg.get_port()?, imsg.get_comp()?),
+            comp: imsg.get_comp()?.into(),
+            port: imsg.get_port()?.into(),
+            selection: imsg.get_selection()?.into(),
+            output: false,
+        });
+    }
+    Ok(uses)
+}
+
+/// Check that the agents and the ports used by the graph `path` exist, and return all the
+/// problems found in one `InvalidGraph`
+fn validate(sched: &Scheduler, path: &str, uses: &[PortUse]) -> Result<()> {
+    let errors: Vec<String> = uses.iter()
+        .filter_map(|u| {
+            let err = if u.output {
+                check_output(sched, &u.comp, &u.port, &u.selection)
+            } else {
+                check_input(sched, &u.comp, &u.port, &u.selection)
+            };
+            err.map(|err| { format!("{} : {}", u.what, err) })
+        })
+        .collect();
+    if errors.len() > 0 {
+        return Err(result::Error::InvalidGraph(path.into(), errors));
+    }
+    Ok(())
+}
+
+fn check_output(sched: &Scheduler, comp: &str, port: &str, selection: &str) -> Option<result::Error> {
+    let res = if selection == "" {
+        sched.get_schema_output(comp, port)
+    } else {
+        sched.get_schema_output_array(comp, port)
+    };
+    res.err()
+}
+
+fn check_input(sched: &Scheduler, comp: &str, port: &str, selection: &str) -> Option<result::Error> {
+    let res = if selection == "" {
+        sched.get_schema_input(comp, port)
+    } else {
+        sched.get_schema_input_array(comp, port)
+    };
+    res.err()
+}
+
 fn split_input(s: &str) -> Result<(String, Option<String>)> {
     let pos2 = s.find("~");
     if let Some(pos) = pos2 {
@@ -250,3 +354,47 @@ fn connect_ports(sched: &mut Scheduler, o_name: &str, o_port: &str, o_selection:
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfbp::builtin::{VecSink, VecSource};
+
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn port(what: &str, comp: &str, port: &str, output: bool) -> PortUse {
+        PortUse {
+            what: what.into(),
+            comp: comp.into(),
+            port: port.into(),
+            selection: "".into(),
+            output: output,
+        }
+    }
+
+    #[test]
+    fn invalid_subnet_reports_every_problem() {
+        let mut sched = Scheduler::new();
+        sched.add_agent_boxed("source", |id, sc, ctx| { VecSource::create(id, sc, ctx, vec![]) }).unwrap();
+        sched.add_agent_boxed("sink", |id, sc, ctx| { VecSink::create(id, sc, ctx, Arc::new(Mutex::new(vec![]))) }).unwrap();
+        let uses = vec![
+            port("source() output -> input sink()", "source", "output", true),
+            port("source() output -> input sink()", "sink", "input", false),
+            port("'2017-03-15' -> date sink()", "sink", "date", false),
+            port("ghost() output -> input sink()", "ghost", "output", true),
+            port("ghost() output -> input sink()", "sink", "input", false),
+        ];
+        match validate(&sched, "subnet", &uses) {
+            Err(result::Error::InvalidGraph(ref path, ref errors)) => {
+                assert_eq!(path, "subnet");
+                assert_eq!(errors.len(), 2);
+                assert!(errors[0].starts_with("'2017-03-15' -> date sink() : "));
+                assert!(errors[1].starts_with("ghost() output -> input sink() : "));
+            },
+            _ => panic!("the bad port and the unknown agent are not both reported"),
+        }
+        validate(&sched, "subnet", &uses[..2]).unwrap();
+        sched.shutdown(Duration::from_secs(5)).unwrap();
+    }
+}